@@ -0,0 +1,22 @@
+#![no_std]
+
+//! Makes sure the message codec of `torrent-poc` builds and works without `std`.
+//!
+//! Run with `cargo test -p no-std-test`, which builds `torrent-poc` without its default
+//! features. (`cargo test --workspace` turns them back on, as features are unified.)
+
+#[cfg(test)]
+mod tests {
+    use torrent_poc::{Handshake, InfoHash, Message, PeerId, SansIo};
+
+    #[test]
+    fn roundtrip_handshake() {
+        let handshake = Handshake::new(InfoHash::new([1; 20]), PeerId::new([2; 20]));
+
+        let encoded = Message::Handshake(handshake).encode();
+        let decoded = Message::from_partial_buffer(&encoded).unwrap().unwrap();
+
+        assert_eq!(decoded.message, Message::Handshake(handshake));
+        assert_eq!(decoded.consumed_bytes, encoded.len());
+    }
+}
@@ -6,14 +6,34 @@ use crate::actor::outcome::Outcome;
 #[allow(clippy::type_complexity)]
 /// An action to be run by an actor. Actions are fallible, run in the background, but cannot
 /// return any values to the caller directly.
-pub struct Action<A>(Box<dyn FnOnce(&mut A) -> Result<Outcome> + Send + 'static>);
+pub struct Action<A> {
+    f: Box<dyn FnOnce(&mut A) -> Result<Outcome> + Send + 'static>,
+    stops: bool,
+}
 
 impl<A> Action<A> {
     pub fn new(f: impl FnOnce(&mut A) -> Result<Outcome> + Send + 'static) -> Self {
-        Self(Box::new(f))
+        Self {
+            f: Box::new(f),
+            stops: false,
+        }
+    }
+
+    /// An action that only stops the actor. Unlike other actions that stop it, a mailbox
+    /// knows this one for what it is, so it's never thrown away to make room.
+    pub fn stop() -> Self {
+        Self {
+            f: Box::new(|_| Ok(Outcome::Stop)),
+            stops: true,
+        }
+    }
+
+    /// Whether this was made by [Action::stop].
+    pub fn stops(&self) -> bool {
+        self.stops
     }
 
     pub fn run(self, a: &mut A) -> Result<Outcome> {
-        self.0(a)
+        (self.f)(a)
     }
 }
@@ -1,10 +1,25 @@
+use std::fmt::Debug;
+
 use crate::actor::handle::Handle;
 
 /// Actors must implement this trait in order to receive a 'self' handle.
-pub trait Actor: Sized + Send + 'static {
+///
+/// The [Debug] representation is logged if the actor panics.
+pub trait Actor: Debug + Sized + Send + 'static {
     /// This method is called by the actor system when the actor is started.
     fn set_handle(&mut self, _handle: &Handle<Self>) {}
 
-    /// This method is called by the actor system when the actor is stopped.
+    /// This method is called by the actor system when an action returns
+    /// [Outcome::Restart](crate::actor::outcome::Outcome::Restart), or when an action of an
+    /// actor spawned with [Handle::spawn_resuming] failed and it's resumed, before the next
+    /// action runs. The handle given to [set_handle](Self::set_handle) is still the actor's,
+    /// so it isn't given again; anything else that should start over is reset here.
+    ///
+    /// An actor spawned with [Handle::spawn_supervised] is replaced after failing instead, so
+    /// this isn't called then: the new actor gets [set_handle](Self::set_handle).
+    fn restart(&mut self) {}
+
+    /// This method is called by the actor system when the actor is stopped, including when
+    /// [Handle::spawn_supervised] replaces it after it failed.
     fn stop(&mut self) {}
 }
@@ -1,13 +1,20 @@
-use std::sync::mpsc::Sender;
-use std::sync::{Arc, Mutex, TryLockError};
+use std::any::Any;
+use std::panic::{catch_unwind, resume_unwind, AssertUnwindSafe};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Mutex, MutexGuard, TryLockError};
 use std::thread::JoinHandle;
+use std::time::Duration;
 
 use eyre::{bail, eyre, Result};
-use tracing::error;
 
 use crate::actor::action::Action;
 use crate::actor::actor::Actor;
+use crate::actor::mailbox::{
+    mailbox, Mailbox, MailboxReceiver, MailboxSender, Overflow, SendError,
+};
 use crate::actor::outcome::Outcome;
+use crate::actor::supervisor::{RestartPolicy, Restarts, Stopped, Supervisor};
+use crate::log::error;
 
 /// A handle to an actor. It can be used to send actions to the actor, and to stop it.
 ///
@@ -21,7 +28,33 @@ where
     A: Actor,
 {
     join_handle: Arc<Mutex<Option<JoinHandle<()>>>>,
-    sender: Sender<Action<A>>,
+    sender: MailboxSender<A>,
+    /// Set if an action panicked, so later calls can say so instead of failing vaguely.
+    panic: Arc<Mutex<Option<String>>>,
+    /// Whether the actor resumes after failing, in which case actions keep being queued.
+    supervised: bool,
+    /// Cleared once the actor thread has finished.
+    running: Arc<AtomicBool>,
+    /// Set for actors made by [Handle::spawn_manual], which don't have a thread.
+    #[cfg(any(test, feature = "test-util"))]
+    manual: Option<Arc<Manual<A>>>,
+}
+
+/// An actor without a thread, whose actions are run by [Handle::run_pending] instead.
+#[cfg(any(test, feature = "test-util"))]
+struct Manual<A> {
+    /// Taken once the actor stops.
+    actor: Mutex<Option<A>>,
+    receiver: MailboxReceiver<A>,
+    /// The thread running the actions right now, if any.
+    runner: Mutex<Option<std::thread::ThreadId>>,
+}
+
+#[cfg(any(test, feature = "test-util"))]
+impl<A> std::fmt::Debug for Manual<A> {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("Manual").finish_non_exhaustive()
+    }
 }
 
 // Manual Clone implementation because A does not need to be Clone for Handle<A> to be Clone.
@@ -33,6 +66,11 @@ where
         Self {
             join_handle: self.join_handle.clone(),
             sender: self.sender.clone(),
+            panic: self.panic.clone(),
+            supervised: self.supervised,
+            running: self.running.clone(),
+            #[cfg(any(test, feature = "test-util"))]
+            manual: self.manual.clone(),
         }
     }
 }
@@ -43,38 +81,340 @@ where
 {
     /// Turns almost any Send self-mutating type into an actor.
     /// The only requirement is that it implements the Actor trait.
-    pub fn spawn(mut actor: A) -> Self {
-        let (sender, receiver) = std::sync::mpsc::channel::<Action<A>>();
-        let join_handle = Arc::new(Mutex::new(None));
-        let s = Self {
-            join_handle: join_handle.clone(),
-            sender,
-        };
+    #[cfg(test)]
+    pub fn spawn(actor: A) -> Self {
+        Self::spawn_with_mailbox(actor, Mailbox::Unbounded)
+    }
+
+    /// Turns the actor into an actor like `spawn`, but without a thread: its actions are only
+    /// run when [Handle::run_pending] is called, on the thread that calls it. This makes tests
+    /// deterministic, as nothing happens behind their back.
+    ///
+    /// [Handle::ask] and [Handle::stop] run the pending actions themselves, as they'd wait
+    /// forever otherwise.
+    #[cfg(any(test, feature = "test-util"))]
+    // Only the crate's own tests make manual actors so far.
+    #[cfg_attr(not(test), allow(dead_code))]
+    pub fn spawn_manual(mut actor: A) -> Self {
+        let (mut s, receiver) = Self::channel(false, Mailbox::Unbounded);
         actor.set_handle(&s);
-        *join_handle.lock().expect("mutex to not be poisoned") =
-            Some(std::thread::spawn(move || {
-                while let Ok(action) = receiver.recv() {
-                    let outcome = action.run(&mut actor);
-                    match outcome {
-                        Ok(Outcome::Continue) => {}
-                        Ok(Outcome::Stop) => break,
-                        Err(e) => {
-                            error!("Unhandled error in actor thread: {:?}", e);
-                            break;
-                        }
-                    }
+        s.manual = Some(Arc::new(Manual {
+            actor: Mutex::new(Some(actor)),
+            receiver,
+            runner: Mutex::new(None),
+        }));
+        s
+    }
+
+    /// Run the actions that are waiting for an actor made by [Handle::spawn_manual], including
+    /// the ones they queue up for it in turn, and return how many ran. Does nothing if another
+    /// thread is already running them.
+    #[cfg(any(test, feature = "test-util"))]
+    pub fn run_pending(&self) -> usize {
+        let manual = self
+            .manual
+            .as_ref()
+            .expect("only actors made by spawn_manual to be run by hand");
+        let Ok(mut guard) = manual.actor.try_lock() else {
+            return 0;
+        };
+        *manual.runner.lock().expect("mutex to not be poisoned") =
+            Some(std::thread::current().id());
+        let mut count = 0;
+        while let Some(actor) = guard.as_mut() {
+            let Some(action) = manual.receiver.try_recv() else {
+                break;
+            };
+            count += 1;
+            if Self::run_action(actor, action, &self.panic).is_some() {
+                if let Some(mut actor) = guard.take() {
+                    actor.stop();
                 }
+                self.running.store(false, Ordering::Release);
+            }
+        }
+        *manual.runner.lock().expect("mutex to not be poisoned") = None;
+        count
+    }
+
+    /// Turns the actor into an actor like `spawn`, with its actions queued in `mailbox`.
+    /// A bounded one keeps a flood of actions from using up all memory; what happens to
+    /// actions sent once it's full depends on its [Overflow](crate::actor::mailbox::Overflow).
+    #[cfg(test)]
+    pub fn spawn_with_mailbox(mut actor: A, mailbox: Mailbox) -> Self {
+        let (s, receiver) = Self::channel(false, mailbox);
+        actor.set_handle(&s);
+        let panic = s.panic.clone();
+        s.start_thread(move || {
+            Self::run(&mut actor, &receiver, &panic);
+            actor.stop();
+        });
+        s
+    }
+
+    /// Like [Handle::spawn_with_mailbox], but supervised: when an action fails or panics, the
+    /// actor is stopped and `factory` makes a fresh one to take over, as long as `policy`
+    /// allows it. The factory is given the failed actor to take whatever it needs from, e.g.
+    /// its settings. `start` is the first thing every one of them runs, before any action.
+    ///
+    /// The handle stays the same, with the new actor behind it. Actions that were still
+    /// queued for the failed one are thrown away, so the new one starts with `start` as the
+    /// original did. If one of them was stopping the actor, it stays stopped instead. The
+    /// thread holds on to a handle, so the actor only stops when told to, or when it gives up.
+    pub fn spawn_supervised(
+        mut actor: A,
+        start: fn(&mut A) -> Result<Outcome>,
+        mailbox: Mailbox,
+        policy: RestartPolicy,
+        factory: impl FnMut(&A) -> Result<A> + Send + 'static,
+    ) -> Self {
+        let (s, receiver) = Self::channel(true, mailbox);
+        let mut supervisor = Supervisor::new(factory, policy);
+        actor.set_handle(&s);
+        let handle = s.clone();
+        s.start_thread(move || {
+            let panic = &handle.panic;
+            let run = |actor: &mut A| {
+                Self::run_action(actor, Action::new(start), panic)
+                    .unwrap_or_else(|| Self::run(actor, &receiver, panic))
+            };
+            let mut stopped = run(&mut actor);
+            loop {
                 actor.stop();
-            }));
+                let Some(replacement) = supervisor.restart(stopped, &actor) else {
+                    break;
+                };
+                if receiver.discard_pending() {
+                    break;
+                }
+                // The panic is over with, the new actor is fine.
+                *panic.lock().expect("mutex to not be poisoned") = None;
+                actor = replacement;
+                actor.set_handle(&handle);
+                stopped = run(&mut actor);
+            }
+        });
         s
     }
 
+    /// Like [Handle::spawn_with_mailbox], but supervised: when an action fails or panics, the
+    /// actor carries on with the next action, after [Actor::restart] resets whatever it has
+    /// to, as long as `policy` allows it. The actor isn't replaced or stopped in between, so
+    /// nothing is lost but what the failed action was doing, and the handle stays the same.
+    ///
+    /// This is for actors that [Handle::spawn_supervised] couldn't make afresh, as the state
+    /// they hold can't be had again, e.g. the torrent, and connections we didn't dial.
+    pub fn spawn_resuming(mut actor: A, mailbox: Mailbox, policy: RestartPolicy) -> Self {
+        let (s, receiver) = Self::channel(true, mailbox);
+        let mut restarts = Restarts::new(policy);
+        actor.set_handle(&s);
+        let panic = s.panic.clone();
+        s.start_thread(move || {
+            while restarts.restart_after(Self::run(&mut actor, &receiver, &panic)) {
+                // The panic is over with, the actor is fine again.
+                *panic.lock().expect("mutex to not be poisoned") = None;
+                actor.restart();
+            }
+            actor.stop();
+        });
+        s
+    }
+
+    fn channel(supervised: bool, mailbox_kind: Mailbox) -> (Self, MailboxReceiver<A>) {
+        let (sender, receiver) = mailbox(mailbox_kind);
+        let s = Self {
+            join_handle: Arc::new(Mutex::new(None)),
+            sender,
+            panic: Arc::new(Mutex::new(None)),
+            supervised,
+            running: Arc::new(AtomicBool::new(true)),
+            #[cfg(any(test, feature = "test-util"))]
+            manual: None,
+        };
+        (s, receiver)
+    }
+
+    fn start_thread(&self, f: impl FnOnce() + Send + 'static) {
+        let running = self.running.clone();
+        let thread = std::thread::spawn(move || {
+            // Cleared even if the actor panics while stopping.
+            struct Finished(Arc<AtomicBool>);
+            impl Drop for Finished {
+                fn drop(&mut self) {
+                    self.0.store(false, Ordering::Release);
+                }
+            }
+            let _finished = Finished(running);
+            f();
+        });
+        *self.join_handle.lock().expect("mutex to not be poisoned") = Some(thread);
+    }
+
+    /// Whether the actor thread is still running. Once this returns `false`, every further
+    /// action will fail.
+    pub fn is_running(&self) -> bool {
+        self.running.load(Ordering::Acquire)
+    }
+
+    /// Whether both handles are to the same actor, e.g. to tell an actor apart from one that
+    /// replaced it.
+    pub fn is_same(&self, other: &Self) -> bool {
+        Arc::ptr_eq(&self.running, &other.running)
+    }
+
+    /// Another handle to the same actor, which handles a full bounded mailbox as `overflow`
+    /// says instead of how the mailbox was spawned with. An unbounded mailbox is never full.
+    pub fn with_overflow(&self, overflow: Overflow) -> Self {
+        Self {
+            sender: self.sender.with_overflow(overflow),
+            ..self.clone()
+        }
+    }
+
+    /// Whether an action didn't fit in the mailbox with [Overflow::Stop], so that the actor
+    /// is stopping rather than running anything else.
+    pub fn has_overflowed(&self) -> bool {
+        self.sender.has_overflowed()
+    }
+
+    /// Block until the actor stops by itself, or is stopped by someone else, without asking it
+    /// to stop. Reports a panic in the actor thread, like [Handle::stop].
+    pub fn wait(&self) -> Result<()> {
+        let guard = self
+            .join_handle
+            .lock()
+            .map_err(|_| eyre!("Actor thread poisoned"))?;
+        self.join(guard)
+    }
+
+    /// Run actions until one stops the actor, or there are no more coming, and tell which.
+    fn run(actor: &mut A, receiver: &MailboxReceiver<A>, panic: &Mutex<Option<String>>) -> Stopped {
+        while let Ok(action) = receiver.recv() {
+            if let Some(stopped) = Self::run_action(actor, action, panic) {
+                return stopped;
+            }
+        }
+        Stopped::Done
+    }
+
+    /// Run a single action. Returns `None` if the actor carries on, or how it stopped if not.
+    fn run_action(
+        actor: &mut A,
+        action: Action<A>,
+        panic: &Mutex<Option<String>>,
+    ) -> Option<Stopped> {
+        // The actor is only stopped after a panic, so it being in a broken state is fine.
+        let run = || {
+            let outcome = action.run(actor);
+            if let Ok(Outcome::Restart) = outcome {
+                actor.restart();
+            }
+            outcome
+        };
+        let outcome = match catch_unwind(AssertUnwindSafe(run)) {
+            Ok(outcome) => outcome,
+            Err(e) => {
+                let msg = record_panic(panic, e.as_ref());
+                error!("Panic in actor thread: {msg}, actor was {actor:?}");
+                return Some(Stopped::Panicked);
+            }
+        };
+        match outcome {
+            Ok(Outcome::Continue | Outcome::Restart) => None,
+            Ok(Outcome::Stop) => Some(Stopped::Done),
+            Err(e) => {
+                error!("Unhandled error in actor thread: {:?}", e);
+                Some(Stopped::Failed)
+            }
+        }
+    }
+
+    /// The error for when the actor is gone, which says so if it's because it panicked.
+    fn stopped_error(&self, context: &str) -> eyre::Report {
+        match &*self.panic.lock().expect("mutex to not be poisoned") {
+            Some(msg) => eyre!("Actor panicked: {msg}"),
+            None => eyre!("{context}"),
+        }
+    }
+
     /// Enqueue an action to be run by the actor thread.
     /// The action will not be able to return any values, and will be run in the background.
     pub fn act(&self, f: impl FnOnce(&mut A) -> Result<Outcome> + Send + 'static) -> Result<()> {
-        self.sender
-            .send(Action::new(f))
-            .map_err(|_| eyre!("Failed to send action to actor"))
+        // The actor thread might still be winding down, with the channel open. A supervised
+        // actor is about to resume instead, and the action is for it once it has.
+        if !self.supervised {
+            if let Some(msg) = &*self.panic.lock().expect("mutex to not be poisoned") {
+                bail!("Actor panicked: {msg}");
+            }
+        }
+        // A mailbox that drops the oldest action stays open for as long as there are handles,
+        // so that alone doesn't tell that the actor is gone.
+        if !self.is_running() {
+            return Err(self.stopped_error("Failed to send action to actor"));
+        }
+        self.sender.send(Action::new(f)).map_err(|e| match e {
+            SendError::Closed => self.stopped_error("Failed to send action to actor"),
+            SendError::Full => eyre!("Actor mailbox is full"),
+        })
+    }
+
+    /// Run an action on the actor thread, and wait for it to return a value.
+    /// Unlike with [Handle::act], an error is handed back to the caller instead of stopping
+    /// the actor.
+    ///
+    /// Never call this from the actor's own thread, as it would wait for itself forever.
+    pub fn ask<T>(&self, f: impl FnOnce(&mut A) -> Result<T> + Send + 'static) -> Result<T>
+    where
+        T: Send + 'static,
+    {
+        let (sender, receiver) = std::sync::mpsc::sync_channel(1);
+        let panic = self.panic.clone();
+        self.act(move |actor| {
+            // Record the panic before `sender` is dropped, so the asker can tell why there's
+            // no answer. The actor thread takes care of the rest.
+            let answer = catch_unwind(AssertUnwindSafe(|| f(actor))).unwrap_or_else(|e| {
+                record_panic(&panic, e.as_ref());
+                resume_unwind(e)
+            });
+            // The asker might have given up waiting, that's fine.
+            let _ = sender.send(answer);
+            Ok(Outcome::Continue)
+        })?;
+        #[cfg(any(test, feature = "test-util"))]
+        if let Some(manual) = &self.manual {
+            let runner = *manual.runner.lock().expect("mutex to not be poisoned");
+            assert!(
+                runner != Some(std::thread::current().id()),
+                "ask on a manual actor from one of its own actions would wait for itself forever"
+            );
+            self.run_pending();
+        }
+        receiver
+            .recv()
+            .map_err(|_| self.stopped_error("Actor stopped before answering"))?
+    }
+
+    /// Enqueue an action to be run by the actor thread every `interval`, until the actor stops.
+    /// The timing is best-effort: the interval is measured from when the previous run was
+    /// enqueued, not from when it finished running.
+    pub fn act_every(
+        &self,
+        interval: Duration,
+        f: impl Fn(&mut A) -> Result<Outcome> + Send + Sync + 'static,
+    ) {
+        // A run that doesn't fit in a bounded mailbox is skipped, rather than waiting for room,
+        // or taking the place of, or stopping, whatever else the actor has to do.
+        let handle = self.with_overflow(Overflow::Error);
+        let f = Arc::new(f);
+        // The ticker thread notices the actor stopping the next time it tries to enqueue,
+        // so there's no need to keep track of it.
+        let _ = std::thread::spawn(move || loop {
+            std::thread::sleep(interval);
+            let f = f.clone();
+            if handle.act(move |actor| f(actor)).is_err() && !handle.is_running() {
+                break;
+            }
+        });
     }
 
     /// Stop the actor thread. This will give the actor thread a chance to finish its currently
@@ -84,22 +424,17 @@ where
     pub fn stop(&self) -> Result<()> {
         // Attempt to stop the actor thread if it isn't already stopped.
         // TODO: Use a separate high-priority one-shot channel to signal the actor thread to stop.
-        let _ = self.act(|_| Ok(Outcome::Stop));
-        match self.join_handle.try_lock() {
-            Ok(mut guard) => {
-                if let Some(handle) = guard.take() {
-                    if let Err(e) = handle.join() {
-                        let msg = if let Some(msg) = e.downcast_ref::<&'static str>() {
-                            (*msg).to_string()
-                        } else if let Some(msg) = e.downcast_ref::<String>() {
-                            msg.clone()
-                        } else {
-                            format!("?{e:?}")
-                        };
-                        bail!("Panic in actor thread: {msg}");
-                    }
-                }
+        // Stopping must not be dropped or refused because the mailbox is full.
+        let _ = self.sender.send_blocking(Action::stop());
+        #[cfg(any(test, feature = "test-util"))]
+        if self.manual.is_some() {
+            self.run_pending();
+            if let Some(msg) = &*self.panic.lock().expect("mutex to not be poisoned") {
+                bail!("Panic in actor thread: {msg}");
             }
+        }
+        match self.join_handle.try_lock() {
+            Ok(guard) => self.join(guard)?,
             Err(TryLockError::WouldBlock) => {
                 // This is fine, we can get into circular dependencies with handles being cloned around.
                 // If this would block, the actor thread is already about to be stopped from another thread.
@@ -111,14 +446,50 @@ where
 
         Ok(())
     }
+
+    /// Wait for the actor thread to finish, if no one else has already.
+    fn join(&self, mut guard: MutexGuard<Option<JoinHandle<()>>>) -> Result<()> {
+        if let Some(handle) = guard.take() {
+            // Panics in actions are caught, but not those in `Actor::stop`.
+            if let Err(e) = handle.join() {
+                bail!("Panic in actor thread: {}", panic_message(e.as_ref()));
+            }
+            if let Some(msg) = &*self.panic.lock().expect("mutex to not be poisoned") {
+                bail!("Panic in actor thread: {msg}");
+            }
+        }
+        Ok(())
+    }
+}
+
+fn record_panic(panic: &Mutex<Option<String>>, e: &(dyn Any + Send)) -> String {
+    let msg = panic_message(e);
+    *panic.lock().expect("mutex to not be poisoned") = Some(msg.clone());
+    msg
+}
+
+fn panic_message(e: &(dyn Any + Send)) -> String {
+    if let Some(msg) = e.downcast_ref::<&'static str>() {
+        (*msg).to_string()
+    } else if let Some(msg) = e.downcast_ref::<String>() {
+        msg.clone()
+    } else {
+        format!("?{e:?}")
+    }
 }
 
 #[cfg(test)]
 mod tests {
+    use std::sync::mpsc::Receiver;
     use std::sync::{Arc, Mutex};
+    use std::thread::sleep;
+    use std::time::Duration;
 
     use crate::actor::actor::Actor;
     use crate::actor::handle::Handle;
+    use crate::actor::mailbox::{Mailbox, Overflow};
+    use crate::actor::outcome::Outcome;
+    use crate::actor::supervisor::RestartPolicy;
 
     #[derive(Debug, Default, Clone)]
     struct TestActor {
@@ -138,12 +509,12 @@ mod tests {
         assert!(actor.handle.lock().unwrap().is_some());
     }
 
-    #[derive(Default, Clone)]
+    #[derive(Debug, Default, Clone)]
     struct CyclicActorA {
         other: Arc<Mutex<Option<Handle<CyclicActorB>>>>,
     }
 
-    #[derive(Default, Clone)]
+    #[derive(Debug, Default, Clone)]
     struct CyclicActorB {
         other: Arc<Mutex<Option<Handle<CyclicActorA>>>>,
     }
@@ -152,6 +523,540 @@ mod tests {
 
     impl Actor for CyclicActorB {}
 
+    #[test]
+    fn full_mailbox_refuses_actions() {
+        let mailbox = Mailbox::Bounded {
+            capacity: 2,
+            overflow: Overflow::Error,
+        };
+        let handle = Handle::spawn_with_mailbox(CountingActor::default(), mailbox);
+        let (blocked, unblock) = std::sync::mpsc::channel::<()>();
+        let (started, wait_started) = std::sync::mpsc::channel::<()>();
+        handle
+            .act(move |_| {
+                started.send(()).unwrap();
+                let _ = unblock.recv();
+                Ok(Outcome::Continue)
+            })
+            .unwrap();
+        wait_started.recv().unwrap();
+        let (ran, wait_ran) = std::sync::mpsc::channel::<()>();
+        let increment = move |actor: &mut CountingActor| {
+            actor.count += 1;
+            let _ = ran.send(());
+            Ok(Outcome::Continue)
+        };
+
+        handle.act(increment.clone()).unwrap();
+        handle.act(increment.clone()).unwrap();
+        let _ = handle.act(increment).unwrap_err();
+
+        blocked.send(()).unwrap();
+        // Asking has to wait for room in the mailbox too.
+        wait_ran.recv().unwrap();
+        wait_ran.recv().unwrap();
+        assert_eq!(handle.ask(|actor| Ok(actor.count)).unwrap(), 2);
+        handle.stop().unwrap();
+    }
+
+    #[test]
+    fn full_mailbox_drops_the_oldest_action() {
+        let mailbox = Mailbox::Bounded {
+            capacity: 2,
+            overflow: Overflow::DropOldest,
+        };
+        let handle = Handle::spawn_with_mailbox(CountingActor::default(), mailbox);
+        let (blocked, unblock) = std::sync::mpsc::channel::<()>();
+        let (started, wait_started) = std::sync::mpsc::channel::<()>();
+        handle
+            .act(move |_| {
+                started.send(()).unwrap();
+                let _ = unblock.recv();
+                Ok(Outcome::Continue)
+            })
+            .unwrap();
+        wait_started.recv().unwrap();
+
+        let (ran, wait_ran) = std::sync::mpsc::channel();
+        for add in [1, 10, 100] {
+            let ran = ran.clone();
+            handle
+                .act(move |actor| {
+                    actor.count += add;
+                    let _ = ran.send(add);
+                    Ok(Outcome::Continue)
+                })
+                .unwrap();
+        }
+
+        // Adding 1 was pushed out to make room for adding 100.
+        blocked.send(()).unwrap();
+        assert_eq!(
+            [wait_ran.recv().unwrap(), wait_ran.recv().unwrap()],
+            [10, 100]
+        );
+        assert_eq!(handle.ask(|actor| Ok(actor.count)).unwrap(), 110);
+        handle.stop().unwrap();
+    }
+
+    #[test]
+    fn dropping_the_oldest_action_never_drops_a_stop() {
+        let mailbox = Mailbox::Bounded {
+            capacity: 2,
+            overflow: Overflow::DropOldest,
+        };
+        let handle = Handle::spawn_with_mailbox(CountingActor::default(), mailbox);
+        let (blocked, unblock) = std::sync::mpsc::channel::<()>();
+        let (started, wait_started) = std::sync::mpsc::channel::<()>();
+        handle
+            .act(move |_| {
+                started.send(()).unwrap();
+                let _ = unblock.recv();
+                Ok(Outcome::Continue)
+            })
+            .unwrap();
+        wait_started.recv().unwrap();
+
+        let (stopped, wait_stopped) = std::sync::mpsc::channel();
+        let stopping = handle.clone();
+        let _ = std::thread::spawn(move || stopped.send(stopping.stop()));
+        sleep(Duration::from_millis(100));
+        // The stop is the oldest action, and would be the one pushed out.
+        for _ in 0..2 {
+            handle
+                .act(|actor| {
+                    actor.count += 1;
+                    Ok(Outcome::Continue)
+                })
+                .unwrap();
+        }
+
+        blocked.send(()).unwrap();
+        wait_stopped
+            .recv_timeout(Duration::from_secs(1))
+            .unwrap()
+            .unwrap();
+        assert!(!handle.is_running());
+    }
+
+    #[test]
+    fn overflowing_a_mailbox_stops_the_actor() {
+        let mailbox = Mailbox::Bounded {
+            capacity: 2,
+            overflow: Overflow::Block,
+        };
+        let handle = Handle::spawn_with_mailbox(CountingActor::default(), mailbox);
+        let impatient = handle.with_overflow(Overflow::Stop);
+        let (blocked, unblock) = std::sync::mpsc::channel::<()>();
+        let (started, wait_started) = std::sync::mpsc::channel::<()>();
+        handle
+            .act(move |_| {
+                started.send(()).unwrap();
+                let _ = unblock.recv();
+                Ok(Outcome::Continue)
+            })
+            .unwrap();
+        wait_started.recv().unwrap();
+        let increment = |actor: &mut CountingActor| {
+            actor.count += 1;
+            Ok(Outcome::Continue)
+        };
+
+        impatient.act(increment).unwrap();
+        impatient.act(increment).unwrap();
+        assert!(!impatient.has_overflowed());
+        // Doesn't wait for room, unlike the handle it was made from.
+        impatient.act(increment).unwrap();
+        assert!(impatient.has_overflowed());
+        assert!(handle.has_overflowed());
+
+        // The queued actions never run, the actor stops instead.
+        blocked.send(()).unwrap();
+        handle.wait().unwrap();
+        assert!(!handle.is_running());
+        let _ = handle.ask(|actor| Ok(actor.count)).unwrap_err();
+    }
+
+    #[derive(Debug, Default)]
+    struct CountingActor {
+        count: u32,
+        restarts: u32,
+    }
+
+    impl Actor for CountingActor {
+        fn restart(&mut self) {
+            self.count = 0;
+            self.restarts += 1;
+        }
+    }
+
+    #[test]
+    fn restart_resets_the_actor_and_keeps_it_running() {
+        let handle = Handle::spawn(CountingActor::default());
+        let increment = |actor: &mut CountingActor| {
+            actor.count += 1;
+            Ok(Outcome::Continue)
+        };
+        handle.act(increment).unwrap();
+        handle.act(increment).unwrap();
+
+        handle.act(|_| Ok(Outcome::Restart)).unwrap();
+        handle.act(increment).unwrap();
+
+        let (count, restarts) = handle
+            .ask(|actor| Ok((actor.count, actor.restarts)))
+            .unwrap();
+        assert_eq!((count, restarts), (1, 1));
+        assert!(handle.is_running());
+        handle.stop().unwrap();
+    }
+
+    #[test]
+    fn act_every_runs_repeatedly() {
+        let actor = TestActor::default();
+        let handle = Handle::spawn(actor);
+        let count = Arc::new(Mutex::new(0));
+        handle.act_every(Duration::from_millis(10), {
+            let count = count.clone();
+            move |_| {
+                *count.lock().unwrap() += 1;
+                Ok(Outcome::Continue)
+            }
+        });
+        sleep(Duration::from_millis(200));
+        handle.stop().unwrap();
+        assert!(*count.lock().unwrap() >= 2);
+    }
+
+    #[test]
+    fn act_every_skips_runs_that_dont_fit() {
+        let mailbox = Mailbox::Bounded {
+            capacity: 1,
+            overflow: Overflow::Stop,
+        };
+        let handle = Handle::spawn_with_mailbox(CountingActor::default(), mailbox);
+        let (unblock, blocked) = std::sync::mpsc::channel::<()>();
+        handle
+            .act(move |_| {
+                let _ = blocked.recv();
+                Ok(Outcome::Continue)
+            })
+            .unwrap();
+        handle.act_every(Duration::from_millis(10), |actor| {
+            actor.count += 1;
+            Ok(Outcome::Continue)
+        });
+
+        // Only one run fits while the actor is busy, the others would have stopped it.
+        sleep(Duration::from_millis(100));
+        unblock.send(()).unwrap();
+        sleep(Duration::from_millis(50));
+
+        assert!(handle.is_running());
+        let count = handle
+            .with_overflow(Overflow::Block)
+            .ask(|actor| Ok(actor.count));
+        assert!(count.unwrap() >= 2);
+        handle.stop().unwrap();
+    }
+
+    #[test]
+    fn ask_returns_value() {
+        #[derive(Debug, Default)]
+        struct Counter(usize);
+        impl Actor for Counter {}
+
+        let handle = Handle::spawn(Counter::default());
+        handle
+            .act(|counter| {
+                counter.0 += 1;
+                Ok(Outcome::Continue)
+            })
+            .unwrap();
+
+        assert_eq!(handle.ask(|counter| Ok(counter.0)).unwrap(), 1);
+        assert!(handle
+            .ask(|_| -> eyre::Result<()> { eyre::bail!("nope") })
+            .is_err());
+        // Errors are handed back to the caller, the actor keeps running.
+        assert_eq!(handle.ask(|counter| Ok(counter.0 + 1)).unwrap(), 2);
+
+        handle.stop().unwrap();
+        assert!(handle.ask(|counter| Ok(counter.0)).is_err());
+    }
+
+    #[test]
+    fn panicking_action_is_reported() {
+        let handle = Handle::spawn(TestActor::default());
+        let answer = handle.ask(|_| -> eyre::Result<()> { panic!("oh no") });
+
+        assert_eq!(answer.unwrap_err().to_string(), "Actor panicked: oh no");
+        let error = handle.act(|_| Ok(Outcome::Continue)).unwrap_err();
+        assert_eq!(error.to_string(), "Actor panicked: oh no");
+        let error = handle.stop().unwrap_err();
+        assert_eq!(error.to_string(), "Panic in actor thread: oh no");
+    }
+
+    /// Counts the actors that came before it, and says so once it has its handle.
+    #[derive(Debug)]
+    struct Generation {
+        generation: usize,
+        started: std::sync::mpsc::Sender<usize>,
+    }
+    impl Actor for Generation {
+        fn set_handle(&mut self, _handle: &Handle<Self>) {
+            let _ = self.started.send(self.generation);
+        }
+    }
+
+    fn generations(policy: RestartPolicy) -> (Handle<Generation>, Receiver<usize>) {
+        let (started, generations) = std::sync::mpsc::channel();
+        let handle = Handle::spawn_supervised(
+            Generation {
+                generation: 0,
+                started,
+            },
+            |_| Ok(Outcome::Continue),
+            Mailbox::Unbounded,
+            policy,
+            |failed: &Generation| {
+                Ok(Generation {
+                    generation: failed.generation + 1,
+                    started: failed.started.clone(),
+                })
+            },
+        );
+        (handle, generations)
+    }
+
+    #[test]
+    fn failed_actor_is_restarted_from_the_factory() {
+        let (handle, started) = generations(RestartPolicy::default());
+        assert_eq!(started.recv().unwrap(), 0);
+
+        handle.act(|_| eyre::bail!("first action fails")).unwrap();
+        // What's queued for the failed actor is thrown away, so wait for the new one.
+        assert_eq!(started.recv().unwrap(), 1);
+        assert_eq!(handle.ask(|actor| Ok(actor.generation)).unwrap(), 1);
+
+        let _ = handle.ask(|_| -> eyre::Result<()> { panic!("oh no") });
+        assert_eq!(started.recv().unwrap(), 2);
+        assert_eq!(handle.ask(|actor| Ok(actor.generation)).unwrap(), 2);
+        handle.stop().unwrap();
+    }
+
+    #[test]
+    fn supervisor_gives_up_after_too_many_restarts() {
+        let policy = RestartPolicy {
+            max_restarts: 1,
+            window: Duration::from_secs(60),
+            ..RestartPolicy::default()
+        };
+        let (handle, started) = generations(policy);
+        assert_eq!(started.recv().unwrap(), 0);
+
+        handle.act(|_| eyre::bail!("fails")).unwrap();
+        assert_eq!(started.recv().unwrap(), 1);
+        handle.act(|_| eyre::bail!("fails again")).unwrap();
+
+        handle.wait().unwrap();
+        assert!(!handle.is_running());
+    }
+
+    #[test]
+    fn supervised_actor_runs_start_first() {
+        #[derive(Debug, Default)]
+        struct Started(Vec<&'static str>);
+        impl Actor for Started {}
+
+        let handle = Handle::spawn_supervised(
+            Started::default(),
+            |actor| {
+                actor.0.push("start");
+                Ok(Outcome::Continue)
+            },
+            Mailbox::Unbounded,
+            RestartPolicy::default(),
+            |_: &Started| Ok(Started::default()),
+        );
+        handle
+            .act(|actor| {
+                actor.0.push("action");
+                Ok(Outcome::Continue)
+            })
+            .unwrap();
+
+        assert_eq!(
+            handle.ask(|actor| Ok(actor.0.clone())).unwrap(),
+            ["start", "action"]
+        );
+        handle.stop().unwrap();
+    }
+
+    #[derive(Debug, Default)]
+    struct Resumed {
+        count: usize,
+        restarts: usize,
+    }
+    impl Actor for Resumed {
+        fn restart(&mut self) {
+            self.restarts += 1;
+        }
+    }
+
+    #[test]
+    fn failed_actor_is_resumed_and_runs_the_next_action() {
+        let handle = Handle::spawn_resuming(
+            Resumed::default(),
+            Mailbox::Unbounded,
+            RestartPolicy::default(),
+        );
+
+        handle.act(|_| eyre::bail!("first action fails")).unwrap();
+        handle
+            .act(|actor| {
+                actor.count += 1;
+                Ok(Outcome::Continue)
+            })
+            .unwrap();
+
+        assert_eq!(
+            handle
+                .ask(|actor| Ok((actor.count, actor.restarts)))
+                .unwrap(),
+            (1, 1)
+        );
+        handle.stop().unwrap();
+    }
+
+    #[test]
+    fn supervision_gives_up_after_too_many_restarts() {
+        let policy = RestartPolicy {
+            max_restarts: 1,
+            window: Duration::from_secs(60),
+            ..RestartPolicy::default()
+        };
+        let handle = Handle::spawn_resuming(Resumed::default(), Mailbox::Unbounded, policy);
+
+        handle.act(|_| eyre::bail!("fails")).unwrap();
+        assert_eq!(handle.ask(|actor| Ok(actor.restarts)).unwrap(), 1);
+        handle.act(|_| eyre::bail!("fails again")).unwrap();
+
+        handle.wait().unwrap();
+        assert!(!handle.is_running());
+    }
+
+    #[test]
+    fn resumed_actor_keeps_its_state() {
+        let handle = Handle::spawn_resuming(
+            Resumed::default(),
+            Mailbox::Unbounded,
+            RestartPolicy::default(),
+        );
+
+        handle
+            .act(|actor| {
+                actor.count += 1;
+                eyre::bail!("fails after counting")
+            })
+            .unwrap();
+        let _ = handle.ask(|_| -> eyre::Result<()> { panic!("oh no") });
+
+        assert_eq!(
+            handle
+                .ask(|actor| Ok((actor.count, actor.restarts)))
+                .unwrap(),
+            (1, 2)
+        );
+        handle.stop().unwrap();
+    }
+
+    #[test]
+    fn resumed_actor_stops_on_errors_if_only_resumed_after_panics() {
+        let policy = RestartPolicy {
+            panics_only: true,
+            ..RestartPolicy::default()
+        };
+        let handle = Handle::spawn_resuming(Resumed::default(), Mailbox::Unbounded, policy);
+
+        let _ = handle.ask(|_| -> eyre::Result<()> { panic!("oh no") });
+        assert_eq!(handle.ask(|actor| Ok(actor.restarts)).unwrap(), 1);
+        handle.act(|_| eyre::bail!("fails")).unwrap();
+
+        handle.wait().unwrap();
+        assert!(!handle.is_running());
+    }
+
+    #[test]
+    fn is_running_until_stopped() {
+        let handle = Handle::spawn(TestActor::default());
+        assert!(handle.is_running());
+
+        handle.stop().unwrap();
+        assert!(!handle.is_running());
+    }
+
+    #[test]
+    fn manual_actions_only_run_when_pumped() {
+        let handle = Handle::spawn_manual(CountingActor::default());
+        for _ in 0..2 {
+            handle
+                .act(|actor| {
+                    actor.count += 1;
+                    Ok(Outcome::Continue)
+                })
+                .unwrap();
+        }
+        assert_eq!(handle.run_pending(), 2);
+        assert_eq!(handle.run_pending(), 0);
+        assert_eq!(handle.ask(|actor| Ok(actor.count)).unwrap(), 2);
+
+        handle.act(|_| Ok(Outcome::Stop)).unwrap();
+        assert!(handle.is_running());
+        handle.run_pending();
+        assert!(!handle.is_running());
+        let _ = handle.ask(|actor| Ok(actor.count)).unwrap_err();
+    }
+
+    #[test]
+    fn manual_actor_asking_itself_panics() {
+        let handle = Handle::spawn_manual(CountingActor::default());
+        let inner = handle.clone();
+        handle
+            .act(move |_| {
+                inner.ask(|actor| Ok(actor.count))?;
+                Ok(Outcome::Continue)
+            })
+            .unwrap();
+
+        handle.run_pending();
+
+        let e = handle.ask(|actor| Ok(actor.count)).unwrap_err();
+        assert!(e.to_string().contains("wait for itself forever"), "{e}");
+    }
+
+    #[test]
+    fn wait_returns_once_stopped_elsewhere() {
+        let handle = Handle::spawn(TestActor::default());
+        let waiter = std::thread::spawn({
+            let handle = handle.clone();
+            move || handle.wait()
+        });
+        // The waiter holds on to the thread's join handle until the thread has finished.
+        while handle.join_handle.try_lock().is_ok() {
+            std::thread::yield_now();
+        }
+        assert!(!waiter.is_finished());
+
+        // The waiter is already joining the thread, so this doesn't wait itself.
+        handle.stop().unwrap();
+
+        waiter.join().unwrap().unwrap();
+        assert!(!handle.is_running());
+        // Already stopped, so there's nothing left to wait for.
+        handle.wait().unwrap();
+    }
+
     #[test]
     fn cyclic_structure_can_be_stopped() {
         let a = CyclicActorA::default();
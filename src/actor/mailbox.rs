@@ -0,0 +1,276 @@
+use std::fmt::Debug;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::mpsc::{Receiver, RecvError, Sender, SyncSender, TrySendError};
+use std::sync::{Arc, Mutex};
+
+use crate::actor::action::Action;
+
+/// How many actions can be queued up for an actor. See [Handle::spawn_resuming].
+///
+/// [Handle::spawn_resuming]: crate::actor::handle::Handle::spawn_resuming
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum Mailbox {
+    /// As many as will fit in memory, so sending never waits or fails while the actor runs.
+    #[default]
+    Unbounded,
+    /// At most `capacity` actions, with `overflow` deciding what happens to any more.
+    Bounded { capacity: usize, overflow: Overflow },
+}
+
+/// What to do with an action that's sent to a full [Mailbox::Bounded].
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Overflow {
+    /// Wait for the actor to make room. Never do this from the actor's own thread.
+    Block,
+    /// Throw away the action that's been waiting the longest to make room. If that was an
+    /// `ask`, the asker gets an error instead of an answer. If it was stopping the actor, the
+    /// actor still stops, before anything queued after it runs.
+    DropOldest,
+    /// Fail to send the action, e.g. for one that's of no use unless it runs soon.
+    Error,
+    /// Give up on the actor: the action is thrown away, and the actor stops before it runs
+    /// any other action, as if it had been stopped. For actors that are of no use once they
+    /// fall that far behind, where waiting for them would hold up the sender.
+    Stop,
+}
+
+/// Why an action couldn't be sent.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) enum SendError {
+    /// The actor is gone.
+    Closed,
+    /// The mailbox is full, and its [Overflow] is [Overflow::Error].
+    Full,
+}
+
+/// The sending side of an actor's mailbox, shared by all of its handles.
+pub(crate) enum MailboxSender<A> {
+    Unbounded(Sender<Action<A>>),
+    Bounded {
+        sender: SyncSender<Action<A>>,
+        overflow: Overflow,
+        /// Only kept to make room for [Overflow::DropOldest]. Otherwise the receiver is
+        /// dropped along with the actor, so that senders waiting for room give up.
+        receiver: Option<MailboxReceiver<A>>,
+        /// Set once an action overflowed with [Overflow::Stop], shared with the receiver.
+        overflowed: Arc<AtomicBool>,
+    },
+}
+
+// Manual Clone implementation because A does not need to be Clone.
+impl<A> Clone for MailboxSender<A> {
+    fn clone(&self) -> Self {
+        match self {
+            MailboxSender::Unbounded(sender) => MailboxSender::Unbounded(sender.clone()),
+            MailboxSender::Bounded {
+                sender,
+                overflow,
+                receiver,
+                overflowed,
+            } => MailboxSender::Bounded {
+                sender: sender.clone(),
+                overflow: *overflow,
+                receiver: receiver.clone(),
+                overflowed: overflowed.clone(),
+            },
+        }
+    }
+}
+
+impl<A> Debug for MailboxSender<A> {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            MailboxSender::Unbounded(_) => f.write_str("Unbounded"),
+            MailboxSender::Bounded { overflow, .. } => f
+                .debug_struct("Bounded")
+                .field("overflow", overflow)
+                .finish(),
+        }
+    }
+}
+
+/// The receiving side of an actor's mailbox. It's only ever received from by the actor
+/// thread, and by senders throwing away the oldest action.
+pub(crate) struct MailboxReceiver<A> {
+    receiver: Arc<Mutex<Receiver<Action<A>>>>,
+    overflowed: Arc<AtomicBool>,
+    /// Set when [Overflow::DropOldest] made room by taking out the action stopping the actor,
+    /// which then stops before running anything queued after it, as it would have.
+    stop_dropped: Arc<AtomicBool>,
+}
+
+impl<A> Clone for MailboxReceiver<A> {
+    fn clone(&self) -> Self {
+        Self {
+            receiver: self.receiver.clone(),
+            overflowed: self.overflowed.clone(),
+            stop_dropped: self.stop_dropped.clone(),
+        }
+    }
+}
+
+impl<A> MailboxReceiver<A> {
+    fn new(receiver: Receiver<Action<A>>) -> Self {
+        Self {
+            receiver: Arc::new(Mutex::new(receiver)),
+            overflowed: Arc::default(),
+            stop_dropped: Arc::default(),
+        }
+    }
+
+    /// Wait for the next action, failing once every sender is gone. Once the mailbox
+    /// overflowed with [Overflow::Stop], or the action stopping the actor was taken out to make
+    /// room, that's an action stopping the actor instead.
+    pub(crate) fn recv(&self) -> Result<Action<A>, RecvError> {
+        let action = self.lock().recv()?;
+        Ok(self.unless_overflowed(action))
+    }
+
+    /// The next action, if one is waiting.
+    #[cfg(any(test, feature = "test-util"))]
+    pub(crate) fn try_recv(&self) -> Option<Action<A>> {
+        let action = self.lock().try_recv().ok()?;
+        Some(self.unless_overflowed(action))
+    }
+
+    /// Throw away every action that's waiting, and tell whether the actor is to stop: because
+    /// one of them was stopping it, or because the mailbox overflowed with [Overflow::Stop].
+    pub(crate) fn discard_pending(&self) -> bool {
+        let receiver = self.lock();
+        let mut stopping = false;
+        while let Ok(action) = receiver.try_recv() {
+            stopping |= action.stops();
+        }
+        stopping
+            || self.overflowed.load(Ordering::Acquire)
+            || self.stop_dropped.load(Ordering::Acquire)
+    }
+
+    fn lock(&self) -> std::sync::MutexGuard<'_, Receiver<Action<A>>> {
+        self.receiver.lock().expect("mutex to not be poisoned")
+    }
+
+    fn unless_overflowed(&self, action: Action<A>) -> Action<A> {
+        if self.overflowed.load(Ordering::Acquire) || self.stop_dropped.load(Ordering::Acquire) {
+            Action::stop()
+        } else {
+            action
+        }
+    }
+}
+
+/// Create a mailbox, see [Mailbox] for the kinds.
+pub(crate) fn mailbox<A>(mailbox: Mailbox) -> (MailboxSender<A>, MailboxReceiver<A>) {
+    match mailbox {
+        Mailbox::Unbounded => {
+            let (sender, receiver) = std::sync::mpsc::channel();
+            (
+                MailboxSender::Unbounded(sender),
+                MailboxReceiver::new(receiver),
+            )
+        }
+        Mailbox::Bounded { capacity, overflow } => {
+            let (sender, receiver) = std::sync::mpsc::sync_channel(capacity);
+            let receiver = MailboxReceiver::new(receiver);
+            let sender = MailboxSender::Bounded {
+                sender,
+                overflow,
+                receiver: (overflow == Overflow::DropOldest).then(|| receiver.clone()),
+                overflowed: receiver.overflowed.clone(),
+            };
+            (sender, receiver)
+        }
+    }
+}
+
+impl<A> MailboxSender<A> {
+    /// Queue `action`, handling a full mailbox as its [Overflow] says.
+    pub(crate) fn send(&self, action: Action<A>) -> Result<(), SendError> {
+        let (sender, overflow, receiver, overflowed) = match self {
+            MailboxSender::Unbounded(sender) => {
+                return sender.send(action).map_err(|_| SendError::Closed)
+            }
+            MailboxSender::Bounded {
+                sender,
+                overflow,
+                receiver,
+                overflowed,
+            } => (sender, overflow, receiver, overflowed),
+        };
+        // The actor is stopping, anything else is thrown away.
+        if overflowed.load(Ordering::Acquire) {
+            return Ok(());
+        }
+        let mut action = action;
+        loop {
+            match sender.try_send(action) {
+                Ok(()) => return Ok(()),
+                Err(TrySendError::Disconnected(_)) => return Err(SendError::Closed),
+                Err(TrySendError::Full(rejected)) => match overflow {
+                    Overflow::Block => return sender.send(rejected).map_err(|_| SendError::Closed),
+                    Overflow::Error => return Err(SendError::Full),
+                    Overflow::Stop => {
+                        overflowed.store(true, Ordering::Release);
+                        return Ok(());
+                    }
+                    Overflow::DropOldest => {
+                        // The actor might have made room by itself in the meantime, so this
+                        // doesn't wait for an action if there is none.
+                        if let Some(receiver) = receiver {
+                            let oldest = receiver.lock().try_recv();
+                            if oldest.is_ok_and(|oldest| oldest.stops()) {
+                                receiver.stop_dropped.store(true, Ordering::Release);
+                            }
+                        }
+                        action = rejected;
+                    }
+                },
+            }
+        }
+    }
+
+    /// The same mailbox, handling a full mailbox as `overflow` says instead. Dropping the
+    /// oldest action needs the receiver, so that only works if the mailbox already did.
+    pub(crate) fn with_overflow(&self, overflow: Overflow) -> Self {
+        match self {
+            MailboxSender::Unbounded(_) => self.clone(),
+            MailboxSender::Bounded {
+                sender,
+                receiver,
+                overflowed,
+                ..
+            } => {
+                assert!(
+                    overflow != Overflow::DropOldest || receiver.is_some(),
+                    "mailbox to drop the oldest action already"
+                );
+                MailboxSender::Bounded {
+                    sender: sender.clone(),
+                    overflow,
+                    receiver: receiver
+                        .clone()
+                        .filter(|_| overflow == Overflow::DropOldest),
+                    overflowed: overflowed.clone(),
+                }
+            }
+        }
+    }
+
+    /// Whether the mailbox overflowed with [Overflow::Stop], so that the actor is stopping.
+    pub(crate) fn has_overflowed(&self) -> bool {
+        match self {
+            MailboxSender::Unbounded(_) => false,
+            MailboxSender::Bounded { overflowed, .. } => overflowed.load(Ordering::Acquire),
+        }
+    }
+
+    /// Queue `action`, waiting for room if the mailbox is full, whatever its [Overflow].
+    /// Used for stopping the actor, which must not get lost.
+    pub(crate) fn send_blocking(&self, action: Action<A>) -> Result<(), SendError> {
+        match self {
+            MailboxSender::Unbounded(sender) => sender.send(action),
+            MailboxSender::Bounded { sender, .. } => sender.send(action),
+        }
+        .map_err(|_| SendError::Closed)
+    }
+}
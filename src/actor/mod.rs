@@ -1,4 +1,6 @@
 mod action;
 pub mod actor;
 pub mod handle;
+pub mod mailbox;
 pub mod outcome;
+pub mod supervisor;
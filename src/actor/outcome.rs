@@ -1,7 +1,17 @@
-/// The outcome of an actor action. If the action returns `Ok(Outcome::Stop)` or `Err(_)`,
-/// the actor will stop. If the action returns `Ok(Outcome::Continue)`, the actor will continue.
+/// The outcome of an actor action. If the action returns `Ok(Outcome::Continue)`, the actor
+/// will continue. If it returns `Ok(Outcome::Stop)`, the actor will stop. If it returns
+/// `Err(_)`, the actor will stop too, unless it's supervised by
+/// [Handle::spawn_supervised](crate::actor::handle::Handle::spawn_supervised) or
+/// [Handle::spawn_resuming](crate::actor::handle::Handle::spawn_resuming), and its
+/// [RestartPolicy](crate::actor::supervisor::RestartPolicy) restarts it after errors.
 #[derive(Debug)]
 pub enum Outcome {
     Continue,
     Stop,
+    /// Reset the actor with [Actor::restart](crate::actor::actor::Actor::restart), and then
+    /// continue. The actions queued behind this one still run,
+    /// [Actor::stop](crate::actor::actor::Actor::stop) isn't called, and whatever `restart`
+    /// doesn't reset is kept as is. This is what an actor resumed by `spawn_resuming` goes
+    /// through after failing, but asked for by the actor itself, and never given up on.
+    Restart,
 }
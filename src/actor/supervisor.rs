@@ -0,0 +1,121 @@
+use std::collections::VecDeque;
+use std::time::{Duration, Instant};
+
+use eyre::Result;
+
+use crate::actor::actor::Actor;
+use crate::log::error;
+
+/// How often an actor spawned with [Handle::spawn_supervised] or [Handle::spawn_resuming] may
+/// be restarted: at most `max_restarts` times within any `window`. Failing more often than
+/// that means something is persistently wrong, so the supervisor gives up and lets the actor
+/// stop.
+///
+/// With `panics_only`, only panics are restarted from: an action returning an error stops
+/// the actor as it would without a supervisor, for actors that fail on purpose.
+///
+/// [Handle::spawn_supervised]: crate::actor::handle::Handle::spawn_supervised
+/// [Handle::spawn_resuming]: crate::actor::handle::Handle::spawn_resuming
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct RestartPolicy {
+    pub max_restarts: usize,
+    pub window: Duration,
+    pub panics_only: bool,
+}
+
+impl Default for RestartPolicy {
+    fn default() -> Self {
+        Self {
+            max_restarts: 3,
+            window: Duration::from_secs(60),
+            panics_only: false,
+        }
+    }
+}
+
+/// How an actor stopped running actions.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(super) enum Stopped {
+    /// It was told to stop, or there are no more actions coming.
+    Done,
+    /// An action returned an error.
+    Failed,
+    /// An action panicked.
+    Panicked,
+}
+
+/// Keeps track of how often an actor was restarted, to tell whether it may be again.
+pub(super) struct Restarts {
+    policy: RestartPolicy,
+    /// When the actor was restarted, within the policy's window.
+    restarts: VecDeque<Instant>,
+}
+
+impl Restarts {
+    pub(super) fn new(policy: RestartPolicy) -> Self {
+        Self {
+            policy,
+            restarts: VecDeque::new(),
+        }
+    }
+
+    /// Whether an actor that stopped as `stopped` is to be restarted. Once this says no
+    /// because it failed too often, the actor is stopped for good.
+    pub(super) fn restart_after(&mut self, stopped: Stopped) -> bool {
+        match stopped {
+            Stopped::Done => return false,
+            Stopped::Failed if self.policy.panics_only => return false,
+            Stopped::Failed | Stopped::Panicked => {}
+        }
+        let now = Instant::now();
+        while let Some(restart) = self.restarts.front() {
+            if now.duration_since(*restart) > self.policy.window {
+                self.restarts.pop_front();
+            } else {
+                break;
+            }
+        }
+        if self.restarts.len() >= self.policy.max_restarts {
+            error!("Actor failed too often, not restarting it again");
+            return false;
+        }
+        self.restarts.push_back(now);
+        true
+    }
+}
+
+/// Makes an actor to take over from the one it's given, which failed.
+type Factory<A> = Box<dyn FnMut(&A) -> Result<A> + Send>;
+
+/// Creates a fresh actor from a factory whenever the previous one failed, as long as the
+/// policy allows it.
+pub(super) struct Supervisor<A> {
+    factory: Factory<A>,
+    restarts: Restarts,
+}
+
+impl<A> Supervisor<A>
+where
+    A: Actor,
+{
+    pub(super) fn new(
+        factory: impl FnMut(&A) -> Result<A> + Send + 'static,
+        policy: RestartPolicy,
+    ) -> Self {
+        Self {
+            factory: Box::new(factory),
+            restarts: Restarts::new(policy),
+        }
+    }
+
+    /// A replacement for `failed`, which stopped as `stopped`, or `None` if it isn't to be
+    /// restarted. The factory failing as well counts as giving up.
+    pub(super) fn restart(&mut self, stopped: Stopped, failed: &A) -> Option<A> {
+        if !self.restarts.restart_after(stopped) {
+            return None;
+        }
+        (self.factory)(failed)
+            .inspect_err(|e| error!("Failed to restart actor: {e:?}"))
+            .ok()
+    }
+}
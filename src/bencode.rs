@@ -0,0 +1,256 @@
+use std::collections::BTreeMap;
+
+use eyre::{ensure, eyre, Result};
+use nom::branch::alt;
+use nom::bytes::streaming::{tag, take, take_while1};
+use nom::combinator::{map, map_res, opt, recognize};
+use nom::error::{Error, ErrorKind};
+use nom::sequence::{pair, terminated};
+use nom::IResult;
+
+use crate::SansIo;
+
+/// Lists and dictionaries nested deeper than this are rejected, to avoid blowing the stack
+/// on malicious input.
+const MAX_DEPTH: usize = 32;
+
+/// A bencoded value, as used by metainfo files, trackers and the extension protocol.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum BValue {
+    Integer(i64),
+    Bytes(Vec<u8>),
+    List(Vec<BValue>),
+    /// Dictionary keys are always byte strings, and must be sorted when encoded.
+    /// A [BTreeMap] takes care of the sorting for us.
+    Dict(BTreeMap<Vec<u8>, BValue>),
+}
+
+impl BValue {
+    /// Look up a key, if this is a dictionary.
+    #[must_use]
+    pub fn get(&self, key: &[u8]) -> Option<&BValue> {
+        match self {
+            BValue::Dict(dict) => dict.get(key),
+            _ => None,
+        }
+    }
+
+    #[must_use]
+    pub fn as_integer(&self) -> Option<i64> {
+        match self {
+            BValue::Integer(integer) => Some(*integer),
+            _ => None,
+        }
+    }
+
+    #[must_use]
+    pub fn as_bytes(&self) -> Option<&[u8]> {
+        match self {
+            BValue::Bytes(bytes) => Some(bytes),
+            _ => None,
+        }
+    }
+
+    #[must_use]
+    pub fn as_list(&self) -> Option<&[BValue]> {
+        match self {
+            BValue::List(list) => Some(list),
+            _ => None,
+        }
+    }
+
+    #[must_use]
+    pub fn as_dict(&self) -> Option<&BTreeMap<Vec<u8>, BValue>> {
+        match self {
+            BValue::Dict(dict) => Some(dict),
+            _ => None,
+        }
+    }
+}
+
+/// Encode a value, with the keys of every dictionary in the sorted order the spec requires.
+#[must_use]
+pub fn encode(value: &BValue) -> Vec<u8> {
+    value.encode()
+}
+
+/// Decode a value that takes up all of `bytes`, like a whole info dictionary.
+/// Use [BValue::decode] for a value that's followed by other data.
+pub fn decode(bytes: &[u8]) -> Result<BValue> {
+    let (remaining, value) = BValue::decode(bytes).map_err(|e| eyre!("Invalid bencode: {e:?}"))?;
+    ensure!(
+        remaining.is_empty(),
+        "{} bytes of trailing data after bencoded value",
+        remaining.len()
+    );
+    Ok(value)
+}
+
+fn encode_bytes(bytes: &[u8], buf: &mut Vec<u8>) {
+    buf.extend(bytes.len().to_string().as_bytes());
+    buf.push(b':');
+    buf.extend(bytes);
+}
+
+fn digits(i: &[u8]) -> IResult<&[u8], &[u8]> {
+    take_while1(|b: u8| b.is_ascii_digit())(i)
+}
+
+fn parse_number<T: std::str::FromStr>(digits: &[u8]) -> Result<T, ()> {
+    std::str::from_utf8(digits)
+        .map_err(|_| ())?
+        .parse()
+        .map_err(|_| ())
+}
+
+fn integer(i: &[u8]) -> IResult<&[u8], i64> {
+    let (i, _) = tag("i")(i)?;
+    terminated(
+        map_res(recognize(pair(opt(tag("-")), digits)), parse_number),
+        tag("e"),
+    )(i)
+}
+
+fn bytes(i: &[u8]) -> IResult<&[u8], Vec<u8>> {
+    let (i, length) = map_res(digits, parse_number::<usize>)(i)?;
+    let (i, _) = tag(":")(i)?;
+    let (i, bytes) = take(length)(i)?;
+    Ok((i, bytes.to_vec()))
+}
+
+fn value(i: &[u8], depth: usize) -> IResult<&[u8], BValue> {
+    if depth > MAX_DEPTH {
+        return Err(nom::Err::Failure(Error::new(i, ErrorKind::TooLarge)));
+    }
+    alt((
+        map(integer, BValue::Integer),
+        map(bytes, BValue::Bytes),
+        map(|i| list(i, depth), BValue::List),
+        map(|i| dict(i, depth), BValue::Dict),
+    ))(i)
+}
+
+fn list(i: &[u8], depth: usize) -> IResult<&[u8], Vec<BValue>> {
+    let (mut i, _) = tag("l")(i)?;
+    let mut list = Vec::new();
+    loop {
+        if let (rest, Some(_)) = opt(tag("e"))(i)? {
+            return Ok((rest, list));
+        }
+        let (rest, value) = value(i, depth + 1)?;
+        list.push(value);
+        i = rest;
+    }
+}
+
+fn dict(i: &[u8], depth: usize) -> IResult<&[u8], BTreeMap<Vec<u8>, BValue>> {
+    let (mut i, _) = tag("d")(i)?;
+    let mut dict = BTreeMap::new();
+    loop {
+        if let (rest, Some(_)) = opt(tag("e"))(i)? {
+            return Ok((rest, dict));
+        }
+        let (rest, key) = bytes(i)?;
+        let (rest, value) = value(rest, depth + 1)?;
+        dict.insert(key, value);
+        i = rest;
+    }
+}
+
+impl SansIo for BValue {
+    fn decode(i: &[u8]) -> IResult<&[u8], Self> {
+        value(i, 0)
+    }
+
+    fn encode(&self) -> Vec<u8> {
+        let mut buf = Vec::new();
+        self.encode_into(&mut buf);
+        buf
+    }
+
+    fn encode_into(&self, buf: &mut Vec<u8>) {
+        match self {
+            BValue::Integer(integer) => {
+                buf.push(b'i');
+                buf.extend(integer.to_string().as_bytes());
+                buf.push(b'e');
+            }
+            BValue::Bytes(bytes) => encode_bytes(bytes, buf),
+            BValue::List(list) => {
+                buf.push(b'l');
+                for value in list {
+                    value.encode_into(buf);
+                }
+                buf.push(b'e');
+            }
+            BValue::Dict(dict) => {
+                buf.push(b'd');
+                for (key, value) in dict {
+                    encode_bytes(key, buf);
+                    value.encode_into(buf);
+                }
+                buf.push(b'e');
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn roundtrip_dict() {
+        let value = BValue::Dict(BTreeMap::from([
+            (b"a".to_vec(), BValue::Integer(-12)),
+            (b"b".to_vec(), BValue::Bytes(b"spam".to_vec())),
+            (
+                b"c".to_vec(),
+                BValue::List(vec![BValue::Integer(0), BValue::Bytes(vec![])]),
+            ),
+        ]));
+
+        let encoded = value.encode();
+        let (remaining, decoded) = BValue::decode(&encoded).unwrap();
+
+        assert_eq!(encoded, b"d1:ai-12e1:b4:spam1:cli0e0:ee");
+        assert_eq!(value, decoded);
+        assert_eq!(remaining.len(), 0);
+    }
+
+    #[test]
+    fn nested_dicts_are_encoded_with_sorted_keys() {
+        let inner = BValue::Dict(BTreeMap::from([
+            (b"ut_pex".to_vec(), BValue::Integer(2)),
+            (b"ut_metadata".to_vec(), BValue::Integer(1)),
+        ]));
+        let value = BValue::Dict(BTreeMap::from([
+            (b"v".to_vec(), BValue::Bytes(b"Rp 0.1".to_vec())),
+            (b"m".to_vec(), inner),
+            (b"metadata_size".to_vec(), BValue::Integer(31235)),
+        ]));
+
+        let encoded = encode(&value);
+
+        assert_eq!(
+            encoded,
+            b"d1:md11:ut_metadatai1e6:ut_pexi2ee13:metadata_sizei31235e1:v6:Rp 0.1e"
+        );
+        assert_eq!(decode(&encoded).unwrap(), value);
+    }
+
+    #[test]
+    fn decode_needs_exactly_one_whole_value() {
+        assert_eq!(decode(b"i3e").unwrap(), BValue::Integer(3));
+        let _ = decode(b"i3ei4e").unwrap_err();
+        let _ = decode(b"d1:a").unwrap_err();
+    }
+
+    #[test]
+    fn deeply_nested_lists_are_rejected() {
+        let mut encoded = vec![b'l'; MAX_DEPTH + 2];
+        encoded.extend(vec![b'e'; MAX_DEPTH + 2]);
+
+        assert!(BValue::decode(&encoded).is_err());
+    }
+}
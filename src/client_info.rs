@@ -0,0 +1,70 @@
+use alloc::string::{String, ToString};
+use alloc::vec::Vec;
+use core::fmt::{Display, Formatter};
+
+/// Names of common clients, by the two-letter code they put in their peer IDs.
+const CLIENT_NAMES: &[(&[u8; 2], &str)] = &[
+    (b"AZ", "Vuze"),
+    (b"BC", "BitComet"),
+    (b"BI", "BiglyBT"),
+    (b"BT", "BitTorrent"),
+    (b"DE", "Deluge"),
+    (b"FD", "Free Download Manager"),
+    (b"KT", "KTorrent"),
+    (b"LT", "libtorrent (Rasterbar)"),
+    (b"lt", "libTorrent (rakshasa)"),
+    (b"qB", "qBittorrent"),
+    (b"Rp", "torrent-poc"),
+    (b"TR", "Transmission"),
+    (b"UM", "µTorrent for Mac"),
+    (b"UT", "µTorrent"),
+    (b"UW", "µTorrent Web"),
+    (b"WW", "WebTorrent"),
+    (b"XL", "Xunlei"),
+];
+
+/// The client a peer claims to be running, as decoded from an Azureus-style peer ID.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ClientInfo {
+    /// The two-letter client code, e.g. `qB`.
+    pub code: String,
+    /// Human-readable name of the client, if the code is a well-known one.
+    pub name: Option<&'static str>,
+    /// The four version characters, separated by dots, e.g. `4.5.5.0`.
+    /// Clients are free to encode their version however they like, so this isn't always
+    /// a plain version number.
+    pub version: String,
+}
+
+impl ClientInfo {
+    /// Decode the `-XY1234-` prefix of a peer ID, if it has one.
+    pub(crate) fn from_peer_id(peer_id: &[u8; 20]) -> Option<Self> {
+        let [b'-', code @ .., b'-'] = &peer_id[..8] else {
+            return None;
+        };
+        let (code, version) = code.split_at(2);
+        if !code.iter().chain(version).all(u8::is_ascii_alphanumeric) {
+            return None;
+        }
+        let name = CLIENT_NAMES
+            .iter()
+            .find(|(known, _)| known.as_slice() == code)
+            .map(|(_, name)| *name);
+        let version = version
+            .iter()
+            .map(|byte| char::from(*byte).to_string())
+            .collect::<Vec<_>>()
+            .join(".");
+        Some(Self {
+            code: String::from_utf8_lossy(code).into_owned(),
+            name,
+            version,
+        })
+    }
+}
+
+impl Display for ClientInfo {
+    fn fmt(&self, f: &mut Formatter<'_>) -> core::fmt::Result {
+        write!(f, "{} {}", self.name.unwrap_or(&self.code), self.version)
+    }
+}
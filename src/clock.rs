@@ -0,0 +1,60 @@
+use std::fmt::Debug;
+use std::time::Instant;
+
+/// A source of the current time. Anything time-based (rates, timeouts, periodic work) should
+/// go through a [Clock] instead of calling [Instant::now] directly, so that tests can control
+/// the passage of time instead of sleeping.
+pub trait Clock: Debug + Send + Sync + 'static {
+    /// The current time.
+    fn now(&self) -> Instant;
+}
+
+/// The real-world [Clock], backed by [Instant::now].
+#[derive(Debug, Default, Clone, Copy)]
+pub struct SystemClock;
+
+impl Clock for SystemClock {
+    fn now(&self) -> Instant {
+        Instant::now()
+    }
+}
+
+#[cfg(any(test, feature = "test-util"))]
+pub use mock::MockClock;
+
+#[cfg(any(test, feature = "test-util"))]
+mod mock {
+    use std::sync::{Arc, Mutex};
+    use std::time::{Duration, Instant};
+
+    use super::Clock;
+
+    /// A [Clock] that only moves when told to. Clones share the same time.
+    #[derive(Debug, Clone)]
+    pub struct MockClock(Arc<Mutex<Instant>>);
+
+    impl MockClock {
+        /// A clock that's stopped at the current time.
+        #[must_use]
+        pub fn new() -> Self {
+            Self(Arc::new(Mutex::new(Instant::now())))
+        }
+
+        /// Move the time forward by `duration`, for this clock and all of its clones.
+        pub fn advance(&self, duration: Duration) {
+            *self.0.lock().expect("mutex to not be poisoned") += duration;
+        }
+    }
+
+    impl Default for MockClock {
+        fn default() -> Self {
+            Self::new()
+        }
+    }
+
+    impl Clock for MockClock {
+        fn now(&self) -> Instant {
+            *self.0.lock().expect("mutex to not be poisoned")
+        }
+    }
+}
@@ -0,0 +1,87 @@
+use std::sync::mpsc::{Receiver, RecvTimeoutError, Sender};
+use std::time::Duration;
+
+use eyre::{bail, eyre, Result, WrapErr};
+
+use crate::messages::Message;
+use crate::{ConnectionRead, ConnectionWrite};
+
+/// The "read" half of one end of a [loopback] connection.
+pub struct LoopbackRead {
+    receiver: Receiver<Message>,
+}
+
+/// The "write" half of one end of a [loopback] connection.
+pub struct LoopbackWrite {
+    sender: Sender<Message>,
+}
+
+/// Two ends of an in-memory connection, wired to each other: whatever is sent on one end is
+/// received on the other. Useful for connecting two [Torrent](crate::Torrent)s in a test.
+///
+/// Dropping a write half closes the connection for the other end's read half.
+#[must_use]
+pub fn loopback() -> ((LoopbackWrite, LoopbackRead), (LoopbackWrite, LoopbackRead)) {
+    let (a_sender, b_receiver) = std::sync::mpsc::channel();
+    let (b_sender, a_receiver) = std::sync::mpsc::channel();
+    (
+        (
+            LoopbackWrite { sender: a_sender },
+            LoopbackRead {
+                receiver: a_receiver,
+            },
+        ),
+        (
+            LoopbackWrite { sender: b_sender },
+            LoopbackRead {
+                receiver: b_receiver,
+            },
+        ),
+    )
+}
+
+impl ConnectionRead for LoopbackRead {
+    fn receive(&self) -> Result<Message> {
+        self.receiver
+            .recv()
+            .wrap_err("Connection closed, no more messages coming")
+    }
+
+    fn receive_timeout(&self, timeout: Duration) -> Result<Option<Message>> {
+        match self.receiver.recv_timeout(timeout) {
+            Ok(message) => Ok(Some(message)),
+            Err(RecvTimeoutError::Timeout) => Ok(None),
+            Err(RecvTimeoutError::Disconnected) => {
+                bail!("Connection closed, no more messages coming")
+            }
+        }
+    }
+}
+
+impl ConnectionWrite for LoopbackWrite {
+    fn send(&mut self, message: Message) -> Result<()> {
+        self.sender
+            .send(message)
+            .map_err(|_| eyre!("Connection closed, can't send any more messages"))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::messages::{Choke, Unchoke};
+
+    use super::*;
+
+    #[test]
+    fn each_end_receives_what_the_other_sends() {
+        let ((mut a_write, a_read), (mut b_write, b_read)) = loopback();
+
+        a_write.send(Message::Choke(Choke)).unwrap();
+        b_write.send(Message::Unchoke(Unchoke)).unwrap();
+
+        assert_eq!(b_read.receive().unwrap(), Message::Choke(Choke));
+        assert_eq!(a_read.receive().unwrap(), Message::Unchoke(Unchoke));
+        drop(a_write);
+        let _ = b_read.receive().unwrap_err();
+    }
+}
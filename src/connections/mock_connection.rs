@@ -0,0 +1,92 @@
+use std::collections::VecDeque;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Mutex};
+use std::thread::sleep;
+use std::time::Duration;
+
+use eyre::{eyre, Result};
+
+use crate::messages::Message;
+use crate::{ConnectionRead, ConnectionWrite, SendStatus};
+
+/// An in-memory connection for tests, which records everything sent to it and hands out
+/// a fixed queue of messages when receiving.
+#[derive(Clone)]
+pub struct MockConnection {
+    pub sent_messages: Arc<Mutex<Vec<Message>>>,
+    pub queued_for_receive: Arc<Mutex<VecDeque<Message>>>,
+    /// While set, [ConnectionWrite::try_send] acts like the peer is too slow to keep up.
+    pub would_block: Arc<AtomicBool>,
+    /// Whether the connection is closed once the queue is empty, instead of going quiet.
+    closed: bool,
+}
+
+impl MockConnection {
+    pub fn new(queued_for_receive: VecDeque<Message>) -> Self {
+        Self {
+            sent_messages: Arc::default(),
+            queued_for_receive: Arc::new(Mutex::new(queued_for_receive)),
+            would_block: Arc::default(),
+            closed: false,
+        }
+    }
+
+    /// A connection that the peer already closed, so receiving fails right away.
+    pub fn closed() -> Self {
+        Self {
+            closed: true,
+            ..Self::new(VecDeque::new())
+        }
+    }
+}
+
+impl ConnectionRead for MockConnection {
+    fn receive(&self) -> Result<Message> {
+        if self.closed {
+            return self
+                .queued_for_receive
+                .lock()
+                .unwrap()
+                .pop_front()
+                .ok_or_else(|| eyre!("connection closed"));
+        }
+        self.queued_for_receive
+            .lock()
+            .unwrap()
+            .pop_front()
+            // This simulates not getting any more network messages for 1 second, then
+            // closing the connection.
+            // The reason for this is that the `receive()` method will block until a message
+            // is received, and in the test we want to verify that a connection exists -
+            // if it is closed instantly, there's no way to verify that.
+            .ok_or_else(|| {
+                sleep(Duration::from_secs(1));
+                eyre!("no message")
+            })
+    }
+
+    fn receive_timeout(&self, timeout: Duration) -> Result<Option<Message>> {
+        let message = self.queued_for_receive.lock().unwrap().pop_front();
+        if message.is_none() && self.closed {
+            return Err(eyre!("connection closed"));
+        }
+        if message.is_none() {
+            sleep(timeout);
+        }
+        Ok(message)
+    }
+}
+
+impl ConnectionWrite for MockConnection {
+    fn send(&mut self, message: Message) -> Result<()> {
+        self.sent_messages.lock().unwrap().push(message.clone());
+        Ok(())
+    }
+
+    fn try_send(&mut self, message: Message) -> Result<SendStatus> {
+        if self.would_block.load(Ordering::SeqCst) {
+            return Ok(SendStatus::WouldBlock(message));
+        }
+        self.send(message).map(|()| SendStatus::Sent)
+    }
+}
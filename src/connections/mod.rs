@@ -1,8 +1,18 @@
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+use std::time::Duration;
+
 use eyre::Result;
 
 use crate::messages::Message;
 
+#[cfg(any(test, feature = "test-util"))]
+pub mod loopback;
+#[cfg(test)]
+pub mod mock_connection;
+pub mod mse;
 pub mod std_io_connection;
+pub mod transport;
 
 // TODO: Could this be adjusted to support both async and sync connections?
 //       We're probably stuck with colored functions locking us out of this,
@@ -20,6 +30,17 @@ pub trait ConnectionRead {
     /// The [ConnectionRead] is also in charge of decoding the message (using the [SansIo](crate::SansIo) trait)
     /// as well as any necessary buffering/retrying if the message is incomplete.
     fn receive(&self) -> Result<Message>;
+
+    /// Like [receive](Self::receive), but gives up after `timeout`, returning `Ok(None)`.
+    ///
+    /// The default implementation can't time out and just blocks until a message arrives.
+    /// Torrents use this to time out handshakes and to stop reading once a connection is
+    /// closed, so without an override neither happens; implementations should override it
+    /// if at all possible.
+    fn receive_timeout(&self, timeout: Duration) -> Result<Option<Message>> {
+        let _ = timeout;
+        self.receive().map(Some)
+    }
 }
 
 /// The "write" half a Connection.
@@ -31,4 +52,162 @@ pub trait ConnectionWrite {
     /// Send a message to the peer. The [ConnectionWrite] is in charge of encoding the message
     /// (using the [SansIo](crate::SansIo) trait) and sending it over whatever transport it is using.
     fn send(&mut self, message: Message) -> Result<()>;
+
+    /// Like [send](Self::send), but hands the message back instead of blocking if it can't be
+    /// sent right away, e.g. because a slow peer hasn't caught up with what was sent before.
+    /// The caller can then decide to drop it, or to try again later.
+    ///
+    /// The default implementation never reports [SendStatus::WouldBlock], it just sends.
+    fn try_send(&mut self, message: Message) -> Result<SendStatus> {
+        self.send(message).map(|()| SendStatus::Sent)
+    }
+
+    /// Send several messages in a row, e.g. a burst of requests. Implementations can write
+    /// them out together, instead of one at a time like [send](Self::send).
+    ///
+    /// The default implementation just sends them one by one.
+    fn send_all(&mut self, messages: &[Message]) -> Result<()> {
+        for message in messages {
+            self.send(message.clone())?;
+        }
+        Ok(())
+    }
+}
+
+/// What happened to a message given to [ConnectionWrite::try_send].
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum SendStatus {
+    /// The message is on its way.
+    Sent,
+    /// The message couldn't be sent without blocking, so here it is back.
+    WouldBlock(Message),
+}
+
+/// How often something blocked on a connection checks whether its [ShutdownSignal] was signalled.
+pub(crate) const SHUTDOWN_POLL_INTERVAL: Duration = Duration::from_millis(50);
+
+/// Tells a connection that it's being torn down, so that whoever is blocked receiving from it
+/// gives up instead of waiting for a peer that may never send anything again.
+///
+/// Clones share the same signal, and once signalled it stays that way.
+#[derive(Debug, Default, Clone)]
+pub struct ShutdownSignal(Arc<AtomicBool>);
+
+impl ShutdownSignal {
+    /// A signal that hasn't been signalled yet.
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Signal the shutdown.
+    pub fn signal(&self) {
+        self.0.store(true, Ordering::Release);
+    }
+
+    /// Whether anyone has [signalled](Self::signal) the shutdown yet.
+    #[must_use]
+    pub fn is_signalled(&self) -> bool {
+        self.0.load(Ordering::Acquire)
+    }
+}
+
+impl<T: ConnectionRead + ?Sized> ConnectionRead for Box<T> {
+    fn receive(&self) -> Result<Message> {
+        (**self).receive()
+    }
+
+    fn receive_timeout(&self, timeout: Duration) -> Result<Option<Message>> {
+        (**self).receive_timeout(timeout)
+    }
+}
+
+impl<T: ConnectionWrite + ?Sized> ConnectionWrite for Box<T> {
+    fn send(&mut self, message: Message) -> Result<()> {
+        (**self).send(message)
+    }
+
+    fn try_send(&mut self, message: Message) -> Result<SendStatus> {
+        (**self).try_send(message)
+    }
+
+    fn send_all(&mut self, messages: &[Message]) -> Result<()> {
+        (**self).send_all(messages)
+    }
+}
+
+/// Both halves of a Connection, boxed so that different kinds of connections can be mixed.
+pub type BoxedConnection = (
+    Box<dyn ConnectionWrite + Send>,
+    Box<dyn ConnectionRead + Send>,
+);
+
+/// Both halves of a Connection together, as opened by e.g. [std_io_connection](std_io_connection::std_io_connection),
+/// waiting to be [split](Self::split) and handed to a [Torrent](crate::Torrent).
+///
+/// Any `(write, read)` pair of halves is a [Connection].
+pub trait Connection {
+    /// The "write" half.
+    type Write: ConnectionWrite + Send + 'static;
+    /// The "read" half.
+    type Read: ConnectionRead + Send + 'static;
+
+    /// Separate the two halves, so they can be used from different threads.
+    fn split(self) -> (Self::Write, Self::Read);
+}
+
+impl<W, R> Connection for (W, R)
+where
+    W: ConnectionWrite + Send + 'static,
+    R: ConnectionRead + Send + 'static,
+{
+    type Write = W;
+    type Read = R;
+
+    fn split(self) -> (W, R) {
+        self
+    }
+}
+
+/// Opens connections to a single peer. Unlike a Connection, which is gone once it closes,
+/// a [ConnectionFactory] lets the torrent reconnect to the peer whenever it needs to.
+///
+/// Any `Fn() -> Result<BoxedConnection>` closure is a [ConnectionFactory].
+pub trait ConnectionFactory: Send {
+    /// Open a new connection to the peer.
+    fn connect(&self) -> Result<BoxedConnection>;
+}
+
+impl<F> ConnectionFactory for F
+where
+    F: Fn() -> Result<BoxedConnection> + Send,
+{
+    fn connect(&self) -> Result<BoxedConnection> {
+        self()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::messages::KeepAlive;
+
+    use super::*;
+
+    /// Only implements the required [ConnectionRead::receive].
+    struct BlockingRead;
+
+    impl ConnectionRead for BlockingRead {
+        fn receive(&self) -> Result<Message> {
+            Ok(Message::KeepAlive(KeepAlive))
+        }
+    }
+
+    #[test]
+    fn receive_timeout_falls_back_to_receive() {
+        assert_eq!(
+            BlockingRead
+                .receive_timeout(Duration::from_millis(10))
+                .unwrap(),
+            Some(Message::KeepAlive(KeepAlive))
+        );
+    }
 }
@@ -0,0 +1,218 @@
+//! The Diffie-Hellman key exchange of Message Stream Encryption, over the fixed 768-bit prime
+//! and generator 2 that the spec prescribes.
+//!
+//! The arithmetic is done with Montgomery multiplication on 32-bit limbs. It isn't constant
+//! time, but then again MSE isn't meant to stand up to a serious attacker.
+
+use rand::Rng;
+
+/// The length of public keys and shared secrets, in bytes.
+pub const KEY_LENGTH: usize = 96;
+/// The spec recommends 160-bit private keys.
+const PRIVATE_KEY_LENGTH: usize = 20;
+const PRIME: &str = "FFFFFFFFFFFFFFFFC90FDAA22168C234C4C6628B80DC1CD129024E088A67CC74020BBEA63B139B22514A08798E3404DDEF9519B3CD3A431B302B0A6DF25F14374FE1356D6D51C245E485B576625E7EC6F44C42E9A63A36210000000000090563";
+const GENERATOR: u32 = 2;
+
+const LIMBS: usize = KEY_LENGTH / 4;
+/// A number below 2^768, least significant limb first.
+type Limbs = [u32; LIMBS];
+
+/// Our half of a key exchange.
+pub struct KeyPair {
+    private: [u8; PRIVATE_KEY_LENGTH],
+    /// What we send to the peer, big-endian like everything else on the wire.
+    pub public: [u8; KEY_LENGTH],
+}
+
+impl KeyPair {
+    pub fn new(private: [u8; PRIVATE_KEY_LENGTH]) -> Self {
+        let modulus = Modulus::new();
+        let mut generator = [0; LIMBS];
+        generator[0] = GENERATOR;
+        let public = to_bytes(&modulus.pow(&generator, &private));
+        Self { private, public }
+    }
+
+    pub fn random() -> Self {
+        Self::new(rand::thread_rng().gen())
+    }
+
+    /// The secret we share with the peer whose public key this is.
+    pub fn shared_secret(&self, peer_public: &[u8; KEY_LENGTH]) -> [u8; KEY_LENGTH] {
+        let modulus = Modulus::new();
+        to_bytes(&modulus.pow(&from_bytes(peer_public), &self.private))
+    }
+}
+
+fn from_bytes(bytes: &[u8; KEY_LENGTH]) -> Limbs {
+    let mut limbs = [0; LIMBS];
+    for (limb, chunk) in limbs.iter_mut().zip(bytes.rchunks_exact(4)) {
+        *limb = u32::from_be_bytes(chunk.try_into().expect("chunks to be 4 bytes"));
+    }
+    limbs
+}
+
+fn to_bytes(limbs: &Limbs) -> [u8; KEY_LENGTH] {
+    let mut bytes = [0; KEY_LENGTH];
+    for (chunk, limb) in bytes.rchunks_exact_mut(4).zip(limbs) {
+        chunk.copy_from_slice(&limb.to_be_bytes());
+    }
+    bytes
+}
+
+fn at_least(a: &Limbs, b: &Limbs) -> bool {
+    a.iter().rev().cmp(b.iter().rev()).is_ge()
+}
+
+/// `a -= b`, wrapping around if `b` is bigger.
+fn subtract(a: &mut Limbs, b: &Limbs) {
+    let mut borrow = false;
+    for (a, b) in a.iter_mut().zip(b) {
+        let (difference, borrowed) = a.overflowing_sub(*b);
+        let (difference, borrowed_again) = difference.overflowing_sub(u32::from(borrow));
+        *a = difference;
+        borrow = borrowed || borrowed_again;
+    }
+}
+
+struct Modulus {
+    n: Limbs,
+    /// `-n^-1 mod 2^32`
+    n_inverse: u32,
+    /// `R^2 mod n`, with `R = 2^768`, to move numbers into Montgomery form.
+    r_squared: Limbs,
+}
+
+// Truncating casts are how the limbs are split off of the wider intermediate results.
+#[allow(clippy::cast_possible_truncation)]
+impl Modulus {
+    fn new() -> Self {
+        let mut bytes = [0; KEY_LENGTH];
+        hex::decode_to_slice(PRIME, &mut bytes).expect("the prime to be valid hex");
+        let n = from_bytes(&bytes);
+
+        // Newton's method, every step doubles the number of correct bits.
+        let mut inverse: u32 = 1;
+        for _ in 0..5 {
+            inverse = inverse.wrapping_mul(2u32.wrapping_sub(n[0].wrapping_mul(inverse)));
+        }
+
+        // Double 1 until it's 2^1536, reducing along the way.
+        let mut r_squared = [0; LIMBS];
+        r_squared[0] = 1;
+        for _ in 0..2 * 32 * LIMBS {
+            let overflow = r_squared[LIMBS - 1] >> 31 == 1;
+            for index in (1..LIMBS).rev() {
+                r_squared[index] = (r_squared[index] << 1) | (r_squared[index - 1] >> 31);
+            }
+            r_squared[0] <<= 1;
+            if overflow || at_least(&r_squared, &n) {
+                subtract(&mut r_squared, &n);
+            }
+        }
+
+        Self {
+            n,
+            n_inverse: inverse.wrapping_neg(),
+            r_squared,
+        }
+    }
+
+    /// `a * b * R^-1 mod n`
+    fn multiply(&self, a: &Limbs, b: &Limbs) -> Limbs {
+        let mut t = [0u32; LIMBS + 2];
+        for b in b {
+            let mut carry = 0u64;
+            for (t, a) in t.iter_mut().zip(a) {
+                let sum = u64::from(*t) + u64::from(*a) * u64::from(*b) + carry;
+                *t = sum as u32;
+                carry = sum >> 32;
+            }
+            let sum = u64::from(t[LIMBS]) + carry;
+            t[LIMBS] = sum as u32;
+            t[LIMBS + 1] = (sum >> 32) as u32;
+
+            // Add a multiple of n that makes the lowest limb zero, then shift it out.
+            let m = t[0].wrapping_mul(self.n_inverse);
+            let mut carry = (u64::from(t[0]) + u64::from(m) * u64::from(self.n[0])) >> 32;
+            for index in 1..LIMBS {
+                let sum = u64::from(t[index]) + u64::from(m) * u64::from(self.n[index]) + carry;
+                t[index - 1] = sum as u32;
+                carry = sum >> 32;
+            }
+            let sum = u64::from(t[LIMBS]) + carry;
+            t[LIMBS - 1] = sum as u32;
+            t[LIMBS] = t[LIMBS + 1] + (sum >> 32) as u32;
+        }
+        let mut result: Limbs = t[..LIMBS].try_into().expect("t to have enough limbs");
+        if t[LIMBS] != 0 || at_least(&result, &self.n) {
+            subtract(&mut result, &self.n);
+        }
+        result
+    }
+
+    /// `base^exponent mod n`, with the exponent in big-endian bytes.
+    fn pow(&self, base: &Limbs, exponent: &[u8]) -> Limbs {
+        let mut base = *base;
+        // 2^768 < 2n, so a single subtraction is enough
+        if at_least(&base, &self.n) {
+            subtract(&mut base, &self.n);
+        }
+        let mut one = [0; LIMBS];
+        one[0] = 1;
+        let base = self.multiply(&base, &self.r_squared);
+        let mut result = self.multiply(&one, &self.r_squared);
+        for byte in exponent {
+            for bit in (0..8).rev() {
+                result = self.multiply(&result, &result);
+                if byte >> bit & 1 == 1 {
+                    result = self.multiply(&result, &base);
+                }
+            }
+        }
+        self.multiply(&result, &one)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn public_key_of_small_private_key() {
+        let mut private = [0; PRIVATE_KEY_LENGTH];
+        private[PRIVATE_KEY_LENGTH - 1] = 10;
+        let mut expected = [0; KEY_LENGTH];
+        expected[KEY_LENGTH - 2..].copy_from_slice(&1024u16.to_be_bytes());
+
+        assert_eq!(KeyPair::new(private).public, expected);
+    }
+
+    #[test]
+    fn known_identities_hold() {
+        let modulus = Modulus::new();
+        let mut minus_one = modulus.n;
+        minus_one[0] -= 1;
+        let mut one = [0; LIMBS];
+        one[0] = 1;
+
+        assert_eq!(modulus.pow(&minus_one, &[2]), one);
+        assert_eq!(modulus.pow(&modulus.n, &[1]), [0; LIMBS]);
+        // Fermat's little theorem, as the modulus is prime
+        let mut two = [0; LIMBS];
+        two[0] = 2;
+        assert_eq!(modulus.pow(&two, &to_bytes(&minus_one)), one);
+    }
+
+    #[test]
+    fn both_sides_derive_the_same_secret() {
+        let a = KeyPair::new([1; PRIVATE_KEY_LENGTH]);
+        let b = KeyPair::new([0xfe; PRIVATE_KEY_LENGTH]);
+
+        let secret = a.shared_secret(&b.public);
+
+        assert_eq!(secret, b.shared_secret(&a.public));
+        assert_ne!(secret, [0; KEY_LENGTH]);
+        assert_ne!(a.public, b.public);
+    }
+}
@@ -0,0 +1,440 @@
+//! Message Stream Encryption (MSE, also known as Protocol Encryption), which obfuscates
+//! connections so that they don't look like BitTorrent to anyone watching the traffic.
+//!
+//! Both peers agree on a key with Diffie-Hellman, after which everything is encrypted with RC4.
+//! The receiving side also accepts plaintext connections, so it's safe to always use.
+
+use std::io::{BufReader, BufWriter, Cursor, Read, Write};
+
+use eyre::{bail, ensure, Result, WrapErr};
+use rand::Rng;
+
+use crate::connections::mse::dh::{KeyPair, KEY_LENGTH};
+use crate::connections::mse::rc4::Rc4;
+use crate::connections::std_io_connection::{
+    spawn_connection, StdIoConfig, StdIoConnectionRead, StdIoConnectionWrite,
+};
+use crate::sha1::sha1;
+use crate::InfoHash;
+
+mod dh;
+mod rc4;
+
+/// The verification constant, which tells the receiving side that it decrypted correctly.
+const VC: [u8; 8] = [0; 8];
+const CRYPTO_PLAINTEXT: u32 = 0x01;
+const CRYPTO_RC4: u32 = 0x02;
+/// Random padding of up to this many bytes hides the length of the handshake.
+const MAX_PADDING: usize = 512;
+/// How every plaintext connection starts, which can't be mistaken for a public key.
+const PLAINTEXT_HANDSHAKE: &[u8; 20] = b"\x13BitTorrent protocol";
+
+/// Negotiate encryption on a fresh connection, and then create a Connection like
+/// [std_io_connection_with_config](crate::std_io_connection_with_config) on top of it.
+///
+/// `outgoing` is whether we initiated the connection. Incoming connections that start with a
+/// plaintext handshake are accepted as-is, and either side can end up picking plaintext if the
+/// other side doesn't want RC4. An outgoing connection can't fall back to plaintext if the peer
+/// doesn't support MSE at all though, that would take a new connection.
+///
+/// This blocks until the key exchange is done.
+pub fn mse_connection<R, W>(
+    config: StdIoConfig,
+    info_hash: InfoHash,
+    outgoing: bool,
+    reader: R,
+    mut writer: W,
+) -> Result<(StdIoConnectionWrite, StdIoConnectionRead)>
+where
+    R: Read + Send + 'static,
+    W: Write + Send + 'static,
+{
+    // Buffered before the key exchange already, which reads the padding a byte at a time.
+    let mut reader = BufReader::with_capacity(config.read_capacity, reader);
+    let negotiated = if outgoing {
+        initiate(info_hash, &mut reader, &mut writer)
+    } else {
+        accept(info_hash, &mut reader, &mut writer)
+    }
+    .wrap_err("MSE handshake failed")?;
+    let reader = Cursor::new(negotiated.already_read).chain(MseRead {
+        inner: reader,
+        cipher: negotiated.read_cipher,
+    });
+    let writer = MseWrite {
+        inner: writer,
+        cipher: negotiated.write_cipher,
+        buf: Vec::new(),
+    };
+    Ok(spawn_connection(
+        config.initial_buffer_size,
+        config.flush_linger,
+        reader,
+        BufWriter::with_capacity(config.write_capacity, writer),
+    ))
+}
+
+/// The outcome of the MSE handshake. The ciphers are `None` if plaintext was picked.
+struct Negotiated {
+    read_cipher: Option<Rc4>,
+    write_cipher: Option<Rc4>,
+    /// Data that was read during the handshake, but belongs to the BitTorrent connection.
+    already_read: Vec<u8>,
+}
+
+fn hash(parts: &[&[u8]]) -> [u8; 20] {
+    sha1(&parts.concat())
+}
+
+/// Lets the receiving side know which torrent we want, without telling anyone else.
+fn torrent_hash(secret: &[u8], info_hash: &[u8]) -> [u8; 20] {
+    let mut hash_req2 = hash(&[b"req2", info_hash]);
+    for (byte, mask) in hash_req2.iter_mut().zip(hash(&[b"req3", secret])) {
+        *byte ^= mask;
+    }
+    hash_req2
+}
+
+fn send_public_key(keys: &KeyPair, writer: &mut impl Write) -> Result<()> {
+    let mut rng = rand::thread_rng();
+    let padding: Vec<u8> = (0..rng.gen_range(0..=MAX_PADDING))
+        .map(|_| rng.gen())
+        .collect();
+    writer.write_all(&keys.public)?;
+    writer.write_all(&padding)?;
+    writer.flush()?;
+    Ok(())
+}
+
+/// Skip the peer's random padding, up to and including `marker`.
+fn skip_past(reader: &mut impl Read, marker: &[u8]) -> Result<()> {
+    let mut window = Vec::with_capacity(MAX_PADDING + marker.len());
+    while !window.ends_with(marker) {
+        ensure!(
+            window.len() < MAX_PADDING + marker.len(),
+            "Peer sent more than {MAX_PADDING} bytes of padding"
+        );
+        let mut byte = [0];
+        reader.read_exact(&mut byte)?;
+        window.push(byte[0]);
+    }
+    Ok(())
+}
+
+/// Read `length` bytes, and decrypt them.
+fn read_decrypted(reader: &mut impl Read, cipher: &mut Rc4, length: usize) -> Result<Vec<u8>> {
+    let mut data = vec![0; length];
+    reader.read_exact(&mut data)?;
+    cipher.apply(&mut data);
+    Ok(data)
+}
+
+fn read_u16(reader: &mut impl Read, cipher: &mut Rc4) -> Result<u16> {
+    let bytes = read_decrypted(reader, cipher, 2)?;
+    Ok(u16::from_be_bytes([bytes[0], bytes[1]]))
+}
+
+fn read_padding(reader: &mut impl Read, cipher: &mut Rc4) -> Result<()> {
+    let length = usize::from(read_u16(reader, cipher)?);
+    ensure!(
+        length <= MAX_PADDING,
+        "Peer sent {length} bytes of padding, more than {MAX_PADDING}"
+    );
+    read_decrypted(reader, cipher, length)?;
+    Ok(())
+}
+
+fn initiate(
+    info_hash: InfoHash,
+    reader: &mut impl Read,
+    writer: &mut impl Write,
+) -> Result<Negotiated> {
+    let keys = KeyPair::random();
+    send_public_key(&keys, writer)?;
+    let mut peer_public = [0; KEY_LENGTH];
+    reader.read_exact(&mut peer_public)?;
+    let secret = keys.shared_secret(&peer_public);
+    let info_hash = Vec::from(info_hash);
+    let mut encrypt = Rc4::for_mse(&hash(&[b"keyA", &secret, &info_hash]));
+    let mut decrypt = Rc4::for_mse(&hash(&[b"keyB", &secret, &info_hash]));
+
+    let mut message = hash(&[b"req1", &secret]).to_vec();
+    message.extend(torrent_hash(&secret, &info_hash));
+    let mut encrypted = VC.to_vec();
+    encrypted.extend((CRYPTO_PLAINTEXT | CRYPTO_RC4).to_be_bytes());
+    // No padding, and no initial payload: the BitTorrent handshake is sent afterwards.
+    encrypted.extend(0u16.to_be_bytes());
+    encrypted.extend(0u16.to_be_bytes());
+    encrypt.apply(&mut encrypted);
+    message.extend(encrypted);
+    writer.write_all(&message)?;
+    writer.flush()?;
+
+    // The peer's reply starts with the encrypted VC, right after its padding.
+    let mut encrypted_vc = VC;
+    decrypt.apply(&mut encrypted_vc);
+    skip_past(reader, &encrypted_vc)?;
+    let crypto_select = read_decrypted(reader, &mut decrypt, 4)?;
+    let crypto_select = u32::from_be_bytes(crypto_select.try_into().expect("4 bytes"));
+    read_padding(reader, &mut decrypt)?;
+    match crypto_select {
+        CRYPTO_RC4 => Ok(Negotiated {
+            read_cipher: Some(decrypt),
+            write_cipher: Some(encrypt),
+            already_read: Vec::new(),
+        }),
+        CRYPTO_PLAINTEXT => Ok(Negotiated {
+            read_cipher: None,
+            write_cipher: None,
+            already_read: Vec::new(),
+        }),
+        other => bail!("Peer selected unknown encryption method {other:#x}"),
+    }
+}
+
+fn accept(
+    info_hash: InfoHash,
+    reader: &mut impl Read,
+    writer: &mut impl Write,
+) -> Result<Negotiated> {
+    let mut peer_public = [0; KEY_LENGTH];
+    reader.read_exact(&mut peer_public[..PLAINTEXT_HANDSHAKE.len()])?;
+    if peer_public.starts_with(PLAINTEXT_HANDSHAKE) {
+        return Ok(Negotiated {
+            read_cipher: None,
+            write_cipher: None,
+            already_read: PLAINTEXT_HANDSHAKE.to_vec(),
+        });
+    }
+    reader.read_exact(&mut peer_public[PLAINTEXT_HANDSHAKE.len()..])?;
+    let keys = KeyPair::random();
+    send_public_key(&keys, writer)?;
+    let secret = keys.shared_secret(&peer_public);
+    let info_hash = Vec::from(info_hash);
+
+    skip_past(reader, &hash(&[b"req1", &secret]))?;
+    let mut requested_torrent = [0; 20];
+    reader.read_exact(&mut requested_torrent)?;
+    ensure!(
+        requested_torrent == torrent_hash(&secret, &info_hash),
+        "Peer wants a different torrent"
+    );
+    let mut decrypt = Rc4::for_mse(&hash(&[b"keyA", &secret, &info_hash]));
+    let mut encrypt = Rc4::for_mse(&hash(&[b"keyB", &secret, &info_hash]));
+
+    ensure!(
+        read_decrypted(reader, &mut decrypt, VC.len())? == VC,
+        "Peer sent an invalid verification constant"
+    );
+    let crypto_provide = read_decrypted(reader, &mut decrypt, 4)?;
+    let crypto_provide = u32::from_be_bytes(crypto_provide.try_into().expect("4 bytes"));
+    read_padding(reader, &mut decrypt)?;
+    let initial_payload_length = usize::from(read_u16(reader, &mut decrypt)?);
+    let initial_payload = read_decrypted(reader, &mut decrypt, initial_payload_length)?;
+
+    let crypto_select = if crypto_provide & CRYPTO_RC4 != 0 {
+        CRYPTO_RC4
+    } else if crypto_provide & CRYPTO_PLAINTEXT != 0 {
+        CRYPTO_PLAINTEXT
+    } else {
+        bail!("Peer provided no supported encryption method: {crypto_provide:#x}");
+    };
+    let mut reply = VC.to_vec();
+    reply.extend(crypto_select.to_be_bytes());
+    reply.extend(0u16.to_be_bytes());
+    encrypt.apply(&mut reply);
+    writer.write_all(&reply)?;
+    writer.flush()?;
+
+    let rc4 = crypto_select == CRYPTO_RC4;
+    Ok(Negotiated {
+        read_cipher: rc4.then_some(decrypt),
+        write_cipher: rc4.then_some(encrypt),
+        already_read: initial_payload,
+    })
+}
+
+/// Decrypts everything read from the inner reader, if RC4 was picked.
+struct MseRead<R> {
+    inner: R,
+    cipher: Option<Rc4>,
+}
+
+impl<R: Read> Read for MseRead<R> {
+    fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+        let bytes_read = self.inner.read(buf)?;
+        if let Some(cipher) = &mut self.cipher {
+            cipher.apply(&mut buf[..bytes_read]);
+        }
+        Ok(bytes_read)
+    }
+}
+
+/// Encrypts everything written to the inner writer, if RC4 was picked.
+struct MseWrite<W> {
+    inner: W,
+    cipher: Option<Rc4>,
+    /// Reused for every write, to hold the encrypted data.
+    buf: Vec<u8>,
+}
+
+impl<W: Write> Write for MseWrite<W> {
+    fn write(&mut self, data: &[u8]) -> std::io::Result<usize> {
+        let Some(cipher) = &mut self.cipher else {
+            return self.inner.write(data);
+        };
+        // The cipher can't be rewound, so everything it encrypted has to be written.
+        self.buf.clear();
+        self.buf.extend(data);
+        cipher.apply(&mut self.buf);
+        self.inner.write_all(&self.buf)?;
+        Ok(data.len())
+    }
+
+    fn flush(&mut self) -> std::io::Result<()> {
+        self.inner.flush()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::mpsc::{channel, Receiver, Sender};
+    use std::sync::{Arc, Mutex};
+    use std::thread;
+    use std::time::Duration;
+
+    use crate::messages::{Handshake, Message};
+    use crate::{std_io_connection, ConnectionRead, ConnectionWrite, PeerId};
+
+    use super::*;
+
+    /// One direction of an in-memory connection, which keeps a copy of everything sent over it.
+    fn pipe() -> (PipeWrite, PipeRead, Arc<Mutex<Vec<u8>>>) {
+        let (sender, receiver) = channel();
+        let sent = Arc::new(Mutex::new(Vec::new()));
+        let write = PipeWrite {
+            sender,
+            sent: sent.clone(),
+        };
+        let read = PipeRead {
+            receiver,
+            pending: Vec::new(),
+        };
+        (write, read, sent)
+    }
+
+    struct PipeWrite {
+        sender: Sender<Vec<u8>>,
+        sent: Arc<Mutex<Vec<u8>>>,
+    }
+
+    impl Write for PipeWrite {
+        fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+            self.sent.lock().unwrap().extend(buf);
+            // The other side hanging up just looks like a slow connection.
+            let _ = self.sender.send(buf.to_vec());
+            Ok(buf.len())
+        }
+
+        fn flush(&mut self) -> std::io::Result<()> {
+            Ok(())
+        }
+    }
+
+    struct PipeRead {
+        receiver: Receiver<Vec<u8>>,
+        pending: Vec<u8>,
+    }
+
+    impl Read for PipeRead {
+        fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+            if self.pending.is_empty() {
+                match self.receiver.recv() {
+                    Ok(data) => self.pending = data,
+                    Err(_) => return Ok(0),
+                }
+            }
+            let length = buf.len().min(self.pending.len());
+            buf[..length].copy_from_slice(&self.pending[..length]);
+            self.pending.drain(..length);
+            Ok(length)
+        }
+    }
+
+    fn handshake(id: u8) -> Message {
+        Message::Handshake(Handshake::new(
+            InfoHash::new([1; 20]),
+            PeerId::new([id; 20]),
+        ))
+    }
+
+    #[test]
+    fn encrypted_handshake() {
+        let info_hash = InfoHash::new([1; 20]);
+        let (a_write, b_read, sent_by_a) = pipe();
+        let (b_write, a_read, _) = pipe();
+        let receiver = thread::spawn(move || {
+            mse_connection(StdIoConfig::default(), info_hash, false, b_read, b_write)
+        });
+        let (mut a_write, a_read) =
+            mse_connection(StdIoConfig::default(), info_hash, true, a_read, a_write).unwrap();
+        let (mut b_write, b_read) = receiver.join().unwrap().unwrap();
+
+        a_write.send(handshake(2)).unwrap();
+        b_write.send(handshake(3)).unwrap();
+
+        let timeout = Duration::from_secs(1);
+        assert_eq!(b_read.receive_timeout(timeout).unwrap(), Some(handshake(2)));
+        assert_eq!(a_read.receive_timeout(timeout).unwrap(), Some(handshake(3)));
+        let sent_by_a = sent_by_a.lock().unwrap();
+        assert!(!sent_by_a
+            .windows(PLAINTEXT_HANDSHAKE.len())
+            .any(|window| window == PLAINTEXT_HANDSHAKE));
+    }
+
+    #[test]
+    fn plaintext_connections_are_accepted() {
+        let info_hash = InfoHash::new([1; 20]);
+        let (a_write, b_read, _) = pipe();
+        let (b_write, a_read, _) = pipe();
+        let receiver = thread::spawn(move || {
+            mse_connection(StdIoConfig::default(), info_hash, false, b_read, b_write)
+        });
+        let (mut a_write, a_read) = std_io_connection(1024, a_read, a_write);
+
+        a_write.send(handshake(2)).unwrap();
+        let (mut b_write, b_read) = receiver.join().unwrap().unwrap();
+        b_write.send(handshake(3)).unwrap();
+
+        let timeout = Duration::from_secs(1);
+        assert_eq!(b_read.receive_timeout(timeout).unwrap(), Some(handshake(2)));
+        assert_eq!(a_read.receive_timeout(timeout).unwrap(), Some(handshake(3)));
+    }
+
+    #[test]
+    fn connection_for_another_torrent_is_refused() {
+        let (a_write, b_read, _) = pipe();
+        let (b_write, a_read, _) = pipe();
+        let receiver = thread::spawn(move || {
+            mse_connection(
+                StdIoConfig::default(),
+                InfoHash::new([2; 20]),
+                false,
+                b_read,
+                b_write,
+            )
+            .map(|_| ())
+        });
+
+        let initiated = mse_connection(
+            StdIoConfig::default(),
+            InfoHash::new([1; 20]),
+            true,
+            a_read,
+            a_write,
+        );
+
+        assert!(initiated.is_err());
+        assert!(receiver.join().unwrap().is_err());
+    }
+}
@@ -0,0 +1,79 @@
+//! RC4, the stream cipher used by Message Stream Encryption. Like SHA-1 it's long broken,
+//! but MSE is only meant to obfuscate traffic, not to keep it secret.
+
+/// MSE throws away this much of the keystream, as its start is the weakest part.
+const DISCARD: usize = 1024;
+
+#[derive(Clone)]
+pub struct Rc4 {
+    state: [u8; 256],
+    i: u8,
+    j: u8,
+}
+
+impl Rc4 {
+    /// A cipher that hasn't discarded any of its keystream.
+    #[must_use]
+    pub fn new(key: &[u8]) -> Self {
+        let mut state = [0; 256];
+        for (index, byte) in state.iter_mut().enumerate() {
+            // at most 255, so the cast is safe
+            #[allow(clippy::cast_possible_truncation)]
+            let value = index as u8;
+            *byte = value;
+        }
+        let mut j: u8 = 0;
+        for index in 0..256 {
+            j = j
+                .wrapping_add(state[index])
+                .wrapping_add(key[index % key.len()]);
+            state.swap(index, usize::from(j));
+        }
+        Self { state, i: 0, j: 0 }
+    }
+
+    /// A cipher set up the way MSE uses it, with the first 1024 bytes of keystream discarded.
+    #[must_use]
+    pub fn for_mse(key: &[u8]) -> Self {
+        let mut rc4 = Self::new(key);
+        rc4.apply(&mut [0; DISCARD]);
+        rc4
+    }
+
+    /// Encrypt or decrypt `data` in place, which is the same thing for a stream cipher.
+    pub fn apply(&mut self, data: &mut [u8]) {
+        for byte in data {
+            self.i = self.i.wrapping_add(1);
+            self.j = self.j.wrapping_add(self.state[usize::from(self.i)]);
+            self.state.swap(usize::from(self.i), usize::from(self.j));
+            let index =
+                self.state[usize::from(self.i)].wrapping_add(self.state[usize::from(self.j)]);
+            *byte ^= self.state[usize::from(index)];
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn known_vectors() {
+        let mut data = *b"Plaintext";
+        Rc4::new(b"Key").apply(&mut data);
+        assert_eq!(hex::encode(data), "bbf316e8d940af0ad3");
+
+        let mut data = *b"Attack at dawn";
+        Rc4::new(b"Secret").apply(&mut data);
+        assert_eq!(hex::encode(data), "45a01f645fc35b383552544b9bf5");
+    }
+
+    #[test]
+    fn decrypts_what_it_encrypts() {
+        let mut data = b"Hello, world!".to_vec();
+        Rc4::for_mse(b"key").apply(&mut data);
+        assert_ne!(data, b"Hello, world!");
+        Rc4::for_mse(b"key").apply(&mut data);
+        assert_eq!(data, b"Hello, world!");
+    }
+}
@@ -1,74 +1,269 @@
 use std::cmp::min;
-use std::io::{Read, Write};
-use std::sync::atomic::AtomicBool;
-use std::sync::mpsc::{Receiver, SyncSender};
-use std::sync::Arc;
+use std::io::{BufReader, BufWriter, ErrorKind, Read, Write};
+use std::sync::mpsc::{Receiver, RecvTimeoutError, SyncSender, TrySendError};
+use std::thread::JoinHandle;
+use std::time::{Duration, Instant};
 
-use eyre::Result;
-use eyre::WrapErr;
-use tracing::{error, warn};
+use eyre::{bail, eyre, Result};
 
-use crate::messages::{DecodedMessage, Message};
-use crate::{ConnectionRead, ConnectionWrite, SansIo};
+use crate::connections::SHUTDOWN_POLL_INTERVAL;
+use crate::log::{error, trace, warn};
+use crate::messages::Message;
+use crate::{ConnectionRead, ConnectionWrite, SansIo, SendStatus, ShutdownSignal};
 
 // 64 kB * 10 messages => at most 640 kB per connection
 // In practice the first connection causes the application to allocate about ~10mB of memory,
 // but after that even malicious connections actually use a lot less than 640 kB each.
 const MAX_BUFFER_SIZE: usize = 64 * 1024;
 const MAX_BUFFERED_MESSAGES: usize = 10;
+/// How many encoded messages can be waiting to be written before sending blocks.
+const MAX_QUEUED_MESSAGES: usize = 64;
+/// Flush at least this often while bulk data is being written.
+const FLUSH_THRESHOLD: usize = 64 * 1024;
+/// How long to wait for more data before flushing what has been written so far.
+pub const DEFAULT_FLUSH_LINGER: Duration = Duration::from_millis(5);
+/// The default capacity of the buffers around the reader and writer, see [StdIoConfig].
+pub const DEFAULT_IO_BUFFER_CAPACITY: usize = 64 * 1024;
+/// How long dropping a connection waits for its queued messages to be written.
+const DRAIN_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// How a Connection created by [std_io_connection_with_config] buffers its data.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct StdIoConfig {
+    /// The initial size of the buffer that received messages are decoded from. It grows
+    /// for bigger messages, so this only has to fit the common ones.
+    pub initial_buffer_size: usize,
+    /// How long bulk data can wait for more before it's flushed, see
+    /// [std_io_connection_with_linger].
+    pub flush_linger: Duration,
+    /// The capacity of the [BufReader] around the reader, so that small messages don't each
+    /// take a read. Zero reads straight from the reader.
+    pub read_capacity: usize,
+    /// The capacity of the [BufWriter] around the writer. Messages sent between flushes are
+    /// coalesced into writes of up to this size. Zero writes straight to the writer.
+    pub write_capacity: usize,
+}
+
+impl Default for StdIoConfig {
+    fn default() -> Self {
+        Self {
+            initial_buffer_size: 1024,
+            flush_linger: DEFAULT_FLUSH_LINGER,
+            read_capacity: DEFAULT_IO_BUFFER_CAPACITY,
+            write_capacity: DEFAULT_IO_BUFFER_CAPACITY,
+        }
+    }
+}
 
 /// A [ConnectionRead] implementation built on top of [std::io::Read].
+///
+/// Receiving gives up with an error once the [shutdown_signal](Self::shutdown_signal) is
+/// signalled, which dropping the connection does too.
 pub struct StdIoConnectionRead {
     receiver: Receiver<Message>,
-    #[allow(dead_code)]
-    state: Arc<ConnectionState>,
+    shutdown: ShutdownSignal,
 }
 
 /// A [ConnectionWrite] implementation built on top of [std::io::Write].
-pub struct StdIoConnectionWrite<W> {
-    writer: W,
-    #[allow(dead_code)]
-    state: Arc<ConnectionState>,
+///
+/// Messages are written on a separate thread, so that a slow peer doesn't hold up whoever is
+/// sending to it. Dropping the connection waits for all queued messages to be written, but
+/// only for a few seconds, as a peer that stopped reading could keep them from ever being
+/// written. The thread is left to finish on its own after that.
+pub struct StdIoConnectionWrite {
+    sender: Option<SyncSender<QueuedMessages>>,
+    join_handle: Option<JoinHandle<()>>,
+    /// Disconnected once the send loop has finished.
+    finished: Receiver<()>,
+    drain_timeout: Duration,
+}
+
+/// One or more messages that are written out together.
+struct QueuedMessages {
+    messages: Vec<Message>,
+    /// Whether the messages should be flushed right away, instead of lingering.
+    urgent: bool,
+}
+
+impl QueuedMessages {
+    fn new(messages: Vec<Message>) -> Self {
+        Self {
+            urgent: messages
+                .iter()
+                .any(|message| !matches!(message, Message::Piece(_) | Message::KeepAlive(_))),
+            messages,
+        }
+    }
+
+    fn single(message: Message) -> Self {
+        Self::new(vec![message])
+    }
+
+    fn into_single(mut self) -> Message {
+        self.messages
+            .pop()
+            .expect("to have been queued as a single message")
+    }
 }
 
 /// Create a Connection built on top of [std::io::Read] and [std::io::Write].
+///
+/// Every message is written to `writer` as-is, which is best when it's already buffered.
+/// Use [std_io_connection_with_config] to have the streams buffered for you.
 pub fn std_io_connection<R, W>(
     initial_buffer_size: usize,
     reader: R,
     writer: W,
-) -> (StdIoConnectionWrite<W>, StdIoConnectionRead)
+) -> (StdIoConnectionWrite, StdIoConnectionRead)
+where
+    R: Read + Send + 'static,
+    W: Write + Send + 'static,
+{
+    std_io_connection_with_linger(initial_buffer_size, DEFAULT_FLUSH_LINGER, reader, writer)
+}
+
+/// Create a Connection like [std_io_connection], but with a custom flush linger.
+///
+/// Bulk data (pieces and keep-alives) isn't flushed right away, but only once `flush_linger`
+/// has passed without anything else being sent, so that back-to-back blocks share a flush.
+/// All other messages are flushed immediately, as the peer is probably waiting for them.
+pub fn std_io_connection_with_linger<R, W>(
+    initial_buffer_size: usize,
+    flush_linger: Duration,
+    reader: R,
+    writer: W,
+) -> (StdIoConnectionWrite, StdIoConnectionRead)
 where
     R: Read + Send + 'static,
-    W: Write,
+    W: Write + Send + 'static,
+{
+    spawn_connection(initial_buffer_size, flush_linger, reader, writer)
+}
+
+/// Create a Connection like [std_io_connection], with the reader and writer wrapped in a
+/// [BufReader] and [BufWriter] of the capacities in `config`.
+///
+/// This is how unbuffered streams, like the ones opened by a [TcpTransport](crate::TcpTransport),
+/// are meant to be used.
+pub fn std_io_connection_with_config<R, W>(
+    config: StdIoConfig,
+    reader: R,
+    writer: W,
+) -> (StdIoConnectionWrite, StdIoConnectionRead)
+where
+    R: Read + Send + 'static,
+    W: Write + Send + 'static,
+{
+    spawn_connection(
+        config.initial_buffer_size,
+        config.flush_linger,
+        BufReader::with_capacity(config.read_capacity, reader),
+        BufWriter::with_capacity(config.write_capacity, writer),
+    )
+}
+
+/// Start the threads that read and write the connection.
+pub(crate) fn spawn_connection<R, W>(
+    initial_buffer_size: usize,
+    flush_linger: Duration,
+    reader: R,
+    writer: W,
+) -> (StdIoConnectionWrite, StdIoConnectionRead)
+where
+    R: Read + Send + 'static,
+    W: Write + Send + 'static,
 {
     let (sender, receiver) = std::sync::mpsc::sync_channel(MAX_BUFFERED_MESSAGES);
-    let state = Arc::new(ConnectionState::new());
-    // Letting this thread die on shutdown is fine, since the connection doesn't directly write
-    // to disk or anything, it's just a buffer that then communicates with the actors.
+    let shutdown = ShutdownSignal::new();
+    // A read can't be interrupted, so this thread only notices the shutdown once its current
+    // read returns. A reader with a read timeout lets it notice sooner.
     let _ = std::thread::spawn({
-        let state = state.clone();
-        move || receive_loop(initial_buffer_size, reader, sender, state)
+        let shutdown = shutdown.clone();
+        move || receive_loop(initial_buffer_size, reader, sender, &shutdown)
+    });
+    let read = StdIoConnectionRead { receiver, shutdown };
+
+    let (sender, receiver) = std::sync::mpsc::sync_channel(MAX_QUEUED_MESSAGES);
+    let (done, finished) = std::sync::mpsc::channel();
+    let join_handle = std::thread::spawn(move || {
+        send_loop(flush_linger, writer, receiver);
+        drop(done);
     });
     let write = StdIoConnectionWrite {
-        writer,
-        state: state.clone(),
+        sender: Some(sender),
+        join_handle: Some(join_handle),
+        finished,
+        drain_timeout: DRAIN_TIMEOUT,
     };
-    let read = StdIoConnectionRead { receiver, state };
     (write, read)
 }
 
+fn send_loop<W: Write>(flush_linger: Duration, mut writer: W, receiver: Receiver<QueuedMessages>) {
+    let mut unflushed = 0;
+    // Reused for every message, so that encoding doesn't allocate once it's grown big enough.
+    let mut buf = Vec::new();
+    // Stops once the sender is dropped and everything queued has been written.
+    loop {
+        let message = if unflushed > 0 {
+            receiver.recv_timeout(flush_linger)
+        } else {
+            receiver.recv().map_err(|_| RecvTimeoutError::Disconnected)
+        };
+        let result = match message {
+            Ok(QueuedMessages { messages, urgent }) => {
+                buf.clear();
+                for message in messages {
+                    message.encode_into(&mut buf);
+                }
+                writer.write_all(&buf).and_then(|()| {
+                    unflushed += buf.len();
+                    if urgent || unflushed >= FLUSH_THRESHOLD {
+                        unflushed = 0;
+                        writer.flush()?;
+                    }
+                    Ok(())
+                })
+            }
+            Err(RecvTimeoutError::Timeout) => {
+                unflushed = 0;
+                writer.flush()
+            }
+            Err(RecvTimeoutError::Disconnected) => {
+                if unflushed > 0 {
+                    if let Err(e) = writer.flush() {
+                        warn!("error flushing the connection: {:?}", e);
+                    }
+                }
+                break;
+            }
+        };
+        if let Err(e) = result {
+            warn!("error writing to the connection: {:?}", e);
+            break;
+        }
+    }
+}
+
 fn receive_loop<R: Read>(
     initial_buffer_size: usize,
     mut reader: R,
     sender: SyncSender<Message>,
-    _state: Arc<crate::connections::std_io_connection::ConnectionState>,
+    shutdown: &ShutdownSignal,
 ) {
     let mut buffer = vec![255; initial_buffer_size];
     let mut buffer_offset = 0;
     'thread: loop {
         'message: loop {
+            if shutdown.is_signalled() {
+                break 'thread;
+            }
             let bytes_read = match reader.read(&mut buffer[buffer_offset..]) {
                 Ok(bytes_read) => bytes_read,
+                // A read timeout only means the peer was quiet, it's a chance to check for
+                // the shutdown.
+                Err(e) if matches!(e.kind(), ErrorKind::WouldBlock | ErrorKind::TimedOut) => {
+                    continue 'message;
+                }
                 Err(e) => {
                     warn!("error reading from the connection: {:?}", e);
                     break 'thread;
@@ -79,30 +274,39 @@ fn receive_loop<R: Read>(
                 break 'thread;
             }
 
-            let opt_message =
-                match Message::from_partial_buffer(&buffer[..buffer_offset + bytes_read]) {
-                    Ok(opt_message) => opt_message,
+            // A single read can deliver many small messages at once, e.g. a burst of `Have`s,
+            // so decode all of them before reading again.
+            let (messages, consumed_bytes) =
+                match Message::decode_all(&buffer[..buffer_offset + bytes_read]) {
+                    Ok(decoded) => decoded,
                     Err(e) => {
                         error!("unexpected error decoding a message: {:?}", e);
                         break 'thread;
                     }
                 };
-
-            if let Some(DecodedMessage {
-                consumed_bytes,
-                message,
-            }) = opt_message
+            // Don't wait for the rest of a message that would never fit in the buffer.
+            // Otherwise a peer could make us hold on to a big buffer by never finishing it.
+            let remaining = &buffer[consumed_bytes..buffer_offset + bytes_read];
+            if let Some(length) =
+                Message::advertised_len(remaining).filter(|length| *length > MAX_BUFFER_SIZE)
             {
+                warn!("Peer announced a message of {length} bytes, which is too big, closing");
+                break 'thread;
+            }
+
+            if !messages.is_empty() {
                 // Reset the buffer, but keep the bytes we didn't consume.
                 // This could probably be done more efficiently, perhaps with a separate offset
                 // or using virtual memory tricks, but eh.
                 buffer.copy_within(consumed_bytes.., 0);
                 buffer_offset = buffer_offset + bytes_read - consumed_bytes;
-                if sender.try_send(message.clone()).is_err() {
-                    warn!("Receiver is full, waiting");
-                    if sender.send(message).is_err() {
-                        // The receiver is gone, we're probably about to exit; stop the thread
-                        break 'thread;
+                for message in messages {
+                    if sender.try_send(message.clone()).is_err() {
+                        warn!("Receiver is full, waiting");
+                        if sender.send(message).is_err() {
+                            // The receiver is gone, we're probably about to exit; stop the thread
+                            break 'thread;
+                        }
                     }
                 }
                 break 'message;
@@ -131,38 +335,129 @@ fn receive_loop<R: Read>(
     }
 }
 
+impl StdIoConnectionRead {
+    /// The signal that makes a blocked [receive](ConnectionRead::receive) give up, and stops
+    /// the thread reading from the peer.
+    pub fn shutdown_signal(&self) -> ShutdownSignal {
+        self.shutdown.clone()
+    }
+}
+
 impl ConnectionRead for StdIoConnectionRead {
     fn receive(&self) -> Result<Message> {
-        self.receiver
-            .recv()
-            .wrap_err("Connection closed, no more messages coming")
+        loop {
+            if let Some(message) = self.receive_timeout(SHUTDOWN_POLL_INTERVAL)? {
+                return Ok(message);
+            }
+        }
+    }
+
+    fn receive_timeout(&self, timeout: Duration) -> Result<Option<Message>> {
+        let deadline = Instant::now() + timeout;
+        loop {
+            if self.shutdown.is_signalled() {
+                bail!("Connection shut down, no more messages coming");
+            }
+            let remaining = deadline.saturating_duration_since(Instant::now());
+            match self
+                .receiver
+                .recv_timeout(min(remaining, SHUTDOWN_POLL_INTERVAL))
+            {
+                Ok(message) => return Ok(Some(message)),
+                Err(RecvTimeoutError::Timeout) if remaining <= SHUTDOWN_POLL_INTERVAL => {
+                    return Ok(None)
+                }
+                Err(RecvTimeoutError::Timeout) => {}
+                Err(RecvTimeoutError::Disconnected) => {
+                    bail!("Connection closed, no more messages coming")
+                }
+            }
+        }
     }
 }
 
-impl<W: Write> ConnectionWrite for StdIoConnectionWrite<W> {
-    fn send(&mut self, message: Message) -> Result<()> {
-        self.writer.write_all(&message.encode())?;
-        // TODO: excessive flushing might not be a good idea, figure it out later
-        self.writer.flush()?;
-        Ok(())
+impl Drop for StdIoConnectionRead {
+    fn drop(&mut self) {
+        self.shutdown.signal();
     }
 }
 
-#[allow(dead_code)]
-struct ConnectionState {
-    am_choking: AtomicBool,
-    am_interested: AtomicBool,
-    peer_choking: AtomicBool,
-    peer_interested: AtomicBool,
+impl StdIoConnectionWrite {
+    fn sender(&self) -> &SyncSender<QueuedMessages> {
+        self.sender
+            .as_ref()
+            .expect("sender to be set until dropped")
+    }
 }
 
-impl ConnectionState {
-    fn new() -> Self {
-        Self {
-            am_choking: AtomicBool::new(true),
-            am_interested: AtomicBool::new(false),
-            peer_choking: AtomicBool::new(true),
-            peer_interested: AtomicBool::new(false),
+fn closed() -> eyre::Report {
+    eyre!("Connection closed, can't send any more messages")
+}
+
+impl ConnectionWrite for StdIoConnectionWrite {
+    fn send(&mut self, message: Message) -> Result<()> {
+        match self.try_send(message)? {
+            SendStatus::Sent => Ok(()),
+            SendStatus::WouldBlock(message) => {
+                warn!("Send queue is full, waiting");
+                let queued = QueuedMessages::single(message);
+                self.sender().send(queued).map_err(|_| closed())
+            }
+        }
+    }
+
+    /// Encodes all of the messages into a single write, sharing one flush.
+    fn send_all(&mut self, messages: &[Message]) -> Result<()> {
+        if messages.is_empty() {
+            return Ok(());
+        }
+        match self
+            .sender()
+            .try_send(QueuedMessages::new(messages.to_vec()))
+        {
+            Ok(()) => Ok(()),
+            Err(TrySendError::Full(queued)) => {
+                warn!("Send queue is full, waiting");
+                self.sender().send(queued).map_err(|_| closed())
+            }
+            Err(TrySendError::Disconnected(_)) => Err(closed()),
+        }
+    }
+
+    /// Only waits for room in the queue of messages to write, never for the writer itself.
+    fn try_send(&mut self, message: Message) -> Result<SendStatus> {
+        match self.sender().try_send(QueuedMessages::single(message)) {
+            Ok(()) => Ok(SendStatus::Sent),
+            Err(TrySendError::Full(queued)) => match queued.into_single() {
+                // Keep-alives are only there to keep an idle connection open,
+                // so if there's this much queued up they're not needed.
+                Message::KeepAlive(_) => {
+                    trace!("Send queue is full, dropping keep-alive");
+                    Ok(SendStatus::Sent)
+                }
+                message => Ok(SendStatus::WouldBlock(message)),
+            },
+            Err(TrySendError::Disconnected(_)) => Err(closed()),
+        }
+    }
+}
+
+impl Drop for StdIoConnectionWrite {
+    fn drop(&mut self) {
+        // Closing the channel lets the send loop finish writing what's queued, and then stop.
+        drop(self.sender.take());
+        if let Some(join_handle) = self.join_handle.take() {
+            match self.finished.recv_timeout(self.drain_timeout) {
+                Err(RecvTimeoutError::Timeout) => {
+                    warn!(
+                        "Queued messages still not written after {:?}, not waiting for them",
+                        self.drain_timeout
+                    );
+                }
+                Ok(()) | Err(RecvTimeoutError::Disconnected) => {
+                    let _ = join_handle.join();
+                }
+            }
         }
     }
 }
@@ -174,7 +469,7 @@ mod tests {
     use std::io::{Read, Write};
     use std::sync::{Arc, Mutex};
 
-    use crate::messages::Handshake;
+    use crate::messages::{Handshake, KeepAlive, Piece, Request};
     use crate::{InfoHash, PeerId};
 
     use super::*;
@@ -222,6 +517,42 @@ mod tests {
         }
     }
 
+    /// A reader for a peer that connected, but doesn't say anything for a while.
+    struct SilentReader;
+
+    impl Read for SilentReader {
+        fn read(&mut self, _buf: &mut [u8]) -> io::Result<usize> {
+            std::thread::sleep(Duration::from_secs(1));
+            Ok(0)
+        }
+    }
+
+    /// A reader for a peer that sends the start of a message, and then never the rest of it.
+    struct StallingReader(Option<Vec<u8>>);
+
+    impl Read for StallingReader {
+        fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+            let Some(bytes) = self.0.take() else {
+                std::thread::sleep(Duration::from_secs(10));
+                return Ok(0);
+            };
+            buf[..bytes.len()].copy_from_slice(&bytes);
+            Ok(bytes.len())
+        }
+    }
+
+    /// A reader for a quiet peer on a socket with a read timeout, counting how often it's read.
+    #[derive(Debug, Default, Clone)]
+    struct TimingOutReader(Arc<Mutex<usize>>);
+
+    impl Read for TimingOutReader {
+        fn read(&mut self, _buf: &mut [u8]) -> io::Result<usize> {
+            *self.0.lock().unwrap() += 1;
+            std::thread::sleep(Duration::from_millis(10));
+            Err(io::ErrorKind::WouldBlock.into())
+        }
+    }
+
     #[derive(Debug, Default, Clone)]
     struct MockWriter {
         responses: Arc<Mutex<Vec<Vec<u8>>>>,
@@ -250,6 +581,8 @@ mod tests {
         connection_write
             .send(Message::Handshake(handshake))
             .unwrap();
+        // Wait for the queued message to be written.
+        drop(connection_write);
 
         assert_eq!(
             *writer.responses.lock().unwrap(),
@@ -257,6 +590,206 @@ mod tests {
         );
     }
 
+    /// A writer for a slow peer, every write blocks until it's allowed through.
+    struct BlockingWriter {
+        gate: std::sync::mpsc::Receiver<()>,
+        written: Arc<Mutex<Vec<u8>>>,
+    }
+
+    impl Write for BlockingWriter {
+        fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+            self.gate.recv().map_err(|_| io::ErrorKind::BrokenPipe)?;
+            self.written.lock().unwrap().extend(buf);
+            Ok(buf.len())
+        }
+
+        fn flush(&mut self) -> io::Result<()> {
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn test_send_does_not_wait_for_slow_writer() {
+        let (gate, gate_receiver) = std::sync::mpsc::channel();
+        let written = Arc::new(Mutex::new(vec![]));
+        let writer = BlockingWriter {
+            gate: gate_receiver,
+            written: written.clone(),
+        };
+        let (mut connection_write, _) = std_io_connection(1024, MockReader::default(), writer);
+        let handshake = Handshake::new(InfoHash::new([1; 20]), PeerId::new([2; 20]));
+
+        // Neither of these wait for the writer, which is stuck until the gate opens.
+        connection_write
+            .send(Message::Handshake(handshake))
+            .unwrap();
+        connection_write
+            .send(Message::KeepAlive(KeepAlive))
+            .unwrap();
+        assert_eq!(*written.lock().unwrap(), Vec::<u8>::new());
+
+        gate.send(()).unwrap();
+        gate.send(()).unwrap();
+        // Dropping waits for everything queued to be written.
+        drop(connection_write);
+
+        let mut expected = handshake.encode();
+        expected.extend(KeepAlive.encode());
+        assert_eq!(*written.lock().unwrap(), expected);
+    }
+
+    #[test]
+    fn test_try_send_reports_a_full_queue() {
+        let (gate, gate_receiver) = std::sync::mpsc::channel();
+        let written = Arc::new(Mutex::new(vec![]));
+        let writer = BlockingWriter {
+            gate: gate_receiver,
+            written: written.clone(),
+        };
+        let (mut connection_write, _) = std_io_connection(1024, MockReader::default(), writer);
+        let piece = Message::Piece(Piece::new(0, 0, vec![1; 10]));
+
+        // One for the writer to be stuck on, and the rest to fill up the queue.
+        for _ in 0..=MAX_QUEUED_MESSAGES {
+            while connection_write.try_send(piece.clone()).unwrap() != SendStatus::Sent {
+                std::thread::yield_now();
+            }
+        }
+        assert_eq!(
+            connection_write.try_send(piece.clone()).unwrap(),
+            SendStatus::WouldBlock(piece.clone())
+        );
+        // Keep-alives aren't worth waiting for.
+        assert_eq!(
+            connection_write
+                .try_send(Message::KeepAlive(KeepAlive))
+                .unwrap(),
+            SendStatus::Sent
+        );
+
+        drop(gate);
+        drop(connection_write);
+    }
+
+    #[test]
+    fn test_back_to_back_pieces_share_a_flush() {
+        let writer = MockWriter::default();
+        let (mut connection_write, _) = std_io_connection_with_linger(
+            1024,
+            Duration::from_secs(1),
+            MockReader::default(),
+            writer.clone(),
+        );
+        let first = Piece::new(0, 0, vec![1; 10]);
+        let second = Piece::new(0, 10, vec![2; 10]);
+        let request = Request::new(1, 0, 10);
+
+        connection_write
+            .send(Message::Piece(first.clone()))
+            .unwrap();
+        connection_write
+            .send(Message::Piece(second.clone()))
+            .unwrap();
+        connection_write.send(Message::Request(request)).unwrap();
+        drop(connection_write);
+
+        assert_eq!(
+            *writer.responses.lock().unwrap(),
+            vec![first.encode(), second.encode(), request.encode(), vec![]]
+        );
+    }
+
+    /// A writer whose writes block until `unblock` is dropped, like a peer that stopped
+    /// reading.
+    struct StalledWriter {
+        unblock: std::sync::mpsc::Receiver<()>,
+    }
+
+    impl Write for StalledWriter {
+        fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+            let _ = self.unblock.recv();
+            Ok(buf.len())
+        }
+
+        fn flush(&mut self) -> io::Result<()> {
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn dropping_gives_up_on_a_stalled_writer() {
+        let (unblock, stalled) = std::sync::mpsc::channel();
+        let (mut connection_write, _) = std_io_connection(
+            1024,
+            MockReader::default(),
+            StalledWriter { unblock: stalled },
+        );
+        connection_write.drain_timeout = Duration::from_millis(50);
+        connection_write
+            .send(Message::Request(Request::new(0, 0, 10)))
+            .unwrap();
+
+        let started = Instant::now();
+        drop(connection_write);
+
+        assert!(started.elapsed() < Duration::from_secs(1));
+        drop(unblock);
+    }
+
+    #[test]
+    fn test_write_buffer_coalesces_writes() {
+        let pieces: Vec<Piece> = (0..4)
+            .map(|i| Piece::new(0, i * 1000, vec![1; 1000]))
+            .collect();
+        let encoded: Vec<Vec<u8>> = pieces.iter().map(SansIo::encode).collect();
+        let send_pieces = |write_capacity| {
+            let writer = MockWriter::default();
+            let config = StdIoConfig {
+                flush_linger: Duration::from_secs(1),
+                write_capacity,
+                ..StdIoConfig::default()
+            };
+            let (mut connection_write, _) =
+                std_io_connection_with_config(config, MockReader::default(), writer.clone());
+            for piece in &pieces {
+                connection_write
+                    .send(Message::Piece(piece.clone()))
+                    .unwrap();
+            }
+            drop(connection_write);
+            let responses = writer.responses.lock().unwrap().clone();
+            responses
+        };
+
+        // All four pieces fit in the buffer, so they're written at once.
+        assert_eq!(send_pieces(8 * 1024), vec![encoded.concat(), vec![]]);
+        // Only two fit at a time.
+        assert_eq!(
+            send_pieces(2048),
+            vec![encoded[..2].concat(), encoded[2..].concat(), vec![]]
+        );
+    }
+
+    #[test]
+    fn test_send_all_writes_and_flushes_once() {
+        let writer = MockWriter::default();
+        let (mut connection_write, _) =
+            std_io_connection(1024, MockReader::default(), writer.clone());
+        let requests = [
+            Request::new(0, 0, 10),
+            Request::new(0, 10, 10),
+            Request::new(1, 0, 10),
+        ];
+
+        connection_write
+            .send_all(&requests.map(Message::Request))
+            .unwrap();
+        drop(connection_write);
+
+        let expected: Vec<u8> = requests.iter().flat_map(Request::encode).collect();
+        assert_eq!(*writer.responses.lock().unwrap(), vec![expected, vec![]]);
+    }
+
     #[test]
     fn test_receive_within_buffer_size() {
         let writer = MockWriter::default();
@@ -286,6 +819,27 @@ mod tests {
         );
     }
 
+    #[test]
+    fn test_back_to_back_keep_alives_through_a_tiny_buffer() {
+        let writer = MockWriter::default();
+        let keep_alives = Message::KeepAlive(KeepAlive).encode().repeat(10);
+        let reader = MockReader::new(vec![keep_alives]);
+        let (_, connection_read) = std_io_connection(1, reader.clone(), writer.clone());
+
+        for _ in 0..10 {
+            let message = connection_read
+                .receive_timeout(Duration::from_secs(1))
+                .unwrap();
+            assert_eq!(message, Some(Message::KeepAlive(KeepAlive)));
+        }
+
+        // A keep-alive is only its length prefix, so the buffer grows to those 4 bytes once
+        // and then every read is exactly one keep-alive.
+        let reads = reader.reads.lock().unwrap();
+        assert_eq!(reads[..3], [1, 1, 2]);
+        assert!(reads[3..].iter().all(|&read| read == 4), "{reads:?}");
+    }
+
     #[test]
     fn test_receive_incomplete_message() {
         let writer = MockWriter::default();
@@ -336,6 +890,24 @@ mod tests {
         );
     }
 
+    #[test]
+    fn test_receive_timeout() {
+        let handshake = Handshake::new(InfoHash::new([1; 20]), PeerId::new([2; 20]));
+        let reader = MockReader::new(vec![handshake.encode()]);
+        let (_, connection_read) = std_io_connection(1024, reader, MockWriter::default());
+        let (_, silent_read) = std_io_connection(1024, SilentReader, MockWriter::default());
+
+        let message = connection_read
+            .receive_timeout(Duration::from_secs(1))
+            .unwrap();
+        let silence = silent_read
+            .receive_timeout(Duration::from_millis(50))
+            .unwrap();
+
+        assert_eq!(message, Some(Message::Handshake(handshake)));
+        assert_eq!(silence, None);
+    }
+
     #[test]
     fn test_receive_unknown_message() {
         let writer = MockWriter::default();
@@ -347,4 +919,54 @@ mod tests {
 
         let _ = connection_read.receive().unwrap_err();
     }
+
+    #[test]
+    fn test_oversized_length_prefix_closes_the_connection() {
+        // the start of a 900 kB piece, which doesn't fit in the buffer
+        let mut prefix = (900 * 1024u32).to_be_bytes().to_vec();
+        prefix.push(7);
+        let reader = StallingReader(Some(prefix));
+        let (_, connection_read) = std_io_connection(1024, reader, MockWriter::default());
+
+        let _ = connection_read
+            .receive_timeout(Duration::from_millis(500))
+            .unwrap_err();
+    }
+
+    #[test]
+    fn test_shutdown_interrupts_a_blocked_receive() {
+        let (_, connection_read) =
+            std_io_connection(1024, StallingReader(None), MockWriter::default());
+        let shutdown = connection_read.shutdown_signal();
+        let signaller = std::thread::spawn(move || {
+            std::thread::sleep(Duration::from_millis(100));
+            shutdown.signal();
+        });
+
+        let start = Instant::now();
+        let _ = connection_read.receive().unwrap_err();
+
+        // The reader won't return for another ten seconds.
+        assert!(start.elapsed() < Duration::from_secs(2));
+        signaller.join().unwrap();
+    }
+
+    #[test]
+    fn test_dropping_the_read_half_stops_the_reader_thread() {
+        let reader = TimingOutReader::default();
+        let (_, connection_read) = std_io_connection(1024, reader.clone(), MockWriter::default());
+        assert_eq!(
+            connection_read
+                .receive_timeout(Duration::from_millis(50))
+                .unwrap(),
+            None
+        );
+
+        drop(connection_read);
+        std::thread::sleep(Duration::from_millis(50));
+        let reads = *reader.0.lock().unwrap();
+        std::thread::sleep(Duration::from_millis(100));
+
+        assert_eq!(*reader.0.lock().unwrap(), reads);
+    }
 }
@@ -0,0 +1,137 @@
+use std::io::{Read, Write};
+use std::net::{Shutdown, SocketAddr, TcpListener, TcpStream};
+
+use eyre::Result;
+
+/// Both directions of a byte stream to a peer, boxed so that different transports can be mixed.
+/// The streams aren't buffered, turn them into a Connection with e.g.
+/// [std_io_connection_with_config](crate::std_io_connection_with_config), which buffers them.
+pub type BoxedStream = (Box<dyn Read + Send>, Box<dyn Write + Send>);
+
+/// The network that peers are reached over, such as TCP or uTP.
+///
+/// A [Transport] only moves bytes, everything BitTorrent-specific happens on top of the
+/// streams it opens, so the torrent works the same regardless of which one is used.
+pub trait Transport: Send + Sync {
+    /// Open a stream to the peer at `addr`.
+    fn connect(&self, addr: SocketAddr) -> Result<BoxedStream>;
+
+    /// Start accepting streams from peers on `addr`.
+    fn listen(&self, addr: SocketAddr) -> Result<Box<dyn TransportListener>>;
+}
+
+/// Accepts streams from peers that connect to us, created by [Transport::listen].
+pub trait TransportListener: Send {
+    /// The address we're listening on, useful when listening on port 0.
+    fn local_addr(&self) -> Result<SocketAddr>;
+
+    /// Wait for a peer to connect, returning its stream and address.
+    fn accept(&self) -> Result<(BoxedStream, SocketAddr)>;
+}
+
+/// A [Transport] over plain TCP.
+#[derive(Debug, Default, Clone, Copy)]
+pub struct TcpTransport;
+
+impl TcpTransport {
+    fn split(stream: TcpStream) -> Result<BoxedStream> {
+        Ok((Box::new(stream.try_clone()?), Box::new(TcpWrite(stream))))
+    }
+}
+
+/// The writing half of a TCP stream. Dropping it shuts the whole stream down, so that the
+/// thread reading the other half isn't left waiting on a peer that we're done with.
+struct TcpWrite(TcpStream);
+
+impl Write for TcpWrite {
+    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+        self.0.write(buf)
+    }
+
+    fn flush(&mut self) -> std::io::Result<()> {
+        self.0.flush()
+    }
+}
+
+impl Drop for TcpWrite {
+    fn drop(&mut self) {
+        // The peer might have closed it already.
+        let _ = self.0.shutdown(Shutdown::Both);
+    }
+}
+
+impl Transport for TcpTransport {
+    fn connect(&self, addr: SocketAddr) -> Result<BoxedStream> {
+        Self::split(TcpStream::connect(addr)?)
+    }
+
+    fn listen(&self, addr: SocketAddr) -> Result<Box<dyn TransportListener>> {
+        Ok(Box::new(TcpListener::bind(addr)?))
+    }
+}
+
+impl TransportListener for TcpListener {
+    fn local_addr(&self) -> Result<SocketAddr> {
+        Ok(TcpListener::local_addr(self)?)
+    }
+
+    fn accept(&self) -> Result<(BoxedStream, SocketAddr)> {
+        let (stream, addr) = TcpListener::accept(self)?;
+        Ok((TcpTransport::split(stream)?, addr))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::Read;
+    use std::time::Duration;
+
+    use crate::messages::{Handshake, Message};
+    use crate::{std_io_connection, ConnectionRead, ConnectionWrite, InfoHash, PeerId};
+
+    use super::*;
+
+    #[test]
+    fn tcp_roundtrips_a_handshake_over_loopback() {
+        let listener = TcpTransport
+            .listen(SocketAddr::from(([127, 0, 0, 1], 0)))
+            .unwrap();
+        let addr = listener.local_addr().unwrap();
+        let handshake = Handshake::new(InfoHash::new([1; 20]), PeerId::new([2; 20]));
+
+        let (reader, writer) = TcpTransport.connect(addr).unwrap();
+        let (mut connection_write, _read) = std_io_connection(1024, reader, writer);
+        connection_write
+            .send(Message::Handshake(handshake))
+            .unwrap();
+
+        let ((reader, writer), _) = listener.accept().unwrap();
+        let (_write, connection_read) = std_io_connection(1024, reader, writer);
+        assert_eq!(
+            connection_read
+                .receive_timeout(Duration::from_secs(1))
+                .unwrap(),
+            Some(Message::Handshake(handshake))
+        );
+    }
+
+    #[test]
+    fn dropping_the_write_half_closes_a_tcp_stream() {
+        let listener = TcpTransport
+            .listen(SocketAddr::from(([127, 0, 0, 1], 0)))
+            .unwrap();
+        let (reader, writer) = TcpTransport
+            .connect(listener.local_addr().unwrap())
+            .unwrap();
+        let ((mut peer_read, _peer_write), _) = listener.accept().unwrap();
+        let (connection_write, connection_read) = std_io_connection(1024, reader, writer);
+
+        drop(connection_write);
+
+        // The thread reading from the peer stops, though the peer never said a word.
+        let _ = connection_read
+            .receive_timeout(Duration::from_secs(1))
+            .unwrap_err();
+        assert_eq!(peer_read.read(&mut [0; 1]).unwrap(), 0);
+    }
+}
@@ -1,13 +1,21 @@
-use std::convert::TryFrom;
-use std::fmt::{Debug, Display, Formatter};
-use std::str::FromStr;
+use alloc::string::String;
+use alloc::vec::Vec;
+use core::array::TryFromSliceError;
+use core::convert::TryFrom;
+use core::fmt::{Debug, Display, Formatter};
+use core::str::FromStr;
 
 use nom::bytes::streaming::take;
 use nom::combinator::map_res;
 
 use crate::SansIo;
 
+/// The RFC 4648 base32 alphabet, as used by magnet links.
+const BASE32_ALPHABET: &[u8; 32] = b"ABCDEFGHIJKLMNOPQRSTUVWXYZ234567";
+
 /// A 20 byte hash of a torrent, usually represented as a hex string.
+///
+/// It can also be parsed from the base32 form that some magnet links use.
 #[derive(Clone, Copy, PartialEq, Eq, Hash)]
 pub struct InfoHash([u8; 20]);
 
@@ -17,8 +25,79 @@ impl InfoHash {
     pub fn new(hash: [u8; 20]) -> Self {
         Self(hash)
     }
+
+    /// The raw bytes, borrowed.
+    #[must_use]
+    pub fn as_bytes(&self) -> &[u8; 20] {
+        &self.0
+    }
+
+    /// The hash in base32 without padding, which for 20 bytes is exactly 32 characters.
+    #[must_use]
+    pub fn to_base32(&self) -> String {
+        let mut encoded = String::with_capacity(32);
+        for chunk in self.0.chunks_exact(5) {
+            let bits = chunk
+                .iter()
+                .fold(0u64, |bits, byte| bits << 8 | u64::from(*byte));
+            for shift in (0..8).rev() {
+                let index = (bits >> (shift * 5)) & 0x1f;
+                encoded.push(char::from(BASE32_ALPHABET[index as usize]));
+            }
+        }
+        encoded
+    }
+
+    fn from_base32(value: &str) -> Result<Self, ParseInfoHashError> {
+        let mut hash = [0u8; 20];
+        let mut bits = 0u64;
+        for (index, character) in value.chars().enumerate() {
+            let digit = BASE32_ALPHABET
+                .iter()
+                .position(|c| char::from(*c) == character.to_ascii_uppercase())
+                .ok_or(ParseInfoHashError::InvalidBase32Character { character, index })?;
+            bits = bits << 5 | digit as u64;
+            // Every 8 characters make up 5 whole bytes.
+            if index % 8 == 7 {
+                let chunk = index / 8 * 5;
+                hash[chunk..chunk + 5].copy_from_slice(&bits.to_be_bytes()[3..]);
+                bits = 0;
+            }
+        }
+        Ok(Self(hash))
+    }
+}
+
+/// Why a string isn't an [InfoHash].
+#[derive(Debug, Clone, PartialEq)]
+pub enum ParseInfoHashError {
+    /// The string wasn't 40 hex digits.
+    InvalidHex(hex::FromHexError),
+    /// The string was 32 characters long, so base32, but had a character outside of its
+    /// alphabet.
+    InvalidBase32Character {
+        /// The offending character.
+        character: char,
+        /// Where in the string it was.
+        index: usize,
+    },
+}
+
+impl Display for ParseInfoHashError {
+    fn fmt(&self, f: &mut Formatter<'_>) -> core::fmt::Result {
+        match self {
+            ParseInfoHashError::InvalidHex(e) => write!(f, "Invalid hex info hash: {e}"),
+            ParseInfoHashError::InvalidBase32Character { character, index } => write!(
+                f,
+                "Invalid base32 info hash: {character:?} at position {index}"
+            ),
+        }
+    }
 }
 
+#[cfg(feature = "std")]
+impl std::error::Error for ParseInfoHashError {}
+
 impl SansIo for InfoHash {
     fn decode(i: &[u8]) -> nom::IResult<&[u8], Self> {
         let (i, info_hash) = map_res(take(20usize), TryInto::try_into)(i)?;
@@ -28,10 +107,14 @@ impl SansIo for InfoHash {
     fn encode(&self) -> Vec<u8> {
         self.0.to_vec()
     }
+
+    fn encode_into(&self, buf: &mut Vec<u8>) {
+        buf.extend(self.0);
+    }
 }
 
 impl FromStr for InfoHash {
-    type Err = hex::FromHexError;
+    type Err = ParseInfoHashError;
 
     fn from_str(s: &str) -> Result<Self, Self::Err> {
         Self::try_from(s)
@@ -41,32 +124,80 @@ impl FromStr for InfoHash {
 impl TryFrom<&str> for InfoHash {
     type Error = <Self as FromStr>::Err;
 
+    /// Accepts either 40 hex digits, or 32 base32 characters.
     fn try_from(value: &str) -> Result<Self, Self::Error> {
+        if value.len() == 32 {
+            return Self::from_base32(value);
+        }
         let mut hash = [0u8; 20];
-        hex::decode_to_slice(value, &mut hash)?;
+        hex::decode_to_slice(value, &mut hash).map_err(ParseInfoHashError::InvalidHex)?;
         Ok(Self(hash))
     }
 }
 
 impl Display for InfoHash {
-    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+    fn fmt(&self, f: &mut Formatter<'_>) -> core::fmt::Result {
         write!(f, "{}", hex::encode(self.0))
     }
 }
 
 // Manually implemented because the derived Vec<u8> Debug reads awfully.
 impl Debug for InfoHash {
-    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+    fn fmt(&self, f: &mut Formatter<'_>) -> core::fmt::Result {
         write!(f, "InfoHash({})", hex::encode(self.0))
     }
 }
 
+/// Fails unless the slice is exactly 20 bytes long.
+impl TryFrom<&[u8]> for InfoHash {
+    type Error = TryFromSliceError;
+
+    fn try_from(value: &[u8]) -> Result<Self, Self::Error> {
+        Ok(Self(value.try_into()?))
+    }
+}
+
+/// Fails unless the vector is exactly 20 bytes long.
+impl TryFrom<Vec<u8>> for InfoHash {
+    type Error = TryFromSliceError;
+
+    fn try_from(value: Vec<u8>) -> Result<Self, Self::Error> {
+        Self::try_from(value.as_slice())
+    }
+}
+
 impl From<InfoHash> for Vec<u8> {
     fn from(info_hash: InfoHash) -> Self {
         info_hash.0.to_vec()
     }
 }
 
+impl AsRef<[u8]> for InfoHash {
+    fn as_ref(&self) -> &[u8] {
+        &self.0
+    }
+}
+
+/// Serialized as its hex form, like it's shown everywhere else.
+#[cfg(feature = "serde")]
+impl serde::Serialize for InfoHash {
+    fn serialize<S: serde::Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.collect_str(self)
+    }
+}
+
+/// Only accepts the hex form, not base32.
+#[cfg(feature = "serde")]
+impl<'de> serde::Deserialize<'de> for InfoHash {
+    fn deserialize<D: serde::Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        let hex = String::deserialize(deserializer)?;
+        let mut hash = [0u8; 20];
+        hex::decode_to_slice(hex, &mut hash)
+            .map_err(|e| serde::de::Error::custom(ParseInfoHashError::InvalidHex(e)))?;
+        Ok(Self(hash))
+    }
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
@@ -83,6 +214,85 @@ mod tests {
         assert_eq!(hash, InfoHash(HASH_BYTES));
     }
 
+    #[test]
+    fn base32_and_hex_parse_to_the_same_hash() {
+        let hash = InfoHash::try_from(HASH).unwrap();
+        let base32 = hash.to_base32();
+
+        assert_eq!(base32, "AGHFBNMBA24EUQWCEPGPASKDGT4NKWKY");
+        assert_eq!(base32.parse::<InfoHash>().unwrap(), hash);
+        assert_eq!(
+            base32.to_lowercase().parse::<InfoHash>().unwrap(),
+            InfoHash(HASH_BYTES)
+        );
+        assert_eq!(hash.to_string().parse::<InfoHash>().unwrap(), hash);
+    }
+
+    #[test]
+    fn invalid_base32_err() {
+        assert_eq!(
+            "AGHFBNMBA24EUQWCEPGPASKDGT4NKWK1".parse::<InfoHash>(),
+            Err(ParseInfoHashError::InvalidBase32Character {
+                character: '1',
+                index: 31
+            })
+        );
+        assert!("AGHFBNMBA24EUQWCEPGPASKDGT4NKWK"
+            .parse::<InfoHash>()
+            .is_err());
+    }
+
+    #[cfg(feature = "serde")]
+    #[test]
+    fn serde_uses_hex() {
+        let hash = InfoHash(HASH_BYTES);
+
+        let json = serde_json::to_string(&hash).unwrap();
+
+        assert_eq!(json, format!("\"{HASH}\""));
+        assert_eq!(serde_json::from_str::<InfoHash>(&json).unwrap(), hash);
+        let too_short = serde_json::from_value::<InfoHash>(HASH[2..].into()).unwrap_err();
+        assert_eq!(
+            too_short.to_string(),
+            "Invalid hex info hash: Invalid string length"
+        );
+        let not_hex = format!("\"zz{}\"", &HASH[2..]);
+        let _ = serde_json::from_str::<InfoHash>(&not_hex).unwrap_err();
+        // Base32 is for magnet links, not for what we serialize ourselves.
+        let _ = serde_json::from_value::<InfoHash>(hash.to_base32().into()).unwrap_err();
+    }
+
+    #[test]
+    fn try_from_bytes() {
+        assert_eq!(
+            InfoHash::try_from(HASH_BYTES.as_slice()).unwrap(),
+            InfoHash(HASH_BYTES)
+        );
+        assert_eq!(
+            InfoHash::try_from(HASH_BYTES.to_vec()).unwrap(),
+            InfoHash(HASH_BYTES)
+        );
+    }
+
+    #[test]
+    fn borrowed_bytes() {
+        fn length(bytes: impl AsRef<[u8]>) -> usize {
+            bytes.as_ref().len()
+        }
+
+        let value = InfoHash::new(HASH_BYTES);
+        assert_eq!(value.as_bytes(), &HASH_BYTES);
+        assert_eq!(value.as_ref(), HASH_BYTES.as_slice());
+        assert_eq!(length(value), 20);
+    }
+
+    #[test]
+    fn try_from_bytes_of_wrong_length_err() {
+        assert!(InfoHash::try_from(&HASH_BYTES[..19]).is_err());
+        assert!(InfoHash::try_from(vec![0; 21]).is_err());
+        assert!(InfoHash::try_from(Vec::new()).is_err());
+    }
+
     #[test]
     fn display() {
         let hash = InfoHash::new(HASH_BYTES);
@@ -1,6 +1,7 @@
 #![warn(clippy::unwrap_used)]
 #![allow(clippy::module_inception)]
 #![warn(missing_docs)]
+#![cfg_attr(not(feature = "std"), no_std)]
 
 //! A sans-io proof-of-concept implementation of the BitTorrent protocol,
 //! implemented in Rust as a programming challenge for recruitment purposes.
@@ -11,20 +12,97 @@
 //! The paradigm used in this crate is that of an actor model, where each piece of logic
 //! (in this case, a [Torrent] and its individual connections) is an actor that can be
 //! independently started and stopped, and runs on a separate thread.
+//!
+//! Without the default `std` feature only the message codec is available, which works
+//! with just `core` and `alloc`.
+
+extern crate alloc;
 
+pub use client_info::ClientInfo;
+#[cfg(feature = "test-util")]
+pub use clock::MockClock;
+#[cfg(feature = "std")]
+pub use clock::{Clock, SystemClock};
+#[cfg(feature = "test-util")]
+pub use connections::loopback::{loopback, LoopbackRead, LoopbackWrite};
+#[cfg(feature = "std")]
+pub use connections::mse::mse_connection;
+#[cfg(feature = "std")]
 pub use connections::std_io_connection::{
-    std_io_connection, StdIoConnectionRead, StdIoConnectionWrite,
+    std_io_connection, std_io_connection_with_config, std_io_connection_with_linger, StdIoConfig,
+    StdIoConnectionRead, StdIoConnectionWrite,
+};
+#[cfg(feature = "std")]
+pub use connections::transport::{BoxedStream, TcpTransport, Transport, TransportListener};
+#[cfg(feature = "std")]
+pub use connections::{
+    BoxedConnection, Connection, ConnectionFactory, ConnectionRead, ConnectionWrite, SendStatus,
+    ShutdownSignal,
 };
-pub use connections::{ConnectionRead, ConnectionWrite};
-pub use info_hash::InfoHash;
+pub use info_hash::{InfoHash, ParseInfoHashError};
+pub use messages::{
+    DecodedMessage, Handshake, Message, MessageFrames, ProtocolError, ReservedBits, DHT_BIT,
+    EXTENDED_HANDSHAKE_ID, EXTENSION_PROTOCOL_BIT, FAST_EXTENSION_BIT,
+};
+#[cfg(feature = "std")]
+pub use metainfo::{FileEntry, Info};
+#[cfg(feature = "std")]
+pub use metrics::{MetricsSink, NoMetrics};
+#[cfg(feature = "std")]
+pub use observer::{EventLevel, EventObserver, EventRecord, NoObserver};
 pub use peer_id::PeerId;
+#[cfg(feature = "std")]
+pub use peer_source::{DiscoveredPeers, PeerSource, StaticPeers};
 pub use sans_io::SansIo;
+#[cfg(feature = "std")]
+pub use torrent::ban_list::IpRange;
+#[cfg(feature = "std")]
+pub use torrent::config::{ReservedBitsPolicy, TorrentConfig};
+#[cfg(feature = "std")]
+pub use torrent::event::TorrentEvent;
+#[cfg(feature = "std")]
+pub use torrent::merkle::{block_hashes, merkle_root, pieces_root, verify_pieces_root};
+#[cfg(feature = "std")]
+pub use torrent::piece_store::{FilePieceStore, MemoryPieceStore, PieceStore, VerifyOnlyStore};
+#[cfg(feature = "std")]
+pub use torrent::session::{peek_handshake, Session, SessionListener};
+#[cfg(feature = "std")]
+pub use torrent::stats::TorrentStats;
+#[cfg(feature = "std")]
 pub use torrent::torrent::Torrent;
+#[cfg(feature = "std")]
+pub use tracker::{
+    AnnounceEvent, AnnounceRequest, AnnounceResponse, HttpTracker, TrackerError, UdpTracker,
+};
 
+#[cfg(feature = "std")]
 pub(crate) mod actor;
+#[cfg(feature = "std")]
+pub(crate) mod bencode;
+mod client_info;
+#[cfg(feature = "std")]
+mod clock;
+#[cfg(feature = "std")]
 mod connections;
 mod info_hash;
+#[cfg(feature = "std")]
+mod log;
 pub(crate) mod messages;
+#[cfg(feature = "std")]
+mod metainfo;
+#[cfg(feature = "std")]
+mod metrics;
+#[cfg(feature = "std")]
+mod observer;
 mod peer_id;
+#[cfg(feature = "std")]
+mod peer_source;
 mod sans_io;
+#[cfg(feature = "std")]
+mod sha1;
+#[cfg(feature = "std")]
+mod sha256;
+#[cfg(feature = "std")]
 mod torrent;
+#[cfg(feature = "std")]
+mod tracker;
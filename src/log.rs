@@ -0,0 +1,19 @@
+//! The logging macros used throughout the crate. With the `tracing` feature they're the ones
+//! from `tracing`, without it they don't log anything, but still check their arguments.
+
+#[cfg(feature = "tracing")]
+pub(crate) use tracing::{debug, error, info, trace, warn};
+
+#[cfg(not(feature = "tracing"))]
+macro_rules! disabled {
+    ($($arg:tt)*) => {
+        if false {
+            let _ = ::std::format!($($arg)*);
+        }
+    };
+}
+
+#[cfg(not(feature = "tracing"))]
+pub(crate) use {
+    disabled as debug, disabled as error, disabled as info, disabled as trace, disabled as warn,
+};
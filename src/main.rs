@@ -1,10 +1,14 @@
-use std::io::{BufReader, BufWriter};
-use std::net::{IpAddr, TcpListener, TcpStream};
+use std::net::{IpAddr, SocketAddr};
+use std::sync::mpsc::{Receiver, RecvTimeoutError};
+use std::time::{Duration, Instant};
 
 use clap::Parser;
 use tracing::{info, warn};
 
-use torrent_poc::{std_io_connection, InfoHash, PeerId, Torrent};
+use torrent_poc::{
+    std_io_connection_with_config, InfoHash, PeerId, StdIoConfig, TcpTransport, Torrent,
+    TorrentEvent, Transport,
+};
 
 /// A simple program to handshake with a known BitTorrent peer for a given Torrent info hash.
 ///
@@ -36,6 +40,10 @@ enum Cli {
         /// *Consent is important.*
         #[arg(long, default_value_t = false)]
         malicious: bool,
+
+        /// Give up on the download after this many seconds.
+        #[arg(long, default_value_t = 600)]
+        timeout: u64,
     },
     /// Listen for incoming connections and start seeding a torrent.
     Seed {
@@ -70,15 +78,15 @@ fn main() -> Result<(), eyre::Report> {
             port,
             info_hash,
             malicious,
+            timeout,
         } => {
             info!("Connecting to peer at {}:{}", ip, port);
             info!("Info hash: {}", info_hash);
             let torrent = Torrent::new(own_peer_id, info_hash);
-            let stream = TcpStream::connect((ip, port))?;
-            let reader = BufReader::new(stream.try_clone()?);
-            let writer = BufWriter::new(stream);
-            let (connection_write, connection_read) = std_io_connection(1024, reader, writer);
-            torrent.connect_to_peer(None, connection_read, connection_write)?;
+            let events = torrent.subscribe()?;
+            let peer_addr = SocketAddr::new(ip, port);
+            // The torrent dials the peer itself, so it can reconnect if the connection drops.
+            torrent.add_peer(peer_addr, None)?;
             if malicious {
                 warn!("Running in malicious mode, sending a lot of keep-alive messages");
                 // Time to be mean. Send a lot of keep-alive messages to the peer.
@@ -86,10 +94,10 @@ fn main() -> Result<(), eyre::Report> {
                     torrent.send_keep_alive()?;
                 }
             }
-            // Since actor threads are stopped on Drop, we just sleep here to let them tick a bit.
             // In a real application the Torrents would be stored in some kind of data structure
             // and the actor threads would be started and stopped as the user is manipulating the GUI.
-            std::thread::sleep(std::time::Duration::from_secs(10));
+            wait_for_download(&events, Duration::from_secs(timeout));
+            torrent.shutdown()?;
         }
         Cli::Seed {
             ip,
@@ -99,15 +107,41 @@ fn main() -> Result<(), eyre::Report> {
             info!("Listening on {}:{}", ip, port);
             info!("Info hash: {}", info_hash);
             let torrent = Torrent::new(own_peer_id, info_hash);
-            for stream in TcpListener::bind((ip, port))?.incoming() {
-                let stream = stream?;
-                let reader = BufReader::new(stream.try_clone()?);
-                let writer = BufWriter::new(stream);
-                let (connection_write, connection_read) = std_io_connection(1024, reader, writer);
-                torrent.accept_peer_connection(None, connection_read, connection_write)?;
+            let listener = TcpTransport.listen(SocketAddr::new(ip, port))?;
+            while torrent.is_running() {
+                let ((reader, writer), peer_addr) = listener.accept()?;
+                let (connection_write, connection_read) =
+                    std_io_connection_with_config(StdIoConfig::default(), reader, writer);
+                torrent.accept_peer_connection(
+                    None,
+                    Some(peer_addr),
+                    connection_read,
+                    connection_write,
+                )?;
             }
         }
     }
 
     Ok(())
 }
+
+/// Block until the torrent has downloaded everything, or `timeout` has passed.
+fn wait_for_download(events: &Receiver<TorrentEvent>, timeout: Duration) {
+    let deadline = Instant::now() + timeout;
+    loop {
+        match events.recv_timeout(deadline.saturating_duration_since(Instant::now())) {
+            Ok(TorrentEvent::PieceCompleted(index)) => info!("Completed piece {index}"),
+            Ok(TorrentEvent::Progress(progress)) if progress >= 1.0 => {
+                info!("Download complete");
+                return;
+            }
+            Ok(_) => {}
+            Err(RecvTimeoutError::Timeout) => {
+                warn!("Download not complete after {timeout:?}, giving up");
+                return;
+            }
+            // The torrent stopped by itself, there's nothing left to wait for.
+            Err(RecvTimeoutError::Disconnected) => return,
+        }
+    }
+}
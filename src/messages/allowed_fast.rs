@@ -0,0 +1,54 @@
+use alloc::vec::Vec;
+
+use nom::bytes::streaming::tag;
+use nom::number::streaming::be_u32;
+
+use crate::SansIo;
+
+const ALLOWED_FAST_PREFIX: [u8; 5] = [0, 0, 0, 5, 17];
+
+/// Part of the Fast Extension, tells the peer that it may request blocks of this piece
+/// even while we're choking it.
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct AllowedFast {
+    pub index: u32,
+}
+
+impl AllowedFast {
+    #[must_use]
+    pub fn new(index: u32) -> Self {
+        Self { index }
+    }
+}
+
+impl SansIo for AllowedFast {
+    fn decode(i: &[u8]) -> nom::IResult<&[u8], Self> {
+        let (i, _) = tag(ALLOWED_FAST_PREFIX)(i)?;
+        let (i, index) = be_u32(i)?;
+        Ok((i, Self::new(index)))
+    }
+
+    fn encode(&self) -> Vec<u8> {
+        let mut buf = Vec::with_capacity(4 + 1 + 4);
+        buf.extend(ALLOWED_FAST_PREFIX);
+        buf.extend(self.index.to_be_bytes());
+        buf
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn roundtrip() {
+        let allowed_fast = AllowedFast::new(7);
+
+        let encoded = allowed_fast.encode();
+        let (remaining, decoded) = AllowedFast::decode(&encoded).unwrap();
+
+        assert_eq!(allowed_fast, decoded);
+        assert_eq!(remaining.len(), 0);
+    }
+}
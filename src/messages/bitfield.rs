@@ -0,0 +1,215 @@
+use alloc::vec;
+use alloc::vec::Vec;
+use core::fmt;
+
+use nom::bytes::streaming::{tag, take};
+use nom::combinator::verify;
+use nom::number::streaming::be_u32;
+
+use crate::messages::MAX_MESSAGE_LENGTH;
+use crate::SansIo;
+
+const BITFIELD_ID: u8 = 5;
+
+/// The most pieces a bitfield can hold, as that's as much as fits in a message.
+const MAX_BITFIELD_PIECES: usize = (MAX_MESSAGE_LENGTH as usize - 1) * 8;
+
+/// The pieces a peer has, sent right after the handshake. The highest bit of the first byte
+/// is piece 0, and any spare bits at the end are zero.
+#[derive(Clone, Default, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct Bitfield {
+    pub bytes: Vec<u8>,
+}
+
+impl Bitfield {
+    #[must_use]
+    pub fn new(bytes: Vec<u8>) -> Self {
+        Self { bytes }
+    }
+
+    /// A bitfield with all of the first `piece_count` pieces set.
+    #[must_use]
+    pub fn full(piece_count: usize) -> Self {
+        let mut bitfield = Self::new(vec![0; piece_count.div_ceil(8)]);
+        for index in 0..piece_count {
+            bitfield.set(index);
+        }
+        bitfield
+    }
+
+    /// Whether the piece is set. Pieces past the end of the bitfield are never set.
+    #[must_use]
+    pub fn has(&self, index: usize) -> bool {
+        self.bytes
+            .get(index / 8)
+            .is_some_and(|byte| byte & (0x80 >> (index % 8)) != 0)
+    }
+
+    /// Whether this is a valid bitfield for a torrent with `piece_count` pieces: exactly long
+    /// enough to hold them, with the spare bits at the end all zero.
+    #[must_use]
+    pub fn fits(&self, piece_count: usize) -> bool {
+        self.bytes.len() == piece_count.div_ceil(8)
+            && (piece_count..self.bytes.len() * 8).all(|index| !self.has(index))
+    }
+
+    /// How many of the first `piece_count` pieces are set. Spare bits past them don't count.
+    #[must_use]
+    pub fn count_ones(&self, piece_count: usize) -> usize {
+        let full_bytes: usize = self
+            .bytes
+            .iter()
+            .take(piece_count / 8)
+            .map(|byte| byte.count_ones() as usize)
+            .sum();
+        let spare_mask = 0xff_u8 >> (piece_count % 8);
+        let last_byte = self
+            .bytes
+            .get(piece_count / 8)
+            .map_or(0, |byte| (byte & !spare_mask).count_ones() as usize);
+        full_bytes + last_byte
+    }
+
+    /// The pieces among the first `piece_count` that `other` has, but that are missing from
+    /// this bitfield, in order. Spare bits past them are never missing.
+    pub fn missing_from<'a>(
+        &'a self,
+        other: &'a Bitfield,
+        piece_count: usize,
+    ) -> impl Iterator<Item = u32> + 'a {
+        (0..piece_count.min(other.bytes.len() * 8))
+            .filter(|index| other.has(*index) && !self.has(*index))
+            // bitfields are shorter than the max message length, so every index fits
+            .map(|index| u32::try_from(index).expect("piece index to fit in a u32"))
+    }
+
+    /// Whether all of the first `piece_count` pieces are set. Spare bits past them don't matter.
+    #[must_use]
+    pub fn is_complete(&self, piece_count: usize) -> bool {
+        (0..piece_count).all(|index| self.has(index))
+    }
+
+    /// Set the piece, growing the bitfield if it's too short. Pieces past the longest bitfield
+    /// that fits in a message can't be sent to anyone, so they're ignored.
+    pub fn set(&mut self, index: usize) {
+        if index >= MAX_BITFIELD_PIECES {
+            return;
+        }
+        if self.bytes.len() <= index / 8 {
+            self.bytes.resize(index / 8 + 1, 0);
+        }
+        self.bytes[index / 8] |= 0x80 >> (index % 8);
+    }
+}
+
+/// Big torrents have big bitfields, so only the length is shown.
+impl fmt::Debug for Bitfield {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("Bitfield")
+            .field("bytes", &format_args!("{} bytes", self.bytes.len()))
+            .finish()
+    }
+}
+
+impl SansIo for Bitfield {
+    fn decode(i: &[u8]) -> nom::IResult<&[u8], Self> {
+        let (i, message_length) =
+            verify(be_u32, |length| (1..MAX_MESSAGE_LENGTH).contains(length))(i)?;
+        let (i, _) = tag([BITFIELD_ID])(i)?;
+        let (i, bytes) = take(message_length - 1)(i)?;
+        Ok((i, Self::new(bytes.to_vec())))
+    }
+
+    fn encode(&self) -> Vec<u8> {
+        let mut buf = Vec::with_capacity(4 + 1 + self.bytes.len());
+        // the bitfield is shorter than the max message length, so the cast is safe
+        #[allow(clippy::cast_possible_truncation)]
+        buf.extend((1 + self.bytes.len() as u32).to_be_bytes());
+        buf.push(BITFIELD_ID);
+        buf.extend(&self.bytes);
+        buf
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn roundtrip() {
+        let bitfield = Bitfield::new(vec![0b1010_0000, 0b0000_0001]);
+
+        let encoded = bitfield.encode();
+        let (remaining, decoded) = Bitfield::decode(&encoded).unwrap();
+
+        assert_eq!(bitfield, decoded);
+        assert_eq!(remaining.len(), 0);
+    }
+
+    #[test]
+    fn has_and_set() {
+        let mut bitfield = Bitfield::new(vec![0b1010_0000]);
+
+        assert!(bitfield.has(0));
+        assert!(!bitfield.has(1));
+        assert!(bitfield.has(2));
+        assert!(!bitfield.has(12));
+
+        bitfield.set(12);
+
+        assert!(bitfield.has(12));
+        assert_eq!(bitfield.bytes, [0b1010_0000, 0b0000_1000]);
+        assert_eq!(Bitfield::full(10).bytes, [0xff, 0b1100_0000]);
+
+        bitfield.set(u32::MAX as usize);
+        assert_eq!(bitfield.bytes.len(), 2);
+    }
+
+    #[test]
+    fn missing_from_overlapping_bitfield() {
+        let ours = Bitfield::new(vec![0b1100_1000]);
+        let theirs = Bitfield::new(vec![0b1010_1001, 0b1000_0000]);
+
+        assert_eq!(ours.missing_from(&theirs, 9).collect::<Vec<_>>(), [2, 7, 8]);
+        assert_eq!(theirs.missing_from(&ours, 9).collect::<Vec<_>>(), [1]);
+        assert_eq!(ours.count_ones(9), 3);
+        assert_eq!(theirs.count_ones(9), 5);
+    }
+
+    #[test]
+    fn spare_bits_are_ignored() {
+        // 10 pieces, with spare bits 10 and 15 set, as a peer might send before we know
+        // the piece count to check it against.
+        let ours = Bitfield::new(vec![0b1000_0000, 0b0000_0000]);
+        let theirs = Bitfield::new(vec![0b1100_0000, 0b0110_0001]);
+
+        assert_eq!(theirs.count_ones(10), 3);
+        assert_eq!(theirs.count_ones(8), 2);
+        assert_eq!(theirs.count_ones(0), 0);
+        assert_eq!(Bitfield::full(10).count_ones(100), 10);
+        assert_eq!(ours.missing_from(&theirs, 10).collect::<Vec<_>>(), [1, 9]);
+        assert_eq!(ours.missing_from(&theirs, 100).count(), 4);
+    }
+
+    #[test]
+    fn is_complete_respects_piece_count() {
+        let bitfield = Bitfield::new(vec![0b1111_1111, 0b1110_0000]);
+
+        assert!(bitfield.is_complete(11));
+        assert!(bitfield.is_complete(8));
+        assert!(!bitfield.is_complete(12));
+        assert!(!bitfield.is_complete(17));
+        assert!(Bitfield::full(11).is_complete(11));
+    }
+
+    #[test]
+    fn fits_piece_count() {
+        assert!(Bitfield::new(vec![0b1111_1111, 0b1110_0000]).fits(11));
+        assert!(Bitfield::new(vec![]).fits(0));
+        // too short, too long, and with a spare bit set
+        assert!(!Bitfield::new(vec![0b1111_1111]).fits(11));
+        assert!(!Bitfield::new(vec![0, 0, 0]).fits(11));
+        assert!(!Bitfield::new(vec![0, 0b0001_0000]).fits(11));
+    }
+}
@@ -0,0 +1,75 @@
+use alloc::vec::Vec;
+
+use nom::bytes::streaming::tag;
+use nom::number::streaming::be_u32;
+
+use crate::messages::Request;
+use crate::SansIo;
+
+const CANCEL_PREFIX: [u8; 5] = [0, 0, 0, 13, 8];
+
+/// Cancels a previously sent [Request](super::Request), e.g. because it was taking too long
+/// and the block was requested from someone else instead.
+#[derive(Debug, Copy, Clone, PartialEq, Eq, Hash)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct Cancel {
+    pub index: u32,
+    pub begin: u32,
+    pub length: u32,
+}
+
+impl Cancel {
+    #[must_use]
+    pub fn new(index: u32, begin: u32, length: u32) -> Self {
+        Self {
+            index,
+            begin,
+            length,
+        }
+    }
+}
+
+impl From<Request> for Cancel {
+    fn from(request: Request) -> Self {
+        Self::new(request.index, request.begin, request.length)
+    }
+}
+
+impl SansIo for Cancel {
+    fn decode(i: &[u8]) -> nom::IResult<&[u8], Self> {
+        let (i, _) = tag(CANCEL_PREFIX)(i)?;
+        let (i, index) = be_u32(i)?;
+        let (i, begin) = be_u32(i)?;
+        let (i, length) = be_u32(i)?;
+        Ok((i, Self::new(index, begin, length)))
+    }
+
+    fn encode(&self) -> Vec<u8> {
+        let mut buf = Vec::with_capacity(4 + 1 + 12);
+        self.encode_into(&mut buf);
+        buf
+    }
+
+    fn encode_into(&self, buf: &mut Vec<u8>) {
+        buf.extend(CANCEL_PREFIX);
+        buf.extend(self.index.to_be_bytes());
+        buf.extend(self.begin.to_be_bytes());
+        buf.extend(self.length.to_be_bytes());
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn roundtrip() {
+        let cancel = Cancel::new(1, 16384, 16384);
+
+        let encoded = cancel.encode();
+        let (remaining, decoded) = Cancel::decode(&encoded).unwrap();
+
+        assert_eq!(cancel, decoded);
+        assert_eq!(remaining.len(), 0);
+    }
+}
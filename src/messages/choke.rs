@@ -0,0 +1,40 @@
+use alloc::vec::Vec;
+
+use nom::bytes::streaming::tag;
+
+use crate::SansIo;
+
+const CHOKE: [u8; 5] = [0, 0, 0, 1, 0];
+
+/// The choke message tells the peer that we won't be answering any of their requests
+/// until we unchoke them again. It doesn't contain any information besides its id.
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct Choke;
+
+impl SansIo for Choke {
+    fn decode(i: &[u8]) -> nom::IResult<&[u8], Self> {
+        let (i, _) = tag(CHOKE)(i)?;
+        Ok((i, Self))
+    }
+
+    fn encode(&self) -> Vec<u8> {
+        CHOKE.to_vec()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn roundtrip() {
+        let choke = Choke;
+
+        let encoded = choke.encode();
+        let (remaining, decoded) = Choke::decode(&encoded).unwrap();
+
+        assert_eq!(choke, decoded);
+        assert_eq!(remaining.len(), 0);
+    }
+}
@@ -0,0 +1,194 @@
+use alloc::vec::Vec;
+use core::fmt;
+#[cfg(feature = "std")]
+use std::collections::BTreeMap;
+
+#[cfg(feature = "std")]
+use eyre::{eyre, OptionExt, Result};
+use nom::bytes::streaming::{tag, take};
+use nom::combinator::verify;
+use nom::number::streaming::{be_u32, u8};
+
+#[cfg(feature = "std")]
+use crate::bencode::{self, BValue};
+use crate::SansIo;
+
+const EXTENDED_ID: u8 = 20;
+/// The id and extended message id that come before the payload.
+const HEADER_LENGTH: u32 = 1 + 1;
+/// The extended message id reserved for the extended handshake itself.
+pub const EXTENDED_HANDSHAKE_ID: u8 = 0;
+/// The bit in the reserved handshake bytes that signals support for the extension protocol.
+pub const EXTENSION_PROTOCOL_BIT: (usize, u8) = (5, 0x10);
+
+/// A message of the extension protocol (BEP 10). The extended message id is either
+/// [EXTENDED_HANDSHAKE_ID], or an id that was negotiated in the extended handshake.
+/// The payload is usually a bencoded dictionary, but some extensions append raw data after it.
+#[derive(Clone, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct Extended {
+    pub id: u8,
+    pub payload: Vec<u8>,
+}
+
+impl Extended {
+    #[must_use]
+    pub fn new(id: u8, payload: Vec<u8>) -> Self {
+        Self { id, payload }
+    }
+}
+
+/// Payloads can be entire metadata pieces, so only their length is shown.
+impl fmt::Debug for Extended {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("Extended")
+            .field("id", &self.id)
+            .field("payload", &format_args!("{} bytes", self.payload.len()))
+            .finish()
+    }
+}
+
+impl SansIo for Extended {
+    fn decode(i: &[u8]) -> nom::IResult<&[u8], Self> {
+        let (i, message_length) = verify(be_u32, |length| *length >= HEADER_LENGTH)(i)?;
+        let (i, _) = tag([EXTENDED_ID])(i)?;
+        let (i, id) = u8(i)?;
+        let (i, payload) = take(message_length - HEADER_LENGTH)(i)?;
+        Ok((i, Self::new(id, payload.to_vec())))
+    }
+
+    fn encode(&self) -> Vec<u8> {
+        let mut buf = Vec::with_capacity(4 + HEADER_LENGTH as usize + self.payload.len());
+        // extended messages are small, so the cast is safe
+        #[allow(clippy::cast_possible_truncation)]
+        buf.extend((HEADER_LENGTH + self.payload.len() as u32).to_be_bytes());
+        buf.push(EXTENDED_ID);
+        buf.push(self.id);
+        buf.extend(&self.payload);
+        buf
+    }
+}
+
+/// The extended handshake, telling the peer which extensions we support.
+#[cfg(feature = "std")]
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct ExtendedHandshake {
+    /// Extension names, mapped to the extended message id the sender wants to receive
+    /// that extension's messages with.
+    pub extensions: BTreeMap<String, u8>,
+    /// Size of the info dictionary in bytes, if the sender has it and supports
+    /// the metadata extension.
+    pub metadata_size: Option<usize>,
+}
+
+#[cfg(feature = "std")]
+impl ExtendedHandshake {
+    #[must_use]
+    pub fn new(extensions: BTreeMap<String, u8>) -> Self {
+        Self {
+            extensions,
+            metadata_size: None,
+        }
+    }
+
+    /// Parse the payload of an [Extended] message with id [EXTENDED_HANDSHAKE_ID].
+    ///
+    /// Extensions that the peer explicitly disabled (with id 0) are left out,
+    /// as are any with names that aren't valid UTF-8.
+    pub fn from_payload(payload: &[u8]) -> Result<Self> {
+        let (_, value) =
+            BValue::decode(payload).map_err(|e| eyre!("Invalid extended handshake: {e:?}"))?;
+        let m = value
+            .get(b"m")
+            .and_then(BValue::as_dict)
+            .ok_or_eyre("Extended handshake is missing the 'm' dictionary")?;
+        let extensions = m
+            .iter()
+            .filter_map(|(name, id)| {
+                let name = String::from_utf8(name.clone()).ok()?;
+                let id = u8::try_from(id.as_integer()?).ok()?;
+                (id != 0).then_some((name, id))
+            })
+            .collect();
+        let metadata_size = value
+            .get(b"metadata_size")
+            .and_then(BValue::as_integer)
+            .and_then(|size| usize::try_from(size).ok());
+        Ok(Self {
+            extensions,
+            metadata_size,
+        })
+    }
+
+    #[must_use]
+    pub fn to_message(&self) -> Extended {
+        let m = self
+            .extensions
+            .iter()
+            .map(|(name, id)| (name.as_bytes().to_vec(), BValue::Integer(i64::from(*id))))
+            .collect();
+        let mut dict = BTreeMap::from([(b"m".to_vec(), BValue::Dict(m))]);
+        if let Some(size) = self.metadata_size {
+            let size = i64::try_from(size).expect("metadata to be reasonably sized");
+            dict.insert(b"metadata_size".to_vec(), BValue::Integer(size));
+        }
+        Extended::new(EXTENDED_HANDSHAKE_ID, bencode::encode(&BValue::Dict(dict)))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn roundtrip() {
+        let extended = Extended::new(3, vec![1, 2, 3]);
+
+        let encoded = extended.encode();
+        let (remaining, decoded) = Extended::decode(&encoded).unwrap();
+
+        assert_eq!(extended, decoded);
+        assert_eq!(remaining.len(), 0);
+    }
+
+    #[test]
+    fn roundtrip_handshake() {
+        let handshake = ExtendedHandshake::new(BTreeMap::from([
+            ("ut_metadata".to_string(), 3),
+            ("ut_pex".to_string(), 1),
+        ]));
+
+        let message = handshake.to_message();
+        let decoded = ExtendedHandshake::from_payload(&message.payload).unwrap();
+
+        assert_eq!(message.id, EXTENDED_HANDSHAKE_ID);
+        assert_eq!(message.payload, b"d1:md11:ut_metadatai3e6:ut_pexi1eee");
+        assert_eq!(handshake, decoded);
+    }
+
+    #[test]
+    fn roundtrip_handshake_with_metadata_size() {
+        let handshake = ExtendedHandshake {
+            metadata_size: Some(1234),
+            ..ExtendedHandshake::default()
+        };
+
+        let message = handshake.to_message();
+        let decoded = ExtendedHandshake::from_payload(&message.payload).unwrap();
+
+        assert_eq!(message.payload, b"d1:mde13:metadata_sizei1234ee");
+        assert_eq!(handshake, decoded);
+    }
+
+    #[test]
+    fn handshake_skips_disabled_extensions() {
+        let decoded =
+            ExtendedHandshake::from_payload(b"d1:md11:ut_metadatai0e6:ut_pexi1ee1:v3:abce")
+                .unwrap();
+
+        assert_eq!(
+            decoded.extensions,
+            BTreeMap::from([("ut_pex".to_string(), 1)])
+        );
+    }
+}
@@ -1,49 +1,198 @@
-use nom::bytes::streaming::{tag, take};
-use nom::combinator::cut;
+use alloc::string::String;
+use alloc::vec::Vec;
 
+use nom::bytes::streaming::take;
+use nom::combinator::{cut, map_res, verify};
+use nom::number::streaming::u8;
+
+use crate::messages::{ProtocolError, EXTENSION_PROTOCOL_BIT};
 use crate::{InfoHash, PeerId, SansIo};
 
 const BITTORRENT_PROTOCOL: &[u8] = b"BitTorrent protocol";
-const RESERVED_ZEROES: &[u8] = b"\0\0\0\0\0\0\0\0";
+/// The bit in the reserved handshake bytes that signals support for the Fast Extension.
+pub const FAST_EXTENSION_BIT: (usize, u8) = (7, 0x04);
+/// The bit in the reserved handshake bytes that signals running a DHT node, whose port is
+/// sent in a `Port` message.
+pub const DHT_BIT: (usize, u8) = (7, 0x01);
+
+/// The protocol extensions advertised in the reserved bytes of a handshake.
+/// The default advertises none of them, like a plain [Handshake::new].
+#[derive(Debug, Default, Copy, Clone, PartialEq, Eq, Hash)]
+pub struct ReservedBits {
+    /// BEP 10, the extension protocol.
+    pub extension_protocol: bool,
+    /// BEP 6, the Fast Extension.
+    pub fast_extension: bool,
+    /// BEP 5, the DHT.
+    pub dht: bool,
+}
+
+impl ReservedBits {
+    fn bits(self) -> [((usize, u8), bool); 3] {
+        [
+            (EXTENSION_PROTOCOL_BIT, self.extension_protocol),
+            (FAST_EXTENSION_BIT, self.fast_extension),
+            (DHT_BIT, self.dht),
+        ]
+    }
+}
+
+impl From<ReservedBits> for [u8; 8] {
+    fn from(bits: ReservedBits) -> Self {
+        let mut reserved = [0; 8];
+        for ((byte, mask), enabled) in bits.bits() {
+            if enabled {
+                reserved[byte] |= mask;
+            }
+        }
+        reserved
+    }
+}
+
+/// The extensions a peer advertised, ignoring any bits we don't know about.
+impl From<[u8; 8]> for ReservedBits {
+    fn from(reserved: [u8; 8]) -> Self {
+        let set = |(byte, mask): (usize, u8)| reserved[byte] & mask != 0;
+        Self {
+            extension_protocol: set(EXTENSION_PROTOCOL_BIT),
+            fast_extension: set(FAST_EXTENSION_BIT),
+            dht: set(DHT_BIT),
+        }
+    }
+}
 
 /// The handshake is the first message sent by either peer when they start a connection.
 #[derive(Debug, Copy, Clone, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
 pub struct Handshake {
+    /// 8 bytes reserved for future use, in practice used to advertise protocol extensions.
+    pub reserved: [u8; 8],
+    /// The torrent the sender wants to talk about.
     pub info_hash: InfoHash,
+    /// The sender's peer ID.
     pub peer_id: PeerId,
 }
 
 impl Handshake {
+    /// Create a handshake that doesn't advertise any protocol extensions.
     #[must_use]
     pub fn new(info_hash: InfoHash, peer_id: PeerId) -> Self {
-        Self { info_hash, peer_id }
+        Self::with_reserved([0; 8], info_hash, peer_id)
+    }
+
+    /// Create a handshake with the given reserved bytes, e.g. to advertise protocol extensions.
+    #[must_use]
+    pub fn with_reserved(reserved: [u8; 8], info_hash: InfoHash, peer_id: PeerId) -> Self {
+        Self {
+            reserved,
+            info_hash,
+            peer_id,
+        }
+    }
+
+    /// Whether the sender runs a DHT node (BEP 5), whose port it can send in a `Port` message.
+    #[must_use]
+    pub fn supports_dht(&self) -> bool {
+        ReservedBits::from(self.reserved).dht
+    }
+
+    /// Whether the sender supports the Fast Extension (BEP 6), e.g. `HaveAll`.
+    #[must_use]
+    pub fn supports_fast(&self) -> bool {
+        ReservedBits::from(self.reserved).fast_extension
+    }
+
+    /// Whether the sender supports the extension protocol (BEP 10), and so the extended
+    /// handshake.
+    #[must_use]
+    pub fn supports_extension_protocol(&self) -> bool {
+        ReservedBits::from(self.reserved).extension_protocol
+    }
+
+    /// The reserved bits that don't stand for any extension we know about, which some clients
+    /// set for their own purposes.
+    #[must_use]
+    pub fn unknown_reserved_bits(&self) -> [u8; 8] {
+        let known = <[u8; 8]>::from(ReservedBits {
+            extension_protocol: true,
+            fast_extension: true,
+            dht: true,
+        });
+        core::array::from_fn(|byte| self.reserved[byte] & !known[byte])
+    }
+
+    /// Check that the handshake is for the expected torrent, that it's not our own handshake
+    /// (from connecting to ourselves), and, if a specific peer ID is expected, that it's from
+    /// that peer.
+    pub fn validate(
+        &self,
+        info_hash: InfoHash,
+        own_peer_id: PeerId,
+        expected_peer_id: Option<PeerId>,
+    ) -> Result<(), ProtocolError> {
+        if self.peer_id == own_peer_id {
+            return Err(ProtocolError::SelfConnection);
+        }
+        if self.info_hash != info_hash {
+            return Err(ProtocolError::InfoHashMismatch {
+                expected: info_hash,
+                received: self.info_hash,
+            });
+        }
+        match expected_peer_id {
+            Some(expected) if expected != self.peer_id => Err(ProtocolError::PeerIdMismatch {
+                expected,
+                received: self.peer_id,
+            }),
+            _ => Ok(()),
+        }
+    }
+
+    /// If `buffer` starts like a handshake, but for a protocol other than BitTorrent,
+    /// returns the name of that protocol (or as much of it as has been received).
+    #[must_use]
+    pub fn unsupported_protocol(buffer: &[u8]) -> Option<String> {
+        let (&length, rest) = buffer.split_first()?;
+        if length == 0 {
+            return None;
+        }
+        let protocol = &rest[..rest.len().min(usize::from(length))];
+        let supported = usize::from(length) == BITTORRENT_PROTOCOL.len()
+            && BITTORRENT_PROTOCOL.starts_with(protocol);
+        (!supported).then(|| String::from_utf8_lossy(protocol).into_owned())
     }
 }
 
 impl SansIo for Handshake {
     fn decode(i: &[u8]) -> nom::IResult<&[u8], Self> {
-        // We only support the BitTorrent protocol, this is necessary to distinguish between
-        // the handshake and the other messages.
+        // The handshake starts with the length of the protocol name. Every other message starts
+        // with a 4 byte length, the first byte of which is zero for anything shorter than 16 MiB.
+        // That's what distinguishes the handshake from the other messages.
         // (without building some kind of "only parse the handshake once" logic)
-        let (i, _) = tag([19])(i)?;
-        let (i, _) = tag(BITTORRENT_PROTOCOL)(i)?;
+        let (i, length) = verify(u8, |length| *length != 0)(i)?;
         // Past this point, we're definitely in the handshake, so we can cut other message types.
-        // 8 bytes reserved for future use
-        let (i, _) = cut(take(8usize))(i)?;
+        // We only support the BitTorrent protocol, but other names can be of any length.
+        let (i, _) = cut(verify(take(length), |protocol: &[u8]| {
+            protocol == BITTORRENT_PROTOCOL
+        }))(i)?;
+        let (i, reserved) = cut(map_res(take(8usize), TryInto::try_into))(i)?;
         let (i, info_hash) = InfoHash::decode(i)?;
         let (i, peer_id) = PeerId::decode(i)?;
-        Ok((i, Self::new(info_hash, peer_id)))
+        Ok((i, Self::with_reserved(reserved, info_hash, peer_id)))
     }
 
     fn encode(&self) -> Vec<u8> {
         let mut buf = Vec::with_capacity(1 + 19 + 8 + 20 + 20);
+        self.encode_into(&mut buf);
+        buf
+    }
+
+    fn encode_into(&self, buf: &mut Vec<u8>) {
         buf.push(19u8);
         buf.extend(BITTORRENT_PROTOCOL);
-        // 8 bytes reserved for future use
-        buf.extend(RESERVED_ZEROES);
-        buf.extend(self.info_hash.encode());
-        buf.extend(self.peer_id.encode());
-        buf
+        buf.extend(self.reserved);
+        self.info_hash.encode_into(buf);
+        self.peer_id.encode_into(buf);
     }
 }
 
@@ -57,6 +206,7 @@ mod tests {
     use super::*;
 
     const PEER_BYTES: [u8; 20] = *b"-Rp0123-HahW9F2VDDzU";
+    const OWN_PEER_ID: PeerId = PeerId::new([9; 20]);
 
     #[test]
     fn roundtrip() {
@@ -69,6 +219,145 @@ mod tests {
         assert_eq!(remaining.len(), 0);
     }
 
+    #[test]
+    fn roundtrip_with_reserved_bits() {
+        let reserved = [0, 0, 0, 0, 0, 0x10, 0, 0x05];
+        let handshake =
+            Handshake::with_reserved(reserved, InfoHash::new([0; 20]), PeerId::new(PEER_BYTES));
+
+        let encoded = handshake.encode();
+        let (remaining, decoded) = Handshake::decode(&encoded).unwrap();
+
+        assert_eq!(&encoded[20..28], &reserved);
+        assert_eq!(handshake, decoded);
+        assert_eq!(remaining.len(), 0);
+    }
+
+    #[test]
+    fn each_reserved_bit_has_its_own_predicate() {
+        let with_bit = |(byte, mask): (usize, u8)| {
+            let mut reserved = [0; 8];
+            reserved[byte] |= mask;
+            Handshake::with_reserved(reserved, InfoHash::new([0; 20]), PeerId::new(PEER_BYTES))
+        };
+        let predicates = |handshake: Handshake| {
+            [
+                handshake.supports_dht(),
+                handshake.supports_fast(),
+                handshake.supports_extension_protocol(),
+            ]
+        };
+
+        assert_eq!(predicates(with_bit((7, 0x01))), [true, false, false]);
+        assert_eq!(predicates(with_bit((7, 0x04))), [false, true, false]);
+        assert_eq!(predicates(with_bit((5, 0x10))), [false, false, true]);
+        assert_eq!(
+            predicates(Handshake::new(
+                InfoHash::new([0; 20]),
+                PeerId::new(PEER_BYTES)
+            )),
+            [false; 3]
+        );
+    }
+
+    #[test]
+    fn validate_matching_handshake() {
+        let handshake = Handshake::new(InfoHash::new([1; 20]), PeerId::new(PEER_BYTES));
+
+        assert_eq!(
+            handshake.validate(InfoHash::new([1; 20]), OWN_PEER_ID, None),
+            Ok(())
+        );
+        assert_eq!(
+            handshake.validate(
+                InfoHash::new([1; 20]),
+                OWN_PEER_ID,
+                Some(PeerId::new(PEER_BYTES))
+            ),
+            Ok(())
+        );
+    }
+
+    #[test]
+    fn validate_mismatched_info_hash() {
+        let handshake = Handshake::new(InfoHash::new([1; 20]), PeerId::new(PEER_BYTES));
+
+        assert_eq!(
+            handshake.validate(InfoHash::new([2; 20]), OWN_PEER_ID, None),
+            Err(ProtocolError::InfoHashMismatch {
+                expected: InfoHash::new([2; 20]),
+                received: InfoHash::new([1; 20]),
+            })
+        );
+    }
+
+    #[test]
+    fn validate_mismatched_peer_id() {
+        let handshake = Handshake::new(InfoHash::new([1; 20]), PeerId::new(PEER_BYTES));
+
+        assert_eq!(
+            handshake.validate(
+                InfoHash::new([1; 20]),
+                OWN_PEER_ID,
+                Some(PeerId::new([3; 20]))
+            ),
+            Err(ProtocolError::PeerIdMismatch {
+                expected: PeerId::new([3; 20]),
+                received: PeerId::new(PEER_BYTES),
+            })
+        );
+    }
+
+    #[test]
+    fn validate_own_handshake() {
+        let handshake = Handshake::new(InfoHash::new([1; 20]), OWN_PEER_ID);
+
+        assert_eq!(
+            handshake.validate(InfoHash::new([1; 20]), OWN_PEER_ID, None),
+            Err(ProtocolError::SelfConnection)
+        );
+    }
+
+    #[test]
+    fn unsupported_protocol_is_not_parsed_as_another_message() {
+        let handshake = Handshake::new(InfoHash::new([0; 20]), PeerId::new(PEER_BYTES));
+        let mut encoded = handshake.encode();
+        assert_eq!(Handshake::unsupported_protocol(&encoded), None);
+        encoded[1..20].copy_from_slice(b"Bogus protocol v1.0");
+
+        let err = Handshake::decode(&encoded).unwrap_err();
+
+        assert!(matches!(err, nom::Err::Failure(_)));
+        assert_eq!(
+            Handshake::unsupported_protocol(&encoded).as_deref(),
+            Some("Bogus protocol v1.0")
+        );
+        assert_eq!(
+            Handshake::unsupported_protocol(&encoded[..5]).as_deref(),
+            Some("Bogu")
+        );
+    }
+
+    #[test]
+    fn protocol_names_of_other_lengths_are_unsupported() {
+        let mut encoded = vec![20];
+        encoded.extend(b"BitTorrent protocol2");
+        encoded.extend([0; 8 + 20 + 20]);
+
+        assert!(matches!(
+            Handshake::decode(&encoded[..15]),
+            Err(nom::Err::Incomplete(_))
+        ));
+        assert!(matches!(
+            Handshake::decode(&encoded),
+            Err(nom::Err::Failure(_))
+        ));
+        assert_eq!(
+            Handshake::unsupported_protocol(&encoded).as_deref(),
+            Some("BitTorrent protocol2")
+        );
+    }
+
     #[test]
     fn roundtrip_with_extra_bytes() {
         let handshake = Handshake::new(InfoHash::new([0; 20]), PeerId::new(PEER_BYTES));
@@ -100,4 +389,20 @@ mod tests {
             panic!("expected Incomplete");
         }
     }
+
+    #[test]
+    fn extension_protocol_sets_bit_20() {
+        let bits = ReservedBits {
+            extension_protocol: true,
+            ..ReservedBits::default()
+        };
+        let handshake =
+            Handshake::with_reserved(bits.into(), InfoHash::new([1; 20]), PeerId::new([2; 20]));
+
+        let encoded = handshake.encode();
+
+        assert_eq!(encoded[1 + 19..1 + 19 + 8], [0, 0, 0, 0, 0, 0x10, 0, 0]);
+        assert_eq!(ReservedBits::from(handshake.reserved), bits);
+        assert_eq!(<[u8; 8]>::from(ReservedBits::default()), [0; 8]);
+    }
 }
@@ -0,0 +1,53 @@
+use alloc::vec::Vec;
+
+use nom::bytes::streaming::tag;
+use nom::number::streaming::be_u32;
+
+use crate::SansIo;
+
+const HAVE_PREFIX: [u8; 5] = [0, 0, 0, 5, 4];
+
+/// Tells the peer that we've completed (and verified) a piece.
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct Have {
+    pub index: u32,
+}
+
+impl Have {
+    #[must_use]
+    pub fn new(index: u32) -> Self {
+        Self { index }
+    }
+}
+
+impl SansIo for Have {
+    fn decode(i: &[u8]) -> nom::IResult<&[u8], Self> {
+        let (i, _) = tag(HAVE_PREFIX)(i)?;
+        let (i, index) = be_u32(i)?;
+        Ok((i, Self::new(index)))
+    }
+
+    fn encode(&self) -> Vec<u8> {
+        let mut buf = Vec::with_capacity(4 + 1 + 4);
+        buf.extend(HAVE_PREFIX);
+        buf.extend(self.index.to_be_bytes());
+        buf
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn roundtrip() {
+        let have = Have::new(7);
+
+        let encoded = have.encode();
+        let (remaining, decoded) = Have::decode(&encoded).unwrap();
+
+        assert_eq!(have, decoded);
+        assert_eq!(remaining.len(), 0);
+    }
+}
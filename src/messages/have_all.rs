@@ -0,0 +1,39 @@
+use alloc::vec::Vec;
+
+use nom::bytes::streaming::tag;
+
+use crate::SansIo;
+
+const HAVE_ALL: [u8; 5] = [0, 0, 0, 1, 14];
+
+/// Part of the Fast Extension, sent instead of a bitfield to say that we have every piece.
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct HaveAll;
+
+impl SansIo for HaveAll {
+    fn decode(i: &[u8]) -> nom::IResult<&[u8], Self> {
+        let (i, _) = tag(HAVE_ALL)(i)?;
+        Ok((i, Self))
+    }
+
+    fn encode(&self) -> Vec<u8> {
+        HAVE_ALL.to_vec()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn roundtrip() {
+        let have_all = HaveAll;
+
+        let encoded = have_all.encode();
+        let (remaining, decoded) = HaveAll::decode(&encoded).unwrap();
+
+        assert_eq!(have_all, decoded);
+        assert_eq!(remaining.len(), 0);
+    }
+}
@@ -0,0 +1,39 @@
+use alloc::vec::Vec;
+
+use nom::bytes::streaming::tag;
+
+use crate::SansIo;
+
+const HAVE_NONE: [u8; 5] = [0, 0, 0, 1, 15];
+
+/// Part of the Fast Extension, sent instead of a bitfield to say that we don't have any pieces.
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct HaveNone;
+
+impl SansIo for HaveNone {
+    fn decode(i: &[u8]) -> nom::IResult<&[u8], Self> {
+        let (i, _) = tag(HAVE_NONE)(i)?;
+        Ok((i, Self))
+    }
+
+    fn encode(&self) -> Vec<u8> {
+        HAVE_NONE.to_vec()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn roundtrip() {
+        let have_none = HaveNone;
+
+        let encoded = have_none.encode();
+        let (remaining, decoded) = HaveNone::decode(&encoded).unwrap();
+
+        assert_eq!(have_none, decoded);
+        assert_eq!(remaining.len(), 0);
+    }
+}
@@ -0,0 +1,40 @@
+use alloc::vec::Vec;
+
+use nom::bytes::streaming::tag;
+
+use crate::SansIo;
+
+const INTERESTED: [u8; 5] = [0, 0, 0, 1, 2];
+
+/// The interested message tells the peer that they have pieces we want,
+/// and that we'll start requesting them once we're unchoked.
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct Interested;
+
+impl SansIo for Interested {
+    fn decode(i: &[u8]) -> nom::IResult<&[u8], Self> {
+        let (i, _) = tag(INTERESTED)(i)?;
+        Ok((i, Self))
+    }
+
+    fn encode(&self) -> Vec<u8> {
+        INTERESTED.to_vec()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn roundtrip() {
+        let interested = Interested;
+
+        let encoded = interested.encode();
+        let (remaining, decoded) = Interested::decode(&encoded).unwrap();
+
+        assert_eq!(interested, decoded);
+        assert_eq!(remaining.len(), 0);
+    }
+}
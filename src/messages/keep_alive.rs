@@ -1,5 +1,7 @@
+use alloc::vec;
+use alloc::vec::Vec;
+
 use nom::bytes::streaming::tag;
-use nom::combinator::{cut, success};
 
 use crate::SansIo;
 
@@ -8,14 +10,14 @@ use crate::SansIo;
 /// It is encoded as a 4-byte message only containing the length of the message,
 /// and that length is always 0.
 #[derive(Debug, Copy, Clone, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
 pub struct KeepAlive;
 
 impl SansIo for KeepAlive {
     fn decode(i: &[u8]) -> nom::IResult<&[u8], Self> {
+        // Keep-alive messages are the only zero-length messages, every other message has at
+        // least an id, so nothing else can start like this.
         let (i, _) = tag([0; 4])(i)?;
-        // Keep-alive messages are the only zero-length messages, cut other message types.
-        // Sidenote: how do you cut -after- the last parser? This works but looks odd.
-        let (i, ()) = cut(success(()))(i)?;
         Ok((i, Self))
     }
 
@@ -0,0 +1,136 @@
+use std::collections::BTreeMap;
+
+use eyre::{bail, eyre, OptionExt, Result};
+
+use crate::bencode::BValue;
+use crate::messages::Extended;
+use crate::SansIo;
+
+/// Name of the metadata extension (BEP 9) in the extended handshake.
+pub const UT_METADATA: &str = "ut_metadata";
+/// The extended message id we ask peers to use when sending us metadata messages.
+pub const UT_METADATA_ID: u8 = 1;
+/// Metadata is exchanged in pieces of this size, only the last piece may be shorter.
+pub const METADATA_PIECE_SIZE: usize = 16 * 1024;
+
+const REQUEST: i64 = 0;
+const DATA: i64 = 1;
+const REJECT: i64 = 2;
+
+/// A message of the metadata extension, used to download the info dictionary from peers
+/// when all we have is the info hash (e.g. from a magnet link).
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Metadata {
+    /// Ask the peer for a piece of the metadata.
+    Request {
+        /// Index of the requested piece.
+        piece: usize,
+    },
+    /// A piece of the metadata, in response to a request.
+    Data {
+        /// Index of the piece.
+        piece: usize,
+        /// Size of the whole metadata, not just this piece.
+        total_size: usize,
+        /// The contents of the piece.
+        data: Vec<u8>,
+    },
+    /// The peer doesn't have the requested piece, or doesn't want to share it.
+    Reject {
+        /// Index of the requested piece.
+        piece: usize,
+    },
+}
+
+impl Metadata {
+    /// Parse the payload of an [Extended] message of the metadata extension.
+    pub fn from_payload(payload: &[u8]) -> Result<Self> {
+        // The dictionary is followed by the raw piece contents in data messages,
+        // so anything that's left over after decoding it is the data.
+        let (data, value) =
+            BValue::decode(payload).map_err(|e| eyre!("Invalid metadata message: {e:?}"))?;
+        let integer = |key: &[u8]| -> Result<usize> {
+            let integer = value
+                .get(key)
+                .and_then(BValue::as_integer)
+                .ok_or_else(|| eyre!("Metadata message is missing '{}'", key.escape_ascii()))?;
+            usize::try_from(integer).map_err(|_| eyre!("Invalid value {integer} in metadata"))
+        };
+        let message_type = value
+            .get(b"msg_type")
+            .and_then(BValue::as_integer)
+            .ok_or_eyre("Metadata message is missing 'msg_type'")?;
+        let piece = integer(b"piece")?;
+        Ok(match message_type {
+            REQUEST => Self::Request { piece },
+            DATA => Self::Data {
+                piece,
+                total_size: integer(b"total_size")?,
+                data: data.to_vec(),
+            },
+            REJECT => Self::Reject { piece },
+            other => bail!("Unknown metadata message type {other}"),
+        })
+    }
+
+    /// Wrap this in an [Extended] message, using the id the peer asked for in its
+    /// extended handshake.
+    #[must_use]
+    pub fn to_message(&self, id: u8) -> Extended {
+        let (message_type, piece) = match self {
+            Self::Request { piece } => (REQUEST, piece),
+            Self::Data { piece, .. } => (DATA, piece),
+            Self::Reject { piece } => (REJECT, piece),
+        };
+        let integer = |value: usize| {
+            BValue::Integer(i64::try_from(value).expect("metadata to be reasonably sized"))
+        };
+        let mut dict = BTreeMap::from([
+            (b"msg_type".to_vec(), BValue::Integer(message_type)),
+            (b"piece".to_vec(), integer(*piece)),
+        ]);
+        if let Self::Data { total_size, .. } = self {
+            dict.insert(b"total_size".to_vec(), integer(*total_size));
+        }
+        let mut payload = BValue::Dict(dict).encode();
+        if let Self::Data { data, .. } = self {
+            payload.extend(data);
+        }
+        Extended::new(id, payload)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn roundtrip_data() {
+        let metadata = Metadata::Data {
+            piece: 1,
+            total_size: 20000,
+            data: vec![1, 2, 3],
+        };
+
+        let message = metadata.to_message(3);
+        let decoded = Metadata::from_payload(&message.payload).unwrap();
+
+        assert_eq!(message.id, 3);
+        assert_eq!(
+            message.payload,
+            b"d8:msg_typei1e5:piecei1e10:total_sizei20000ee\x01\x02\x03"
+        );
+        assert_eq!(metadata, decoded);
+    }
+
+    #[test]
+    fn roundtrip_request_and_reject() {
+        for metadata in [
+            Metadata::Request { piece: 2 },
+            Metadata::Reject { piece: 0 },
+        ] {
+            let message = metadata.to_message(3);
+            assert_eq!(metadata, Metadata::from_payload(&message.payload).unwrap());
+        }
+    }
+}
@@ -1,35 +1,178 @@
-use eyre::Result;
+use alloc::vec::Vec;
+use core::fmt;
+
 use nom::branch::alt;
-use nom::combinator::map;
+use nom::combinator::{cut, map, peek, verify};
+use nom::number::streaming::be_u32;
+use nom::sequence::preceded;
 use nom::{IResult, Offset};
 
-pub use handshake::Handshake;
+pub use allowed_fast::AllowedFast;
+pub use bitfield::Bitfield;
+pub use cancel::Cancel;
+pub use choke::Choke;
+pub use extended::Extended;
+#[cfg(feature = "std")]
+pub use extended::ExtendedHandshake;
+pub use extended::{EXTENDED_HANDSHAKE_ID, EXTENSION_PROTOCOL_BIT};
+pub use handshake::{Handshake, ReservedBits, DHT_BIT, FAST_EXTENSION_BIT};
+pub use have::Have;
+pub use have_all::HaveAll;
+pub use have_none::HaveNone;
+pub use interested::Interested;
 pub use keep_alive::KeepAlive;
-pub use unknown::Unknown;
+#[cfg(feature = "std")]
+pub use metadata::{Metadata, METADATA_PIECE_SIZE, UT_METADATA, UT_METADATA_ID};
+pub use not_interested::NotInterested;
+#[cfg(feature = "std")]
+pub use pex::{Pex, MAX_PEX_PEERS, PEX_INTERVAL, UT_PEX, UT_PEX_ID};
+pub use piece::Piece;
+pub use port::Port;
+pub use protocol_error::ProtocolError;
+pub use reject_request::RejectRequest;
+pub use request::Request;
+pub use suggest_piece::SuggestPiece;
+pub use unchoke::Unchoke;
+pub use unknown::{Unknown, MAX_MESSAGE_LENGTH};
 
 use crate::SansIo;
 
+mod allowed_fast;
+mod bitfield;
+mod cancel;
+mod choke;
+mod extended;
 mod handshake;
+mod have;
+mod have_all;
+mod have_none;
+mod interested;
 mod keep_alive;
+#[cfg(feature = "std")]
+mod metadata;
+mod not_interested;
+#[cfg(feature = "std")]
+mod pex;
+mod piece;
+mod port;
+mod protocol_error;
+mod reject_request;
+mod request;
+mod suggest_piece;
+mod unchoke;
 mod unknown;
 
+/// The ids of every message we implement. [Unknown] refuses to decode these, so that e.g. a
+/// `Request` with the wrong length is reported as malformed instead of passing as unknown.
+///
+/// Remember to add the id here when implementing a new message.
+pub const KNOWN_MESSAGE_IDS: &[u8] = &[0, 1, 2, 3, 4, 5, 6, 7, 8, 9, 13, 14, 15, 16, 17, 20];
+
 /// Wrapper type for all messages that can be sent or received.
+///
+/// With the `serde` feature it's serialized as the inner message, tagged with the variant
+/// name in a `type` field, e.g. `{"type":"Have","index":3}`.
 #[derive(Debug, Clone, PartialEq, Eq)]
+#[cfg_attr(
+    feature = "serde",
+    derive(serde::Serialize, serde::Deserialize),
+    serde(tag = "type")
+)]
 pub enum Message {
+    /// The first message on every connection.
     Handshake(Handshake),
+    /// Sent when there's nothing else to send, so the connection isn't closed as idle.
     KeepAlive(KeepAlive),
+    /// The peer won't answer our requests.
+    Choke(Choke),
+    /// The peer will answer our requests.
+    Unchoke(Unchoke),
+    /// The peer wants to download from us.
+    Interested(Interested),
+    /// The peer doesn't want to download from us.
+    NotInterested(NotInterested),
+    /// The peer completed a piece.
+    Have(Have),
+    /// All pieces the peer has.
+    Bitfield(Bitfield),
+    /// Asks for a block of a piece.
+    Request(Request),
+    /// A block of a piece, in response to a request.
+    Piece(Piece),
+    /// Takes back a request.
+    Cancel(Cancel),
+    /// The port of the peer's DHT node.
+    Port(Port),
+    /// A message of the extension protocol.
+    Extended(Extended),
+    /// Fast Extension: a piece the peer thinks we should download.
+    SuggestPiece(SuggestPiece),
+    /// Fast Extension: the peer has all pieces.
+    HaveAll(HaveAll),
+    /// Fast Extension: the peer has no pieces.
+    HaveNone(HaveNone),
+    /// Fast Extension: the peer won't answer a request.
+    RejectRequest(RejectRequest),
+    /// Fast Extension: a piece we may request even while choked.
+    AllowedFast(AllowedFast),
+    /// Any other message, which we don't understand.
     Unknown(Unknown),
 }
 
+/// Shows just the message itself, e.g. `Choke` or `Have { index: 3 }`, with data like the block
+/// of a `Piece` summarized by its length.
+impl fmt::Display for Message {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Message::Handshake(message) => fmt::Debug::fmt(message, f),
+            Message::KeepAlive(message) => fmt::Debug::fmt(message, f),
+            Message::Choke(message) => fmt::Debug::fmt(message, f),
+            Message::Unchoke(message) => fmt::Debug::fmt(message, f),
+            Message::Interested(message) => fmt::Debug::fmt(message, f),
+            Message::NotInterested(message) => fmt::Debug::fmt(message, f),
+            Message::Have(message) => fmt::Debug::fmt(message, f),
+            Message::Bitfield(message) => fmt::Debug::fmt(message, f),
+            Message::Request(message) => fmt::Debug::fmt(message, f),
+            Message::Piece(message) => fmt::Debug::fmt(message, f),
+            Message::Cancel(message) => fmt::Debug::fmt(message, f),
+            Message::Port(message) => fmt::Debug::fmt(message, f),
+            Message::Extended(message) => fmt::Debug::fmt(message, f),
+            Message::SuggestPiece(message) => fmt::Debug::fmt(message, f),
+            Message::HaveAll(message) => fmt::Debug::fmt(message, f),
+            Message::HaveNone(message) => fmt::Debug::fmt(message, f),
+            Message::RejectRequest(message) => fmt::Debug::fmt(message, f),
+            Message::AllowedFast(message) => fmt::Debug::fmt(message, f),
+            Message::Unknown(message) => fmt::Debug::fmt(message, f),
+        }
+    }
+}
+
 impl Message {
     /// Decode a message from a buffer, which might only contain a part of the message.
     /// Returns `Ok(None)` if the message was incomplete, and more data is needed.
     /// Returns `Err` if the message format was invalid.
-    pub fn from_partial_buffer(buffer: &[u8]) -> Result<Option<DecodedMessage>> {
-        let (i, message) = map(Message::decode, Some)(buffer).or_else(|e| match e {
-            nom::Err::Incomplete(_) => Ok((buffer, None)),
-            e => Err(e.to_owned()),
-        })?;
+    pub fn from_partial_buffer(buffer: &[u8]) -> Result<Option<DecodedMessage>, ProtocolError> {
+        let (i, message) = match map(Message::decode, Some)(buffer) {
+            Ok(decoded) => decoded,
+            Err(nom::Err::Incomplete(_)) => (buffer, None),
+            Err(nom::Err::Error(e) | nom::Err::Failure(e)) => {
+                if let Some(protocol) = Handshake::unsupported_protocol(buffer) {
+                    return Err(ProtocolError::UnsupportedProtocol(protocol));
+                }
+                if let Some(length) = buffer
+                    .first_chunk()
+                    .map(|length| u32::from_be_bytes(*length))
+                {
+                    if length >= MAX_MESSAGE_LENGTH {
+                        return Err(ProtocolError::Oversized {
+                            length,
+                            limit: MAX_MESSAGE_LENGTH,
+                        });
+                    }
+                }
+                return Err(ProtocolError::Malformed(e.code));
+            }
+        };
         if let Some(message) = message {
             Ok(Some(DecodedMessage {
                 consumed_bytes: buffer.offset(i),
@@ -39,28 +182,222 @@ impl Message {
             Ok(None)
         }
     }
+
+    /// Decode every complete message at the start of `buffer`, along with the number of
+    /// bytes they took up. A trailing partial message is left unconsumed, to be decoded once
+    /// the rest of it has arrived.
+    pub fn decode_all(buffer: &[u8]) -> Result<(Vec<Message>, usize), ProtocolError> {
+        let mut messages = Vec::new();
+        let mut consumed = 0;
+        while let Some(DecodedMessage {
+            consumed_bytes,
+            message,
+        }) = Message::from_partial_buffer(&buffer[consumed..])?
+        {
+            messages.push(message);
+            consumed += consumed_bytes;
+        }
+        Ok((messages, consumed))
+    }
+
+    /// How many bytes the first message in `buffer` takes up on the wire, going by its length
+    /// prefix alone, or `None` if not even that has arrived yet.
+    ///
+    /// This lets a connection turn away messages that are too big before buffering them.
+    #[must_use]
+    pub fn advertised_len(buffer: &[u8]) -> Option<usize> {
+        match buffer.first()? {
+            // The rest of the handshake is the protocol name, followed by the reserved bytes,
+            // info hash and peer ID.
+            &protocol_length @ 1.. => Some(1 + usize::from(protocol_length) + 8 + 20 + 20),
+            0 => buffer
+                .first_chunk()
+                .map(|length| 4 + u32::from_be_bytes(*length) as usize),
+        }
+    }
+
+    /// The protocol id of the message, or `None` for the handshake and keep-alive,
+    /// which don't have one.
+    #[must_use]
+    pub fn id(&self) -> Option<u8> {
+        match self {
+            Message::Handshake(_) | Message::KeepAlive(_) => None,
+            Message::Choke(_) => Some(0),
+            Message::Unchoke(_) => Some(1),
+            Message::Interested(_) => Some(2),
+            Message::NotInterested(_) => Some(3),
+            Message::Have(_) => Some(4),
+            Message::Bitfield(_) => Some(5),
+            Message::Request(_) => Some(6),
+            Message::Piece(_) => Some(7),
+            Message::Cancel(_) => Some(8),
+            Message::Port(_) => Some(9),
+            Message::SuggestPiece(_) => Some(13),
+            Message::HaveAll(_) => Some(14),
+            Message::HaveNone(_) => Some(15),
+            Message::RejectRequest(_) => Some(16),
+            Message::AllowedFast(_) => Some(17),
+            Message::Extended(_) => Some(20),
+            Message::Unknown(unknown) => Some(unknown.id),
+        }
+    }
+
+    /// The number of bytes the encoded message takes up on the wire, including the length
+    /// prefix. This is the same as `self.encode().len()`, but without encoding the message.
+    #[must_use]
+    pub fn wire_len(&self) -> usize {
+        // everything but the handshake is prefixed by its length
+        let payload_len = match self {
+            Message::Handshake(_) => return 1 + 19 + 8 + 20 + 20,
+            Message::KeepAlive(_) => return 4,
+            Message::Choke(_)
+            | Message::Unchoke(_)
+            | Message::Interested(_)
+            | Message::NotInterested(_)
+            | Message::HaveAll(_)
+            | Message::HaveNone(_) => 0,
+            Message::Request(_) | Message::Cancel(_) | Message::RejectRequest(_) => 12,
+            Message::Piece(piece) => 8 + piece.block.len(),
+            Message::Port(_) => 2,
+            Message::Have(_) | Message::SuggestPiece(_) | Message::AllowedFast(_) => 4,
+            Message::Bitfield(bitfield) => bitfield.bytes.len(),
+            Message::Extended(extended) => 1 + extended.payload.len(),
+            Message::Unknown(unknown) => unknown.bytes.len(),
+        };
+        4 + 1 + payload_len
+    }
 }
 
 /// The outcome of trying to decode a message from a buffer.
 pub struct DecodedMessage {
     /// The number of bytes consumed by the decoder.
     pub consumed_bytes: usize,
+    /// The decoded message.
     pub message: Message,
 }
 
+/// Iterates over the messages in a buffer, such as a captured stream of bytes, without a
+/// connection. Stops at the end of the last complete message, or after the first error.
+#[derive(Debug, Clone)]
+pub struct MessageFrames<'a> {
+    remaining: &'a [u8],
+}
+
+impl<'a> MessageFrames<'a> {
+    /// Iterate over the messages at the start of `buffer`.
+    #[must_use]
+    pub fn new(buffer: &'a [u8]) -> Self {
+        Self { remaining: buffer }
+    }
+
+    /// The bytes that haven't been decoded yet. Once the iterator is done, this is the
+    /// trailing partial message if there was one.
+    #[must_use]
+    pub fn remaining(&self) -> &'a [u8] {
+        self.remaining
+    }
+}
+
+impl Iterator for MessageFrames<'_> {
+    type Item = Result<Message, ProtocolError>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        match Message::from_partial_buffer(self.remaining) {
+            Ok(Some(DecodedMessage {
+                consumed_bytes,
+                message,
+            })) => {
+                self.remaining = &self.remaining[consumed_bytes..];
+                Some(Ok(message))
+            }
+            Ok(None) => None,
+            Err(e) => {
+                // Where the next message would start is unknown after an error.
+                self.remaining = &[];
+                Some(Err(e))
+            }
+        }
+    }
+}
+
 impl SansIo for Message {
     fn decode(i: &[u8]) -> IResult<&[u8], Self> {
         let handshake = map(Handshake::decode, Message::Handshake);
         let keep_alive = map(KeepAlive::decode, Message::KeepAlive);
+        let choke = map(Choke::decode, Message::Choke);
+        let unchoke = map(Unchoke::decode, Message::Unchoke);
+        let interested = map(Interested::decode, Message::Interested);
+        let not_interested = map(NotInterested::decode, Message::NotInterested);
+        let have = map(Have::decode, Message::Have);
+        let bitfield = map(Bitfield::decode, Message::Bitfield);
+        let request = map(Request::decode, Message::Request);
+        let piece = map(Piece::decode, Message::Piece);
+        let cancel = map(Cancel::decode, Message::Cancel);
+        let port = map(Port::decode, Message::Port);
+        let extended = map(Extended::decode, Message::Extended);
+        let suggest_piece = map(SuggestPiece::decode, Message::SuggestPiece);
+        let have_all = map(HaveAll::decode, Message::HaveAll);
+        let have_none = map(HaveNone::decode, Message::HaveNone);
+        let reject_request = map(RejectRequest::decode, Message::RejectRequest);
+        let allowed_fast = map(AllowedFast::decode, Message::AllowedFast);
         let unknown = map(Unknown::decode, Message::Unknown);
-        alt((handshake, keep_alive, unknown))(i)
+        // Everything but the handshake starts with its length. Checking that up front keeps
+        // the decoders of variable-length messages from waiting for a message we'd never
+        // accept, instead of failing.
+        let length = cut(peek(verify(be_u32, |length| *length < MAX_MESSAGE_LENGTH)));
+        // A length of zero is a keep-alive, and every other message claims its id and length
+        // exactly, so at most one decoder accepts any message. Whatever none of them accept
+        // is malformed, there's no fallback that could misparse it.
+        let framed = alt((
+            keep_alive,
+            choke,
+            unchoke,
+            interested,
+            not_interested,
+            have,
+            bitfield,
+            request,
+            piece,
+            cancel,
+            port,
+            extended,
+            suggest_piece,
+            have_all,
+            have_none,
+            reject_request,
+            allowed_fast,
+            unknown,
+        ));
+        alt((handshake, preceded(length, framed)))(i)
     }
 
     fn encode(&self) -> Vec<u8> {
+        let mut buf = Vec::with_capacity(self.wire_len());
+        self.encode_into(&mut buf);
+        buf
+    }
+
+    fn encode_into(&self, buf: &mut Vec<u8>) {
         match self {
-            Message::Handshake(handshake) => handshake.encode(),
-            Message::KeepAlive(keep_alive) => keep_alive.encode(),
-            Message::Unknown(unknown) => unknown.encode(),
+            Message::Handshake(handshake) => handshake.encode_into(buf),
+            Message::KeepAlive(keep_alive) => keep_alive.encode_into(buf),
+            Message::Choke(choke) => choke.encode_into(buf),
+            Message::Unchoke(unchoke) => unchoke.encode_into(buf),
+            Message::Interested(interested) => interested.encode_into(buf),
+            Message::NotInterested(not_interested) => not_interested.encode_into(buf),
+            Message::Have(have) => have.encode_into(buf),
+            Message::Bitfield(bitfield) => bitfield.encode_into(buf),
+            Message::Request(request) => request.encode_into(buf),
+            Message::Piece(piece) => piece.encode_into(buf),
+            Message::Cancel(cancel) => cancel.encode_into(buf),
+            Message::Port(port) => port.encode_into(buf),
+            Message::Extended(extended) => extended.encode_into(buf),
+            Message::SuggestPiece(suggest_piece) => suggest_piece.encode_into(buf),
+            Message::HaveAll(have_all) => have_all.encode_into(buf),
+            Message::HaveNone(have_none) => have_none.encode_into(buf),
+            Message::RejectRequest(reject_request) => reject_request.encode_into(buf),
+            Message::AllowedFast(allowed_fast) => allowed_fast.encode_into(buf),
+            Message::Unknown(unknown) => unknown.encode_into(buf),
         }
     }
 }
@@ -71,6 +408,288 @@ mod tests {
 
     use super::*;
 
+    #[test]
+    fn id_and_wire_len_of_request() {
+        let message = Message::Request(Request::new(1, 16384, 16384));
+
+        assert_eq!(message.id(), Some(6));
+        assert_eq!(message.wire_len(), 17);
+        assert_eq!(message.wire_len(), message.encode().len());
+    }
+
+    #[test]
+    fn id_and_wire_len_of_piece() {
+        let message = Message::Piece(Piece::new(1, 16384, vec![0; 16384]));
+
+        assert_eq!(message.id(), Some(7));
+        assert_eq!(message.wire_len(), 4 + 9 + 16384);
+        assert_eq!(message.wire_len(), message.encode().len());
+    }
+
+    #[test]
+    fn id_and_wire_len_match_encoding() {
+        let messages = [
+            Message::Handshake(Handshake::new(InfoHash::new([1; 20]), PeerId::new([2; 20]))),
+            Message::KeepAlive(KeepAlive),
+            Message::Choke(Choke),
+            Message::Unchoke(Unchoke),
+            Message::Interested(Interested),
+            Message::NotInterested(NotInterested),
+            Message::Have(Have::new(1)),
+            Message::Bitfield(Bitfield::new(vec![0b1010_0000])),
+            Message::Request(Request::new(1, 2, 3)),
+            Message::Piece(Piece::new(1, 2, vec![3, 4, 5])),
+            Message::Cancel(Cancel::new(1, 2, 3)),
+            Message::Port(Port::new(6881)),
+            Message::Extended(Extended::new(1, vec![2, 3])),
+            Message::SuggestPiece(SuggestPiece::new(1)),
+            Message::HaveAll(HaveAll),
+            Message::HaveNone(HaveNone),
+            Message::RejectRequest(RejectRequest::new(1, 2, 3)),
+            Message::AllowedFast(AllowedFast::new(1)),
+            Message::Unknown(Unknown::new(23, vec![3, 4, 5])),
+        ];
+
+        for message in messages {
+            let encoded = message.encode();
+            assert_eq!(message.wire_len(), encoded.len(), "{message:?}");
+            match message.id() {
+                Some(id) => {
+                    assert_eq!(encoded[4], id, "{message:?}");
+                    let unknown = matches!(message, Message::Unknown(_));
+                    assert_eq!(KNOWN_MESSAGE_IDS.contains(&id), !unknown, "{message:?}");
+                }
+                None => assert!(matches!(
+                    message,
+                    Message::Handshake(_) | Message::KeepAlive(_)
+                )),
+            }
+        }
+    }
+
+    /// A tiny deterministic xorshift generator, so that fuzzing finds the same inputs every run.
+    struct Xorshift(u64);
+
+    impl Xorshift {
+        fn next(&mut self) -> u64 {
+            self.0 ^= self.0 << 13;
+            self.0 ^= self.0 >> 7;
+            self.0 ^= self.0 << 17;
+            self.0
+        }
+
+        fn below(&mut self, n: usize) -> usize {
+            // n is tiny, so the truncation is fine
+            #[allow(clippy::cast_possible_truncation)]
+            let below = (self.next() % n as u64) as usize;
+            below
+        }
+    }
+
+    /// Throws garbage and mangled messages at the decoders, which may reject them, but must never
+    /// panic. Any panic fails the test, and the input that caused it should become a regression
+    /// test like `decode_regressions`.
+    #[test]
+    fn fuzz_from_partial_buffer() {
+        let seeds = [
+            Message::Handshake(Handshake::new(InfoHash::new([1; 20]), PeerId::new([2; 20]))),
+            Message::KeepAlive(KeepAlive),
+            Message::Have(Have::new(1)),
+            Message::Bitfield(Bitfield::new(vec![0b1010_0000])),
+            Message::Request(Request::new(1, 2, 3)),
+            Message::Piece(Piece::new(1, 2, vec![3, 4, 5])),
+            Message::Port(Port::new(6881)),
+            Message::Extended(Extended::new(1, vec![2, 3])),
+            Message::Unknown(Unknown::new(23, vec![3, 4, 5])),
+        ]
+        .map(|message| message.encode());
+        let mut rng = Xorshift(0x5eed_f00d_dead_beef);
+
+        for _ in 0..200_000 {
+            let mut buffer = if rng.below(4) == 0 {
+                (0..rng.below(80)).map(|_| rng.next() as u8).collect()
+            } else {
+                seeds[rng.below(seeds.len())].clone()
+            };
+            for _ in 0..rng.below(4) {
+                if !buffer.is_empty() {
+                    // Mostly mangle the length and id, as that's where the arithmetic is.
+                    let range = if rng.below(2) == 0 { 5 } else { 80 };
+                    let index = rng.below(buffer.len().min(range));
+                    buffer[index] = match rng.below(4) {
+                        0 => 0,
+                        1 => 0xff,
+                        _ => rng.next() as u8,
+                    };
+                }
+            }
+            buffer.truncate(rng.below(buffer.len() + 1));
+
+            if let Ok(Some(decoded)) = Message::from_partial_buffer(&buffer) {
+                assert!(decoded.consumed_bytes <= buffer.len(), "{buffer:?}");
+            }
+            let _ = Message::decode_all(&buffer);
+            // Message::decode only gets to some decoders with some inputs, so try each one.
+            let _ = Handshake::decode(&buffer);
+            let _ = Bitfield::decode(&buffer);
+            let _ = Piece::decode(&buffer);
+            let _ = Extended::decode(&buffer);
+            let _ = Unknown::decode(&buffer);
+        }
+    }
+
+    #[test]
+    fn advertised_len_matches_encoding() {
+        let handshake =
+            Message::Handshake(Handshake::new(InfoHash::new([1; 20]), PeerId::new([2; 20])));
+        let piece = Message::Piece(Piece::new(1, 2, vec![3; 100]));
+
+        for message in [handshake, piece, Message::KeepAlive(KeepAlive)] {
+            let encoded = message.encode();
+            assert_eq!(Message::advertised_len(&encoded[..4]), Some(encoded.len()));
+        }
+        assert_eq!(Message::advertised_len(&[0, 0, 1]), None);
+        assert_eq!(
+            Message::advertised_len(&[0, 0x0e, 0x10, 0]),
+            Some(900 * 1024 + 4)
+        );
+    }
+
+    #[test]
+    fn display_summarizes_payloads() {
+        let piece = Message::Piece(Piece::new(3, 16384, vec![0xab; 16384]));
+        let unknown = Message::Unknown(Unknown::new(23, vec![0xab; 100]));
+
+        assert_eq!(
+            piece.to_string(),
+            "Piece { index: 3, begin: 16384, block: 16384 bytes }"
+        );
+        assert_eq!(unknown.to_string(), "Unknown { id: 23, bytes: 100 bytes }");
+        assert_eq!(
+            format!("{unknown:?}"),
+            "Unknown(Unknown { id: 23, bytes: 100 bytes })"
+        );
+        assert_eq!(Message::Choke(Choke).to_string(), "Choke");
+        assert!(!format!("{piece:?}").contains("171"));
+    }
+
+    /// Inputs that used to make a decoder panic.
+    #[test]
+    fn decode_regressions() {
+        // A message length of zero used to underflow when subtracting the id.
+        assert!(Unknown::decode(&[0, 0, 0, 0, 99]).is_err());
+        assert!(Unknown::decode(&[0, 0, 0, 0]).is_err());
+    }
+
+    #[test]
+    fn keep_alive_and_handshake_in_one_buffer_are_two_messages() {
+        let handshake =
+            Message::Handshake(Handshake::new(InfoHash::new([1; 20]), PeerId::new([2; 20])));
+        let mut buffer = KeepAlive.encode();
+        buffer.extend(handshake.encode());
+        buffer.extend(KeepAlive.encode());
+
+        let (messages, consumed) = Message::decode_all(&buffer).unwrap();
+
+        assert_eq!(
+            messages,
+            [
+                Message::KeepAlive(KeepAlive),
+                handshake,
+                Message::KeepAlive(KeepAlive)
+            ]
+        );
+        assert_eq!(consumed, buffer.len());
+    }
+
+    #[test]
+    fn framing_errors_are_reported_not_misparsed() {
+        // Too short to tell yet.
+        assert!(matches!(Message::from_partial_buffer(&[0, 0, 0]), Ok(None)));
+        assert!(matches!(
+            Message::from_partial_buffer(&[0, 0, 0, 5, 4]),
+            Ok(None)
+        ));
+        // A choke with a payload, and a have that's too short.
+        for buffer in [&[0, 0, 0, 2, 0, 1][..], &[0, 0, 0, 3, 4, 0, 1]] {
+            assert!(matches!(
+                Message::from_partial_buffer(buffer),
+                Err(ProtocolError::Malformed(_))
+            ));
+        }
+        // Pieces and extended messages used to wait for the rest of an oversized message.
+        for id in [5, 7, 20, 99] {
+            assert!(matches!(
+                Message::from_partial_buffer(&[0, 0x10, 0, 0, id]),
+                Err(ProtocolError::Oversized { .. })
+            ));
+        }
+    }
+
+    #[test]
+    fn encode_into_appends_to_buffer() {
+        let messages = [
+            Message::Handshake(Handshake::new(InfoHash::new([1; 20]), PeerId::new([2; 20]))),
+            Message::KeepAlive(KeepAlive),
+            Message::Request(Request::new(1, 2, 3)),
+            Message::Piece(Piece::new(1, 2, vec![3, 4, 5])),
+            Message::Cancel(Cancel::new(1, 2, 3)),
+            Message::RejectRequest(RejectRequest::new(1, 2, 3)),
+            Message::Unknown(Unknown::new(23, vec![3, 4, 5])),
+        ];
+
+        let mut buf = vec![9, 9, 9];
+        let mut expected = buf.clone();
+        for message in messages {
+            message.encode_into(&mut buf);
+            expected.extend(message.encode());
+        }
+
+        assert_eq!(buf, expected);
+        assert_eq!(
+            Piece::new(1, 2, vec![3, 4, 5]).encode(),
+            [0, 0, 0, 12, 7, 0, 0, 0, 1, 0, 0, 0, 2, 3, 4, 5]
+        );
+    }
+
+    #[cfg(feature = "serde")]
+    #[test]
+    fn serde_tags_messages_by_type() {
+        let handshake = Handshake::new(
+            InfoHash::new([0xab; 20]),
+            PeerId::new(*b"-qB4550-HahW9F2VDDzU"),
+        );
+        let cases = [
+            (
+                Message::Handshake(handshake),
+                format!(
+                    r#"{{"type":"Handshake","reserved":[0,0,0,0,0,0,0,0],"info_hash":"{}","peer_id":"-qB4550-HahW9F2VDDzU"}}"#,
+                    "ab".repeat(20)
+                ),
+            ),
+            (
+                Message::KeepAlive(KeepAlive),
+                r#"{"type":"KeepAlive"}"#.to_string(),
+            ),
+            (
+                Message::Have(Have::new(3)),
+                r#"{"type":"Have","index":3}"#.to_string(),
+            ),
+            (
+                Message::Piece(Piece::new(1, 2, vec![3, 4])),
+                r#"{"type":"Piece","index":1,"begin":2,"block":[3,4]}"#.to_string(),
+            ),
+        ];
+
+        for (message, expected) in cases {
+            let json = serde_json::to_string(&message).unwrap();
+
+            assert_eq!(json, expected);
+            assert_eq!(serde_json::from_str::<Message>(&json).unwrap(), message);
+        }
+        let _ = serde_json::from_str::<Message>(r#"{"type":"Nope"}"#).unwrap_err();
+    }
+
     #[test]
     fn roundtrip_handshake() {
         let message =
@@ -83,6 +702,89 @@ mod tests {
         assert_eq!(remaining.len(), 0);
     }
 
+    #[test]
+    fn decode_all_leaves_partial_message() {
+        let first = Handshake::new(InfoHash::new([1; 20]), PeerId::new([2; 20]));
+        let second = Handshake::new(InfoHash::new([1; 20]), PeerId::new([3; 20]));
+        let mut buffer = first.encode();
+        second.encode_into(&mut buffer);
+        let complete = buffer.len();
+        buffer.extend(&first.encode()[..30]);
+
+        let (messages, consumed) = Message::decode_all(&buffer).unwrap();
+
+        assert_eq!(
+            messages,
+            vec![Message::Handshake(first), Message::Handshake(second)]
+        );
+        assert_eq!(consumed, complete);
+    }
+
+    #[test]
+    fn frames_of_concatenated_messages() {
+        let messages = [
+            Message::Interested(Interested),
+            Message::Have(Have::new(7)),
+            Message::Request(Request::new(1, 0, 16384)),
+        ];
+        let mut buffer = Vec::new();
+        for message in &messages {
+            message.encode_into(&mut buffer);
+        }
+        let mut frames = MessageFrames::new(&buffer);
+        let decoded: Vec<_> = frames.by_ref().map(Result::unwrap).collect();
+        assert_eq!(decoded, messages);
+        assert_eq!(frames.remaining(), &[] as &[u8]);
+
+        // A partial message at the end is left over, rather than being an error.
+        let partial = Message::Piece(Piece::new(0, 0, vec![1; 10])).encode();
+        buffer.extend(&partial[..7]);
+        let mut frames = MessageFrames::new(&buffer);
+        assert_eq!(frames.by_ref().map(Result::unwrap).count(), 3);
+        assert_eq!(frames.remaining(), &partial[..7]);
+    }
+
+    #[test]
+    fn malformed_request_is_not_unknown() {
+        let err = Message::from_partial_buffer(&[0, 0, 0, 5, 6, 0, 0, 0, 1])
+            .err()
+            .unwrap();
+
+        assert_eq!(err, ProtocolError::Malformed(nom::error::ErrorKind::Verify));
+    }
+
+    #[test]
+    fn unsupported_protocol_is_reported() {
+        let mut encoded = Handshake::new(InfoHash::new([1; 20]), PeerId::new([2; 20])).encode();
+        encoded[1..20].copy_from_slice(b"Bogus protocol v1.0");
+
+        let err = Message::from_partial_buffer(&encoded).err().unwrap();
+
+        assert_eq!(
+            err,
+            ProtocolError::UnsupportedProtocol("Bogus protocol v1.0".to_string())
+        );
+        assert_eq!(
+            err.to_string(),
+            "Unsupported protocol: \"Bogus protocol v1.0\""
+        );
+    }
+
+    #[test]
+    fn oversized_message_is_reported() {
+        let err = Message::from_partial_buffer(&[0, 0x10, 0, 0, 99])
+            .err()
+            .unwrap();
+
+        assert_eq!(
+            err,
+            ProtocolError::Oversized {
+                length: 0x10_0000,
+                limit: MAX_MESSAGE_LENGTH,
+            }
+        );
+    }
+
     #[test]
     fn roundtrip_keep_alive() {
         let message = Message::KeepAlive(KeepAlive);
@@ -94,6 +796,182 @@ mod tests {
         assert_eq!(remaining.len(), 0);
     }
 
+    #[test]
+    fn roundtrip_choke() {
+        let message = Message::Choke(Choke);
+
+        let encoded = message.encode();
+        let (remaining, decoded) = Message::decode(&encoded).unwrap();
+
+        assert_eq!(message, decoded);
+        assert_eq!(remaining.len(), 0);
+    }
+
+    #[test]
+    fn roundtrip_unchoke() {
+        let message = Message::Unchoke(Unchoke);
+
+        let encoded = message.encode();
+        let (remaining, decoded) = Message::decode(&encoded).unwrap();
+
+        assert_eq!(message, decoded);
+        assert_eq!(remaining.len(), 0);
+    }
+
+    #[test]
+    fn roundtrip_interested() {
+        let message = Message::Interested(Interested);
+
+        let encoded = message.encode();
+        let (remaining, decoded) = Message::decode(&encoded).unwrap();
+
+        assert_eq!(message, decoded);
+        assert_eq!(remaining.len(), 0);
+    }
+
+    #[test]
+    fn roundtrip_not_interested() {
+        let message = Message::NotInterested(NotInterested);
+
+        let encoded = message.encode();
+        let (remaining, decoded) = Message::decode(&encoded).unwrap();
+
+        assert_eq!(message, decoded);
+        assert_eq!(remaining.len(), 0);
+    }
+
+    #[test]
+    fn roundtrip_have() {
+        let message = Message::Have(Have::new(1));
+
+        let encoded = message.encode();
+        let (remaining, decoded) = Message::decode(&encoded).unwrap();
+
+        assert_eq!(message, decoded);
+        assert_eq!(remaining.len(), 0);
+    }
+
+    #[test]
+    fn roundtrip_bitfield() {
+        let message = Message::Bitfield(Bitfield::new(vec![0b1010_0000, 0]));
+
+        let encoded = message.encode();
+        let (remaining, decoded) = Message::decode(&encoded).unwrap();
+
+        assert_eq!(message, decoded);
+        assert_eq!(remaining.len(), 0);
+    }
+
+    #[test]
+    fn roundtrip_request() {
+        let message = Message::Request(Request::new(1, 2, 3));
+
+        let encoded = message.encode();
+        let (remaining, decoded) = Message::decode(&encoded).unwrap();
+
+        assert_eq!(message, decoded);
+        assert_eq!(remaining.len(), 0);
+    }
+
+    #[test]
+    fn roundtrip_piece() {
+        let message = Message::Piece(Piece::new(1, 2, vec![3, 4, 5]));
+
+        let encoded = message.encode();
+        let (remaining, decoded) = Message::decode(&encoded).unwrap();
+
+        assert_eq!(message, decoded);
+        assert_eq!(remaining.len(), 0);
+    }
+
+    #[test]
+    fn roundtrip_cancel() {
+        let message = Message::Cancel(Cancel::new(1, 2, 3));
+
+        let encoded = message.encode();
+        let (remaining, decoded) = Message::decode(&encoded).unwrap();
+
+        assert_eq!(message, decoded);
+        assert_eq!(remaining.len(), 0);
+    }
+
+    #[test]
+    fn roundtrip_port() {
+        let message = Message::Port(Port::new(6881));
+
+        let encoded = message.encode();
+        let (remaining, decoded) = Message::decode(&encoded).unwrap();
+
+        assert_eq!(message, decoded);
+        assert_eq!(remaining.len(), 0);
+    }
+
+    #[test]
+    fn roundtrip_extended() {
+        let message = Message::Extended(Extended::new(1, vec![2, 3]));
+
+        let encoded = message.encode();
+        let (remaining, decoded) = Message::decode(&encoded).unwrap();
+
+        assert_eq!(message, decoded);
+        assert_eq!(remaining.len(), 0);
+    }
+
+    #[test]
+    fn roundtrip_suggest_piece() {
+        let message = Message::SuggestPiece(SuggestPiece::new(1));
+
+        let encoded = message.encode();
+        let (remaining, decoded) = Message::decode(&encoded).unwrap();
+
+        assert_eq!(message, decoded);
+        assert_eq!(remaining.len(), 0);
+    }
+
+    #[test]
+    fn roundtrip_have_all() {
+        let message = Message::HaveAll(HaveAll);
+
+        let encoded = message.encode();
+        let (remaining, decoded) = Message::decode(&encoded).unwrap();
+
+        assert_eq!(message, decoded);
+        assert_eq!(remaining.len(), 0);
+    }
+
+    #[test]
+    fn roundtrip_have_none() {
+        let message = Message::HaveNone(HaveNone);
+
+        let encoded = message.encode();
+        let (remaining, decoded) = Message::decode(&encoded).unwrap();
+
+        assert_eq!(message, decoded);
+        assert_eq!(remaining.len(), 0);
+    }
+
+    #[test]
+    fn roundtrip_reject_request() {
+        let message = Message::RejectRequest(RejectRequest::new(1, 2, 3));
+
+        let encoded = message.encode();
+        let (remaining, decoded) = Message::decode(&encoded).unwrap();
+
+        assert_eq!(message, decoded);
+        assert_eq!(remaining.len(), 0);
+    }
+
+    #[test]
+    fn roundtrip_allowed_fast() {
+        let message = Message::AllowedFast(AllowedFast::new(1));
+
+        let encoded = message.encode();
+        let (remaining, decoded) = Message::decode(&encoded).unwrap();
+
+        assert_eq!(message, decoded);
+        assert_eq!(remaining.len(), 0);
+    }
+
     #[test]
     fn roundtrip_unknown() {
         let message = Message::Unknown(Unknown::new(23, vec![3, 4, 5]));
@@ -0,0 +1,39 @@
+use alloc::vec::Vec;
+
+use nom::bytes::streaming::tag;
+
+use crate::SansIo;
+
+const NOT_INTERESTED: [u8; 5] = [0, 0, 0, 1, 3];
+
+/// The not-interested message tells the peer that they don't have anything we want (anymore).
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct NotInterested;
+
+impl SansIo for NotInterested {
+    fn decode(i: &[u8]) -> nom::IResult<&[u8], Self> {
+        let (i, _) = tag(NOT_INTERESTED)(i)?;
+        Ok((i, Self))
+    }
+
+    fn encode(&self) -> Vec<u8> {
+        NOT_INTERESTED.to_vec()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn roundtrip() {
+        let not_interested = NotInterested;
+
+        let encoded = not_interested.encode();
+        let (remaining, decoded) = NotInterested::decode(&encoded).unwrap();
+
+        assert_eq!(not_interested, decoded);
+        assert_eq!(remaining.len(), 0);
+    }
+}
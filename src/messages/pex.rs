@@ -0,0 +1,118 @@
+use std::collections::BTreeMap;
+use std::net::SocketAddr;
+use std::time::Duration;
+
+use eyre::{Result, WrapErr};
+
+use crate::bencode::{self, BValue};
+use crate::messages::Extended;
+use crate::tracker::{compact_peers_v4, compact_peers_v6};
+
+/// Name of the peer exchange extension (BEP 11) in the extended handshake.
+pub const UT_PEX: &str = "ut_pex";
+/// The extended message id we ask peers to use when sending us peer exchange messages.
+pub const UT_PEX_ID: u8 = 2;
+/// A single message lists at most this many added peers, and this many dropped ones.
+pub const MAX_PEX_PEERS: usize = 50;
+/// Peer exchange messages may not be sent to a peer more often than this.
+pub const PEX_INTERVAL: Duration = Duration::from_secs(60);
+
+/// A message of the peer exchange extension, telling a peer which other peers of the torrent
+/// we connected to, and which ones we dropped, since the last one.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct Pex {
+    pub added: Vec<SocketAddr>,
+    pub dropped: Vec<SocketAddr>,
+}
+
+impl Pex {
+    /// Parse the payload of an [Extended] message of the peer exchange extension.
+    /// IPv4 and IPv6 peers are listed under separate keys, which are all optional.
+    pub fn from_payload(payload: &[u8]) -> Result<Self> {
+        let value = bencode::decode(payload).wrap_err("Invalid peer exchange message")?;
+        let peers = |v4: &[u8], v6: &[u8]| -> Result<Vec<SocketAddr>> {
+            let mut peers = Vec::new();
+            if let Some(bytes) = value.get(v4).and_then(BValue::as_bytes) {
+                peers.extend(compact_peers_v4(bytes)?);
+            }
+            if let Some(bytes) = value.get(v6).and_then(BValue::as_bytes) {
+                peers.extend(compact_peers_v6(bytes)?);
+            }
+            Ok(peers)
+        };
+        Ok(Self {
+            added: peers(b"added", b"added6")?,
+            dropped: peers(b"dropped", b"dropped6")?,
+        })
+    }
+
+    /// Wrap this in an [Extended] message, using the id the peer asked for in its
+    /// extended handshake.
+    #[must_use]
+    pub fn to_message(&self, id: u8) -> Extended {
+        let mut dict = BTreeMap::new();
+        for (v4, v6, peers) in [
+            ("added", "added6", &self.added),
+            ("dropped", "dropped6", &self.dropped),
+        ] {
+            let (mut compact_v4, mut compact_v6) = (Vec::new(), Vec::new());
+            for peer in peers {
+                match peer {
+                    SocketAddr::V4(peer) => {
+                        compact_v4.extend(peer.ip().octets());
+                        compact_v4.extend(peer.port().to_be_bytes());
+                    }
+                    SocketAddr::V6(peer) => {
+                        compact_v6.extend(peer.ip().octets());
+                        compact_v6.extend(peer.port().to_be_bytes());
+                    }
+                }
+            }
+            dict.insert(v4.as_bytes().to_vec(), BValue::Bytes(compact_v4));
+            dict.insert(v6.as_bytes().to_vec(), BValue::Bytes(compact_v6));
+        }
+        Extended::new(id, bencode::encode(&BValue::Dict(dict)))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn added_peers_are_parsed_from_compact_lists() {
+        let payload = b"d5:added12:\x0a\x00\x00\x01\x1a\xe1\xc0\xa8\x01\x02\x00\x507:dropped0:e";
+
+        let pex = Pex::from_payload(payload).unwrap();
+
+        assert_eq!(
+            pex.added,
+            [
+                SocketAddr::from(([10, 0, 0, 1], 6881)),
+                SocketAddr::from(([192, 168, 1, 2], 80))
+            ]
+        );
+        assert_eq!(pex.dropped, []);
+    }
+
+    #[test]
+    fn roundtrip() {
+        let pex = Pex {
+            added: vec![
+                SocketAddr::from(([10, 0, 0, 1], 6881)),
+                "[2001:db8::1]:51413".parse().unwrap(),
+            ],
+            dropped: vec![SocketAddr::from(([10, 0, 0, 2], 6882))],
+        };
+
+        let message = pex.to_message(5);
+
+        assert_eq!(message.id, 5);
+        assert_eq!(Pex::from_payload(&message.payload).unwrap(), pex);
+    }
+
+    #[test]
+    fn truncated_peers_are_rejected() {
+        let _ = Pex::from_payload(b"d5:added5:\x0a\x00\x00\x01\x1ae").unwrap_err();
+    }
+}
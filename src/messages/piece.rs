@@ -0,0 +1,86 @@
+use alloc::vec::Vec;
+use core::fmt;
+
+use nom::bytes::streaming::{tag, take};
+use nom::combinator::verify;
+use nom::number::streaming::be_u32;
+
+use crate::SansIo;
+
+const PIECE_ID: u8 = 7;
+/// The id, index and begin fields that come before the block data.
+const HEADER_LENGTH: u32 = 1 + 4 + 4;
+
+/// A single block of a piece, sent in response to a [Request](super::Request).
+#[derive(Clone, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct Piece {
+    pub index: u32,
+    pub begin: u32,
+    pub block: Vec<u8>,
+}
+
+impl Piece {
+    #[must_use]
+    pub fn new(index: u32, begin: u32, block: Vec<u8>) -> Self {
+        Self {
+            index,
+            begin,
+            block,
+        }
+    }
+}
+
+/// A whole block would flood the logs, so only its length is shown.
+impl fmt::Debug for Piece {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("Piece")
+            .field("index", &self.index)
+            .field("begin", &self.begin)
+            .field("block", &format_args!("{} bytes", self.block.len()))
+            .finish()
+    }
+}
+
+impl SansIo for Piece {
+    fn decode(i: &[u8]) -> nom::IResult<&[u8], Self> {
+        let (i, message_length) = verify(be_u32, |length| *length >= HEADER_LENGTH)(i)?;
+        let (i, _) = tag([PIECE_ID])(i)?;
+        let (i, index) = be_u32(i)?;
+        let (i, begin) = be_u32(i)?;
+        let (i, block) = take(message_length - HEADER_LENGTH)(i)?;
+        Ok((i, Self::new(index, begin, block.to_vec())))
+    }
+
+    fn encode(&self) -> Vec<u8> {
+        let mut buf = Vec::with_capacity(4 + HEADER_LENGTH as usize + self.block.len());
+        self.encode_into(&mut buf);
+        buf
+    }
+
+    fn encode_into(&self, buf: &mut Vec<u8>) {
+        // blocks are at most a few dozen KiB, so the cast is safe
+        #[allow(clippy::cast_possible_truncation)]
+        buf.extend((HEADER_LENGTH + self.block.len() as u32).to_be_bytes());
+        buf.push(PIECE_ID);
+        buf.extend(self.index.to_be_bytes());
+        buf.extend(self.begin.to_be_bytes());
+        buf.extend(&self.block);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn roundtrip() {
+        let piece = Piece::new(1, 16384, vec![1, 2, 3, 4]);
+
+        let encoded = piece.encode();
+        let (remaining, decoded) = Piece::decode(&encoded).unwrap();
+
+        assert_eq!(piece, decoded);
+        assert_eq!(remaining.len(), 0);
+    }
+}
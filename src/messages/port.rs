@@ -0,0 +1,53 @@
+use alloc::vec::Vec;
+
+use nom::bytes::streaming::tag;
+use nom::number::streaming::be_u16;
+
+use crate::SansIo;
+
+const PORT_PREFIX: [u8; 5] = [0, 0, 0, 3, 9];
+
+/// Sent by peers that support the DHT, announcing the port their DHT node listens on.
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct Port {
+    pub port: u16,
+}
+
+impl Port {
+    #[must_use]
+    pub fn new(port: u16) -> Self {
+        Self { port }
+    }
+}
+
+impl SansIo for Port {
+    fn decode(i: &[u8]) -> nom::IResult<&[u8], Self> {
+        let (i, _) = tag(PORT_PREFIX)(i)?;
+        let (i, port) = be_u16(i)?;
+        Ok((i, Self::new(port)))
+    }
+
+    fn encode(&self) -> Vec<u8> {
+        let mut buf = Vec::with_capacity(4 + 1 + 2);
+        buf.extend(PORT_PREFIX);
+        buf.extend(self.port.to_be_bytes());
+        buf
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn roundtrip() {
+        let port = Port::new(6881);
+
+        let encoded = port.encode();
+        let (remaining, decoded) = Port::decode(&encoded).unwrap();
+
+        assert_eq!(port, decoded);
+        assert_eq!(remaining.len(), 0);
+    }
+}
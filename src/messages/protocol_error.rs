@@ -0,0 +1,123 @@
+use alloc::boxed::Box;
+use alloc::string::String;
+use core::fmt::{Display, Formatter};
+use core::time::Duration;
+
+use crate::messages::Message;
+use crate::{InfoHash, PeerId};
+
+/// The ways in which a peer can break the protocol, so that callers can tell e.g. a peer for
+/// the wrong torrent apart from a connection that simply died.
+///
+/// Converts into an [eyre::Report] like any other error, and can be recovered from one
+/// with [eyre::Report::downcast_ref].
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum ProtocolError {
+    /// The peer's handshake was for a different torrent.
+    InfoHashMismatch {
+        /// The info hash of our torrent.
+        expected: InfoHash,
+        /// The info hash in the peer's handshake.
+        received: InfoHash,
+    },
+    /// The peer's handshake had a different peer ID than the one we expected to connect to.
+    PeerIdMismatch {
+        /// The peer ID we expected.
+        expected: PeerId,
+        /// The peer ID in the peer's handshake.
+        received: PeerId,
+    },
+    /// The peer's handshake had our own peer ID, so we connected to ourselves.
+    SelfConnection,
+    /// The peer sent a message that isn't allowed at this point of the connection,
+    /// like anything but a handshake as its first message.
+    UnexpectedMessage {
+        /// What the peer should have sent instead.
+        expected: &'static str,
+        /// The message that was received.
+        received: Box<Message>,
+    },
+    /// The peer didn't send a handshake in time.
+    HandshakeTimeout(Duration),
+    /// The peer wants to speak a protocol other than BitTorrent.
+    UnsupportedProtocol(String),
+    /// The peer announced a message longer than we're willing to receive.
+    Oversized {
+        /// The length in the message's length prefix.
+        length: u32,
+        /// The longest message we accept.
+        limit: u32,
+    },
+    /// The peer's bitfield doesn't match the number of pieces in the torrent, either by
+    /// length or by having spare bits set.
+    InvalidBitfield {
+        /// The length of the peer's bitfield, in bytes.
+        length: usize,
+        /// The number of pieces in the torrent.
+        piece_count: usize,
+    },
+    /// The peer claims to have a piece that the torrent doesn't have.
+    InvalidPieceIndex {
+        /// The index the peer sent.
+        index: u32,
+        /// The number of pieces in the torrent.
+        piece_count: usize,
+    },
+    /// The peer's handshake set reserved bits that don't stand for any extension we know
+    /// about, and we're strict about those.
+    UnknownReservedBits([u8; 8]),
+    /// The peer sent bytes that don't form a valid message.
+    Malformed(nom::error::ErrorKind),
+}
+
+impl Display for ProtocolError {
+    fn fmt(&self, f: &mut Formatter<'_>) -> core::fmt::Result {
+        match self {
+            ProtocolError::InfoHashMismatch { expected, received } => write!(
+                f,
+                "Peer sent an incorrect info hash {received}, expected {expected}"
+            ),
+            ProtocolError::PeerIdMismatch { expected, received } => write!(
+                f,
+                "Peer sent an incorrect peer ID {received}, expected {expected}"
+            ),
+            ProtocolError::SelfConnection => write!(f, "Connected to ourselves"),
+            ProtocolError::UnexpectedMessage { expected, received } => write!(
+                f,
+                "Expected {expected}, peer sent something else: {received:?}"
+            ),
+            ProtocolError::HandshakeTimeout(timeout) => {
+                write!(f, "Peer did not send a handshake within {timeout:?}")
+            }
+            ProtocolError::UnsupportedProtocol(protocol) => {
+                write!(f, "Unsupported protocol: {protocol:?}")
+            }
+            ProtocolError::Oversized { length, limit } => write!(
+                f,
+                "Peer sent a message of {length} bytes, more than the limit of {limit}"
+            ),
+            ProtocolError::InvalidBitfield {
+                length,
+                piece_count,
+            } => write!(
+                f,
+                "Peer sent a bitfield of {length} bytes, which doesn't fit {piece_count} pieces"
+            ),
+            ProtocolError::InvalidPieceIndex { index, piece_count } => write!(
+                f,
+                "Peer has piece {index}, but the torrent only has {piece_count} pieces"
+            ),
+            ProtocolError::UnknownReservedBits(bits) => write!(
+                f,
+                "Peer set reserved bits we don't know about: {}",
+                hex::encode(bits)
+            ),
+            ProtocolError::Malformed(kind) => {
+                write!(f, "Peer sent a malformed message: {}", kind.description())
+            }
+        }
+    }
+}
+
+#[cfg(feature = "std")]
+impl std::error::Error for ProtocolError {}
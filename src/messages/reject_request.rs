@@ -0,0 +1,82 @@
+use alloc::vec::Vec;
+
+use nom::bytes::streaming::tag;
+use nom::number::streaming::be_u32;
+
+use crate::messages::Request;
+use crate::SansIo;
+
+const REJECT_REQUEST_PREFIX: [u8; 5] = [0, 0, 0, 13, 16];
+
+/// Part of the Fast Extension, tells the peer that a [Request](super::Request) won't be
+/// answered. With the Fast Extension, choking no longer implicitly discards requests,
+/// so every request gets either a piece or a rejection.
+#[derive(Debug, Copy, Clone, PartialEq, Eq, Hash)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct RejectRequest {
+    pub index: u32,
+    pub begin: u32,
+    pub length: u32,
+}
+
+impl RejectRequest {
+    #[must_use]
+    pub fn new(index: u32, begin: u32, length: u32) -> Self {
+        Self {
+            index,
+            begin,
+            length,
+        }
+    }
+}
+
+impl From<Request> for RejectRequest {
+    fn from(request: Request) -> Self {
+        Self::new(request.index, request.begin, request.length)
+    }
+}
+
+impl From<RejectRequest> for Request {
+    fn from(reject: RejectRequest) -> Self {
+        Self::new(reject.index, reject.begin, reject.length)
+    }
+}
+
+impl SansIo for RejectRequest {
+    fn decode(i: &[u8]) -> nom::IResult<&[u8], Self> {
+        let (i, _) = tag(REJECT_REQUEST_PREFIX)(i)?;
+        let (i, index) = be_u32(i)?;
+        let (i, begin) = be_u32(i)?;
+        let (i, length) = be_u32(i)?;
+        Ok((i, Self::new(index, begin, length)))
+    }
+
+    fn encode(&self) -> Vec<u8> {
+        let mut buf = Vec::with_capacity(4 + 1 + 12);
+        self.encode_into(&mut buf);
+        buf
+    }
+
+    fn encode_into(&self, buf: &mut Vec<u8>) {
+        buf.extend(REJECT_REQUEST_PREFIX);
+        buf.extend(self.index.to_be_bytes());
+        buf.extend(self.begin.to_be_bytes());
+        buf.extend(self.length.to_be_bytes());
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn roundtrip() {
+        let reject = RejectRequest::new(1, 16384, 16384);
+
+        let encoded = reject.encode();
+        let (remaining, decoded) = RejectRequest::decode(&encoded).unwrap();
+
+        assert_eq!(reject, decoded);
+        assert_eq!(remaining.len(), 0);
+    }
+}
@@ -0,0 +1,78 @@
+use alloc::vec::Vec;
+
+use nom::bytes::streaming::tag;
+use nom::number::streaming::be_u32;
+
+use crate::messages::Piece;
+use crate::SansIo;
+
+const REQUEST_PREFIX: [u8; 5] = [0, 0, 0, 13, 6];
+
+/// A request for a single block of a piece. Blocks are usually 16 KiB, except for the last
+/// block of the last piece.
+#[derive(Debug, Copy, Clone, PartialEq, Eq, Hash)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct Request {
+    pub index: u32,
+    pub begin: u32,
+    pub length: u32,
+}
+
+impl Request {
+    #[must_use]
+    pub fn new(index: u32, begin: u32, length: u32) -> Self {
+        Self {
+            index,
+            begin,
+            length,
+        }
+    }
+}
+
+/// The request that the block answers.
+impl From<&Piece> for Request {
+    fn from(piece: &Piece) -> Self {
+        // bounded by the message length, which is a u32, so the cast is safe
+        #[allow(clippy::cast_possible_truncation)]
+        Self::new(piece.index, piece.begin, piece.block.len() as u32)
+    }
+}
+
+impl SansIo for Request {
+    fn decode(i: &[u8]) -> nom::IResult<&[u8], Self> {
+        let (i, _) = tag(REQUEST_PREFIX)(i)?;
+        let (i, index) = be_u32(i)?;
+        let (i, begin) = be_u32(i)?;
+        let (i, length) = be_u32(i)?;
+        Ok((i, Self::new(index, begin, length)))
+    }
+
+    fn encode(&self) -> Vec<u8> {
+        let mut buf = Vec::with_capacity(4 + 1 + 12);
+        self.encode_into(&mut buf);
+        buf
+    }
+
+    fn encode_into(&self, buf: &mut Vec<u8>) {
+        buf.extend(REQUEST_PREFIX);
+        buf.extend(self.index.to_be_bytes());
+        buf.extend(self.begin.to_be_bytes());
+        buf.extend(self.length.to_be_bytes());
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn roundtrip() {
+        let request = Request::new(1, 16384, 16384);
+
+        let encoded = request.encode();
+        let (remaining, decoded) = Request::decode(&encoded).unwrap();
+
+        assert_eq!(request, decoded);
+        assert_eq!(remaining.len(), 0);
+    }
+}
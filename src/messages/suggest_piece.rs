@@ -0,0 +1,54 @@
+use alloc::vec::Vec;
+
+use nom::bytes::streaming::tag;
+use nom::number::streaming::be_u32;
+
+use crate::SansIo;
+
+const SUGGEST_PIECE_PREFIX: [u8; 5] = [0, 0, 0, 5, 13];
+
+/// Part of the Fast Extension, a hint that the peer would like us to download a piece,
+/// usually because it's cheap for them to serve (e.g. it's already in their cache).
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct SuggestPiece {
+    pub index: u32,
+}
+
+impl SuggestPiece {
+    #[must_use]
+    pub fn new(index: u32) -> Self {
+        Self { index }
+    }
+}
+
+impl SansIo for SuggestPiece {
+    fn decode(i: &[u8]) -> nom::IResult<&[u8], Self> {
+        let (i, _) = tag(SUGGEST_PIECE_PREFIX)(i)?;
+        let (i, index) = be_u32(i)?;
+        Ok((i, Self::new(index)))
+    }
+
+    fn encode(&self) -> Vec<u8> {
+        let mut buf = Vec::with_capacity(4 + 1 + 4);
+        buf.extend(SUGGEST_PIECE_PREFIX);
+        buf.extend(self.index.to_be_bytes());
+        buf
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn roundtrip() {
+        let suggest_piece = SuggestPiece::new(7);
+
+        let encoded = suggest_piece.encode();
+        let (remaining, decoded) = SuggestPiece::decode(&encoded).unwrap();
+
+        assert_eq!(suggest_piece, decoded);
+        assert_eq!(remaining.len(), 0);
+    }
+}
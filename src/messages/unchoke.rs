@@ -0,0 +1,40 @@
+use alloc::vec::Vec;
+
+use nom::bytes::streaming::tag;
+
+use crate::SansIo;
+
+const UNCHOKE: [u8; 5] = [0, 0, 0, 1, 1];
+
+/// The unchoke message tells the peer that we're willing to answer their requests.
+/// It doesn't contain any information besides its id.
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct Unchoke;
+
+impl SansIo for Unchoke {
+    fn decode(i: &[u8]) -> nom::IResult<&[u8], Self> {
+        let (i, _) = tag(UNCHOKE)(i)?;
+        Ok((i, Self))
+    }
+
+    fn encode(&self) -> Vec<u8> {
+        UNCHOKE.to_vec()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn roundtrip() {
+        let unchoke = Unchoke;
+
+        let encoded = unchoke.encode();
+        let (remaining, decoded) = Unchoke::decode(&encoded).unwrap();
+
+        assert_eq!(unchoke, decoded);
+        assert_eq!(remaining.len(), 0);
+    }
+}
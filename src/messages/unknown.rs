@@ -1,14 +1,23 @@
-use nom::combinator::map_res;
-use nom::error::Error;
+use alloc::vec::Vec;
+use core::fmt;
+
+use nom::combinator::verify;
 use nom::multi::count;
 use nom::number::streaming::be_u32;
 
+use crate::messages::KNOWN_MESSAGE_IDS;
 use crate::sans_io::SansIo;
 
+/// No sensible messages should be this long, so anything longer is rejected.
+pub const MAX_MESSAGE_LENGTH: u32 = 1024 * 1024;
+
 /// This message type will catch any unimplemented message types, as the BitTorrent protocol
 /// specifies that all non-handshake messages have the same format, and that format also
 /// includes the message length.
-#[derive(Debug, Clone, PartialEq, Eq)]
+///
+/// Messages we do implement are never decoded as unknown, see [KNOWN_MESSAGE_IDS].
+#[derive(Clone, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
 pub struct Unknown {
     pub id: u8,
     pub bytes: Vec<u8>,
@@ -21,32 +30,40 @@ impl Unknown {
     }
 }
 
+/// We don't know what the bytes mean anyway, so only their length is shown.
+impl fmt::Debug for Unknown {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("Unknown")
+            .field("id", &self.id)
+            .field("bytes", &format_args!("{} bytes", self.bytes.len()))
+            .finish()
+    }
+}
+
 impl SansIo for Unknown {
     fn decode(i: &[u8]) -> nom::IResult<&[u8], Self> {
-        // no sensible messages should be longer than 1MB
-        let (i, message_length) = map_res(be_u32, |length| {
-            if length < 1024 * 1024 {
-                Ok(length)
-            } else {
-                Err(nom::Err::Error(Error::new(
-                    i,
-                    nom::error::ErrorKind::TooLarge,
-                )))
-            }
+        // The length includes the id, so it's at least 1. Empty messages are keep-alives.
+        let (i, message_length) =
+            verify(be_u32, |length| (1..MAX_MESSAGE_LENGTH).contains(length))(i)?;
+        let (i, id) = verify(nom::number::streaming::u8, |id| {
+            !KNOWN_MESSAGE_IDS.contains(id)
         })(i)?;
-        let (i, id) = nom::number::streaming::u8(i)?;
         let (i, bytes) = count(nom::number::streaming::u8, (message_length - 1) as usize)(i)?;
         Ok((i, Self::new(id, bytes)))
     }
 
     fn encode(&self) -> Vec<u8> {
         let mut buf = Vec::with_capacity(4 + 1 + self.bytes.len());
+        self.encode_into(&mut buf);
+        buf
+    }
+
+    fn encode_into(&self, buf: &mut Vec<u8>) {
         // the max length of the byte array is measured with a u32, so the cast is safe
         #[allow(clippy::cast_possible_truncation)]
         buf.extend(((1 + self.bytes.len()) as u32).to_be_bytes());
         buf.push(self.id);
         buf.extend(&self.bytes);
-        buf
     }
 }
 
@@ -64,4 +81,11 @@ mod tests {
         assert_eq!(unknown, decoded);
         assert_eq!(remaining.len(), 0);
     }
+
+    #[test]
+    fn known_ids_are_refused() {
+        let encoded = Unknown::new(6, vec![0; 12]).encode();
+
+        assert!(Unknown::decode(&encoded).is_err());
+    }
 }
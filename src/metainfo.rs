@@ -0,0 +1,323 @@
+use std::collections::BTreeMap;
+
+use eyre::{bail, eyre, OptionExt, Result, WrapErr};
+
+use crate::bencode::{self, BValue};
+use crate::sha1::sha1;
+use crate::{InfoHash, SansIo};
+
+/// The `info` dictionary of a torrent, describing its contents. This is what the info hash is
+/// the hash of, and what gets exchanged with peers when starting from a magnet link.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Info {
+    /// Suggested name of the file, or of the directory in a multi-file torrent.
+    pub name: String,
+    /// Length of every piece, except possibly the last one.
+    pub piece_length: u32,
+    /// Total length of the torrent's contents, summed over all files.
+    pub length: u64,
+    /// SHA-1 hash of each piece.
+    pub pieces: Vec<[u8; 20]>,
+    /// The files of a multi-file torrent, in the order their contents are laid out in the
+    /// pieces. Empty for a single-file torrent.
+    pub files: Vec<FileEntry>,
+}
+
+/// A file of a multi-file torrent.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct FileEntry {
+    /// Where the file goes, as directories and a file name within the torrent's directory.
+    pub path: Vec<String>,
+    /// Length of the file in bytes.
+    pub length: u64,
+    /// Whether this is a padding file (BEP 47), which only aligns the next file to a piece
+    /// boundary. Its contents are all zeroes, and it's never written to disk.
+    pub padding: bool,
+}
+
+impl Info {
+    /// Parse a bencoded info dictionary.
+    pub fn from_bytes(bytes: &[u8]) -> Result<Self> {
+        let value = bencode::decode(bytes).wrap_err("Invalid info dictionary")?;
+
+        let name = value
+            .get(b"name")
+            .and_then(BValue::as_bytes)
+            .ok_or_eyre("Info dictionary is missing 'name'")?;
+        let name = String::from_utf8_lossy(name).into_owned();
+        check_path_component(&name).wrap_err("Invalid torrent name")?;
+
+        let piece_length = value
+            .get(b"piece length")
+            .and_then(BValue::as_integer)
+            .ok_or_eyre("Info dictionary is missing 'piece length'")?;
+        let piece_length = u32::try_from(piece_length)
+            .ok()
+            .filter(|length| *length > 0)
+            .ok_or_else(|| eyre!("Invalid piece length {piece_length}"))?;
+
+        let (length, files) = match (value.get(b"length"), value.get(b"files")) {
+            (Some(length), None) => (file_length(length)?, Vec::new()),
+            (None, Some(BValue::List(files))) => {
+                let files = files.iter().map(file_entry).collect::<Result<Vec<_>>>()?;
+                (files.iter().map(|file| file.length).sum(), files)
+            }
+            _ => bail!("Info dictionary must have exactly one of 'length' or 'files'"),
+        };
+
+        let pieces = value
+            .get(b"pieces")
+            .and_then(BValue::as_bytes)
+            .ok_or_eyre("Info dictionary is missing 'pieces'")?;
+        if pieces.len() % 20 != 0 {
+            bail!("Length of 'pieces' is not a multiple of 20");
+        }
+        let pieces: Vec<[u8; 20]> = pieces
+            .chunks_exact(20)
+            .map(|hash| hash.try_into().expect("chunks to be 20 bytes"))
+            .collect();
+        if pieces.len() as u64 != length.div_ceil(u64::from(piece_length)) {
+            bail!("Number of piece hashes doesn't match the torrent's length");
+        }
+
+        Ok(Self {
+            name,
+            piece_length,
+            length,
+            pieces,
+            files,
+        })
+    }
+
+    /// Describe a single file called `name`, split into pieces of `piece_length` bytes,
+    /// e.g. to start seeding something that doesn't have a torrent yet.
+    ///
+    /// # Panics
+    ///
+    /// If `piece_length` is zero.
+    #[must_use]
+    pub fn from_content(name: &str, piece_length: u32, content: &[u8]) -> Self {
+        Self {
+            name: name.to_string(),
+            piece_length,
+            length: content.len() as u64,
+            pieces: content.chunks(piece_length as usize).map(sha1).collect(),
+            files: Vec::new(),
+        }
+    }
+
+    /// Bencode the info dictionary.
+    #[must_use]
+    pub fn to_bytes(&self) -> Vec<u8> {
+        let length = |length: u64| {
+            BValue::Integer(i64::try_from(length).expect("torrent to be smaller than 8 EiB"))
+        };
+        let contents = if self.files.is_empty() {
+            (b"length".to_vec(), length(self.length))
+        } else {
+            let files = self.files.iter().map(|file| {
+                let mut dict = BTreeMap::from([
+                    (b"length".to_vec(), length(file.length)),
+                    (
+                        b"path".to_vec(),
+                        BValue::List(
+                            file.path
+                                .iter()
+                                .map(|part| BValue::Bytes(part.as_bytes().to_vec()))
+                                .collect(),
+                        ),
+                    ),
+                ]);
+                if file.padding {
+                    dict.insert(b"attr".to_vec(), BValue::Bytes(b"p".to_vec()));
+                }
+                BValue::Dict(dict)
+            });
+            (b"files".to_vec(), BValue::List(files.collect()))
+        };
+        BValue::Dict(BTreeMap::from([
+            contents,
+            (
+                b"name".to_vec(),
+                BValue::Bytes(self.name.as_bytes().to_vec()),
+            ),
+            (
+                b"piece length".to_vec(),
+                BValue::Integer(i64::from(self.piece_length)),
+            ),
+            (b"pieces".to_vec(), BValue::Bytes(self.pieces.concat())),
+        ]))
+        .encode()
+    }
+
+    /// The info hash of the dictionary as encoded by [Info::to_bytes].
+    #[must_use]
+    pub fn info_hash(&self) -> InfoHash {
+        info_hash(&self.to_bytes())
+    }
+
+    /// How long the piece with this index is, which is shorter than the rest for the last one.
+    #[must_use]
+    pub fn piece_size(&self, index: u32) -> u32 {
+        let begin = u64::from(index) * u64::from(self.piece_length);
+        // bounded by piece_length, so the cast is safe
+        #[allow(clippy::cast_possible_truncation)]
+        let size = self
+            .length
+            .saturating_sub(begin)
+            .min(u64::from(self.piece_length)) as u32;
+        size
+    }
+
+    /// Whether `piece` is the piece with this index, going by its SHA-1 hash.
+    #[must_use]
+    pub fn verify_piece(&self, index: u32, piece: &[u8]) -> bool {
+        self.pieces.get(index as usize) == Some(&sha1(piece))
+    }
+}
+
+fn file_entry(file: &BValue) -> Result<FileEntry> {
+    let length = file_length(file.get(b"length").ok_or_eyre("File is missing 'length'")?)?;
+    let path = file
+        .get(b"path")
+        .and_then(BValue::as_list)
+        .ok_or_eyre("File is missing 'path'")?
+        .iter()
+        .map(|part| {
+            let part = part
+                .as_bytes()
+                .ok_or_eyre("File path is not a list of strings")?;
+            let part = String::from_utf8_lossy(part).into_owned();
+            check_path_component(&part)?;
+            Ok(part)
+        })
+        .collect::<Result<Vec<_>>>()?;
+    if path.is_empty() {
+        bail!("File path is empty");
+    }
+    let padding = file
+        .get(b"attr")
+        .and_then(BValue::as_bytes)
+        .is_some_and(|attr| attr.contains(&b'p'));
+    Ok(FileEntry {
+        path,
+        length,
+        padding,
+    })
+}
+
+/// Refuse anything that could point outside the directory a torrent is stored in, if used as
+/// the name of a file or directory in it.
+pub(crate) fn check_path_component(part: &str) -> Result<()> {
+    if part.is_empty() || part == "." || part == ".." || part.contains(['/', '\\']) {
+        bail!("Invalid file path component {part:?}");
+    }
+    Ok(())
+}
+
+fn file_length(value: &BValue) -> Result<u64> {
+    let length = value
+        .as_integer()
+        .ok_or_eyre("File length is not an integer")?;
+    u64::try_from(length).map_err(|_| eyre!("Invalid file length {length}"))
+}
+
+/// The info hash of a bencoded info dictionary.
+#[must_use]
+pub fn info_hash(bytes: &[u8]) -> InfoHash {
+    InfoHash::new(sha1(bytes))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parse_single_file() {
+        let mut bytes = b"d6:lengthi20e4:name4:test12:piece lengthi16e6:pieces40:".to_vec();
+        bytes.extend([1; 20]);
+        bytes.extend([2; 20]);
+        bytes.push(b'e');
+
+        let info = Info::from_bytes(&bytes).unwrap();
+
+        assert_eq!(
+            info,
+            Info {
+                name: "test".to_string(),
+                piece_length: 16,
+                length: 20,
+                pieces: vec![[1; 20], [2; 20]],
+                files: Vec::new(),
+            }
+        );
+    }
+
+    #[test]
+    fn parse_multi_file() {
+        let mut bytes = b"d5:filesld6:lengthi5e4:pathl1:aeed6:lengthi7e4:pathl1:beee\
+            4:name3:dir12:piece lengthi16e6:pieces20:"
+            .to_vec();
+        bytes.extend([1; 20]);
+        bytes.push(b'e');
+
+        let info = Info::from_bytes(&bytes).unwrap();
+
+        assert_eq!(info.length, 12);
+        assert_eq!(
+            info.files,
+            [
+                FileEntry {
+                    path: vec!["a".to_string()],
+                    length: 5,
+                    padding: false,
+                },
+                FileEntry {
+                    path: vec!["b".to_string()],
+                    length: 7,
+                    padding: false,
+                },
+            ]
+        );
+        assert_eq!(Info::from_bytes(&info.to_bytes()).unwrap(), info);
+    }
+
+    #[test]
+    fn paths_outside_the_torrent_are_rejected() {
+        for path in ["2:..", "0:", "4:a/..", ""] {
+            let mut bytes = format!(
+                "d5:filesld6:lengthi5e4:pathl{path}eee4:name3:dir12:piece lengthi16e6:pieces20:"
+            )
+            .into_bytes();
+            bytes.extend([1; 20]);
+            bytes.push(b'e');
+
+            let _ = Info::from_bytes(&bytes).unwrap_err();
+        }
+    }
+
+    #[test]
+    fn names_outside_the_download_dir_are_rejected() {
+        for name in ["2:..", "0:", "4:../x", "6:/etc/x", "4:a\\b"] {
+            let mut bytes =
+                format!("d6:lengthi5e4:name{name}12:piece lengthi16e6:pieces20:").into_bytes();
+            bytes.extend([1; 20]);
+            bytes.push(b'e');
+
+            let _ = Info::from_bytes(&bytes).unwrap_err();
+        }
+    }
+
+    #[test]
+    fn content_roundtrips_through_bytes() {
+        let content: Vec<u8> = (0..40).collect();
+
+        let info = Info::from_content("test", 16, &content);
+
+        assert_eq!(Info::from_bytes(&info.to_bytes()).unwrap(), info);
+        assert_eq!(info.pieces.len(), 3);
+        assert_eq!(info.piece_size(2), 8);
+        assert!(info.verify_piece(2, &content[32..]));
+        assert!(!info.verify_piece(1, &content[32..]));
+    }
+}
@@ -0,0 +1,74 @@
+use std::fmt::Debug;
+
+/// Where a torrent reports what it's doing, as counters and gauges, e.g. to export them to
+/// Prometheus or statsd. Give one to [Torrent::set_metrics_sink](crate::Torrent::set_metrics_sink).
+///
+/// The counters are:
+/// - `handshake_completed`: a peer sent a valid handshake.
+/// - `handshake_rejected`: a peer's handshake was for another torrent, or from ourselves.
+/// - `peer_disconnected`: a connection closed after its handshake completed.
+/// - `pieces_completed`: a piece was downloaded and passed its hash check.
+/// - `bytes_downloaded` and `bytes_uploaded`: block data received from and sent to peers.
+///
+/// The only gauge is `connections`, the number of connected peers.
+pub trait MetricsSink: Debug + Send + Sync + 'static {
+    /// Add `value` to the counter `name`.
+    fn incr(&self, name: &str, value: u64);
+
+    /// Set the gauge `name` to `value`.
+    fn gauge(&self, name: &str, value: f64);
+}
+
+/// The [MetricsSink] used until another one is set, which throws everything away.
+#[derive(Debug, Default, Clone, Copy)]
+pub struct NoMetrics;
+
+impl MetricsSink for NoMetrics {
+    fn incr(&self, _name: &str, _value: u64) {}
+
+    fn gauge(&self, _name: &str, _value: f64) {}
+}
+
+#[cfg(test)]
+pub use recording::RecordingMetrics;
+
+#[cfg(test)]
+mod recording {
+    use std::collections::HashMap;
+    use std::sync::{Arc, Mutex};
+
+    use super::MetricsSink;
+
+    /// A [MetricsSink] that remembers everything it's told. Clones share the same metrics.
+    #[derive(Debug, Default, Clone)]
+    pub struct RecordingMetrics {
+        counters: Arc<Mutex<HashMap<String, u64>>>,
+        gauges: Arc<Mutex<HashMap<String, f64>>>,
+    }
+
+    impl RecordingMetrics {
+        /// The sum of everything added to the counter `name`.
+        pub fn counter(&self, name: &str) -> u64 {
+            let counters = self.counters.lock().expect("mutex to not be poisoned");
+            counters.get(name).copied().unwrap_or(0)
+        }
+
+        /// The last value the gauge `name` was set to.
+        pub fn gauge_value(&self, name: &str) -> Option<f64> {
+            let gauges = self.gauges.lock().expect("mutex to not be poisoned");
+            gauges.get(name).copied()
+        }
+    }
+
+    impl MetricsSink for RecordingMetrics {
+        fn incr(&self, name: &str, value: u64) {
+            let mut counters = self.counters.lock().expect("mutex to not be poisoned");
+            *counters.entry(name.to_string()).or_default() += value;
+        }
+
+        fn gauge(&self, name: &str, value: f64) {
+            let mut gauges = self.gauges.lock().expect("mutex to not be poisoned");
+            gauges.insert(name.to_string(), value);
+        }
+    }
+}
@@ -0,0 +1,88 @@
+use std::fmt::Debug;
+use std::net::SocketAddr;
+
+use crate::PeerId;
+
+/// How much an [EventRecord] matters, like the levels of most loggers.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub enum EventLevel {
+    /// Details that are only interesting when something's wrong.
+    Debug,
+    /// Business as usual.
+    Info,
+    /// Something went wrong, but the torrent carries on.
+    Warn,
+    /// Something went wrong that the torrent can't recover from by itself.
+    Error,
+}
+
+/// Something that happened to a torrent, as told to an [EventObserver].
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct EventRecord {
+    /// How much it matters.
+    pub level: EventLevel,
+    /// What happened, one of the events listed on [EventObserver].
+    pub event: &'static str,
+    /// The peer it happened with, if it's about a connection and the peer is known.
+    pub peer_id: Option<PeerId>,
+    /// The address of that peer, if it's known.
+    pub peer_addr: Option<SocketAddr>,
+    /// A human readable description, the same that's logged with the `tracing` feature.
+    pub message: String,
+}
+
+/// Where a torrent reports the important things that happen to it, for applications that
+/// log in their own way rather than through `tracing`. Give one to
+/// [Torrent::set_event_observer](crate::Torrent::set_event_observer).
+///
+/// The events are:
+/// - `handshake_completed`: a peer sent a valid handshake, and is now connected.
+/// - `handshake_rejected`: a peer's handshake was for another torrent, or from ourselves.
+/// - `peer_disconnected`: a connection closed after its handshake completed.
+/// - `piece_completed`: a piece was downloaded and passed its hash check.
+/// - `piece_failed`: a piece was downloaded but failed its hash check.
+pub trait EventObserver: Debug + Send + Sync + 'static {
+    /// Called on whichever actor thread the event happened on, so don't block for long.
+    fn observe(&self, record: &EventRecord);
+}
+
+/// The [EventObserver] used until another one is set, which ignores everything.
+#[derive(Debug, Default, Clone, Copy)]
+pub struct NoObserver;
+
+impl EventObserver for NoObserver {
+    fn observe(&self, _record: &EventRecord) {}
+}
+
+#[cfg(test)]
+pub use recording::RecordingObserver;
+
+#[cfg(test)]
+mod recording {
+    use std::sync::{Arc, Mutex};
+
+    use super::{EventObserver, EventRecord};
+
+    /// An [EventObserver] that remembers every record. Clones share the same records.
+    #[derive(Debug, Default, Clone)]
+    pub struct RecordingObserver {
+        records: Arc<Mutex<Vec<EventRecord>>>,
+    }
+
+    impl RecordingObserver {
+        /// Every record observed so far, oldest first.
+        pub fn records(&self) -> Vec<EventRecord> {
+            self.records
+                .lock()
+                .expect("mutex to not be poisoned")
+                .clone()
+        }
+    }
+
+    impl EventObserver for RecordingObserver {
+        fn observe(&self, record: &EventRecord) {
+            let mut records = self.records.lock().expect("mutex to not be poisoned");
+            records.push(record.clone());
+        }
+    }
+}
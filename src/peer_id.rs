@@ -1,87 +1,142 @@
-use std::fmt::{Debug, Display, Formatter};
+use alloc::format;
+use alloc::string::String;
+use alloc::vec::Vec;
+use core::array::TryFromSliceError;
+use core::fmt::{Debug, Display, Formatter};
 
-use base58::ToBase58;
-use eyre::{bail, eyre, Result};
+#[cfg(feature = "std")]
+use eyre::{eyre, Result};
 use nom::bytes::streaming::take;
 use nom::combinator::map_res;
+#[cfg(feature = "std")]
 use rand::Rng;
 
-use crate::SansIo;
+use crate::{ClientInfo, SansIo};
 
 /// A 20 byte hash of a torrent, technically _any_ bytes but usually implemented as:
 /// -XY1234-\<random characters\>
 ///
 /// where XY is an application-specific identifier, 1234 is a version number, and the random
 /// characters are a unique identifier for the peer.
-#[derive(Clone, Copy, PartialEq, Eq, Hash)]
+#[derive(Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash)]
 pub struct PeerId([u8; 20]);
 
 impl PeerId {
     /// Create a fixed peer ID from a byte array.
     #[must_use]
-    pub fn new(hash: [u8; 20]) -> Self {
+    pub const fn new(hash: [u8; 20]) -> Self {
         Self(hash)
     }
 
+    /// The raw bytes, borrowed.
+    #[must_use]
+    pub const fn as_bytes(&self) -> &[u8; 20] {
+        &self.0
+    }
+
     /// Create a random peer ID using a supplied identifier and version number.
     ///
-    /// This makes a few assumptions about the version number:
-    /// - Major fits in a single base58 character (0-57)
-    /// - Minor fits in two base58 characters (0-3363)
-    /// - Patch fits in a single base58 character (0-57)
+    /// Each version component is written as base58 digits, using as many characters as it
+    /// needs, but at least:
+    /// - one for major (so 0-57 take one character)
+    /// - two for minor (so 0-3363 take two characters)
+    /// - one for patch (so 0-57 take one character)
+    ///
+    /// For versions within those ranges this is the usual `-XY1234-` format. Larger versions
+    /// push the closing `-` further back, taking up space from the random characters, so that
+    /// any version fits (at the cost of some clients not recognizing the format).
+    #[cfg(feature = "std")]
     pub fn random(identifier: &[u8; 2], major: u8, minor: u16, patch: u8) -> Result<Self> {
+        Self::random_with_rng(&mut rand::thread_rng(), identifier, major, minor, patch)
+    }
+
+    /// Create a peer ID like [PeerId::random], with the random characters drawn from `rng`.
+    /// A seeded `rng` gives the same peer ID every time.
+    #[cfg(feature = "std")]
+    pub fn random_with_rng(
+        rng: &mut impl Rng,
+        identifier: &[u8; 2],
+        major: u8,
+        minor: u16,
+        patch: u8,
+    ) -> Result<Self> {
         let mut hash = Vec::with_capacity(20);
         hash.push(b'-');
         hash.extend_from_slice(identifier);
-
-        let major_str = [major].to_base58();
-        let major_bytes = major_str.as_bytes();
-        if major_bytes.len() != 1 {
-            bail!("Couldn't parse major version {major} as a single base58 character (was: \"{major_str}\")");
-        }
-        hash.push(major_bytes[0]);
-
-        let minor_str = [
-            [u8::try_from(minor / 58)?].to_base58(),
-            [(minor % 58) as u8].to_base58(),
-        ];
-        if minor_str[0].len() != 1 || minor_str[1].len() != 1 {
-            bail!(
-                "Couldn't parse minor version {minor} as two base58 characters (was: \"{}\")",
-                minor_str.join("")
-            );
-        }
-        let minor_bytes = [minor_str[0].as_bytes()[0], minor_str[1].as_bytes()[0]];
-        hash.extend_from_slice(&minor_bytes);
-
-        let patch_str = [patch].to_base58();
-        let patch_bytes = patch_str.as_bytes();
-        if patch_bytes.len() != 1 {
-            bail!("Couldn't parse patch version {patch} as a single base58 character (was: \"{patch_str}\")");
-        }
-        hash.push(patch_bytes[0]);
-
+        hash.extend(base58_digits(major.into(), 1));
+        hash.extend(base58_digits(minor.into(), 2));
+        hash.extend(base58_digits(patch.into(), 1));
         hash.push(b'-');
 
-        let mut rng = rand::thread_rng();
         // Using base58 encoding for random bytes is certainly a choice,
         // but I just like base58. Compact but readable.
-        let random_bytes = random_base58_bytes(&mut rng, 12);
+        let random_bytes = random_base58_bytes(rng, 20 - hash.len());
         hash.extend_from_slice(&random_bytes);
         let hash = hash.try_into().map_err(|_| {
             eyre!("Hash should always work out to 20 bytes, this is a bug in PeerId.")
         })?;
         Ok(Self(hash))
     }
+
+    /// Decode the `(major, minor, patch)` version written by [PeerId::random], if this peer ID
+    /// has its `-XY1234-` layout.
+    ///
+    /// Versions that didn't fit in four characters can't be decoded, as there's no telling
+    /// which of the components took up the extra characters.
+    #[must_use]
+    pub fn version(&self) -> Option<(u8, u16, u8)> {
+        let [b'-', _, _, major, minor @ .., patch, b'-'] = &self.0[..8] else {
+            return None;
+        };
+        let digit = |byte: &u8| ALPHABET.iter().position(|digit| digit == byte);
+        let major = u8::try_from(digit(major)?).ok()?;
+        let minor = minor
+            .iter()
+            .try_fold(0, |minor, byte| Some(minor * 58 + digit(byte)?))?;
+        let patch = u8::try_from(digit(patch)?).ok()?;
+        Some((major, u16::try_from(minor).ok()?, patch))
+    }
+
+    /// Decode which client the peer is running, if the peer ID has the usual
+    /// `-XY1234-` format.
+    #[must_use]
+    pub fn client_info(&self) -> Option<ClientInfo> {
+        ClientInfo::from_peer_id(&self.0)
+    }
+
+    /// Whether the peer ID starts with the usual `-XY1234-`, i.e. a dash, two letters or digits
+    /// for the client, four for the version, and another dash.
+    ///
+    /// Peer IDs from [PeerId::random] are, unless the version was too large to fit.
+    #[must_use]
+    pub fn is_well_formed(&self) -> bool {
+        self.client_info().is_some()
+    }
 }
 
 const ALPHABET: &[u8] = b"123456789ABCDEFGHJKLMNPQRSTUVWXYZabcdefghijkmnopqrstuvwxyz";
 
+/// Write `value` as base58 digits (most significant first), padded to at least `min_width`.
+#[cfg(feature = "std")]
+fn base58_digits(mut value: u32, min_width: usize) -> Vec<u8> {
+    let base = ALPHABET.len() as u32;
+    let mut digits = Vec::new();
+    while value > 0 || digits.len() < min_width {
+        digits.push(ALPHABET[(value % base) as usize]);
+        value /= base;
+    }
+    digits.reverse();
+    digits
+}
+
+#[cfg(feature = "std")]
 fn random_base58_bytes(rng: &mut impl Rng, length: usize) -> Vec<u8> {
-    let dist = rand::distributions::Uniform::new(0, ALPHABET.len());
+    // Sampled as bytes rather than usizes, so a seeded RNG gives the same ID on any platform.
+    let alphabet_len = u8::try_from(ALPHABET.len()).expect("alphabet to fit in a byte");
+    let dist = rand::distributions::Uniform::new(0, alphabet_len);
     rng.sample_iter(dist)
         .take(length)
-        .map(|index| ALPHABET[index])
+        .map(|index| ALPHABET[usize::from(index)])
         .collect()
 }
 
@@ -94,20 +149,101 @@ impl SansIo for PeerId {
     fn encode(&self) -> Vec<u8> {
         self.0.to_vec()
     }
+
+    fn encode_into(&self, buf: &mut Vec<u8>) {
+        buf.extend(self.0);
+    }
+}
+
+impl PeerId {
+    /// Most torrent clients assume the peer ID is a string, so we'll display it as one,
+    /// unless that would be unreadable. Then it's shown as hex, keeping the client prefix
+    /// if there is one.
+    fn readable(&self) -> String {
+        if self.0.iter().all(u8::is_ascii_graphic) {
+            String::from_utf8_lossy(&self.0).into_owned()
+        } else if self.client_info().is_some() {
+            format!(
+                "{}{}",
+                String::from_utf8_lossy(&self.0[..8]),
+                hex::encode(&self.0[8..])
+            )
+        } else {
+            hex::encode(self.0)
+        }
+    }
+
+    /// The inverse of [PeerId::readable], telling the forms apart by their length.
+    #[cfg(feature = "serde")]
+    fn from_readable(readable: &str) -> Option<Self> {
+        let mut hash = [0; 20];
+        match readable.len() {
+            20 if readable.bytes().all(|byte| byte.is_ascii_graphic()) => {
+                hash.copy_from_slice(readable.as_bytes());
+            }
+            32 => {
+                let (prefix, rest) = readable.split_at_checked(8)?;
+                hash[..8].copy_from_slice(prefix.as_bytes());
+                hex::decode_to_slice(rest, &mut hash[8..]).ok()?;
+                ClientInfo::from_peer_id(&hash)?;
+            }
+            40 => hex::decode_to_slice(readable, &mut hash).ok()?,
+            _ => return None,
+        }
+        Some(Self(hash))
+    }
+}
+
+/// Serialized as its [Display] form, e.g. `-qB4550-HahW9F2VDDzU`, since that's what people
+/// recognize peer IDs by.
+#[cfg(feature = "serde")]
+impl serde::Serialize for PeerId {
+    fn serialize<S: serde::Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.collect_str(self)
+    }
+}
+
+#[cfg(feature = "serde")]
+impl<'de> serde::Deserialize<'de> for PeerId {
+    fn deserialize<D: serde::Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        let readable = String::deserialize(deserializer)?;
+        Self::from_readable(&readable).ok_or_else(|| {
+            serde::de::Error::invalid_value(
+                serde::de::Unexpected::Str(&readable),
+                &"a 20 character peer ID, or its hex form",
+            )
+        })
+    }
 }
 
 impl Display for PeerId {
-    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
-        // Most torrent clients assume the peer ID is a string, so we'll do the same.
-        // Even if it displays poorly for some clients, most users won't even see this.
-        write!(f, "{}", String::from_utf8_lossy(&self.0))
+    fn fmt(&self, f: &mut Formatter<'_>) -> core::fmt::Result {
+        write!(f, "{}", self.readable())
     }
 }
 
 // Manually implemented because the derived Vec<u8> Debug reads awfully.
 impl Debug for PeerId {
-    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
-        write!(f, "PeerId({})", String::from_utf8_lossy(&self.0))
+    fn fmt(&self, f: &mut Formatter<'_>) -> core::fmt::Result {
+        write!(f, "PeerId({})", self.readable())
+    }
+}
+
+/// Fails unless the slice is exactly 20 bytes long.
+impl TryFrom<&[u8]> for PeerId {
+    type Error = TryFromSliceError;
+
+    fn try_from(value: &[u8]) -> Result<Self, Self::Error> {
+        Ok(Self(value.try_into()?))
+    }
+}
+
+/// Fails unless the vector is exactly 20 bytes long.
+impl TryFrom<Vec<u8>> for PeerId {
+    type Error = TryFromSliceError;
+
+    fn try_from(value: Vec<u8>) -> Result<Self, Self::Error> {
+        Self::try_from(value.as_slice())
     }
 }
 
@@ -117,9 +253,17 @@ impl From<PeerId> for Vec<u8> {
     }
 }
 
+impl AsRef<[u8]> for PeerId {
+    fn as_ref(&self) -> &[u8] {
+        &self.0
+    }
+}
+
 #[cfg(test)]
 mod tests {
     use eyre::{eyre, WrapErr};
+    use rand::rngs::mock::StepRng;
+    use rand::SeedableRng;
 
     use super::*;
 
@@ -135,6 +279,21 @@ mod tests {
         }
     }
 
+    #[test]
+    fn random_with_seeded_rng_is_stable() {
+        let random = |seed| {
+            let mut rng = rand::rngs::StdRng::seed_from_u64(seed);
+            PeerId::random_with_rng(&mut rng, b"Rp", 0, 123, 0).unwrap()
+        };
+        assert_eq!(random(7), random(7));
+        assert_ne!(random(7), random(8));
+
+        // Unlike StdRng's, the numbers StepRng gives don't change between versions of rand.
+        let mut rng = StepRng::new(0, 0x1234_5678_9abc_def1);
+        let peer_id = PeerId::random_with_rng(&mut rng, b"Rp", 0, 123, 0).unwrap();
+        assert_eq!(peer_id, PeerId::new(*b"-Rp1381-1cDpR2dEqS3e"));
+    }
+
     #[test]
     fn random_using_crate_version_matches_format() {
         fn test(major: u8, minor: u16, patch: u8) {
@@ -166,22 +325,106 @@ mod tests {
     }
 
     #[test]
-    fn random_using_crate_version_out_of_range_err() {
-        let err = PeerId::random(b"Rp", 58, 0, 0).unwrap_err();
+    fn version_roundtrip() {
+        let random = PeerId::random(b"Rp", 22, 502, 11).unwrap();
+        assert_eq!(random.version(), Some((22, 502, 11)));
+
+        let random = PeerId::random(b"Rp", 57, 3363, 57).unwrap();
+        assert_eq!(random.version(), Some((57, 3363, 57)));
+    }
+
+    #[test]
+    fn version_of_other_layouts() {
+        assert_eq!(PeerId::new([0xff; 20]).version(), None);
+        // 0 isn't a base58 digit
+        assert_eq!(PeerId::new(*PEER_BYTES).version(), None);
+        let random = PeerId::random(b"Rp", 100, 0, 0).unwrap();
+        assert_eq!(random.version(), None);
+    }
+
+    #[test]
+    fn random_with_large_version_widens_version_field() {
+        let random = PeerId::random(b"Rp", 100, 0, 0).unwrap();
+        assert_eq!(&random.0[0..9], b"-Rp2j111-");
+        for byte in &random.0[9..] {
+            assert!(ALPHABET.contains(byte));
+        }
+
+        let random = PeerId::random(b"Rp", 255, u16::MAX, 255).unwrap();
+        assert_eq!(&random.0[0..11], b"-Rp5QLUv5Q-");
+        assert_eq!(random.0.len(), 20);
+    }
+
+    #[test]
+    fn try_from_bytes() {
         assert_eq!(
-            err.to_string(),
-            "Couldn't parse major version 58 as a single base58 character (was: \"21\")"
+            PeerId::try_from(PEER_BYTES.as_slice()).unwrap(),
+            PeerId(*PEER_BYTES)
         );
-        let err = PeerId::random(b"Rp", 0, 5002, 0).unwrap_err();
         assert_eq!(
-            err.to_string(),
-            "Couldn't parse minor version 5002 as two base58 characters (was: \"2VF\")"
+            PeerId::try_from(PEER_BYTES.to_vec()).unwrap(),
+            PeerId(*PEER_BYTES)
         );
-        let err = PeerId::random(b"Rp", 0, 0, 255).unwrap_err();
+    }
+
+    #[test]
+    fn borrowed_bytes() {
+        fn length(bytes: impl AsRef<[u8]>) -> usize {
+            bytes.as_ref().len()
+        }
+
+        let value = PeerId::new(*PEER_BYTES);
+        assert_eq!(value.as_bytes(), PEER_BYTES);
+        assert_eq!(value.as_ref(), PEER_BYTES.as_slice());
+        assert_eq!(length(value), 20);
+    }
+
+    #[test]
+    fn try_from_bytes_of_wrong_length_err() {
+        assert!(PeerId::try_from(&PEER_BYTES[..19]).is_err());
+        assert!(PeerId::try_from(vec![0; 21]).is_err());
+    }
+
+    #[test]
+    fn client_info() {
+        let peer_id = PeerId::new(*b"-qB4550-HahW9F2VDDzU");
+
+        let client_info = peer_id.client_info().unwrap();
+
         assert_eq!(
-            err.to_string(),
-            "Couldn't parse patch version 255 as a single base58 character (was: \"5Q\")"
+            client_info,
+            ClientInfo {
+                code: "qB".to_string(),
+                name: Some("qBittorrent"),
+                version: "4.5.5.0".to_string(),
+            }
         );
+        assert_eq!(client_info.to_string(), "qBittorrent 4.5.5.0");
+    }
+
+    #[test]
+    fn well_formed() {
+        assert!(PeerId::new(*PEER_BYTES).is_well_formed());
+        assert!(PeerId::random(b"Rp", 22, 502, 11).unwrap().is_well_formed());
+
+        assert!(!PeerId::new([1; 20]).is_well_formed());
+        assert!(!PeerId::new(*b"-Rp01230HahW9F2VDDzU").is_well_formed());
+        assert!(!PeerId::new(*b"-R_0123-HahW9F2VDDzU").is_well_formed());
+        assert!(!PeerId::random(b"Rp", 100, 0, 0).unwrap().is_well_formed());
+    }
+
+    #[test]
+    fn client_info_of_unknown_client() {
+        let client_info = PeerId::new(*b"-Zz0001-HahW9F2VDDzU").client_info().unwrap();
+
+        assert_eq!(client_info.name, None);
+        assert_eq!(client_info.to_string(), "Zz 0.0.0.1");
+    }
+
+    #[test]
+    fn client_info_of_random_bytes() {
+        assert_eq!(PeerId::new([0xff; 20]).client_info(), None);
+        assert_eq!(PeerId::new(*b"M7-2-0--HahW9F2VDDzU").client_info(), None);
     }
 
     #[test]
@@ -191,10 +434,52 @@ mod tests {
         assert_eq!(formatted, PEER);
     }
 
+    #[test]
+    fn display_non_printable_as_hex() {
+        let hash = PeerId::new([0xff; 20]);
+        assert_eq!(format!("{hash}"), "ff".repeat(20));
+        assert_eq!(format!("{hash:?}"), format!("PeerId({})", "ff".repeat(20)));
+    }
+
+    #[test]
+    fn display_non_printable_keeps_client_prefix() {
+        let mut bytes = [0; 20];
+        bytes[..8].copy_from_slice(b"-qB4550-");
+        let hash = PeerId::new(bytes);
+        assert_eq!(format!("{hash}"), format!("-qB4550-{}", "00".repeat(12)));
+    }
+
     #[test]
     fn debug() {
         let hash = PeerId::new(*PEER_BYTES);
         let formatted = format!("{hash:?}");
         assert_eq!(formatted, format!("PeerId({PEER})"));
     }
+
+    #[cfg(feature = "serde")]
+    #[test]
+    fn serde_uses_the_display_form() {
+        let mut prefixed = [0; 20];
+        prefixed[..8].copy_from_slice(b"-qB4550-");
+        for peer_id in [
+            PeerId::new(*PEER_BYTES),
+            PeerId::new([0xff; 20]),
+            PeerId::new(prefixed),
+        ] {
+            let json = serde_json::to_string(&peer_id).unwrap();
+
+            assert_eq!(json, format!("\"{peer_id}\""));
+            assert_eq!(serde_json::from_str::<PeerId>(&json).unwrap(), peer_id);
+        }
+        let malformed = [
+            "-Rp0123-".to_string(),
+            "-Rp0123-HahW9F2VDDzU0".to_string(),
+            format!("-qB4550-{}", "zz".repeat(12)),
+            "zz".repeat(20),
+        ];
+        for malformed in malformed {
+            let _ = serde_json::from_value::<PeerId>(malformed.into()).unwrap_err();
+        }
+        let _ = serde_json::from_str::<PeerId>("20").unwrap_err();
+    }
 }
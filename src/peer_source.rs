@@ -0,0 +1,156 @@
+use std::net::SocketAddr;
+use std::sync::mpsc::{Receiver, Sender};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+use eyre::Result;
+
+use crate::{AnnounceEvent, TorrentStats};
+
+/// Somewhere to find peers for a torrent, like a tracker or a fixed list.
+///
+/// Give it to [Torrent::add_peer_source](crate::Torrent::add_peer_source), which keeps asking
+/// it for peers for as long as it says to, and connects to the ones it finds.
+pub trait PeerSource: Send {
+    /// Find peers, blocking until they're known.
+    fn discover(&mut self) -> Result<DiscoveredPeers>;
+
+    /// Find peers right away, before the next discovery is due, telling trackers about
+    /// `event`. The next discovery is due as if this one was a regular one.
+    ///
+    /// Returns `None` if the source doesn't want to be asked again this soon, like a tracker
+    /// within its min interval. By default it's the same as [discover](Self::discover).
+    fn discover_now(&mut self, event: AnnounceEvent) -> Result<Option<DiscoveredPeers>> {
+        let _ = event;
+        self.discover().map(Some)
+    }
+
+    /// Called with how the torrent is doing right before every discovery, so that trackers
+    /// can tell how much we've transferred. Ignored by default.
+    fn update_stats(&mut self, stats: &TorrentStats) {
+        let _ = stats;
+    }
+}
+
+/// The result of asking a [PeerSource] for peers.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct DiscoveredPeers {
+    /// The addresses of the peers, which may include ones that were found before.
+    pub peers: Vec<SocketAddr>,
+    /// When to ask the source again, e.g. a tracker's announce interval, or `None` if it
+    /// won't ever have any more peers.
+    pub next_discovery: Option<Duration>,
+}
+
+/// How often peers found through peer exchange are handed out to be dialed.
+const PEX_DIAL_INTERVAL: Duration = Duration::from_secs(5);
+
+/// Peers that other peers told us about through peer exchange, waiting to be dialed.
+/// Clones share the same queue, so the torrent can fill it while its [PeerSource] side
+/// empties it.
+#[derive(Debug, Clone, Default)]
+pub(crate) struct PexPeers(Arc<Mutex<Vec<SocketAddr>>>);
+
+impl PexPeers {
+    fn lock(&self) -> std::sync::MutexGuard<'_, Vec<SocketAddr>> {
+        self.0.lock().expect("mutex to not be poisoned")
+    }
+
+    /// Queue `peer_addr` to be dialed, unless it already is.
+    pub(crate) fn push(&self, peer_addr: SocketAddr) {
+        let mut peers = self.lock();
+        if !peers.contains(&peer_addr) {
+            peers.push(peer_addr);
+        }
+    }
+
+    pub(crate) fn len(&self) -> usize {
+        self.lock().len()
+    }
+
+    #[cfg(test)]
+    pub(crate) fn queued(&self) -> Vec<SocketAddr> {
+        self.lock().clone()
+    }
+}
+
+impl PeerSource for PexPeers {
+    fn discover(&mut self) -> Result<DiscoveredPeers> {
+        Ok(DiscoveredPeers {
+            peers: std::mem::take(&mut *self.lock()),
+            next_discovery: Some(PEX_DIAL_INTERVAL),
+        })
+    }
+}
+
+/// The threads asking a torrent's [PeerSource]s for peers, which can be told to ask right
+/// away. Clones share the same threads, so the torrent can announce that it completed, while
+/// its [Torrent](crate::Torrent) handles announce everything else.
+#[derive(Debug, Clone, Default)]
+pub(crate) struct Announcers(Arc<Mutex<Vec<Announcer>>>);
+
+#[derive(Debug)]
+struct Announcer {
+    events: Sender<AnnounceEvent>,
+    /// Disconnects once the thread is done.
+    finished: Receiver<()>,
+}
+
+impl Announcers {
+    fn lock(&self) -> std::sync::MutexGuard<'_, Vec<Announcer>> {
+        self.0.lock().expect("mutex to not be poisoned")
+    }
+
+    /// Add a thread, which receives the events to announce, and drops the sender once it's
+    /// done.
+    pub(crate) fn add(&self) -> (Receiver<AnnounceEvent>, Sender<()>) {
+        let (events, event_receiver) = std::sync::mpsc::channel();
+        let (finished_sender, finished) = std::sync::mpsc::channel();
+        self.lock().push(Announcer { events, finished });
+        (event_receiver, finished_sender)
+    }
+
+    /// Have every thread announce `event` right away.
+    pub(crate) fn announce(&self, event: AnnounceEvent) {
+        self.lock()
+            .retain(|announcer| announcer.events.send(event).is_ok());
+    }
+
+    /// Have every thread announce that we're stopping, and then stop. Waits up to `timeout`
+    /// for them, as it's only polite to let the trackers know.
+    pub(crate) fn stop(&self, timeout: Duration) {
+        let announcers = std::mem::take(&mut *self.lock());
+        for announcer in &announcers {
+            let _ = announcer.events.send(AnnounceEvent::Stopped);
+        }
+        let deadline = Instant::now() + timeout;
+        for announcer in announcers {
+            let _ = announcer
+                .finished
+                .recv_timeout(deadline.saturating_duration_since(Instant::now()));
+        }
+    }
+}
+
+/// A [PeerSource] with a fixed list of peers, which are all handed out at once.
+#[derive(Debug, Clone, Default)]
+pub struct StaticPeers {
+    peers: Vec<SocketAddr>,
+}
+
+impl StaticPeers {
+    /// A source that finds exactly `peers`.
+    #[must_use]
+    pub fn new(peers: Vec<SocketAddr>) -> Self {
+        Self { peers }
+    }
+}
+
+impl PeerSource for StaticPeers {
+    fn discover(&mut self) -> Result<DiscoveredPeers> {
+        Ok(DiscoveredPeers {
+            peers: self.peers.clone(),
+            next_discovery: None,
+        })
+    }
+}
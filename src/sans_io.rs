@@ -1,3 +1,5 @@
+use alloc::vec::Vec;
+
 use nom::IResult;
 
 /// The [SansIo] trait is used to encode and decode messages without any knowledge of the
@@ -20,4 +22,13 @@ pub trait SansIo: Sized {
     /// The API currently assumes that the message is small enough that fitting it in
     /// memory is not a problem.
     fn encode(&self) -> Vec<u8>;
+
+    /// Encode a message by appending it to `buf`, leaving anything already in there as is.
+    ///
+    /// This lets callers reuse a buffer across messages instead of allocating a new one
+    /// for every call to [SansIo::encode], so it's worth overriding for messages that are
+    /// sent often.
+    fn encode_into(&self, buf: &mut Vec<u8>) {
+        buf.extend(self.encode());
+    }
 }
@@ -0,0 +1,165 @@
+use std::fmt::{Display, Formatter};
+use std::net::IpAddr;
+use std::str::FromStr;
+
+use eyre::{ensure, eyre, Report, Result};
+
+/// A block of IP addresses in CIDR notation, like `10.0.0.0/8`, or a single address.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub struct IpRange {
+    addr: IpAddr,
+    prefix_len: u8,
+}
+
+impl IpRange {
+    /// The addresses that share their first `prefix_len` bits with `addr`.
+    pub fn new(addr: IpAddr, prefix_len: u8) -> Result<Self> {
+        let max = Self::max_prefix_len(addr);
+        ensure!(
+            prefix_len <= max,
+            "Prefix length {prefix_len} is too long for {addr}, which has {max} bits"
+        );
+        // An IPv4-mapped range is stored as IPv4, unless it's wider than the mapped addresses.
+        let canonical = addr.to_canonical();
+        let mapped_bits = max - Self::max_prefix_len(canonical);
+        Ok(match prefix_len.checked_sub(mapped_bits) {
+            Some(prefix_len) => Self {
+                addr: canonical,
+                prefix_len,
+            },
+            None => Self { addr, prefix_len },
+        })
+    }
+
+    fn max_prefix_len(addr: IpAddr) -> u8 {
+        match addr {
+            IpAddr::V4(_) => 32,
+            IpAddr::V6(_) => 128,
+        }
+    }
+
+    /// Whether `addr` is in the range. IPv4 addresses mapped to IPv6 count as IPv4.
+    #[must_use]
+    pub fn contains(&self, addr: IpAddr) -> bool {
+        let mask = |bits: u32| {
+            u128::MAX
+                .checked_shl(bits - u32::from(self.prefix_len))
+                .unwrap_or(0)
+        };
+        match (self.addr, addr.to_canonical()) {
+            (IpAddr::V4(range), IpAddr::V4(addr)) => {
+                let mask = mask(32) as u32;
+                u32::from(range) & mask == u32::from(addr) & mask
+            }
+            (IpAddr::V6(range), IpAddr::V6(addr)) => {
+                let mask = mask(128);
+                u128::from(range) & mask == u128::from(addr) & mask
+            }
+            _ => false,
+        }
+    }
+}
+
+impl From<IpAddr> for IpRange {
+    fn from(addr: IpAddr) -> Self {
+        let addr = addr.to_canonical();
+        Self {
+            addr,
+            prefix_len: Self::max_prefix_len(addr),
+        }
+    }
+}
+
+impl FromStr for IpRange {
+    type Err = Report;
+
+    fn from_str(s: &str) -> Result<Self> {
+        let Some((addr, prefix_len)) = s.split_once('/') else {
+            return Ok(Self::from(s.parse::<IpAddr>()?));
+        };
+        let prefix_len = prefix_len
+            .parse()
+            .map_err(|_| eyre!("Invalid prefix length in {s}"))?;
+        Self::new(addr.parse()?, prefix_len)
+    }
+}
+
+impl Display for IpRange {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}/{}", self.addr, self.prefix_len)
+    }
+}
+
+/// The peers that a torrent refuses to talk to.
+#[derive(Debug, Default, Clone)]
+pub struct BanList {
+    ranges: Vec<IpRange>,
+}
+
+impl BanList {
+    pub fn ban(&mut self, range: IpRange) {
+        if !self.ranges.contains(&range) {
+            self.ranges.push(range);
+        }
+    }
+
+    /// Lift a ban added with [ban](Self::ban). Only the exact same range is lifted, so
+    /// unbanning a single address from a banned range doesn't do anything.
+    pub fn unban(&mut self, range: IpRange) {
+        self.ranges.retain(|banned| *banned != range);
+    }
+
+    #[must_use]
+    pub fn is_banned(&self, addr: IpAddr) -> bool {
+        self.ranges.iter().any(|range| range.contains(addr))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn ip(s: &str) -> IpAddr {
+        s.parse().unwrap()
+    }
+
+    #[test]
+    fn ranges_contain_the_addresses_they_cover() {
+        let range: IpRange = "10.1.0.0/16".parse().unwrap();
+        assert!(range.contains(ip("10.1.2.3")));
+        assert!(range.contains(ip("::ffff:10.1.2.3")));
+        assert!(!range.contains(ip("10.2.0.1")));
+        assert!(!range.contains(ip("::1")));
+
+        let single: IpRange = "2001:db8::1".parse().unwrap();
+        assert!(single.contains(ip("2001:db8::1")));
+        assert!(!single.contains(ip("2001:db8::2")));
+
+        let everything: IpRange = "0.0.0.0/0".parse().unwrap();
+        assert!(everything.contains(ip("192.168.0.1")));
+
+        let mapped: IpRange = "::ffff:10.0.0.0/104".parse().unwrap();
+        assert_eq!(mapped, "10.0.0.0/8".parse().unwrap());
+        assert!(mapped.contains(ip("10.9.9.9")));
+    }
+
+    #[test]
+    fn invalid_ranges_are_rejected() {
+        for s in ["10.0.0.0/33", "10.0.0.0/x", "10.0.0/8", "::/129"] {
+            let _ = s.parse::<IpRange>().unwrap_err();
+        }
+    }
+
+    #[test]
+    fn unban_lifts_only_the_same_range() {
+        let mut bans = BanList::default();
+        bans.ban("10.0.0.0/8".parse().unwrap());
+        bans.ban(ip("192.168.0.1").into());
+
+        bans.unban(ip("10.0.0.1").into());
+        bans.unban(ip("192.168.0.1").into());
+
+        assert!(bans.is_banned(ip("10.0.0.1")));
+        assert!(!bans.is_banned(ip("192.168.0.1")));
+    }
+}
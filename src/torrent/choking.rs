@@ -0,0 +1,335 @@
+use std::collections::BTreeSet;
+use std::time::{Duration, Instant};
+
+use rand::seq::IteratorRandom;
+use rand::Rng;
+
+use crate::PeerId;
+
+/// How many peers are unchoked based on their upload rate to us.
+const REGULAR_SLOTS: usize = 4;
+/// How often the regular slots are re-evaluated.
+const RECHOKE_INTERVAL: Duration = Duration::from_secs(10);
+/// How often the optimistic slot is handed to a new peer.
+const OPTIMISTIC_UNCHOKE_INTERVAL: Duration = Duration::from_secs(30);
+
+/// A peer that could be unchoked, as seen by the [ChokingManager].
+#[derive(Debug, Clone, Copy)]
+pub struct ChokeCandidate {
+    pub peer_id: PeerId,
+    pub interested: bool,
+    /// How fast the peer is uploading to us, in bytes per second.
+    pub download_rate: f64,
+    /// How fast we're uploading to the peer, in bytes per second.
+    pub upload_rate: f64,
+    /// Whether we're currently not choking the peer.
+    pub unchoked: bool,
+}
+
+/// The peers whose choke state should change, according to the [ChokeDecider].
+/// Peers in neither set stay as they are.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct ChokeDecision {
+    pub unchoke: BTreeSet<PeerId>,
+    pub choke: BTreeSet<PeerId>,
+    /// The peer holding the optimistic slot from now on.
+    pub optimistic_unchoke: Option<PeerId>,
+}
+
+/// The tit-for-tat choking algorithm, without any of the timing: the interested peers that
+/// upload the fastest to us get the regular slots, and one random interested peer gets the
+/// optimistic slot regardless of rate, to give it a chance to prove itself (and to let new
+/// peers bootstrap).
+///
+/// Once we're seeding, no one uploads to us anymore, so the regular slots go to the peers
+/// we upload the fastest to instead.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ChokeDecider {
+    pub regular_slots: usize,
+    pub optimistic_slot: bool,
+}
+
+impl Default for ChokeDecider {
+    fn default() -> Self {
+        Self {
+            regular_slots: REGULAR_SLOTS,
+            optimistic_slot: true,
+        }
+    }
+}
+
+impl ChokeDecider {
+    /// Decide who to unchoke. The optimistic slot stays with `optimistic_unchoke` unless
+    /// `rotate_optimistic` is set, or the peer doesn't qualify for it anymore. Peers are
+    /// ranked by their upload rate if `seeding`, or by their download rate if not.
+    ///
+    /// Peers with the same rate are ranked by peer ID, so the same input always gives the
+    /// same regular slots.
+    pub fn decide(
+        &self,
+        candidates: &[ChokeCandidate],
+        optimistic_unchoke: Option<PeerId>,
+        rotate_optimistic: bool,
+        seeding: bool,
+        rng: &mut impl Rng,
+    ) -> ChokeDecision {
+        let rate = |c: &ChokeCandidate| {
+            if seeding {
+                c.upload_rate
+            } else {
+                c.download_rate
+            }
+        };
+        let mut interested: Vec<_> = candidates.iter().filter(|c| c.interested).collect();
+        interested.sort_by(|a, b| rate(b).total_cmp(&rate(a)).then(a.peer_id.cmp(&b.peer_id)));
+        let mut unchoked: BTreeSet<_> = interested
+            .iter()
+            .take(self.regular_slots)
+            .map(|c| c.peer_id)
+            .collect();
+
+        // If the optimistic peer lost interest or earned a regular slot, pick a new one early.
+        let optimistic_still_valid = optimistic_unchoke.is_some_and(|peer_id| {
+            !unchoked.contains(&peer_id) && interested.iter().any(|c| c.peer_id == peer_id)
+        });
+        let optimistic_unchoke = if !self.optimistic_slot {
+            None
+        } else if rotate_optimistic || !optimistic_still_valid {
+            interested
+                .iter()
+                .map(|c| c.peer_id)
+                .filter(|peer_id| !unchoked.contains(peer_id))
+                .choose(rng)
+        } else {
+            optimistic_unchoke
+        };
+        unchoked.extend(optimistic_unchoke);
+
+        ChokeDecision {
+            unchoke: candidates
+                .iter()
+                .filter(|c| !c.unchoked && unchoked.contains(&c.peer_id))
+                .map(|c| c.peer_id)
+                .collect(),
+            choke: candidates
+                .iter()
+                .filter(|c| c.unchoked && !unchoked.contains(&c.peer_id))
+                .map(|c| c.peer_id)
+                .collect(),
+            optimistic_unchoke,
+        }
+    }
+}
+
+/// Runs the [ChokeDecider] on a timer: every [RECHOKE_INTERVAL] the regular slots are
+/// re-evaluated, and every [OPTIMISTIC_UNCHOKE_INTERVAL] the optimistic slot moves on.
+#[derive(Debug)]
+pub struct ChokingManager {
+    decider: ChokeDecider,
+    next_rechoke: Instant,
+    next_optimistic_unchoke: Instant,
+    optimistic_unchoke: Option<PeerId>,
+}
+
+impl ChokingManager {
+    /// The first rechoke happens on the first [tick](ChokingManager::tick).
+    pub fn new(now: Instant) -> Self {
+        Self {
+            decider: ChokeDecider::default(),
+            next_rechoke: now,
+            next_optimistic_unchoke: now,
+            optimistic_unchoke: None,
+        }
+    }
+
+    /// Returns which peers to choke and unchoke, if it's time to re-evaluate that.
+    pub fn tick(
+        &mut self,
+        now: Instant,
+        candidates: &[ChokeCandidate],
+        seeding: bool,
+        rng: &mut impl Rng,
+    ) -> Option<ChokeDecision> {
+        if now < self.next_rechoke {
+            return None;
+        }
+        self.next_rechoke = now + RECHOKE_INTERVAL;
+
+        let rotate_optimistic = now >= self.next_optimistic_unchoke;
+        let decision = self.decider.decide(
+            candidates,
+            self.optimistic_unchoke,
+            rotate_optimistic,
+            seeding,
+            rng,
+        );
+        if rotate_optimistic || decision.optimistic_unchoke != self.optimistic_unchoke {
+            self.next_optimistic_unchoke = now + OPTIMISTIC_UNCHOKE_INTERVAL;
+        }
+        self.optimistic_unchoke = decision.optimistic_unchoke;
+        Some(decision)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use rand::rngs::StdRng;
+    use rand::SeedableRng;
+
+    use super::*;
+
+    fn peer(i: u8) -> PeerId {
+        PeerId::new([i; 20])
+    }
+
+    /// `(peer, interested, rate, unchoked)`
+    type Peer = (u8, bool, f64, bool);
+
+    /// Candidates with the same upload rate to them as their download rate to us.
+    fn candidates(peers: &[Peer]) -> Vec<ChokeCandidate> {
+        peers
+            .iter()
+            .map(|&(i, interested, rate, unchoked)| ChokeCandidate {
+                peer_id: peer(i),
+                interested,
+                download_rate: rate,
+                upload_rate: rate,
+                unchoked,
+            })
+            .collect()
+    }
+
+    fn set(peers: &[u8]) -> BTreeSet<PeerId> {
+        peers.iter().copied().map(peer).collect()
+    }
+
+    #[test]
+    fn regular_slots_go_to_the_fastest_interested_peers() {
+        let decider = ChokeDecider {
+            regular_slots: 2,
+            optimistic_slot: false,
+        };
+        // (candidates, expected unchoke, expected choke)
+        let cases: [(&[Peer], &[u8], &[u8]); 4] = [
+            // More interested peers than slots.
+            (
+                &[
+                    (1, true, 10.0, false),
+                    (2, true, 30.0, false),
+                    (3, true, 20.0, false),
+                ],
+                &[2, 3],
+                &[],
+            ),
+            // The slowest of the unchoked peers is replaced.
+            (
+                &[
+                    (1, true, 10.0, true),
+                    (2, true, 30.0, true),
+                    (3, true, 20.0, false),
+                ],
+                &[3],
+                &[1],
+            ),
+            // Uninterested peers never get a slot, however fast they are.
+            (&[(1, false, 50.0, true), (2, true, 1.0, false)], &[2], &[1]),
+            // A tie is broken by peer ID.
+            (
+                &[
+                    (3, true, 10.0, false),
+                    (1, true, 10.0, false),
+                    (2, true, 10.0, false),
+                ],
+                &[1, 2],
+                &[],
+            ),
+        ];
+
+        for (peers, unchoke, choke) in cases {
+            let decision = decider.decide(
+                &candidates(peers),
+                None,
+                false,
+                false,
+                &mut StdRng::seed_from_u64(0),
+            );
+
+            assert_eq!(decision.unchoke, set(unchoke), "{peers:?}");
+            assert_eq!(decision.choke, set(choke), "{peers:?}");
+        }
+    }
+
+    #[test]
+    fn optimistic_slot_is_deterministic_under_a_seeded_rng() {
+        let decider = ChokeDecider {
+            regular_slots: 1,
+            optimistic_slot: true,
+        };
+        let candidates = candidates(&[
+            (1, true, 50.0, false),
+            (2, true, 0.0, false),
+            (3, true, 0.0, false),
+            (4, true, 0.0, false),
+            (5, false, 0.0, false),
+        ]);
+        let decide = |seed| {
+            decider.decide(
+                &candidates,
+                None,
+                true,
+                false,
+                &mut StdRng::seed_from_u64(seed),
+            )
+        };
+
+        let decision = decide(7);
+
+        assert_eq!(decision, decide(7));
+        let optimistic = decision.optimistic_unchoke.unwrap();
+        assert!(set(&[2, 3, 4]).contains(&optimistic));
+        assert_eq!(decision.unchoke, [peer(1), optimistic].into());
+        // The optimistic peer keeps its slot until it's time to rotate.
+        let kept = decider.decide(
+            &candidates,
+            Some(optimistic),
+            false,
+            false,
+            &mut StdRng::seed_from_u64(1),
+        );
+        assert_eq!(kept.optimistic_unchoke, Some(optimistic));
+    }
+
+    #[test]
+    fn seeding_ranks_peers_by_upload_rate() {
+        let decider = ChokeDecider {
+            regular_slots: 2,
+            optimistic_slot: false,
+        };
+        // No one uploads to a seed, so the download rates are all the same.
+        let candidates: Vec<_> = [(1, 0.0), (2, 30.0), (3, 20.0), (4, 10.0)]
+            .into_iter()
+            .map(|(i, upload_rate)| ChokeCandidate {
+                peer_id: peer(i),
+                interested: true,
+                download_rate: 0.0,
+                upload_rate,
+                unchoked: i == 1,
+            })
+            .collect();
+        let decide = |seeding| {
+            decider.decide(
+                &candidates,
+                None,
+                false,
+                seeding,
+                &mut StdRng::seed_from_u64(0),
+            )
+        };
+
+        let seeding = decide(true);
+        assert_eq!(seeding.unchoke, set(&[2, 3]));
+        assert_eq!(seeding.choke, set(&[1]));
+        // While downloading, the tie on download rate goes to the lowest peer IDs.
+        assert_eq!(decide(false).unchoke, set(&[2]));
+    }
+}
@@ -0,0 +1,155 @@
+use std::time::Duration;
+
+use eyre::{ensure, Result};
+
+use crate::messages::ReservedBits;
+use crate::torrent::piece_selector::BLOCK_SIZE;
+use crate::StdIoConfig;
+
+/// Tunables for a [Torrent](crate::Torrent). The defaults should be sensible for most uses.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct TorrentConfig {
+    /// How many block requests to keep in flight per peer. One request at a time would
+    /// leave the connection idle for a round-trip after every block.
+    pub max_pipeline_depth: usize,
+    /// How many bytes to request at a time. Pieces are split into blocks of this size, with
+    /// a shorter last block if it doesn't divide the piece length. At most 16 KiB, as that's
+    /// the most other clients serve, and at most [max_request_size](Self::max_request_size).
+    pub block_size: u32,
+    /// The largest block that peers may request from us, bigger requests are rejected.
+    pub max_request_size: u32,
+    /// How many of a peer's requests can be waiting for an answer at once. A peer that sends
+    /// more than that without waiting for the blocks is disconnected.
+    pub max_queued_requests: usize,
+    /// How long to wait for a requested block before asking another peer for it.
+    pub request_timeout: Duration,
+    /// A peer that we've had requests out to for a whole [snub_window](Self::snub_window),
+    /// but that delivered less than this many bytes per second in it, is snubbed: it's only
+    /// asked for one block at a time, and the rest go to other peers. It's forgiven once it
+    /// speeds up again.
+    pub snub_threshold: u64,
+    /// The window over which [snub_threshold](Self::snub_threshold) is measured. Should be
+    /// shorter than the [request_timeout](Self::request_timeout), or a stalled peer's
+    /// requests time out before it's ever snubbed.
+    pub snub_window: Duration,
+    /// How long to wait for a peer's handshake before giving up on the connection.
+    pub handshake_timeout: Duration,
+    /// How long a peer can go without sending anything, not even a keep-alive, before it's
+    /// considered gone and disconnected.
+    pub inactivity_timeout: Duration,
+    /// How many peers to be connected to at most. Once reached, no new connections are made,
+    /// and incoming ones are turned away.
+    pub max_connections: usize,
+    /// How many peers to be dialing at once, counting from when we start connecting until
+    /// the handshake is done or fails. Any more peers wait their turn, so that a tracker
+    /// handing out hundreds of peers doesn't open hundreds of sockets at once. Dials that
+    /// are still connecting after the [handshake_timeout](Self::handshake_timeout) are given
+    /// up on, and count as a failed attempt.
+    pub max_pending_dials: usize,
+    /// How long to wait before reconnecting to a peer that dropped, or couldn't be reached.
+    /// Doubles with every attempt that fails, up to [reconnect_max_delay](Self::reconnect_max_delay).
+    pub reconnect_base_delay: Duration,
+    /// The longest to wait between reconnection attempts. A connection that stays up for
+    /// longer than this counts as a success, and resets the backoff.
+    pub reconnect_max_delay: Duration,
+    /// Up to this much random delay is added to every reconnection attempt, so that peers that
+    /// dropped at the same time aren't all redialed at once.
+    pub reconnect_jitter: Duration,
+    /// How many attempts in a row can fail before the peer is given up on.
+    pub max_reconnect_attempts: u32,
+    /// Seed for the torrent's random choices, like which peer to optimistically unchoke, to
+    /// make them reproducible. `None` seeds it from the OS.
+    pub rng_seed: Option<u64>,
+    /// The protocol extensions advertised in our handshake. Defaults to the ones that are
+    /// implemented; turning one off makes us behave as if the peer didn't support it either.
+    pub reserved_bits: ReservedBits,
+    /// What to do about peers whose handshake sets reserved bits that we don't know about.
+    pub unknown_reserved_bits: ReservedBitsPolicy,
+    /// How the connections to peers that the torrent dials itself, like those from
+    /// [Torrent::add_peer](crate::Torrent::add_peer), buffer their data.
+    pub io: StdIoConfig,
+}
+
+/// What to do about a peer whose handshake sets reserved bits that don't stand for any
+/// extension we know about. See [TorrentConfig::unknown_reserved_bits].
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub enum ReservedBitsPolicy {
+    /// Pretend they aren't there, like most clients do.
+    #[default]
+    Ignore,
+    /// Log a warning, but carry on with the connection.
+    Warn,
+    /// Close the connection.
+    Reject,
+}
+
+impl TorrentConfig {
+    /// Check that the tunables make sense together, which torrents do when they're created.
+    pub fn validate(&self) -> Result<()> {
+        ensure!(self.block_size > 0, "Block size can't be 0");
+        ensure!(
+            self.block_size <= BLOCK_SIZE,
+            "Block size {} is bigger than the {BLOCK_SIZE} bytes other clients serve",
+            self.block_size
+        );
+        ensure!(
+            self.block_size <= self.max_request_size,
+            "Block size {} is bigger than the max request size of {}",
+            self.block_size,
+            self.max_request_size
+        );
+        Ok(())
+    }
+}
+
+impl Default for TorrentConfig {
+    fn default() -> Self {
+        Self {
+            max_pipeline_depth: 5,
+            block_size: BLOCK_SIZE,
+            max_request_size: BLOCK_SIZE,
+            max_queued_requests: 256,
+            request_timeout: Duration::from_secs(30),
+            snub_threshold: 1024,
+            snub_window: Duration::from_secs(20),
+            handshake_timeout: Duration::from_secs(10),
+            inactivity_timeout: Duration::from_secs(2 * 60),
+            max_connections: 50,
+            max_pending_dials: 10,
+            reconnect_base_delay: Duration::from_secs(1),
+            reconnect_max_delay: Duration::from_secs(2 * 60),
+            reconnect_jitter: Duration::from_secs(1),
+            max_reconnect_attempts: 10,
+            rng_seed: None,
+            reserved_bits: ReservedBits {
+                extension_protocol: true,
+                fast_extension: true,
+                dht: false,
+            },
+            unknown_reserved_bits: ReservedBitsPolicy::Ignore,
+            io: StdIoConfig::default(),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn block_size_has_to_fit_requests() {
+        TorrentConfig::default().validate().unwrap();
+        let with_block_size = |block_size| TorrentConfig {
+            block_size,
+            ..TorrentConfig::default()
+        };
+        with_block_size(1024).validate().unwrap();
+        let _ = with_block_size(0).validate().unwrap_err();
+        let _ = with_block_size(BLOCK_SIZE + 1).validate().unwrap_err();
+        let small_requests = TorrentConfig {
+            max_request_size: 1024,
+            ..TorrentConfig::default()
+        };
+        let _ = small_requests.validate().unwrap_err();
+    }
+}
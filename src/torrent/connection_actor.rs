@@ -1,25 +1,91 @@
+use std::collections::{BTreeMap, BTreeSet, HashMap, HashSet, VecDeque};
 use std::fmt::Debug;
+use std::net::SocketAddr;
+use std::sync::Arc;
+use std::time::{Duration, Instant};
 
-use eyre::{bail, OptionExt, Result};
-use tracing::{info, trace, warn};
+use eyre::{OptionExt, Result};
 
 use crate::actor::actor::Actor;
 use crate::actor::handle::Handle;
 use crate::actor::outcome::Outcome;
+use crate::clock::{Clock, SystemClock};
+use crate::connections::SHUTDOWN_POLL_INTERVAL;
+use crate::log::{debug, info, trace, warn};
 use crate::messages::Message;
-use crate::messages::{Handshake, KeepAlive};
+use crate::messages::{
+    Bitfield, Cancel, Choke, Extended, ExtendedHandshake, Handshake, Have, HaveAll, HaveNone,
+    Interested, KeepAlive, Metadata, NotInterested, Pex, Piece, Port, ProtocolError, RejectRequest,
+    Request, Unchoke, EXTENDED_HANDSHAKE_ID, MAX_PEX_PEERS, PEX_INTERVAL, UT_METADATA,
+    UT_METADATA_ID, UT_PEX, UT_PEX_ID,
+};
+use crate::metrics::{MetricsSink, NoMetrics};
+use crate::observer::{EventLevel, EventObserver, EventRecord, NoObserver};
+use crate::torrent::config::{ReservedBitsPolicy, TorrentConfig};
+use crate::torrent::connection_state::ConnectionState;
+use crate::torrent::rate_limiter::RateLimiters;
 use crate::torrent::torrent_actor::TorrentActor;
-use crate::{ConnectionRead, ConnectionWrite, InfoHash, PeerId};
+use crate::{ConnectionRead, ConnectionWrite, InfoHash, PeerId, SendStatus, ShutdownSignal};
+
+/// A request for a block that was sent this recently is ignored, the peer already has it.
+const DUPLICATE_REQUEST_WINDOW: Duration = Duration::from_secs(5);
 
 /// This actor handles the connection to a single peer.
 pub struct ConnectionActor {
     handle: Option<Handle<ConnectionActor>>,
     own_peer_id: PeerId,
     peer_id: Option<PeerId>,
+    /// Not every connection has a network address, e.g. in tests.
+    peer_addr: Option<SocketAddr>,
     info_hash: InfoHash,
     torrent: Handle<TorrentActor>,
+    config: TorrentConfig,
+    state: ConnectionState,
+    /// Requests sent to the peer that haven't been answered yet.
+    outstanding_requests: HashSet<Request>,
+    /// The peer's requests that the torrent is reading, or that are queued to be sent.
+    pending_requests: HashSet<Request>,
+    /// When the peer's requests were last answered, to ignore them being repeated right away.
+    served_requests: HashMap<Request, Instant>,
+    /// How many blocks have been asked of the torrent, but not yet handed to us.
+    pending_assignments: usize,
+    /// Whether we sent our handshake yet.
+    handshake_sent: bool,
+    /// Whether the torrent knows about this connection, and should be told when it closes.
+    registered: bool,
+    /// Whether the torrent closed this connection on purpose, so it shouldn't be reconnected.
+    rejected: bool,
+    peer_supports_extensions: bool,
+    /// Whether both sides support the Fast Extension.
+    fast_extension: bool,
+    peer_extensions: BTreeMap<String, u8>,
+    /// The peers we've told the peer about through peer exchange, and when we last did.
+    pex_sent: BTreeSet<SocketAddr>,
+    last_pex: Option<Instant>,
+    /// How many pieces the torrent has, once its metadata is known. Used to check the
+    /// peer's bitfield.
+    piece_count: Option<usize>,
+    /// The pieces we had when the connection was opened, to tell the peer about.
+    own_pieces: Bitfield,
+    rate_limiters: RateLimiters,
+    /// Blocks ready to be sent, waiting for the upload limit to allow it, or for the
+    /// connection to catch up.
+    queued_blocks: VecDeque<Piece>,
+    /// Whether the first queued block was already counted against the upload limit, before
+    /// it turned out that the connection couldn't take it yet.
+    first_block_paid: bool,
+    clock: Arc<dyn Clock>,
+    metrics: Arc<dyn MetricsSink>,
+    observer: Arc<dyn EventObserver>,
+    /// When we last heard from the peer, to notice when it's gone silent.
+    last_activity: Instant,
+    /// When we last sent the peer anything, as of the last activity check, so it doesn't
+    /// think the same of us.
+    last_sent: Instant,
     connection_read: Option<Box<dyn ConnectionRead + Send + 'static>>,
-    connection_write: Box<dyn ConnectionWrite + Send + 'static>,
+    connection_write: TrackedWrite,
+    /// Stops the receive loop once the actor stops, even if the peer has gone quiet.
+    shutdown: ShutdownSignal,
 }
 
 impl ConnectionActor {
@@ -30,116 +96,837 @@ impl ConnectionActor {
         connection_write: impl ConnectionWrite + Send + 'static,
         info_hash: InfoHash,
         torrent: Handle<TorrentActor>,
+        config: TorrentConfig,
     ) -> Self {
+        let clock: Arc<dyn Clock> = Arc::new(SystemClock);
         Self {
             handle: None,
             own_peer_id,
             peer_id: expected_peer_id,
+            peer_addr: None,
             info_hash,
             torrent,
+            config,
+            state: ConnectionState::default(),
+            outstanding_requests: HashSet::new(),
+            pending_requests: HashSet::new(),
+            served_requests: HashMap::new(),
+            pending_assignments: 0,
+            handshake_sent: false,
+            registered: false,
+            rejected: false,
+            peer_supports_extensions: false,
+            fast_extension: false,
+            peer_extensions: BTreeMap::new(),
+            pex_sent: BTreeSet::new(),
+            last_pex: None,
+            piece_count: None,
+            own_pieces: Bitfield::default(),
+            rate_limiters: RateLimiters::unlimited(clock.clone()),
+            queued_blocks: VecDeque::new(),
+            first_block_paid: false,
+            last_activity: clock.now(),
+            last_sent: clock.now(),
+            clock,
+            metrics: Arc::new(NoMetrics),
+            observer: Arc::new(NoObserver),
             connection_read: Some(Box::new(connection_read)),
-            connection_write: Box::new(connection_write),
+            connection_write: TrackedWrite {
+                inner: Box::new(connection_write),
+                sent: false,
+            },
+            shutdown: ShutdownSignal::new(),
+        }
+    }
+
+    #[must_use]
+    pub fn with_peer_addr(mut self, peer_addr: Option<SocketAddr>) -> Self {
+        self.peer_addr = peer_addr;
+        self
+    }
+
+    #[must_use]
+    pub fn with_piece_count(mut self, piece_count: Option<usize>) -> Self {
+        self.piece_count = piece_count;
+        self
+    }
+
+    #[must_use]
+    pub fn with_own_pieces(mut self, own_pieces: Bitfield) -> Self {
+        self.own_pieces = own_pieces;
+        self
+    }
+
+    #[must_use]
+    pub fn with_rate_limiters(mut self, rate_limiters: RateLimiters) -> Self {
+        self.rate_limiters = rate_limiters;
+        self
+    }
+
+    #[must_use]
+    pub fn with_clock(mut self, clock: Arc<dyn Clock>) -> Self {
+        self.last_activity = clock.now();
+        self.last_sent = clock.now();
+        self.clock = clock;
+        self
+    }
+
+    #[must_use]
+    pub fn with_metrics(mut self, metrics: Arc<dyn MetricsSink>) -> Self {
+        self.metrics = metrics;
+        self
+    }
+
+    #[must_use]
+    pub fn with_observer(mut self, observer: Arc<dyn EventObserver>) -> Self {
+        self.observer = observer;
+        self
+    }
+
+    /// A fresh actor for the same peer, over a new connection, to take over from this one
+    /// after it failed. It has the same settings, and expects the peer to have the peer ID
+    /// this one knew it by. Pieces completed while it's reconnecting aren't in its bitfield.
+    pub fn reconnect(
+        &self,
+        connection_read: impl ConnectionRead + Send + 'static,
+        connection_write: impl ConnectionWrite + Send + 'static,
+    ) -> Self {
+        Self::new(
+            self.own_peer_id,
+            self.peer_id,
+            connection_read,
+            connection_write,
+            self.info_hash,
+            self.torrent.clone(),
+            self.config,
+        )
+        .with_peer_addr(self.peer_addr)
+        .with_piece_count(self.piece_count)
+        .with_own_pieces(self.own_pieces.clone())
+        .with_rate_limiters(self.rate_limiters.clone())
+        .with_clock(self.clock.clone())
+        .with_metrics(self.metrics.clone())
+        .with_observer(self.observer.clone())
+    }
+
+    fn observe(&self, level: EventLevel, event: &'static str, message: String) {
+        self.observer.observe(&EventRecord {
+            level,
+            event,
+            peer_id: self.peer_id,
+            peer_addr: self.peer_addr,
+            message,
+        });
+    }
+
+    /// Disconnect the peer if it hasn't sent anything for longer than the inactivity timeout,
+    /// and send it a keep-alive if we haven't sent anything for half of it.
+    pub fn check_activity(&mut self) -> Result<Outcome> {
+        let now = self.clock.now();
+        if std::mem::take(&mut self.connection_write.sent) {
+            self.last_sent = now;
+        } else if self.registered
+            && now.saturating_duration_since(self.last_sent) >= self.config.inactivity_timeout / 2
+        {
+            trace!("Sending keep-alive to peer {:?}", self.peer_id);
+            self.connection_write.send(Message::KeepAlive(KeepAlive))?;
+            self.connection_write.sent = false;
+            self.last_sent = now;
+        }
+        let silent_for = now.saturating_duration_since(self.last_activity);
+        if silent_for > self.config.inactivity_timeout {
+            info!(
+                "Peer {:?} has been silent for {silent_for:?}, disconnecting",
+                self.peer_id
+            );
+            return Ok(Outcome::Stop);
+        }
+        Ok(Outcome::Continue)
+    }
+
+    /// The torrent's metadata arrived, so from now on the peer's bitfield can be checked.
+    pub fn set_piece_count(&mut self, piece_count: usize) -> Result<Outcome> {
+        self.piece_count = Some(piece_count);
+        Ok(Outcome::Continue)
+    }
+
+    fn own_handshake(&self) -> Handshake {
+        let reserved = self.config.reserved_bits.into();
+        Handshake::with_reserved(reserved, self.info_hash, self.own_peer_id)
+    }
+
+    /// Tell the peer which pieces we have, right after the handshake.
+    fn send_have_pieces(&mut self) -> Result<()> {
+        let piece_count = self.piece_count.unwrap_or(0);
+        let have_none = (0..piece_count).all(|index| !self.own_pieces.has(index));
+        let have_all = piece_count > 0 && (0..piece_count).all(|index| self.own_pieces.has(index));
+        let message = match (self.fast_extension, have_none, have_all) {
+            (true, _, true) => Message::HaveAll(HaveAll),
+            (true, true, _) => Message::HaveNone(HaveNone),
+            // Without the Fast Extension, having nothing is said by not sending a bitfield.
+            (false, true, _) => return Ok(()),
+            _ => Message::Bitfield(self.own_pieces.clone()),
+        };
+        self.connection_write.send(message)?;
+        Ok(())
+    }
+
+    /// If the peer supports the extension protocol, tell it which extensions we support.
+    /// Called by the torrent once the connection is established, as only the torrent knows
+    /// whether there's any metadata to share.
+    pub fn start_extension_protocol(&mut self, metadata_size: Option<usize>) -> Result<Outcome> {
+        if !self.peer_supports_extensions {
+            return Ok(Outcome::Continue);
+        }
+        let mut handshake = ExtendedHandshake::new(BTreeMap::from([
+            (UT_METADATA.to_string(), UT_METADATA_ID),
+            (UT_PEX.to_string(), UT_PEX_ID),
+        ]));
+        handshake.metadata_size = metadata_size;
+        self.connection_write
+            .send(Message::Extended(handshake.to_message()))?;
+        Ok(Outcome::Continue)
+    }
+
+    /// Ask the peer for pieces of the metadata.
+    pub fn request_metadata(&mut self, pieces: Vec<usize>) -> Result<Outcome> {
+        for piece in pieces {
+            self.send_metadata(Metadata::Request { piece })?;
+        }
+        Ok(Outcome::Continue)
+    }
+
+    /// Send a metadata message to the peer, which must support the metadata extension.
+    pub fn send_metadata(&mut self, metadata: Metadata) -> Result<Outcome> {
+        let id = *self
+            .peer_extensions
+            .get(UT_METADATA)
+            .ok_or_eyre("Peer does not support the metadata extension")?;
+        self.connection_write
+            .send(Message::Extended(metadata.to_message(id)))?;
+        Ok(Outcome::Continue)
+    }
+
+    /// Tell the peer which of `peers` we connected to, and which ones we dropped, since the
+    /// last time. Does nothing if the peer doesn't support peer exchange, or if it's too soon
+    /// after the last message to send another one.
+    pub fn send_pex(&mut self, peers: BTreeSet<SocketAddr>) -> Result<Outcome> {
+        let Some(&id) = self.peer_extensions.get(UT_PEX) else {
+            return Ok(Outcome::Continue);
+        };
+        let now = self.clock.now();
+        if self
+            .last_pex
+            .is_some_and(|last_pex| now.saturating_duration_since(last_pex) < PEX_INTERVAL)
+        {
+            return Ok(Outcome::Continue);
         }
+        let pex = Pex {
+            added: peers
+                .difference(&self.pex_sent)
+                .take(MAX_PEX_PEERS)
+                .copied()
+                .collect(),
+            dropped: self
+                .pex_sent
+                .difference(&peers)
+                .take(MAX_PEX_PEERS)
+                .copied()
+                .collect(),
+        };
+        if pex.added.is_empty() && pex.dropped.is_empty() {
+            return Ok(Outcome::Continue);
+        }
+        // Whatever didn't fit is sent next time.
+        self.pex_sent.extend(&pex.added);
+        for peer in &pex.dropped {
+            self.pex_sent.remove(peer);
+        }
+        self.last_pex = Some(now);
+        self.connection_write
+            .send(Message::Extended(pex.to_message(id)))?;
+        Ok(Outcome::Continue)
+    }
+
+    /// The extensions the peer supports, mapped to the extended message ids it wants to
+    /// receive them with. Empty until the peer has sent its extended handshake.
+    pub fn peer_extensions(&self) -> &BTreeMap<String, u8> {
+        &self.peer_extensions
     }
 
     /// Initiate handshake with a peer on an outgoing connection.
     pub fn initiate_handshake(&mut self) -> Result<Outcome> {
-        self.connection_write
-            .send(Message::Handshake(Handshake::new(
-                self.info_hash,
-                self.own_peer_id,
-            )))?;
+        self.send_own_handshake()?;
         let connection_read = self
             .connection_read
             .take()
             .expect("connection_read to be set");
-        let message = connection_read.receive()?;
-        if let Message::Handshake(handshake) = message {
-            if handshake.info_hash != self.info_hash {
-                bail!("Peer sent an incorrect info hash");
+        let Some(handshake) = self.receive_handshake(connection_read.as_ref())? else {
+            return Ok(Outcome::Stop);
+        };
+        self.complete_handshake(handshake, connection_read)
+    }
+
+    /// Wait for a handshake from a peer on an incoming connection.
+    pub fn await_handshake(&mut self) -> Result<Outcome> {
+        let connection_read = self.connection_read.take().expect("connection to be set");
+        let Some(handshake) = self.receive_handshake(connection_read.as_ref())? else {
+            return Ok(Outcome::Stop);
+        };
+        self.complete_handshake(handshake, connection_read)
+    }
+
+    fn send_own_handshake(&mut self) -> Result<()> {
+        self.connection_write
+            .send(Message::Handshake(self.own_handshake()))?;
+        self.handshake_sent = true;
+        Ok(())
+    }
+
+    /// Wait for the first message of the connection, which should be a handshake.
+    /// Peers that don't send anything would otherwise keep the connection open forever.
+    ///
+    /// Returns `None` if the peer closed the connection before finishing its handshake.
+    /// That happens all the time, e.g. with port scanners and aborted dials, so it's not
+    /// treated as an error, and only logged at debug level.
+    fn receive_handshake(&self, connection_read: &dyn ConnectionRead) -> Result<Option<Handshake>> {
+        let timeout = self.config.handshake_timeout;
+        let message = match connection_read.receive_timeout(timeout) {
+            Ok(message) => message.ok_or(ProtocolError::HandshakeTimeout(timeout))?,
+            Err(e) => {
+                let message = format!(
+                    "Peer {:?} closed the connection during the handshake: {e:#}",
+                    self.peer_addr
+                );
+                debug!("{message}");
+                self.observe(EventLevel::Debug, "handshake_aborted", message);
+                return Ok(None);
+            }
+        };
+        match message {
+            Message::Handshake(handshake) => Ok(Some(handshake)),
+            message => Err(ProtocolError::UnexpectedMessage {
+                expected: "handshake message",
+                received: Box::new(message),
             }
+            .into()),
+        }
+    }
 
-            if self
-                .peer_id
-                .is_some_and(|expected| expected != handshake.peer_id)
-            {
-                bail!("Peer sent an incorrect peer ID");
+    /// Deal with reserved bits in the peer's handshake that we don't know about, as the
+    /// config says.
+    fn check_reserved_bits(&self, handshake: &Handshake) -> Result<(), ProtocolError> {
+        let unknown = handshake.unknown_reserved_bits();
+        if unknown == [0; 8] {
+            return Ok(());
+        }
+        match self.config.unknown_reserved_bits {
+            ReservedBitsPolicy::Ignore => Ok(()),
+            ReservedBitsPolicy::Warn => {
+                warn!(
+                    "Peer {} set unknown reserved bits {}",
+                    handshake.peer_id,
+                    hex::encode(unknown)
+                );
+                Ok(())
             }
-            self.peer_id = Some(handshake.peer_id);
+            ReservedBitsPolicy::Reject => Err(ProtocolError::UnknownReservedBits(unknown)),
+        }
+    }
 
-            let handle = self.handle.clone().ok_or_eyre("Handle not set")?;
-            self.torrent.act({
-                let handle = handle.clone();
-                move |torrent| {
-                    torrent.add_connection(handshake.peer_id, handle);
-                    Ok(Outcome::Continue)
-                }
-            })?;
+    /// Check the peer's handshake, and if it's valid, register the connection with the torrent
+    /// and start receiving messages.
+    fn complete_handshake(
+        &mut self,
+        handshake: Handshake,
+        connection_read: Box<dyn ConnectionRead + Send + 'static>,
+    ) -> Result<Outcome> {
+        let valid = handshake
+            .validate(self.info_hash, self.own_peer_id, self.peer_id)
+            .and_then(|()| self.check_reserved_bits(&handshake));
+        if let Err(e) = valid {
+            self.metrics.incr("handshake_rejected", 1);
+            self.observe(EventLevel::Warn, "handshake_rejected", e.to_string());
+            Err(e)?;
+        }
+        self.metrics.incr("handshake_completed", 1);
+        self.peer_id = Some(handshake.peer_id);
+        self.last_activity = self.clock.now();
+        let ours = self.config.reserved_bits;
+        self.peer_supports_extensions =
+            ours.extension_protocol && handshake.supports_extension_protocol();
+        self.fast_extension = ours.fast_extension && handshake.supports_fast();
+
+        // Only outgoing connections send their handshake before receiving one.
+        let outgoing = self.handshake_sent;
+        // On incoming connections we only reply once we know the peer is here for our torrent.
+        if !self.handshake_sent {
+            self.send_own_handshake()?;
+        }
 
-            info!("Connection established with peer {}", handshake.peer_id);
-            Self::start_receive_loop(connection_read, handle);
-        } else {
-            bail!("Expected handshake message, peer sent something else: {message:?}");
+        let handle = self.handle.clone().ok_or_eyre("Handle not set")?;
+        let peer_addr = self.peer_addr;
+        let registered = self.torrent.act({
+            let handle = handle.clone();
+            move |torrent| {
+                torrent.add_connection(handshake.peer_id, outgoing, peer_addr, handle)?;
+                Ok(Outcome::Continue)
+            }
+        });
+        if registered.is_err() {
+            // The torrent was shut down while the handshake was underway. It stops all of its
+            // connections, this one just never got to be one of them.
+            debug!(
+                "Torrent is gone, dropping the connection to peer {}",
+                handshake.peer_id
+            );
+            return Ok(Outcome::Stop);
         }
+        self.registered = true;
 
+        self.send_have_pieces()?;
+        let peer_addr = self.peer_addr;
+        let message = match handshake.peer_id.client_info() {
+            Some(client) => format!(
+                "Connection established with peer {} at {peer_addr:?} running {client}",
+                handshake.peer_id
+            ),
+            None => format!(
+                "Connection established with peer {} at {peer_addr:?}",
+                handshake.peer_id
+            ),
+        };
+        info!("{message}");
+        self.observe(EventLevel::Info, "handshake_completed", message);
+        Self::start_receive_loop(connection_read, handle, self.shutdown.clone());
         Ok(Outcome::Continue)
     }
 
+    /// Close a connection that the torrent doesn't want, e.g. because there already is
+    /// another connection to the same peer. The torrent isn't told about it closing,
+    /// as it has already forgotten about this connection.
+    pub fn reject(&mut self, reason: &'static str) -> Result<Outcome> {
+        info!("Closing connection to peer {:?}: {reason}", self.peer_id);
+        self.registered = false;
+        self.rejected = true;
+        Ok(Outcome::Stop)
+    }
+
     fn start_receive_loop(
         connection_read: Box<dyn ConnectionRead + Send>,
         handle: Handle<ConnectionActor>,
+        shutdown: ShutdownSignal,
     ) {
         // TODO: Join handle?
         let _ = std::thread::spawn(move || {
-            // `receive()` will block until a message is received, so it needs to be run in a
-            // separate thread.
-            while let Ok(message) = connection_read.receive() {
-                trace!("Actor received message: {:?}", message);
+            // Receiving blocks until a message arrives, so it needs to be run in a separate
+            // thread. It gives up every now and then to see if the actor has stopped.
+            while !shutdown.is_signalled() {
+                let message = match connection_read.receive_timeout(SHUTDOWN_POLL_INTERVAL) {
+                    Ok(Some(message)) => message,
+                    Ok(None) => continue,
+                    Err(_) => break,
+                };
+                trace!("Actor received message: {}", message);
+                // The actor might have been replaced by one with a connection of its own by
+                // the time this runs, which has no use for what came in on this one.
+                let stopped = shutdown.clone();
+                let handled = handle.act(move |connection| {
+                    if stopped.is_signalled() {
+                        return Ok(Outcome::Continue);
+                    }
+                    connection.handle_message(message)
+                });
+                if handled.is_err() {
+                    break;
+                }
+            }
+            // Once the actor stopped by itself there's nothing left to stop, and the handle
+            // might be another actor's by now.
+            if !shutdown.is_signalled() {
+                handle.stop().expect("thread to not panic");
             }
-            handle.stop().expect("thread to not panic");
         });
     }
 
-    /// Wait for a handshake from a peer on an incoming connection.
-    pub fn await_handshake(&mut self) -> Result<Outcome> {
-        // TODO: This has a lot of shared code with `initiate_handshake()`, refactor?
-        let connection_read = self.connection_read.take().expect("connection to be set");
-
-        let message = connection_read.receive()?;
-        if let Message::Handshake(handshake) = message {
-            if handshake.info_hash != self.info_hash {
-                bail!("Peer sent an incorrect info hash");
+    /// Handle a message received from the peer after the handshake.
+    fn handle_message(&mut self, message: Message) -> Result<Outcome> {
+        let peer_id = self.peer_id.ok_or_eyre("Peer not connected")?;
+        // Any message at all shows that the peer is still there, keep-alives included.
+        self.last_activity = self.clock.now();
+        match message {
+            Message::Handshake(handshake) => Err(ProtocolError::UnexpectedMessage {
+                expected: "anything but a second handshake",
+                received: Box::new(Message::Handshake(handshake)),
+            })?,
+            Message::Choke(_) => {
+                self.state.peer_choking = true;
+                // A choking peer discards all of our pending requests, unless it supports
+                // the Fast Extension, in which case it rejects them one by one.
+                if !self.fast_extension {
+                    let released: Vec<_> = self.outstanding_requests.drain().collect();
+                    self.release_blocks(peer_id, released)?;
+                }
+            }
+            Message::Unchoke(_) => {
+                self.state.peer_choking = false;
+                self.request_more_blocks(peer_id)?;
+            }
+            Message::Interested(_) => self.set_peer_interested(peer_id, true)?,
+            Message::NotInterested(_) => self.set_peer_interested(peer_id, false)?,
+            Message::Piece(piece) => self.receive_piece(peer_id, piece)?,
+            Message::Extended(extended) => return self.receive_extended(peer_id, &extended),
+            Message::RejectRequest(reject) => {
+                let request = Request::from(reject);
+                // The block isn't requested again right away, as the peer would likely
+                // reject it again. The pipeline fills back up as other blocks arrive.
+                if self.outstanding_requests.remove(&request) {
+                    self.release_blocks(peer_id, vec![request])?;
+                }
             }
+            Message::Request(request) => return self.receive_request(peer_id, request),
+            Message::Port(port) => self.receive_port(port)?,
+            Message::Bitfield(bitfield) => {
+                if let Some(piece_count) = self.piece_count {
+                    if !bitfield.fits(piece_count) {
+                        Err(ProtocolError::InvalidBitfield {
+                            length: bitfield.bytes.len(),
+                            piece_count,
+                        })?;
+                    }
+                }
+                self.torrent.act(move |torrent| {
+                    torrent.peer_has_pieces(peer_id, bitfield)?;
+                    Ok(Outcome::Continue)
+                })?;
+            }
+            Message::Have(have) => {
+                if let Some(piece_count) = self.piece_count {
+                    if have.index as usize >= piece_count {
+                        Err(ProtocolError::InvalidPieceIndex {
+                            index: have.index,
+                            piece_count,
+                        })?;
+                    }
+                }
+                self.torrent.act(move |torrent| {
+                    torrent.peer_has_piece(peer_id, have.index)?;
+                    Ok(Outcome::Continue)
+                })?;
+            }
+            Message::HaveAll(_) => self.torrent.act(move |torrent| {
+                torrent.peer_has_all(peer_id)?;
+                Ok(Outcome::Continue)
+            })?,
+            Message::HaveNone(_) => self.torrent.act(move |torrent| {
+                torrent.peer_has_pieces(peer_id, Bitfield::default())?;
+                Ok(Outcome::Continue)
+            })?,
+            Message::Cancel(_)
+            | Message::KeepAlive(_)
+            | Message::SuggestPiece(_)
+            | Message::AllowedFast(_)
+            | Message::Unknown(_) => {}
+        }
+        Ok(Outcome::Continue)
+    }
+
+    fn receive_port(&mut self, port: Port) -> Result<()> {
+        let Some(peer_addr) = self.peer_addr else {
+            trace!("Ignoring DHT port of peer without a known address");
+            return Ok(());
+        };
+        let dht_node = SocketAddr::new(peer_addr.ip(), port.port);
+        self.torrent.act(move |torrent| {
+            torrent.dht_node_found(dht_node);
+            Ok(Outcome::Continue)
+        })
+    }
 
-            if self
-                .peer_id
-                .is_some_and(|expected| expected != handshake.peer_id)
-            {
-                bail!("Peer sent an incorrect peer ID");
+    fn receive_extended(&mut self, peer_id: PeerId, extended: &Extended) -> Result<Outcome> {
+        match extended.id {
+            EXTENDED_HANDSHAKE_ID => {
+                let handshake = ExtendedHandshake::from_payload(&extended.payload)?;
+                self.peer_extensions = handshake.extensions;
+                debug!(
+                    "Peer {peer_id} supports extensions {:?}",
+                    self.peer_extensions()
+                );
+                let metadata_size = handshake
+                    .metadata_size
+                    .filter(|_| self.peer_extensions.contains_key(UT_METADATA));
+                if let Some(size) = metadata_size {
+                    self.torrent.act(move |torrent| {
+                        torrent.peer_has_metadata(peer_id, size)?;
+                        Ok(Outcome::Continue)
+                    })?;
+                }
+            }
+            UT_METADATA_ID => {
+                let metadata = Metadata::from_payload(&extended.payload)?;
+                self.torrent.act(move |torrent| {
+                    torrent.metadata_message(peer_id, metadata)?;
+                    Ok(Outcome::Continue)
+                })?;
             }
-            self.peer_id = Some(handshake.peer_id);
+            UT_PEX_ID => {
+                // Peer exchange is just a hint, so a bad message isn't worth the connection.
+                // The peer may have lost track of what we told it, though, so start over.
+                let pex = match Pex::from_payload(&extended.payload) {
+                    Ok(pex) => pex,
+                    Err(e) => {
+                        debug!("Peer {peer_id} sent a malformed peer exchange message: {e:?}");
+                        return Ok(Outcome::Restart);
+                    }
+                };
+                trace!("Peer {peer_id} knows about {} more peers", pex.added.len());
+                self.torrent.act(move |torrent| {
+                    torrent.pex_received(pex.added);
+                    Ok(Outcome::Continue)
+                })?;
+            }
+            id => trace!("Ignoring unsupported extended message {id}"),
+        }
+        Ok(Outcome::Continue)
+    }
+
+    fn receive_piece(&mut self, peer_id: PeerId, piece: Piece) -> Result<()> {
+        let request = Request::from(&piece);
+        if !self.outstanding_requests.remove(&request) {
+            trace!("Ignoring unrequested block {request:?} from peer {peer_id}");
+            return Ok(());
+        }
+        self.metrics
+            .incr("bytes_downloaded", piece.block.len() as u64);
+        self.torrent.act(move |torrent| {
+            torrent.record_download(peer_id, piece.block.len());
+            torrent.block_received(peer_id, request, piece.block)?;
+            Ok(Outcome::Continue)
+        })?;
+        self.request_more_blocks(peer_id)
+    }
 
+    fn receive_request(&mut self, peer_id: PeerId, request: Request) -> Result<Outcome> {
+        if self.state.am_choking {
+            trace!("Ignoring request {request:?} from choked peer {peer_id}");
+            return self.reject_request(request);
+        }
+        if request.length == 0 || request.length > self.config.max_request_size {
+            warn!(
+                "Peer {peer_id} requested a block of {} bytes",
+                request.length
+            );
+            return self.reject_request(request);
+        }
+        let now = self.clock.now();
+        self.served_requests
+            .retain(|_, served| now.saturating_duration_since(*served) < DUPLICATE_REQUEST_WINDOW);
+        if self.pending_requests.contains(&request) || self.served_requests.contains_key(&request) {
+            trace!("Ignoring duplicate request {request:?} from peer {peer_id}");
+            return Ok(Outcome::Continue);
+        }
+        if self.pending_requests.len() >= self.config.max_queued_requests {
+            warn!(
+                "Peer {peer_id} has more than {} requests waiting, disconnecting",
+                self.config.max_queued_requests
+            );
+            return Ok(Outcome::Stop);
+        }
+        self.pending_requests.insert(request);
+        self.torrent.act(move |torrent| {
+            torrent.block_requested(peer_id, request)?;
+            Ok(Outcome::Continue)
+        })?;
+        Ok(Outcome::Continue)
+    }
+
+    /// Turn down a request from the peer. Only peers with the Fast Extension are told,
+    /// others just never get an answer.
+    pub fn reject_request(&mut self, request: Request) -> Result<Outcome> {
+        self.pending_requests.remove(&request);
+        if self.fast_extension {
             self.connection_write
-                .send(Message::Handshake(Handshake::new(
-                    self.info_hash,
-                    self.own_peer_id,
-                )))?;
-
-            let handle = self.handle.clone().ok_or_eyre("Handle not set")?;
-            self.torrent.act({
-                let handle = handle.clone();
-                move |torrent| {
-                    torrent.add_connection(handshake.peer_id, handle);
-                    Ok(Outcome::Continue)
+                .send(Message::RejectRequest(RejectRequest::from(request)))?;
+        }
+        Ok(Outcome::Continue)
+    }
+
+    /// Called by the torrent in response to a request from the peer.
+    pub fn send_block(&mut self, piece: Piece) -> Result<Outcome> {
+        if self.state.am_choking {
+            // We choked the peer while the torrent was reading the block.
+            let request = Request::from(&piece);
+            return self.reject_request(request);
+        }
+        self.queued_blocks.push_back(piece);
+        self.send_queued_blocks()
+    }
+
+    /// Send as many queued blocks as the upload limit allows. A slow peer doesn't hold up the
+    /// actor: whatever the connection can't take right now is tried again on the next tick.
+    fn send_queued_blocks(&mut self) -> Result<Outcome> {
+        let mut uploaded = 0;
+        while let Some(piece) = self.queued_blocks.front() {
+            if !self.first_block_paid && !self.rate_limiters.upload.try_acquire(piece.block.len()) {
+                break;
+            }
+            let piece = self.queued_blocks.pop_front().expect("front to exist");
+            let length = piece.block.len();
+            let request = Request::from(&piece);
+            match self.connection_write.try_send(Message::Piece(piece))? {
+                SendStatus::WouldBlock(Message::Piece(piece)) => {
+                    self.queued_blocks.push_front(piece);
+                    self.first_block_paid = true;
+                    break;
                 }
+                // Nothing but the piece can be handed back.
+                SendStatus::Sent | SendStatus::WouldBlock(_) => {
+                    self.pending_requests.remove(&request);
+                    self.served_requests.insert(request, self.clock.now());
+                    self.metrics.incr("bytes_uploaded", length as u64);
+                    self.first_block_paid = false;
+                    uploaded += length;
+                }
+            }
+        }
+        if let Some(peer_id) = self.peer_id.filter(|_| uploaded > 0) {
+            self.torrent.act(move |torrent| {
+                torrent.record_upload(peer_id, uploaded);
+                Ok(Outcome::Continue)
             })?;
+        }
+        Ok(Outcome::Continue)
+    }
+
+    /// Called by the torrent every tick, to pick up transfers that were held back by the
+    /// rate limits.
+    pub fn resume_transfers(&mut self) -> Result<Outcome> {
+        if let Some(peer_id) = self.peer_id.filter(|_| self.registered) {
+            self.request_more_blocks(peer_id)?;
+        }
+        self.send_queued_blocks()
+    }
+
+    /// Ask the torrent for enough blocks to fill up the request pipeline.
+    fn request_more_blocks(&mut self, peer_id: PeerId) -> Result<()> {
+        if self.state.peer_choking {
+            return Ok(());
+        }
+        let in_use = self.outstanding_requests.len() + self.pending_assignments;
+        let free_slots = self.config.max_pipeline_depth.saturating_sub(in_use);
+        // Only ask for as many blocks as the download limit allows right now.
+        let block_size = self.config.block_size as usize;
+        let free_slots = (0..free_slots)
+            .take_while(|_| self.rate_limiters.download.try_acquire(block_size))
+            .count();
+        if free_slots == 0 {
+            return Ok(());
+        }
+        self.pending_assignments += free_slots;
+        self.torrent.act(move |torrent| {
+            torrent.assign_blocks(peer_id, free_slots)?;
+            Ok(Outcome::Continue)
+        })
+    }
+
+    /// Called by the torrent in response to [request_more_blocks](Self::request_more_blocks).
+    /// `asked_for` is how many blocks were asked for, `blocks` may contain fewer than that
+    /// if the torrent is running out of blocks to hand out.
+    pub fn request_blocks(&mut self, asked_for: usize, blocks: Vec<Request>) -> Result<Outcome> {
+        let peer_id = self.peer_id.ok_or_eyre("Peer not connected")?;
+        self.pending_assignments = self.pending_assignments.saturating_sub(asked_for);
+        if self.state.peer_choking {
+            // We got choked while waiting for the torrent, so hand the blocks back.
+            self.release_blocks(peer_id, blocks)?;
+            return Ok(Outcome::Continue);
+        }
+        let messages: Vec<_> = blocks.iter().copied().map(Message::Request).collect();
+        self.connection_write.send_all(&messages)?;
+        self.outstanding_requests.extend(blocks);
+        Ok(Outcome::Continue)
+    }
+
+    /// Tell the peer about pieces we completed, all in one write.
+    pub fn send_haves(&mut self, indices: Vec<u32>) -> Result<Outcome> {
+        let messages: Vec<_> = indices
+            .into_iter()
+            .map(|index| {
+                self.own_pieces.set(index as usize);
+                Message::Have(Have::new(index))
+            })
+            .collect();
+        self.connection_write.send_all(&messages)?;
+        Ok(Outcome::Continue)
+    }
+
+    /// Stop waiting for a block, e.g. because it's taking too long or another peer delivered it.
+    /// Does nothing if the block already arrived.
+    pub fn cancel_request(&mut self, request: Request) -> Result<Outcome> {
+        let peer_id = self.peer_id.ok_or_eyre("Peer not connected")?;
+        if self.outstanding_requests.remove(&request) {
+            self.connection_write
+                .send(Message::Cancel(Cancel::from(request)))?;
+            self.request_more_blocks(peer_id)?;
+        }
+        Ok(Outcome::Continue)
+    }
+
+    fn release_blocks(&mut self, peer_id: PeerId, blocks: Vec<Request>) -> Result<()> {
+        if blocks.is_empty() {
+            return Ok(());
+        }
+        self.torrent.act(move |torrent| {
+            for request in blocks {
+                torrent.release_block(peer_id, request);
+            }
+            Ok(Outcome::Continue)
+        })
+    }
+
+    fn set_peer_interested(&mut self, peer_id: PeerId, interested: bool) -> Result<()> {
+        self.state.peer_interested = interested;
+        self.torrent.act(move |torrent| {
+            torrent.set_peer_interested(peer_id, interested);
+            Ok(Outcome::Continue)
+        })
+    }
+
+    /// Called by the torrent whenever the peer's pieces or ours change. Only tells the peer
+    /// when our interest actually changes.
+    pub fn set_interested(&mut self, interested: bool) -> Result<Outcome> {
+        if self.state.am_interested != interested {
+            self.state.am_interested = interested;
+            let message = if interested {
+                Message::Interested(Interested)
+            } else {
+                Message::NotInterested(NotInterested)
+            };
+            self.connection_write.send(message)?;
+        }
+        Ok(Outcome::Continue)
+    }
 
-            info!("Connection established with peer {}", handshake.peer_id);
-            Self::start_receive_loop(connection_read, handle);
-        } else {
-            bail!("Expected handshake message, peer sent something else: {message:?}");
+    /// Stop answering the peer's requests.
+    pub fn choke(&mut self) -> Result<Outcome> {
+        if !self.state.am_choking {
+            self.state.am_choking = true;
+            self.connection_write.send(Message::Choke(Choke))?;
+            // The peer forgets about its requests when choked, so blocks still held back by
+            // the upload limit are dropped.
+            for piece in std::mem::take(&mut self.queued_blocks) {
+                self.reject_request(Request::from(&piece))?;
+            }
+            self.first_block_paid = false;
         }
+        Ok(Outcome::Continue)
+    }
 
+    /// Start answering the peer's requests.
+    pub fn unchoke(&mut self) -> Result<Outcome> {
+        if self.state.am_choking {
+            self.state.am_choking = false;
+            self.connection_write.send(Message::Unchoke(Unchoke))?;
+        }
         Ok(Outcome::Continue)
     }
 
@@ -166,13 +953,65 @@ impl Actor for ConnectionActor {
         self.handle = Some(handle.clone());
     }
 
+    /// Everything but peer exchange is kept, as the peer still knows about it.
+    fn restart(&mut self) {
+        self.pex_sent.clear();
+        self.last_pex = None;
+    }
+
     fn stop(&mut self) {
-        if let Some(peer_id) = self.peer_id {
-            let _ = self.torrent.act(move |torrent| {
-                torrent.remove_connection(peer_id);
-                Ok(Outcome::Continue)
-            });
+        self.shutdown.signal();
+        let established = self.registered;
+        if established {
+            self.metrics.incr("peer_disconnected", 1);
+            let message = format!("Disconnected from peer {:?}", self.peer_id);
+            self.observe(EventLevel::Info, "peer_disconnected", message);
+        }
+        let peer_id = self.peer_id.filter(|_| established);
+        let peer_addr = self.peer_addr.filter(|_| !self.rejected);
+        if peer_id.is_none() && peer_addr.is_none() {
+            return;
+        }
+        let handle = self.handle.clone();
+        let _ = self.torrent.act(move |torrent| {
+            if let Some((peer_id, handle)) = peer_id.zip(handle) {
+                torrent.connection_stopped(peer_id, &handle);
+            }
+            if let Some(peer_addr) = peer_addr {
+                torrent.connection_closed(peer_addr, established);
+            }
+            Ok(Outcome::Continue)
+        });
+    }
+}
+
+/// The write half of a connection, noting whether anything was sent through it, so that a
+/// keep-alive is only sent when the connection would otherwise go quiet.
+struct TrackedWrite {
+    inner: Box<dyn ConnectionWrite + Send + 'static>,
+    /// Set whenever a message is sent, cleared by [ConnectionActor::check_activity].
+    sent: bool,
+}
+
+impl ConnectionWrite for TrackedWrite {
+    fn send(&mut self, message: Message) -> Result<()> {
+        self.inner.send(message)?;
+        self.sent = true;
+        Ok(())
+    }
+
+    fn try_send(&mut self, message: Message) -> Result<SendStatus> {
+        let status = self.inner.try_send(message)?;
+        if status == SendStatus::Sent {
+            self.sent = true;
         }
+        Ok(status)
+    }
+
+    fn send_all(&mut self, messages: &[Message]) -> Result<()> {
+        self.inner.send_all(messages)?;
+        self.sent |= !messages.is_empty();
+        Ok(())
     }
 }
 
@@ -181,7 +1020,13 @@ impl Debug for ConnectionActor {
         f.debug_struct("ConnectionActor")
             .field("own_peer_id", &self.own_peer_id)
             .field("expected_peer_id", &self.peer_id)
+            .field("peer_addr", &self.peer_addr)
             .field("info_hash", &self.info_hash)
+            .field("config", &self.config)
+            .field("state", &self.state)
+            .field("fast_extension", &self.fast_extension)
+            .field("outstanding_requests", &self.outstanding_requests)
+            .field("peer_extensions", &self.peer_extensions)
             .field("torrent", &self.torrent)
             .field("handle", &self.handle)
             .finish_non_exhaustive()
@@ -191,92 +1036,120 @@ impl Debug for ConnectionActor {
 #[cfg(test)]
 mod tests {
     use std::collections::VecDeque;
-    use std::sync::{Arc, Mutex};
     use std::thread;
     use std::time::Duration;
     use thread::sleep;
 
-    use eyre::{eyre, Result};
-
-    use super::*;
+    use std::sync::atomic::Ordering;
+    use std::sync::{Arc, Mutex};
 
-    #[derive(Clone)]
-    struct MockConnection {
-        sent_messages: Arc<Mutex<Vec<Message>>>,
-        queued_for_receive: Arc<Mutex<VecDeque<Message>>>,
-    }
+    use crate::clock::{MockClock, SystemClock};
+    use crate::connections::mock_connection::MockConnection;
+    use crate::messages::{Have, EXTENSION_PROTOCOL_BIT, FAST_EXTENSION_BIT};
+    use crate::metrics::RecordingMetrics;
+    use crate::observer::RecordingObserver;
+    use crate::torrent::piece_selector::BLOCK_SIZE;
+    use crate::torrent::piece_store::PieceStore;
 
-    impl MockConnection {
-        fn new(queued_for_receive: VecDeque<Message>) -> Self {
-            Self {
-                sent_messages: Arc::default(),
-                queued_for_receive: Arc::new(Mutex::new(queued_for_receive)),
-            }
-        }
-    }
+    use super::*;
 
-    impl ConnectionRead for MockConnection {
-        fn receive(&self) -> Result<Message> {
-            self.queued_for_receive
-                .lock()
-                .unwrap()
-                .pop_front()
-                // This simulates not getting any more network messages for 1 second, then
-                // closing the connection.
-                // The reason for this is that the `receive()` method will block until a message
-                // is received, and in the test we want to verify that a connection exists -
-                // if it is closed instantly, there's no way to verify that.
-                .ok_or_else(|| {
-                    sleep(Duration::from_secs(1));
-                    eyre!("no message")
-                })
-        }
+    fn extension_handshake(info_hash: InfoHash, peer_id: PeerId) -> Handshake {
+        let mut reserved = [0; 8];
+        reserved[EXTENSION_PROTOCOL_BIT.0] |= EXTENSION_PROTOCOL_BIT.1;
+        Handshake::with_reserved(reserved, info_hash, peer_id)
     }
 
-    impl ConnectionWrite for MockConnection {
-        fn send(&mut self, message: Message) -> Result<()> {
-            self.sent_messages.lock().unwrap().push(message.clone());
-            Ok(())
-        }
+    /// The handshake we send, advertising every extension we support.
+    fn own_handshake(info_hash: InfoHash, peer_id: PeerId) -> Handshake {
+        let mut handshake = extension_handshake(info_hash, peer_id);
+        handshake.reserved[FAST_EXTENSION_BIT.0] |= FAST_EXTENSION_BIT.1;
+        handshake
     }
 
     #[test]
     fn initiate_handshake() {
-        // This test is a bit of a doozy.
-        // I would love to improve it, but it works for now.
+        // Both actors only run when pumped, so everything below happens in order.
         let client_id = PeerId::new([1; 20]);
         let server_id = PeerId::new([3; 20]);
         let info_hash = InfoHash::new([2; 20]);
-        let torrent_actor = Handle::spawn(TorrentActor::new(client_id, info_hash));
+        let torrent_actor = Handle::spawn_manual(TorrentActor::new(client_id, info_hash));
 
-        let client_handshake = Message::Handshake(Handshake::new(info_hash, client_id));
+        let client_handshake = Message::Handshake(own_handshake(info_hash, client_id));
         let server_handshake = Message::Handshake(Handshake::new(info_hash, server_id));
         let connection = MockConnection::new(VecDeque::from([server_handshake]));
 
-        let connection_actor = Handle::spawn(ConnectionActor::new(
+        let connection_actor = Handle::spawn_manual(ConnectionActor::new(
             client_id,
             None,
             connection.clone(),
             connection.clone(),
             info_hash,
             torrent_actor.clone(),
+            TorrentConfig::default(),
         ));
 
         connection_actor
             .act(ConnectionActor::initiate_handshake)
             .unwrap();
+        assert_eq!(connection_actor.run_pending(), 1);
+
+        let peer_id = connection_actor
+            .ask(|connection_actor| Ok(connection_actor.peer_id))
+            .unwrap();
+        assert_eq!(peer_id, Some(server_id));
+        assert!(torrent_actor
+            .ask(move |torrent_actor| Ok(torrent_actor.has_connection(server_id)))
+            .unwrap());
+        assert_eq!(
+            *connection.sent_messages.lock().unwrap(),
+            vec![client_handshake]
+        );
+        assert_eq!(*connection.queued_for_receive.lock().unwrap(), vec![]);
+
+        connection_actor.stop().unwrap();
+        assert!(!torrent_actor
+            .ask(move |torrent_actor| Ok(torrent_actor.has_connection(server_id)))
+            .unwrap());
+
+        torrent_actor.stop().unwrap();
+    }
+
+    #[test]
+    fn await_handshake() {
+        let server_id = PeerId::new([1; 20]);
+        let client_id = PeerId::new([3; 20]);
+        let info_hash = InfoHash::new([2; 20]);
+        let torrent_actor = Handle::spawn(TorrentActor::new(server_id, info_hash));
+
+        let client_handshake = Message::Handshake(Handshake::new(info_hash, client_id));
+        let server_handshake = Message::Handshake(own_handshake(info_hash, server_id));
+        let connection = MockConnection::new(VecDeque::from([client_handshake]));
+
+        let connection_actor = Handle::spawn(ConnectionActor::new(
+            server_id,
+            None,
+            connection.clone(),
+            connection.clone(),
+            info_hash,
+            torrent_actor.clone(),
+            TorrentConfig::default(),
+        ));
+
+        connection_actor
+            .act(ConnectionActor::await_handshake)
+            .unwrap();
 
         sleep(Duration::from_millis(100));
 
         connection_actor
             .act(move |connection_actor| {
-                assert_eq!(Some(server_id), connection_actor.peer_id);
+                assert_eq!(Some(client_id), connection_actor.peer_id);
                 Ok(Outcome::Continue)
             })
             .unwrap();
         torrent_actor
             .act(move |torrent_actor| {
-                assert!(torrent_actor.has_connection(server_id));
+                assert!(torrent_actor.has_connection(client_id));
                 Ok(Outcome::Continue)
             })
             .unwrap();
@@ -285,9 +1158,8 @@ mod tests {
 
         assert_eq!(
             *connection.sent_messages.lock().unwrap(),
-            vec![client_handshake]
+            vec![server_handshake]
         );
-        assert_eq!(*connection.queued_for_receive.lock().unwrap(), vec![]);
 
         connection_actor.stop().unwrap();
 
@@ -295,12 +1167,1028 @@ mod tests {
 
         torrent_actor
             .act(move |torrent_actor| {
-                assert!(!torrent_actor.has_connection(server_id));
+                assert!(!torrent_actor.has_connection(client_id));
                 Ok(Outcome::Continue)
             })
             .unwrap();
 
-        sleep(Duration::from_millis(100));
+        torrent_actor.stop().unwrap();
+    }
+
+    #[test]
+    fn handshake_after_the_torrent_is_gone_stops_quietly() {
+        let own_id = PeerId::new([1; 20]);
+        let info_hash = InfoHash::new([2; 20]);
+        let torrent_actor = Handle::spawn(TorrentActor::new(own_id, info_hash));
+        let peer_handshake = Message::Handshake(Handshake::new(info_hash, PeerId::new([3; 20])));
+        let connection = MockConnection::new(VecDeque::from([peer_handshake]));
+        let connection_actor = Handle::spawn(ConnectionActor::new(
+            own_id,
+            None,
+            connection.clone(),
+            connection.clone(),
+            info_hash,
+            torrent_actor.clone(),
+            TorrentConfig::default(),
+        ));
+
+        torrent_actor.stop().unwrap();
+        let stopped = connection_actor
+            .ask(|connection| {
+                let outcome = connection.await_handshake()?;
+                Ok(matches!(outcome, Outcome::Stop))
+            })
+            .unwrap();
+
+        assert!(stopped);
+        connection_actor.stop().unwrap();
+    }
+
+    #[test]
+    fn unknown_reserved_bits_are_only_rejected_when_strict() {
+        let own_id = PeerId::new([1; 20]);
+        let peer_id = PeerId::new([3; 20]);
+        let info_hash = InfoHash::new([2; 20]);
+        let mut reserved = [0; 8];
+        reserved[0] = 0x80;
+        let peer_handshake = Handshake::with_reserved(reserved, info_hash, peer_id);
+        assert_eq!(peer_handshake.unknown_reserved_bits(), reserved);
+
+        for (policy, accepted) in [
+            (ReservedBitsPolicy::Ignore, true),
+            (ReservedBitsPolicy::Warn, true),
+            (ReservedBitsPolicy::Reject, false),
+        ] {
+            let torrent_actor = Handle::spawn(TorrentActor::new(own_id, info_hash));
+            let connection =
+                MockConnection::new(VecDeque::from([Message::Handshake(peer_handshake)]));
+            let connection_actor = Handle::spawn(ConnectionActor::new(
+                own_id,
+                None,
+                connection.clone(),
+                connection.clone(),
+                info_hash,
+                torrent_actor.clone(),
+                TorrentConfig {
+                    unknown_reserved_bits: policy,
+                    ..TorrentConfig::default()
+                },
+            ));
+            connection_actor
+                .act(ConnectionActor::await_handshake)
+                .unwrap();
+            sleep(Duration::from_millis(100));
+
+            let connected = torrent_actor
+                .ask(move |torrent| Ok(torrent.has_connection(peer_id)))
+                .unwrap();
+            assert_eq!(connected, accepted, "{policy:?}");
+            assert_eq!(connection_actor.is_running(), accepted, "{policy:?}");
+            let _ = connection_actor.stop();
+            torrent_actor.stop().unwrap();
+        }
+    }
+
+    #[test]
+    fn metrics_count_handshakes_and_disconnects() {
+        let server_id = PeerId::new([1; 20]);
+        let client_id = PeerId::new([3; 20]);
+        let info_hash = InfoHash::new([2; 20]);
+        let metrics = RecordingMetrics::default();
+        let mut torrent = TorrentActor::new(server_id, info_hash);
+        torrent.set_metrics_sink(Arc::new(metrics.clone()));
+        let torrent_actor = Handle::spawn(torrent);
+        let client_handshake = Message::Handshake(Handshake::new(info_hash, client_id));
+        let connection = MockConnection::new(VecDeque::from([client_handshake]));
+
+        let connection_actor = Handle::spawn(
+            ConnectionActor::new(
+                server_id,
+                None,
+                connection.clone(),
+                connection.clone(),
+                info_hash,
+                torrent_actor.clone(),
+                TorrentConfig::default(),
+            )
+            .with_metrics(Arc::new(metrics.clone())),
+        );
+        connection_actor
+            .act(ConnectionActor::await_handshake)
+            .unwrap();
+        sleep(Duration::from_millis(100));
+
+        assert_eq!(metrics.counter("handshake_completed"), 1);
+        assert_eq!(metrics.counter("handshake_rejected"), 0);
+        assert_eq!(metrics.gauge_value("connections"), Some(1.0));
+        assert_eq!(metrics.counter("peer_disconnected"), 0);
+
+        connection_actor.stop().unwrap();
+        connection_actor.wait().unwrap();
+        torrent_actor.ask(|_| Ok(())).unwrap();
+
+        assert_eq!(metrics.counter("peer_disconnected"), 1);
+        assert_eq!(metrics.gauge_value("connections"), Some(0.0));
+        torrent_actor.stop().unwrap();
+    }
+
+    #[test]
+    fn peer_closing_during_handshake_stops_cleanly() {
+        let server_id = PeerId::new([1; 20]);
+        let info_hash = InfoHash::new([2; 20]);
+        let torrent_actor = Handle::spawn(TorrentActor::new(server_id, info_hash));
+        let connection = MockConnection::closed();
+        let observer = RecordingObserver::default();
+
+        let connection_actor = Handle::spawn(
+            ConnectionActor::new(
+                server_id,
+                None,
+                connection.clone(),
+                connection.clone(),
+                info_hash,
+                torrent_actor.clone(),
+                TorrentConfig::default(),
+            )
+            .with_observer(Arc::new(observer.clone())),
+        );
+        connection_actor
+            .act(ConnectionActor::await_handshake)
+            .unwrap();
+
+        connection_actor.wait().unwrap();
+
+        assert!(!connection_actor.is_running());
+        // It happens all the time, so it's nothing to tell anyone about.
+        let levels: Vec<_> = observer
+            .records()
+            .iter()
+            .map(|record| (record.event, record.level))
+            .collect();
+        assert_eq!(levels, [("handshake_aborted", EventLevel::Debug)]);
+        assert!(connection.sent_messages.lock().unwrap().is_empty());
+        assert_eq!(
+            torrent_actor
+                .ask(|torrent_actor| Ok(torrent_actor.peer_count()))
+                .unwrap(),
+            0
+        );
+        torrent_actor.stop().unwrap();
+    }
+
+    #[test]
+    fn unchoke_fills_request_pipeline() {
+        let client_id = PeerId::new([1; 20]);
+        let server_id = PeerId::new([3; 20]);
+        let info_hash = InfoHash::new([2; 20]);
+        let config = TorrentConfig {
+            max_pipeline_depth: 3,
+            ..TorrentConfig::default()
+        };
+        let mut torrent =
+            TorrentActor::with_config(client_id, info_hash, config, Arc::new(SystemClock));
+        torrent.set_piece_layout(4 * BLOCK_SIZE, u64::from(4 * BLOCK_SIZE) * 10);
+        let torrent_actor = Handle::spawn(torrent);
+
+        let client_handshake = Message::Handshake(own_handshake(info_hash, client_id));
+        let server_handshake = Message::Handshake(Handshake::new(info_hash, server_id));
+        let connection = MockConnection::new(VecDeque::from([
+            server_handshake,
+            Message::Unchoke(Unchoke),
+        ]));
+
+        let connection_actor = Handle::spawn(ConnectionActor::new(
+            client_id,
+            None,
+            connection.clone(),
+            connection.clone(),
+            info_hash,
+            torrent_actor.clone(),
+            config,
+        ));
+        connection_actor
+            .act(ConnectionActor::initiate_handshake)
+            .unwrap();
+
+        sleep(Duration::from_millis(200));
+
+        assert_eq!(
+            *connection.sent_messages.lock().unwrap(),
+            vec![
+                client_handshake,
+                Message::Request(Request::new(0, 0, BLOCK_SIZE)),
+                Message::Request(Request::new(0, BLOCK_SIZE, BLOCK_SIZE)),
+                Message::Request(Request::new(0, 2 * BLOCK_SIZE, BLOCK_SIZE)),
+            ]
+        );
+
+        connection_actor.stop().unwrap();
+        torrent_actor.stop().unwrap();
+    }
+
+    #[test]
+    fn exchanges_extended_handshakes() {
+        let client_id = PeerId::new([1; 20]);
+        let server_id = PeerId::new([3; 20]);
+        let info_hash = InfoHash::new([2; 20]);
+        let torrent_actor = Handle::spawn(TorrentActor::new(client_id, info_hash));
+
+        let server_extensions = BTreeMap::from([(UT_METADATA.to_string(), 3)]);
+        let mut server_extended_handshake = ExtendedHandshake::new(server_extensions.clone());
+        server_extended_handshake.metadata_size = Some(100);
+        let connection = MockConnection::new(VecDeque::from([
+            Message::Handshake(extension_handshake(info_hash, server_id)),
+            Message::Extended(server_extended_handshake.to_message()),
+        ]));
+
+        let connection_actor = Handle::spawn(ConnectionActor::new(
+            client_id,
+            None,
+            connection.clone(),
+            connection.clone(),
+            info_hash,
+            torrent_actor.clone(),
+            TorrentConfig::default(),
+        ));
+        connection_actor
+            .act(ConnectionActor::initiate_handshake)
+            .unwrap();
+
+        sleep(Duration::from_millis(200));
+
+        assert_eq!(
+            *connection.sent_messages.lock().unwrap(),
+            vec![
+                Message::Handshake(own_handshake(info_hash, client_id)),
+                Message::Extended(
+                    ExtendedHandshake::new(BTreeMap::from([
+                        (UT_METADATA.to_string(), UT_METADATA_ID),
+                        (UT_PEX.to_string(), UT_PEX_ID),
+                    ]))
+                    .to_message()
+                ),
+                // The torrent has no metadata, so it asks the peer for it.
+                Message::Extended(Metadata::Request { piece: 0 }.to_message(3)),
+            ]
+        );
+        connection_actor
+            .act(move |connection_actor| {
+                assert_eq!(connection_actor.peer_extensions(), &server_extensions);
+                Ok(Outcome::Continue)
+            })
+            .unwrap();
+
+        connection_actor.stop().unwrap();
+        torrent_actor.stop().unwrap();
+    }
+
+    #[test]
+    fn pex_only_sends_changes_once_per_interval() {
+        let client_id = PeerId::new([1; 20]);
+        let info_hash = InfoHash::new([2; 20]);
+        let torrent_actor = Handle::spawn(TorrentActor::new(client_id, info_hash));
+        let connection = MockConnection::new(VecDeque::new());
+        let clock = MockClock::new();
+        let mut connection_actor = ConnectionActor::new(
+            client_id,
+            None,
+            connection.clone(),
+            connection.clone(),
+            info_hash,
+            torrent_actor.clone(),
+            TorrentConfig::default(),
+        )
+        .with_clock(Arc::new(clock.clone()));
+        connection_actor
+            .peer_extensions
+            .insert(UT_PEX.to_string(), 7);
+        let first = SocketAddr::from(([10, 0, 0, 1], 6881));
+        let second = SocketAddr::from(([10, 0, 0, 2], 6881));
+
+        connection_actor
+            .send_pex(BTreeSet::from([first, second]))
+            .unwrap();
+        connection_actor.send_pex(BTreeSet::from([second])).unwrap();
+        clock.advance(PEX_INTERVAL);
+        connection_actor.send_pex(BTreeSet::from([second])).unwrap();
+
+        let pex = |added, dropped| Message::Extended(Pex { added, dropped }.to_message(7));
+        assert_eq!(
+            *connection.sent_messages.lock().unwrap(),
+            vec![pex(vec![first, second], vec![]), pex(vec![], vec![first])]
+        );
+        torrent_actor.stop().unwrap();
+    }
+
+    #[test]
+    fn reject_request_clears_outstanding_request() {
+        let client_id = PeerId::new([1; 20]);
+        let server_id = PeerId::new([3; 20]);
+        let info_hash = InfoHash::new([2; 20]);
+        let torrent_actor = Handle::spawn(TorrentActor::new(client_id, info_hash));
+        let connection = MockConnection::new(VecDeque::new());
+        let mut connection_actor = ConnectionActor::new(
+            client_id,
+            Some(server_id),
+            connection.clone(),
+            connection.clone(),
+            info_hash,
+            torrent_actor.clone(),
+            TorrentConfig::default(),
+        );
+        connection_actor.fast_extension = true;
+        connection_actor.state.peer_choking = false;
+
+        let rejected = Request::new(0, 0, BLOCK_SIZE);
+        let kept = Request::new(0, BLOCK_SIZE, BLOCK_SIZE);
+        connection_actor
+            .request_blocks(0, vec![rejected, kept])
+            .unwrap();
+        // With the Fast Extension, choking doesn't discard requests by itself.
+        connection_actor
+            .handle_message(Message::Choke(Choke))
+            .unwrap();
+        connection_actor
+            .handle_message(Message::RejectRequest(RejectRequest::from(rejected)))
+            .unwrap();
+
+        assert_eq!(connection_actor.outstanding_requests, HashSet::from([kept]));
+
+        torrent_actor.stop().unwrap();
+    }
+
+    #[test]
+    fn port_is_reported_to_dht_node_callback() {
+        let client_id = PeerId::new([1; 20]);
+        let server_id = PeerId::new([3; 20]);
+        let info_hash = InfoHash::new([2; 20]);
+        let dht_nodes = Arc::new(Mutex::new(Vec::new()));
+        let mut torrent = TorrentActor::new(client_id, info_hash);
+        torrent.set_dht_node_callback({
+            let dht_nodes = dht_nodes.clone();
+            move |addr| dht_nodes.lock().unwrap().push(addr)
+        });
+        let torrent_actor = Handle::spawn(torrent);
+        let connection = MockConnection::new(VecDeque::new());
+        let peer_addr = SocketAddr::from(([10, 0, 0, 1], 51413));
+        let mut connection_actor = ConnectionActor::new(
+            client_id,
+            Some(server_id),
+            connection.clone(),
+            connection.clone(),
+            info_hash,
+            torrent_actor.clone(),
+            TorrentConfig::default(),
+        )
+        .with_peer_addr(Some(peer_addr));
+
+        connection_actor
+            .handle_message(Message::Port(Port::new(6881)))
+            .unwrap();
+
+        sleep(Duration::from_millis(100));
+        assert_eq!(
+            *dht_nodes.lock().unwrap(),
+            vec![SocketAddr::from(([10, 0, 0, 1], 6881))]
+        );
+
+        torrent_actor.stop().unwrap();
+    }
+
+    #[test]
+    fn self_connection_is_rejected() {
+        let own_id = PeerId::new([1; 20]);
+        let info_hash = InfoHash::new([2; 20]);
+        let torrent_actor = Handle::spawn(TorrentActor::new(own_id, info_hash));
+
+        for outgoing in [true, false] {
+            let echoed_handshake = Message::Handshake(own_handshake(info_hash, own_id));
+            let connection = MockConnection::new(VecDeque::from([echoed_handshake]));
+            let connection_actor = Handle::spawn(ConnectionActor::new(
+                own_id,
+                None,
+                connection.clone(),
+                connection.clone(),
+                info_hash,
+                torrent_actor.clone(),
+                TorrentConfig::default(),
+            ));
+
+            if outgoing {
+                connection_actor
+                    .act(ConnectionActor::initiate_handshake)
+                    .unwrap();
+            } else {
+                connection_actor
+                    .act(ConnectionActor::await_handshake)
+                    .unwrap();
+            }
+            sleep(Duration::from_millis(100));
+
+            assert!(connection_actor.act(|_| Ok(Outcome::Continue)).is_err());
+            torrent_actor
+                .act(move |torrent_actor| {
+                    assert!(!torrent_actor.has_connection(own_id));
+                    Ok(Outcome::Continue)
+                })
+                .unwrap();
+        }
+
+        sleep(Duration::from_millis(100));
+        torrent_actor.stop().unwrap();
+    }
+
+    #[test]
+    fn serves_requested_blocks_to_unchoked_peers() {
+        let own_id = PeerId::new([1; 20]);
+        let peer_id = PeerId::new([3; 20]);
+        let info_hash = InfoHash::new([2; 20]);
+        let data: Vec<u8> = (0..BLOCK_SIZE).map(|i| i as u8).collect();
+        let mut torrent = TorrentActor::new(own_id, info_hash);
+        torrent.set_piece_layout(BLOCK_SIZE, u64::from(BLOCK_SIZE));
+        torrent
+            .block_received(peer_id, Request::new(0, 0, BLOCK_SIZE), data.clone())
+            .unwrap();
+        let torrent_actor = Handle::spawn(torrent);
+
+        let peer_handshake = Message::Handshake(Handshake::new(info_hash, peer_id));
+        let connection = MockConnection::new(VecDeque::from([peer_handshake]));
+        let connection_actor = Handle::spawn(ConnectionActor::new(
+            own_id,
+            None,
+            connection.clone(),
+            connection.clone(),
+            info_hash,
+            torrent_actor.clone(),
+            TorrentConfig::default(),
+        ));
+        connection_actor
+            .act(ConnectionActor::await_handshake)
+            .unwrap();
+        sleep(Duration::from_millis(100));
+
+        let request = |request| {
+            move |connection: &mut ConnectionActor| {
+                connection.handle_message(Message::Request(request))
+            }
+        };
+        connection_actor
+            .act(request(Request::new(0, 0, 100)))
+            .unwrap();
+        sleep(Duration::from_millis(100));
+        assert_eq!(connection.sent_messages.lock().unwrap().len(), 1);
+
+        connection_actor.act(ConnectionActor::unchoke).unwrap();
+        connection_actor
+            .act(request(Request::new(0, 16, 100)))
+            .unwrap();
+        // Too big, and out of range
+        connection_actor
+            .act(request(Request::new(0, 0, BLOCK_SIZE + 1)))
+            .unwrap();
+        connection_actor
+            .act(request(Request::new(1, 0, 100)))
+            .unwrap();
+        sleep(Duration::from_millis(100));
+
+        assert_eq!(
+            *connection.sent_messages.lock().unwrap(),
+            vec![
+                Message::Handshake(own_handshake(info_hash, own_id)),
+                Message::Unchoke(Unchoke),
+                Message::Piece(Piece::new(0, 16, data[16..116].to_vec())),
+            ]
+        );
+
+        connection_actor.stop().unwrap();
+        torrent_actor.stop().unwrap();
+    }
+
+    #[test]
+    fn requests_past_the_end_of_a_piece_are_rejected() {
+        /// Keeps all pieces back to back, so it reads across piece boundaries like the
+        /// files of a torrent do.
+        #[derive(Debug, Default)]
+        struct ContiguousStore(Vec<u8>);
+
+        impl PieceStore for ContiguousStore {
+            fn write_block(&mut self, index: u32, begin: u32, block: &[u8]) -> Result<()> {
+                let start = (index * BLOCK_SIZE + begin) as usize;
+                if self.0.len() < start + block.len() {
+                    self.0.resize(start + block.len(), 0);
+                }
+                self.0[start..start + block.len()].copy_from_slice(block);
+                Ok(())
+            }
+
+            fn read_block(&self, index: u32, begin: u32, length: u32) -> Result<Vec<u8>> {
+                let start = (index * BLOCK_SIZE + begin) as usize;
+                self.0
+                    .get(start..start + length as usize)
+                    .map(<[u8]>::to_vec)
+                    .ok_or_eyre("Block not stored")
+            }
+        }
+
+        let own_id = PeerId::new([1; 20]);
+        let peer_id = PeerId::new([3; 20]);
+        let info_hash = InfoHash::new([2; 20]);
+        let mut torrent = TorrentActor::new(own_id, info_hash);
+        torrent.set_piece_layout(BLOCK_SIZE, 2 * u64::from(BLOCK_SIZE));
+        torrent.set_piece_store(Box::new(ContiguousStore::default()));
+        for index in 0..2 {
+            torrent
+                .block_received(
+                    peer_id,
+                    Request::new(index, 0, BLOCK_SIZE),
+                    vec![7; BLOCK_SIZE as usize],
+                )
+                .unwrap();
+        }
+        let torrent_actor = Handle::spawn(torrent);
+        let peer_handshake = Message::Handshake(Handshake::new(info_hash, peer_id));
+        let connection = MockConnection::new(VecDeque::from([peer_handshake]));
+        let connection_actor = Handle::spawn(ConnectionActor::new(
+            own_id,
+            None,
+            connection.clone(),
+            connection.clone(),
+            info_hash,
+            torrent_actor.clone(),
+            TorrentConfig::default(),
+        ));
+        connection_actor
+            .act(ConnectionActor::await_handshake)
+            .unwrap();
+        connection_actor.act(ConnectionActor::unchoke).unwrap();
+
+        let crossing = Request::new(0, BLOCK_SIZE - 10, 100);
+        connection_actor
+            .act(move |connection| connection.handle_message(Message::Request(crossing)))
+            .unwrap();
+        sleep(Duration::from_millis(100));
+
+        let sent = connection.sent_messages.lock().unwrap().clone();
+        assert!(
+            !sent
+                .iter()
+                .any(|message| matches!(message, Message::Piece(_))),
+            "{sent:?}"
+        );
+        connection_actor.stop().unwrap();
+        torrent_actor.stop().unwrap();
+    }
+
+    /// A connection to a peer we've unchoked, for a torrent that has a single block.
+    fn serving_connection() -> (
+        Handle<TorrentActor>,
+        Handle<ConnectionActor>,
+        MockConnection,
+    ) {
+        let own_id = PeerId::new([1; 20]);
+        let peer_id = PeerId::new([3; 20]);
+        let info_hash = InfoHash::new([2; 20]);
+        let mut torrent = TorrentActor::new(own_id, info_hash);
+        torrent.set_piece_layout(BLOCK_SIZE, u64::from(BLOCK_SIZE));
+        torrent
+            .block_received(
+                peer_id,
+                Request::new(0, 0, BLOCK_SIZE),
+                vec![7; BLOCK_SIZE as usize],
+            )
+            .unwrap();
+        let torrent_actor = Handle::spawn(torrent);
+        let peer_handshake = Message::Handshake(Handshake::new(info_hash, peer_id));
+        let connection = MockConnection::new(VecDeque::from([peer_handshake]));
+        let connection_actor = Handle::spawn(ConnectionActor::new(
+            own_id,
+            None,
+            connection.clone(),
+            connection.clone(),
+            info_hash,
+            torrent_actor.clone(),
+            TorrentConfig::default(),
+        ));
+        connection_actor
+            .act(ConnectionActor::await_handshake)
+            .unwrap();
+        connection_actor.act(ConnectionActor::unchoke).unwrap();
+        sleep(Duration::from_millis(100));
+        (torrent_actor, connection_actor, connection)
+    }
+
+    #[test]
+    fn duplicate_requests_are_only_served_once() {
+        let (torrent_actor, connection_actor, connection) = serving_connection();
+        let request = Request::new(0, 0, 100);
+
+        for _ in 0..2 {
+            connection_actor
+                .act(move |connection| connection.handle_message(Message::Request(request)))
+                .unwrap();
+        }
+        sleep(Duration::from_millis(100));
+        connection_actor
+            .act(move |connection| connection.handle_message(Message::Request(request)))
+            .unwrap();
+        sleep(Duration::from_millis(100));
+
+        let sent = connection.sent_messages.lock().unwrap().clone();
+        let pieces = sent
+            .iter()
+            .filter(|message| matches!(message, Message::Piece(_)))
+            .count();
+        assert_eq!(pieces, 1, "{sent:?}");
+        connection_actor.stop().unwrap();
+        torrent_actor.stop().unwrap();
+    }
+
+    #[test]
+    fn peer_with_too_many_queued_requests_is_dropped() {
+        let (torrent_actor, connection_actor, _connection) = serving_connection();
+        let max = TorrentConfig::default().max_queued_requests;
+
+        // All in one go, so the torrent can't answer any of them in between.
+        connection_actor
+            .act(move |connection| {
+                for begin in 0..max {
+                    let request = Request::new(0, begin as u32, 1);
+                    let outcome = connection.handle_message(Message::Request(request))?;
+                    assert!(matches!(outcome, Outcome::Continue));
+                }
+                let one_too_many = Request::new(0, max as u32, 1);
+                connection.handle_message(Message::Request(one_too_many))
+            })
+            .unwrap();
+        sleep(Duration::from_millis(100));
+
+        assert!(connection_actor.act(|_| Ok(Outcome::Continue)).is_err());
+        torrent_actor.stop().unwrap();
+    }
+
+    #[test]
+    fn blocks_wait_for_a_backed_up_connection() {
+        let own_id = PeerId::new([1; 20]);
+        let peer_id = PeerId::new([3; 20]);
+        let info_hash = InfoHash::new([2; 20]);
+        let torrent_actor = Handle::spawn(TorrentActor::new(own_id, info_hash));
+        let peer_handshake = Message::Handshake(Handshake::new(info_hash, peer_id));
+        let connection = MockConnection::new(VecDeque::from([peer_handshake]));
+        let connection_actor = Handle::spawn(ConnectionActor::new(
+            own_id,
+            None,
+            connection.clone(),
+            connection.clone(),
+            info_hash,
+            torrent_actor.clone(),
+            TorrentConfig::default(),
+        ));
+        connection_actor
+            .act(ConnectionActor::await_handshake)
+            .unwrap();
+        connection_actor.act(ConnectionActor::unchoke).unwrap();
+        sleep(Duration::from_millis(100));
+        let piece = Piece::new(0, 0, vec![1; 100]);
+
+        connection.would_block.store(true, Ordering::SeqCst);
+        let sent = piece.clone();
+        connection_actor
+            .act(move |connection| connection.send_block(sent))
+            .unwrap();
+        // The actor isn't stuck on the connection, it still answers.
+        assert!(connection_actor
+            .ask(|connection| Ok(connection.queued_blocks.len() == 1))
+            .unwrap());
+
+        connection.would_block.store(false, Ordering::SeqCst);
+        connection_actor
+            .act(ConnectionActor::resume_transfers)
+            .unwrap();
+        sleep(Duration::from_millis(100));
+
+        assert_eq!(
+            connection.sent_messages.lock().unwrap().last(),
+            Some(&Message::Piece(piece))
+        );
+        connection_actor.stop().unwrap();
+        torrent_actor.stop().unwrap();
+    }
+
+    #[test]
+    fn uploads_are_held_back_by_the_upload_limit() {
+        let own_id = PeerId::new([1; 20]);
+        let peer_id = PeerId::new([3; 20]);
+        let info_hash = InfoHash::new([2; 20]);
+        let torrent_actor = Handle::spawn(TorrentActor::new(own_id, info_hash));
+        let clock = MockClock::new();
+        let rate_limiters = RateLimiters::unlimited(Arc::new(clock.clone()));
+        rate_limiters.upload.set_rate(Some(100));
+
+        let peer_handshake = Message::Handshake(Handshake::new(info_hash, peer_id));
+        let connection = MockConnection::new(VecDeque::from([peer_handshake]));
+        let connection_actor = Handle::spawn(
+            ConnectionActor::new(
+                own_id,
+                None,
+                connection.clone(),
+                connection.clone(),
+                info_hash,
+                torrent_actor.clone(),
+                TorrentConfig::default(),
+            )
+            .with_rate_limiters(rate_limiters),
+        );
+        connection_actor
+            .act(ConnectionActor::await_handshake)
+            .unwrap();
+        connection_actor.act(ConnectionActor::unchoke).unwrap();
+        let first = Piece::new(0, 0, vec![1; 100]);
+        let second = Piece::new(0, 100, vec![2; 100]);
+        for piece in [first.clone(), second.clone()] {
+            connection_actor
+                .act(move |connection| connection.send_block(piece))
+                .unwrap();
+        }
+        sleep(Duration::from_millis(100));
+
+        assert_eq!(
+            connection.sent_messages.lock().unwrap().last(),
+            Some(&Message::Piece(first))
+        );
+
+        clock.advance(Duration::from_secs(1));
+        connection_actor
+            .act(ConnectionActor::resume_transfers)
+            .unwrap();
+        sleep(Duration::from_millis(100));
+
+        assert_eq!(
+            connection.sent_messages.lock().unwrap().last(),
+            Some(&Message::Piece(second))
+        );
+
+        connection_actor.stop().unwrap();
+        torrent_actor.stop().unwrap();
+    }
+
+    #[test]
+    fn silent_peer_is_disconnected() {
+        let own_id = PeerId::new([1; 20]);
+        let peer_id = PeerId::new([3; 20]);
+        let info_hash = InfoHash::new([2; 20]);
+        let torrent_actor = Handle::spawn(TorrentActor::new(own_id, info_hash));
+        let clock = MockClock::new();
+        let config = TorrentConfig::default();
+
+        let peer_handshake = Message::Handshake(Handshake::new(info_hash, peer_id));
+        let connection = MockConnection::new(VecDeque::from([peer_handshake]));
+        let connection_actor = Handle::spawn(
+            ConnectionActor::new(
+                own_id,
+                None,
+                connection.clone(),
+                connection.clone(),
+                info_hash,
+                torrent_actor.clone(),
+                config,
+            )
+            .with_clock(Arc::new(clock.clone())),
+        );
+        connection_actor
+            .act(ConnectionActor::await_handshake)
+            .unwrap();
+
+        // A keep-alive halfway through resets the timeout.
+        clock.advance(config.inactivity_timeout / 2);
+        connection_actor
+            .ask(|connection| connection.handle_message(Message::KeepAlive(KeepAlive)))
+            .unwrap();
+        clock.advance(config.inactivity_timeout);
+        connection_actor
+            .act(ConnectionActor::check_activity)
+            .unwrap();
+        sleep(Duration::from_millis(50));
+        assert!(connection_actor.is_running());
+
+        clock.advance(Duration::from_secs(1));
+        connection_actor
+            .act(ConnectionActor::check_activity)
+            .unwrap();
+        connection_actor.wait().unwrap();
+        sleep(Duration::from_millis(50));
+
+        assert!(!torrent_actor
+            .ask(move |torrent_actor| Ok(torrent_actor.has_connection(peer_id)))
+            .unwrap());
+        torrent_actor.stop().unwrap();
+    }
+
+    #[test]
+    fn quiet_connection_sends_keep_alives() {
+        let own_id = PeerId::new([1; 20]);
+        let peer_id = PeerId::new([3; 20]);
+        let info_hash = InfoHash::new([2; 20]);
+        let torrent_actor = Handle::spawn(TorrentActor::new(own_id, info_hash));
+        let clock = MockClock::new();
+        let config = TorrentConfig::default();
+
+        let peer_handshake = Message::Handshake(Handshake::new(info_hash, peer_id));
+        let connection = MockConnection::new(VecDeque::from([peer_handshake]));
+        let connection_actor = Handle::spawn(
+            ConnectionActor::new(
+                own_id,
+                None,
+                connection.clone(),
+                connection.clone(),
+                info_hash,
+                torrent_actor.clone(),
+                config,
+            )
+            .with_clock(Arc::new(clock.clone())),
+        );
+        connection_actor
+            .act(ConnectionActor::await_handshake)
+            .unwrap();
+        // The handshake was just sent.
+        connection_actor
+            .ask(|connection| connection.check_activity())
+            .unwrap();
+        let keep_alives = || {
+            connection
+                .sent_messages
+                .lock()
+                .unwrap()
+                .iter()
+                .filter(|message| matches!(message, Message::KeepAlive(_)))
+                .count()
+        };
+
+        clock.advance(config.inactivity_timeout / 2 - Duration::from_secs(1));
+        connection_actor
+            .ask(|connection| connection.check_activity())
+            .unwrap();
+        assert_eq!(keep_alives(), 0);
+
+        clock.advance(Duration::from_secs(1));
+        connection_actor
+            .ask(|connection| connection.check_activity())
+            .unwrap();
+        assert_eq!(keep_alives(), 1);
+
+        // Only once per half timeout, not on every check.
+        connection_actor
+            .ask(|connection| connection.check_activity())
+            .unwrap();
+        assert_eq!(keep_alives(), 1);
+
+        connection_actor.stop().unwrap();
+        torrent_actor.stop().unwrap();
+    }
+
+    #[test]
+    fn interested_in_peers_with_pieces_we_lack() {
+        let own_id = PeerId::new([1; 20]);
+        let peer_id = PeerId::new([3; 20]);
+        let info_hash = InfoHash::new([2; 20]);
+        let mut torrent = TorrentActor::new(own_id, info_hash);
+        torrent.set_piece_layout(BLOCK_SIZE, u64::from(2 * BLOCK_SIZE));
+        torrent
+            .block_received(peer_id, Request::new(0, 0, BLOCK_SIZE), vec![0; 16384])
+            .unwrap();
+        let torrent_actor = Handle::spawn(torrent);
+
+        let peer_handshake = Message::Handshake(Handshake::new(info_hash, peer_id));
+        let connection = MockConnection::new(VecDeque::from([peer_handshake]));
+        let connection_actor = Handle::spawn(ConnectionActor::new(
+            own_id,
+            None,
+            connection.clone(),
+            connection.clone(),
+            info_hash,
+            torrent_actor.clone(),
+            TorrentConfig::default(),
+        ));
+        connection_actor
+            .act(ConnectionActor::await_handshake)
+            .unwrap();
+        sleep(Duration::from_millis(100));
+
+        let receive = |message: Message| {
+            move |connection: &mut ConnectionActor| connection.handle_message(message)
+        };
+        // Only the piece we already have
+        connection_actor
+            .act(receive(Message::Bitfield(Bitfield::new(vec![0b1000_0000]))))
+            .unwrap();
+        connection_actor
+            .act(receive(Message::Bitfield(Bitfield::new(vec![0b1100_0000]))))
+            .unwrap();
+        connection_actor
+            .act(receive(Message::Have(Have::new(1))))
+            .unwrap();
+        sleep(Duration::from_millis(100));
+
+        assert_eq!(
+            *connection.sent_messages.lock().unwrap(),
+            vec![
+                Message::Handshake(own_handshake(info_hash, own_id)),
+                Message::Interested(Interested),
+            ]
+        );
+
+        torrent_actor
+            .act(move |torrent| {
+                torrent.block_received(peer_id, Request::new(1, 0, BLOCK_SIZE), vec![0; 16384])?;
+                Ok(Outcome::Continue)
+            })
+            .unwrap();
+        sleep(Duration::from_millis(100));
+
+        assert_eq!(
+            connection.sent_messages.lock().unwrap().last(),
+            Some(&Message::NotInterested(NotInterested))
+        );
+
+        connection_actor.stop().unwrap();
+        torrent_actor.stop().unwrap();
+    }
+
+    #[test]
+    fn bitfield_must_fit_piece_count() {
+        let own_id = PeerId::new([1; 20]);
+        let peer_id = PeerId::new([3; 20]);
+        let info_hash = InfoHash::new([2; 20]);
+        let torrent_actor = Handle::spawn(TorrentActor::new(own_id, info_hash));
+
+        for (bitfield, accepted) in [
+            (vec![0b1100_0000], true),
+            (vec![0b1100_0000, 0], false),
+            (vec![0b1110_0000], false),
+        ] {
+            let peer_handshake = Message::Handshake(Handshake::new(info_hash, peer_id));
+            let connection = MockConnection::new(VecDeque::from([peer_handshake]));
+            let connection_actor = Handle::spawn(
+                ConnectionActor::new(
+                    own_id,
+                    None,
+                    connection.clone(),
+                    connection.clone(),
+                    info_hash,
+                    torrent_actor.clone(),
+                    TorrentConfig::default(),
+                )
+                .with_piece_count(Some(2)),
+            );
+            connection_actor
+                .act(ConnectionActor::await_handshake)
+                .unwrap();
+            sleep(Duration::from_millis(100));
+
+            connection_actor
+                .act(move |connection| {
+                    connection.handle_message(Message::Bitfield(Bitfield::new(bitfield)))
+                })
+                .unwrap();
+            sleep(Duration::from_millis(100));
+
+            let alive = connection_actor.act(|_| Ok(Outcome::Continue)).is_ok();
+            assert_eq!(alive, accepted);
+            if alive {
+                connection_actor.stop().unwrap();
+            }
+            sleep(Duration::from_millis(100));
+        }
+
+        torrent_actor.stop().unwrap();
+    }
+
+    #[test]
+    fn handshake_times_out() {
+        let client_id = PeerId::new([1; 20]);
+        let info_hash = InfoHash::new([2; 20]);
+        let torrent_actor = Handle::spawn(TorrentActor::new(client_id, info_hash));
+        let connection = MockConnection::new(VecDeque::new());
+        let config = TorrentConfig {
+            handshake_timeout: Duration::from_millis(100),
+            ..TorrentConfig::default()
+        };
+        let connection_actor = Handle::spawn(ConnectionActor::new(
+            client_id,
+            None,
+            connection.clone(),
+            connection.clone(),
+            info_hash,
+            torrent_actor.clone(),
+            config,
+        ));
+
+        connection_actor
+            .act(ConnectionActor::await_handshake)
+            .unwrap();
+        sleep(Duration::from_millis(300));
+
+        assert!(connection_actor.act(|_| Ok(Outcome::Continue)).is_err());
+        assert_eq!(*connection.sent_messages.lock().unwrap(), vec![]);
 
         torrent_actor.stop().unwrap();
     }
@@ -0,0 +1,63 @@
+use std::net::SocketAddr;
+use std::sync::mpsc::{Receiver, Sender};
+
+use crate::PeerId;
+
+/// Something that happened to a [Torrent](crate::Torrent), for anyone who wants to keep track
+/// of it without polling. See [Torrent::subscribe](crate::Torrent::subscribe).
+#[derive(Debug, Clone, PartialEq)]
+pub enum TorrentEvent {
+    /// A peer finished its handshake, and is now connected. The address is only known if it
+    /// was given when connecting, in-memory connections for example don't have one.
+    PeerConnected(PeerId, Option<SocketAddr>),
+    /// A peer's connection was closed.
+    PeerDisconnected(PeerId),
+    /// Every block of the piece with this index has been downloaded, and the piece passed
+    /// its hash check.
+    PieceCompleted(u32),
+    /// Every block of the piece with this index has been downloaded, but the piece failed its
+    /// hash check, so it's downloaded again.
+    PieceFailed(u32),
+    /// The fraction of the torrent that has been downloaded, from 0 to 1.
+    Progress(f32),
+    /// The fraction of pieces that have been checked so far by
+    /// [Torrent::verify_existing_files](crate::Torrent::verify_existing_files), from 0 to 1.
+    VerifyProgress(f32),
+}
+
+/// Hands every event to all subscribers. Subscribers that have gone away are forgotten
+/// the next time an event is sent, so they never hold anything up.
+#[derive(Debug, Default)]
+pub struct EventSubscribers {
+    senders: Vec<Sender<TorrentEvent>>,
+}
+
+impl EventSubscribers {
+    pub fn subscribe(&mut self) -> Receiver<TorrentEvent> {
+        let (sender, receiver) = std::sync::mpsc::channel();
+        self.senders.push(sender);
+        receiver
+    }
+
+    pub fn send(&mut self, event: &TorrentEvent) {
+        self.senders
+            .retain(|sender| sender.send(event.clone()).is_ok());
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn dropped_subscribers_are_forgotten() {
+        let mut subscribers = EventSubscribers::default();
+        let kept = subscribers.subscribe();
+        drop(subscribers.subscribe());
+
+        subscribers.send(&TorrentEvent::PieceCompleted(1));
+
+        assert_eq!(subscribers.senders.len(), 1);
+        assert_eq!(kept.try_recv(), Ok(TorrentEvent::PieceCompleted(1)));
+    }
+}
@@ -0,0 +1,91 @@
+//! Verification of BitTorrent v2 (BEP 52) files. Rather than a SHA-1 hash per piece, v2 hashes
+//! every 16 KiB block of a file with SHA-256, and combines those into a Merkle tree per file.
+//! The root of that tree is the file's `pieces root` in the info dictionary.
+
+use crate::sha256::sha256;
+
+/// The size of the blocks that make up the leaves of a file's Merkle tree. Only the last
+/// block of a file can be shorter.
+pub const MERKLE_BLOCK_SIZE: usize = 16 * 1024;
+
+/// The hashes of `data`'s blocks, the leaves of its Merkle tree.
+#[must_use]
+pub fn block_hashes(data: &[u8]) -> Vec<[u8; 32]> {
+    data.chunks(MERKLE_BLOCK_SIZE).map(sha256).collect()
+}
+
+/// The root of the Merkle tree with `leaves`. The tree is padded with zero hashes up to
+/// a power of two leaves, as if the file went on.
+#[must_use]
+pub fn merkle_root(leaves: &[[u8; 32]]) -> [u8; 32] {
+    let mut layer = leaves.to_vec();
+    layer.resize(leaves.len().next_power_of_two(), [0; 32]);
+    while layer.len() > 1 {
+        layer = layer
+            .chunks_exact(2)
+            .map(|pair| {
+                let mut both = [0; 64];
+                both[..32].copy_from_slice(&pair[0]);
+                both[32..].copy_from_slice(&pair[1]);
+                sha256(&both)
+            })
+            .collect();
+    }
+    layer[0]
+}
+
+/// The `pieces root` of a file with contents `data`. Empty files don't have one.
+#[must_use]
+pub fn pieces_root(data: &[u8]) -> Option<[u8; 32]> {
+    (!data.is_empty()).then(|| merkle_root(&block_hashes(data)))
+}
+
+/// Whether `data` are the contents of the file with this `pieces root`.
+#[must_use]
+pub fn verify_pieces_root(data: &[u8], expected: &[u8; 32]) -> bool {
+    pieces_root(data).is_some_and(|root| root == *expected)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn hash_pair(left: [u8; 32], right: [u8; 32]) -> [u8; 32] {
+        sha256(&[left, right].concat())
+    }
+
+    #[test]
+    fn root_of_a_small_file() {
+        // Two and a bit blocks, so the tree needs a zero leaf to make four.
+        let data: Vec<u8> = (0..2 * MERKLE_BLOCK_SIZE + 100)
+            .map(|i| (i % 251) as u8)
+            .collect();
+        let [first, second, third] = [
+            &data[..MERKLE_BLOCK_SIZE],
+            &data[MERKLE_BLOCK_SIZE..2 * MERKLE_BLOCK_SIZE],
+            &data[2 * MERKLE_BLOCK_SIZE..],
+        ]
+        .map(sha256);
+        let expected = hash_pair(hash_pair(first, second), hash_pair(third, [0; 32]));
+
+        assert_eq!(pieces_root(&data), Some(expected));
+        assert!(verify_pieces_root(&data, &expected));
+        // A file of a single block is its own root.
+        assert_eq!(pieces_root(b"tiny"), Some(sha256(b"tiny")));
+        assert_eq!(pieces_root(b""), None);
+    }
+
+    #[test]
+    fn tampered_leaf_is_detected() {
+        let data = vec![7; 3 * MERKLE_BLOCK_SIZE];
+        let root = pieces_root(&data).unwrap();
+
+        let mut leaves = block_hashes(&data);
+        leaves[1][0] ^= 1;
+        let mut tampered = data.clone();
+        tampered[MERKLE_BLOCK_SIZE + 5] = 8;
+
+        assert_ne!(merkle_root(&leaves), root);
+        assert!(!verify_pieces_root(&tampered, &root));
+    }
+}
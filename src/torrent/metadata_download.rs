@@ -0,0 +1,149 @@
+use eyre::{bail, Result};
+
+use crate::messages::METADATA_PIECE_SIZE;
+use crate::metainfo::{info_hash, Info};
+use crate::InfoHash;
+
+/// Peers claiming to have metadata bigger than this are ignored, so that they can't make us
+/// allocate arbitrary amounts of memory. Even huge torrents have info dictionaries of a few MiB.
+const MAX_METADATA_SIZE: usize = 16 * 1024 * 1024;
+
+/// Reassembles the info dictionary from pieces downloaded from peers with the metadata
+/// extension, and checks it against the info hash before trusting it.
+#[derive(Debug)]
+pub struct MetadataDownload {
+    info_hash: InfoHash,
+    total_size: usize,
+    pieces: Vec<Option<Vec<u8>>>,
+}
+
+impl MetadataDownload {
+    pub fn new(info_hash: InfoHash, total_size: usize) -> Result<Self> {
+        if total_size == 0 || total_size > MAX_METADATA_SIZE {
+            bail!("Unreasonable metadata size {total_size}");
+        }
+        Ok(Self {
+            info_hash,
+            total_size,
+            pieces: vec![None; total_size.div_ceil(METADATA_PIECE_SIZE)],
+        })
+    }
+
+    pub fn total_size(&self) -> usize {
+        self.total_size
+    }
+
+    /// Indices of the pieces that haven't been received yet.
+    pub fn missing_pieces(&self) -> Vec<usize> {
+        (0..self.pieces.len())
+            .filter(|piece| self.pieces[*piece].is_none())
+            .collect()
+    }
+
+    /// Store a received piece. Pieces of the wrong size, or for the wrong metadata, are rejected.
+    pub fn receive(&mut self, piece: usize, total_size: usize, data: Vec<u8>) -> Result<()> {
+        if total_size != self.total_size {
+            bail!(
+                "Metadata piece has size {total_size}, expected {}",
+                self.total_size
+            );
+        }
+        let Some(slot) = self.pieces.get_mut(piece) else {
+            bail!("Metadata piece {piece} is out of range");
+        };
+        let expected_length =
+            METADATA_PIECE_SIZE.min(self.total_size - piece * METADATA_PIECE_SIZE);
+        if data.len() != expected_length {
+            bail!(
+                "Metadata piece {piece} is {} bytes, expected {expected_length}",
+                data.len()
+            );
+        }
+        *slot = Some(data);
+        Ok(())
+    }
+
+    pub fn is_complete(&self) -> bool {
+        self.pieces.iter().all(Option::is_some)
+    }
+
+    /// Assemble the received pieces and check them against the info hash, returning the raw
+    /// info dictionary and its parsed form. On failure all pieces are discarded,
+    /// as there's no telling which of them were bad.
+    pub fn finish(&mut self) -> Result<(Vec<u8>, Info)> {
+        if !self.is_complete() {
+            bail!("Metadata is not complete yet");
+        }
+        let metadata: Vec<u8> = self.pieces.iter().flatten().flatten().copied().collect();
+        if info_hash(&metadata) != self.info_hash {
+            self.pieces.fill(None);
+            bail!("Metadata does not match the info hash");
+        }
+        let info = Info::from_bytes(&metadata)?;
+        Ok((metadata, info))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// A valid info dictionary that's just over one metadata piece long.
+    fn metadata() -> Vec<u8> {
+        let piece_count = METADATA_PIECE_SIZE / 20 + 1;
+        let length = piece_count * 16;
+        let mut metadata = format!(
+            "d6:lengthi{length}e4:name4:test12:piece lengthi16e6:pieces{}:",
+            piece_count * 20
+        )
+        .into_bytes();
+        metadata.extend(vec![7; piece_count * 20]);
+        metadata.push(b'e');
+        metadata
+    }
+
+    #[test]
+    fn reassembles_pieces() {
+        let metadata = metadata();
+        let mut download = MetadataDownload::new(info_hash(&metadata), metadata.len()).unwrap();
+        assert_eq!(download.missing_pieces(), vec![0, 1]);
+
+        let (first, second) = metadata.split_at(METADATA_PIECE_SIZE);
+        download
+            .receive(1, metadata.len(), second.to_vec())
+            .unwrap();
+        assert_eq!(download.missing_pieces(), vec![0]);
+        download.receive(0, metadata.len(), first.to_vec()).unwrap();
+
+        let (raw, info) = download.finish().unwrap();
+        assert_eq!(raw, metadata);
+        assert_eq!(info.name, "test");
+    }
+
+    #[test]
+    fn rejects_corrupted_metadata() {
+        let metadata = metadata();
+        let mut download = MetadataDownload::new(info_hash(&metadata), metadata.len()).unwrap();
+
+        let mut corrupted = metadata.clone();
+        corrupted[METADATA_PIECE_SIZE + 1] ^= 0xff;
+        let (first, second) = corrupted.split_at(METADATA_PIECE_SIZE);
+        download.receive(0, metadata.len(), first.to_vec()).unwrap();
+        download
+            .receive(1, metadata.len(), second.to_vec())
+            .unwrap();
+
+        assert!(download.finish().is_err());
+        assert_eq!(download.missing_pieces(), vec![0, 1]);
+    }
+
+    #[test]
+    fn rejects_wrongly_sized_pieces() {
+        let mut download = MetadataDownload::new(InfoHash::new([0; 20]), 100).unwrap();
+
+        assert!(download.receive(0, 100, vec![0; 99]).is_err());
+        assert!(download.receive(1, 100, vec![]).is_err());
+        assert!(download.receive(0, 101, vec![0; 100]).is_err());
+        assert!(download.receive(0, 100, vec![0; 100]).is_ok());
+    }
+}
@@ -1,3 +1,17 @@
+pub mod ban_list;
+mod choking;
+pub mod config;
 mod connection_actor;
+mod connection_state;
+pub mod event;
+pub mod merkle;
+mod metadata_download;
+mod piece_selector;
+pub mod piece_store;
+mod rate_estimator;
+mod rate_limiter;
+mod resume;
+pub mod session;
+pub mod stats;
 pub mod torrent;
 mod torrent_actor;
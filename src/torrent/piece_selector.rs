@@ -0,0 +1,407 @@
+use std::collections::{HashMap, VecDeque};
+use std::time::{Duration, Instant};
+
+use crate::messages::{Bitfield, Request};
+use crate::PeerId;
+
+/// The de-facto standard block size, most clients refuse to serve anything bigger.
+pub const BLOCK_SIZE: u32 = 16 * 1024;
+
+/// Decides which blocks to request next, and keeps track of which peer is downloading what.
+///
+/// There's no cleverness to the selection (yet): blocks are handed out in order.
+#[derive(Debug, Default)]
+pub struct PieceSelector {
+    pending: VecDeque<Request>,
+    in_flight: HashMap<Request, (PeerId, Instant)>,
+    /// Blocks that timed out, and the peer they timed out with. They won't be handed to that
+    /// peer again, as it's probably stalled.
+    timed_out: HashMap<Request, PeerId>,
+    /// How many blocks of each piece haven't been downloaded yet.
+    missing_blocks: Vec<usize>,
+    total_blocks: usize,
+    piece_length: u32,
+    total_length: u64,
+    block_size: u32,
+}
+
+/// What happened when a block was marked as downloaded.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Completion {
+    /// The block was downloaded, and nobody else is working on it.
+    Done,
+    /// The block was downloaded, but it had been handed to another peer in the meantime,
+    /// so that peer's request should be cancelled.
+    DoneElsewhere(PeerId),
+    /// The block was already downloaded before, or was never part of the torrent.
+    NotNeeded,
+}
+
+impl PieceSelector {
+    /// Split a torrent of `total_length` bytes into pieces of `piece_length` bytes,
+    /// and those pieces into blocks. The last piece may be shorter than the rest.
+    #[cfg(test)]
+    pub fn new(piece_length: u32, total_length: u64) -> Self {
+        Self::with_block_size(piece_length, total_length, BLOCK_SIZE)
+    }
+
+    /// Like [PieceSelector::new], but with blocks of `block_size` bytes. It doesn't have to
+    /// divide the piece length, the last block of each piece is just shorter than the rest.
+    pub fn with_block_size(piece_length: u32, total_length: u64, block_size: u32) -> Self {
+        let piece_count = total_length.div_ceil(u64::from(piece_length.max(1)));
+        let mut selector = Self {
+            piece_length,
+            total_length,
+            block_size: block_size.max(1),
+            ..Self::default()
+        };
+        for index in 0..piece_count {
+            // the number of pieces has to fit the u32 piece indices of the protocol
+            #[allow(clippy::cast_possible_truncation)]
+            let blocks = selector.blocks(index as u32);
+            selector.missing_blocks.push(blocks.len());
+            selector.pending.extend(blocks);
+        }
+        selector.total_blocks = selector.pending.len();
+        selector
+    }
+
+    /// How long the piece with this index is, which is shorter than the rest for the last one.
+    pub fn piece_size(&self, index: u32) -> u32 {
+        let offset = u64::from(index) * u64::from(self.piece_length);
+        // bounded by piece_length, so the cast is safe
+        #[allow(clippy::cast_possible_truncation)]
+        let size =
+            (self.total_length.saturating_sub(offset)).min(u64::from(self.piece_length)) as u32;
+        size
+    }
+
+    /// Every block of the piece with this index.
+    fn blocks(&self, index: u32) -> Vec<Request> {
+        let piece_length = self.piece_size(index);
+        (0..piece_length)
+            .step_by(self.block_size as usize)
+            .map(|begin| Request::new(index, begin, self.block_size.min(piece_length - begin)))
+            .collect()
+    }
+
+    /// Mark a whole piece as downloaded, e.g. because we had it all along.
+    pub fn complete_piece(&mut self, index: u32) {
+        let Some(missing) = self.missing_blocks.get_mut(index as usize) else {
+            return;
+        };
+        *missing = 0;
+        self.pending.retain(|request| request.index != index);
+        self.in_flight.retain(|request, _| request.index != index);
+        self.timed_out.retain(|request, _| request.index != index);
+    }
+
+    /// Throw away a complete piece, so that it's downloaded again from scratch.
+    /// Used when the piece turned out to be corrupt.
+    pub fn discard_piece(&mut self, index: u32) {
+        if !self.is_piece_complete(index) {
+            return;
+        }
+        let blocks = self.blocks(index);
+        self.missing_blocks[index as usize] = blocks.len();
+        self.pending.extend(blocks);
+    }
+
+    /// Pick the next block for `peer_id` to download, if there are any left.
+    pub fn assign(&mut self, peer_id: PeerId, now: Instant) -> Option<Request> {
+        let position = self
+            .pending
+            .iter()
+            .position(|request| self.timed_out.get(request) != Some(&peer_id))?;
+        let request = self.pending.remove(position)?;
+        self.timed_out.remove(&request);
+        self.in_flight.insert(request, (peer_id, now));
+        Some(request)
+    }
+
+    /// Mark a block as downloaded by `peer_id`.
+    ///
+    /// This accepts blocks that have timed out or been reassigned since they were requested:
+    /// a block that arrives late is just as good as one that arrives on time.
+    pub fn complete(&mut self, peer_id: PeerId, request: Request) -> Completion {
+        let completion = if let Some((assignee, _)) = self.in_flight.remove(&request) {
+            if assignee == peer_id {
+                Completion::Done
+            } else {
+                Completion::DoneElsewhere(assignee)
+            }
+        } else if let Some(position) = self.pending.iter().position(|r| *r == request) {
+            self.pending.remove(position);
+            self.timed_out.remove(&request);
+            Completion::Done
+        } else {
+            return Completion::NotNeeded;
+        };
+        self.missing_blocks[request.index as usize] -= 1;
+        completion
+    }
+
+    /// Undo [complete](Self::complete) for a block that couldn't be stored, putting it back
+    /// at the front of the queue to be downloaded again.
+    pub fn uncomplete(&mut self, request: Request) {
+        if !self.blocks(request.index).contains(&request)
+            || self.pending.contains(&request)
+            || self.in_flight.contains_key(&request)
+        {
+            return;
+        }
+        self.missing_blocks[request.index as usize] += 1;
+        self.pending.push_front(request);
+    }
+
+    /// How many blocks `peer_id` has been assigned, but hasn't delivered yet.
+    pub fn in_flight_to(&self, peer_id: PeerId) -> usize {
+        self.in_flight
+            .values()
+            .filter(|(assignee, _)| *assignee == peer_id)
+            .count()
+    }
+
+    /// How many pieces have had every block downloaded.
+    pub fn complete_piece_count(&self) -> usize {
+        self.missing_blocks
+            .iter()
+            .filter(|missing| **missing == 0)
+            .count()
+    }
+
+    /// Whether every block of the piece has been downloaded.
+    pub fn is_piece_complete(&self, index: u32) -> bool {
+        self.missing_blocks.get(index as usize) == Some(&0)
+    }
+
+    /// Whether every piece has been downloaded. Never while the piece layout isn't known.
+    pub fn is_complete(&self) -> bool {
+        self.piece_count() > 0 && self.complete_piece_count() == self.piece_count()
+    }
+
+    /// How many pieces the torrent has, zero while the piece layout isn't known.
+    pub fn piece_count(&self) -> usize {
+        self.missing_blocks.len()
+    }
+
+    /// Whether a peer with these pieces has any that we still need.
+    pub fn wants_any(&self, pieces: &Bitfield) -> bool {
+        self.missing_blocks
+            .iter()
+            .enumerate()
+            .any(|(index, missing)| *missing > 0 && pieces.has(index))
+    }
+
+    /// The fraction of blocks that have been downloaded, from 0 to 1.
+    pub fn progress(&self) -> f32 {
+        if self.total_blocks == 0 {
+            return 0.0;
+        }
+        let missing: usize = self.missing_blocks.iter().sum();
+        (self.total_blocks - missing) as f32 / self.total_blocks as f32
+    }
+
+    /// Put all blocks that have been in flight for longer than `timeout` back in the queue,
+    /// returning them along with the peer that failed to deliver them.
+    pub fn expire(&mut self, now: Instant, timeout: Duration) -> Vec<(PeerId, Request)> {
+        let expired: Vec<_> = self
+            .in_flight
+            .iter()
+            .filter(|(_, (_, requested_at))| {
+                now.saturating_duration_since(*requested_at) >= timeout
+            })
+            .map(|(request, (peer_id, _))| (*peer_id, *request))
+            .collect();
+        for (peer_id, request) in &expired {
+            self.in_flight.remove(request);
+            self.pending.push_front(*request);
+            self.timed_out.insert(*request, *peer_id);
+        }
+        expired
+    }
+
+    /// Put a block that the peer isn't going to download back at the front of the queue.
+    pub fn release(&mut self, peer_id: PeerId, request: Request) {
+        if self
+            .in_flight
+            .get(&request)
+            .is_some_and(|(assignee, _)| *assignee == peer_id)
+        {
+            self.in_flight.remove(&request);
+            self.pending.push_front(request);
+        }
+    }
+
+    /// Put all blocks assigned to a peer back in the queue, e.g. when it disconnects.
+    pub fn release_peer(&mut self, peer_id: PeerId) {
+        let released: Vec<_> = self
+            .in_flight
+            .iter()
+            .filter(|(_, (assignee, _))| *assignee == peer_id)
+            .map(|(request, _)| *request)
+            .collect();
+        for request in released {
+            self.release(peer_id, request);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn splits_pieces_into_blocks() {
+        let mut selector = PieceSelector::new(2 * BLOCK_SIZE, u64::from(3 * BLOCK_SIZE + 100));
+        let peer_id = PeerId::new([1; 20]);
+        let now = Instant::now();
+
+        let blocks: Vec<_> = std::iter::from_fn(|| selector.assign(peer_id, now)).collect();
+
+        assert_eq!(
+            blocks,
+            vec![
+                Request::new(0, 0, BLOCK_SIZE),
+                Request::new(0, BLOCK_SIZE, BLOCK_SIZE),
+                Request::new(1, 0, BLOCK_SIZE),
+                Request::new(1, BLOCK_SIZE, 100),
+            ]
+        );
+    }
+
+    #[test]
+    fn last_block_of_a_piece_is_short() {
+        let mut selector = PieceSelector::with_block_size(40000, 80000, 16384);
+        let peer_id = PeerId::new([1; 20]);
+        let now = Instant::now();
+
+        let blocks: Vec<_> = std::iter::from_fn(|| selector.assign(peer_id, now))
+            .filter(|request| request.index == 1)
+            .map(|request| request.length)
+            .collect();
+
+        assert_eq!(blocks, vec![16384, 16384, 7232]);
+    }
+
+    #[test]
+    fn tracks_piece_completion_and_progress() {
+        let mut selector = PieceSelector::new(2 * BLOCK_SIZE, u64::from(3 * BLOCK_SIZE));
+        let peer_id = PeerId::new([1; 20]);
+        let now = Instant::now();
+        assert_eq!(selector.progress(), 0.0);
+
+        let first = selector.assign(peer_id, now).unwrap();
+        let second = selector.assign(peer_id, now).unwrap();
+        selector.complete(peer_id, first);
+        assert!(!selector.is_piece_complete(0));
+        selector.complete(peer_id, second);
+        selector.complete(peer_id, second);
+
+        assert!(selector.is_piece_complete(0));
+        assert!(!selector.is_piece_complete(1));
+        assert_eq!(selector.progress(), 2.0 / 3.0);
+        assert!(!selector.wants_any(&Bitfield::new(vec![0b1000_0000])));
+        assert!(selector.wants_any(&Bitfield::new(vec![0b0100_0000])));
+    }
+
+    #[test]
+    fn discarded_piece_is_downloaded_again() {
+        let mut selector = PieceSelector::new(2 * BLOCK_SIZE, u64::from(3 * BLOCK_SIZE));
+        let peer_id = PeerId::new([1; 20]);
+        selector.complete_piece(0);
+        assert!(selector.is_piece_complete(0));
+        assert_eq!(
+            selector.assign(peer_id, Instant::now()),
+            Some(Request::new(1, 0, BLOCK_SIZE))
+        );
+
+        selector.discard_piece(0);
+
+        assert!(!selector.is_piece_complete(0));
+        assert_eq!(
+            selector.assign(peer_id, Instant::now()),
+            Some(Request::new(0, 0, BLOCK_SIZE))
+        );
+        assert_eq!(
+            selector.assign(peer_id, Instant::now()),
+            Some(Request::new(0, BLOCK_SIZE, BLOCK_SIZE))
+        );
+    }
+
+    #[test]
+    fn uncompleted_block_is_downloaded_again() {
+        let mut selector = PieceSelector::new(2 * BLOCK_SIZE, u64::from(3 * BLOCK_SIZE));
+        let peer_id = PeerId::new([1; 20]);
+        let request = selector.assign(peer_id, Instant::now()).unwrap();
+        assert_eq!(selector.complete(peer_id, request), Completion::Done);
+
+        selector.uncomplete(request);
+        // Only blocks that were completed are put back.
+        selector.uncomplete(request);
+        selector.uncomplete(Request::new(5, 0, BLOCK_SIZE));
+
+        assert_eq!(selector.assign(peer_id, Instant::now()), Some(request));
+        assert_eq!(selector.complete(peer_id, request), Completion::Done);
+        assert_eq!(
+            selector.complete(peer_id, Request::new(0, BLOCK_SIZE, BLOCK_SIZE)),
+            Completion::Done
+        );
+        assert!(selector.is_piece_complete(0));
+    }
+
+    #[test]
+    fn released_blocks_are_reassigned_first() {
+        let mut selector = PieceSelector::new(BLOCK_SIZE, u64::from(3 * BLOCK_SIZE));
+        let peer_a = PeerId::new([1; 20]);
+        let peer_b = PeerId::new([2; 20]);
+        let now = Instant::now();
+
+        let first = selector.assign(peer_a, now).unwrap();
+        let second = selector.assign(peer_b, now).unwrap();
+        selector.release_peer(peer_a);
+
+        assert_eq!(selector.assign(peer_b, now), Some(first));
+        assert_eq!(selector.complete(peer_b, second), Completion::Done);
+        assert_eq!(
+            selector.complete(peer_a, first),
+            Completion::DoneElsewhere(peer_b)
+        );
+        assert_eq!(selector.complete(peer_a, first), Completion::NotNeeded);
+    }
+
+    #[test]
+    fn expired_blocks_are_not_reassigned_to_the_same_peer() {
+        let mut selector = PieceSelector::new(BLOCK_SIZE, u64::from(2 * BLOCK_SIZE));
+        let peer_a = PeerId::new([1; 20]);
+        let peer_b = PeerId::new([2; 20]);
+        let start = Instant::now();
+        let timeout = Duration::from_secs(30);
+
+        let first = selector.assign(peer_a, start).unwrap();
+        assert_eq!(selector.expire(start + timeout / 2, timeout), vec![]);
+        assert_eq!(
+            selector.expire(start + timeout, timeout),
+            vec![(peer_a, first)]
+        );
+
+        let second = selector.assign(peer_a, start + timeout).unwrap();
+        assert_ne!(first, second);
+        assert_eq!(selector.assign(peer_a, start + timeout), None);
+        assert_eq!(selector.assign(peer_b, start + timeout), Some(first));
+    }
+
+    #[test]
+    fn late_blocks_are_still_accepted() {
+        let mut selector = PieceSelector::new(BLOCK_SIZE, u64::from(2 * BLOCK_SIZE));
+        let peer_a = PeerId::new([1; 20]);
+        let start = Instant::now();
+        let timeout = Duration::from_secs(30);
+
+        let first = selector.assign(peer_a, start).unwrap();
+        selector.expire(start + timeout, timeout);
+
+        assert_eq!(selector.complete(peer_a, first), Completion::Done);
+        assert_ne!(selector.assign(peer_a, start + timeout), Some(first));
+    }
+}
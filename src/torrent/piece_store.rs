@@ -0,0 +1,468 @@
+use std::collections::HashMap;
+use std::fmt::Debug;
+use std::fs::{self, File, OpenOptions};
+use std::io::{Read, Seek, SeekFrom, Write};
+use std::ops::Range;
+use std::path::{Path, PathBuf};
+
+use eyre::{bail, OptionExt, Result, WrapErr};
+
+use crate::messages::Bitfield;
+use crate::metainfo::check_path_component;
+use crate::Info;
+
+/// Where downloaded blocks are kept, and where blocks are read from to serve other peers.
+///
+/// The store doesn't know which pieces are complete, that's tracked by the torrent.
+pub trait PieceStore: Debug + Send {
+    /// Store a block of `index`, starting `begin` bytes into the piece.
+    fn write_block(&mut self, index: u32, begin: u32, block: &[u8]) -> Result<()>;
+
+    /// Read `length` bytes of `index`, starting `begin` bytes into the piece.
+    /// Fails if any of that hasn't been written.
+    fn read_block(&self, index: u32, begin: u32, length: u32) -> Result<Vec<u8>>;
+
+    /// Whether the store checked the hash of `index` itself, and if so, whether it matched.
+    /// Stores that don't keep what they're given check pieces as they arrive, as they
+    /// couldn't be read back to check them; `None` means the torrent should read the piece
+    /// back and check it.
+    fn verified(&self, _index: u32) -> Option<bool> {
+        None
+    }
+
+    /// Make sure everything written so far is on disk, e.g. before shutting down. Nothing
+    /// to do for stores that don't write to disk.
+    fn flush(&mut self) -> Result<()> {
+        Ok(())
+    }
+}
+
+/// Keeps everything in memory, with nothing written to disk.
+#[derive(Debug, Default)]
+pub struct MemoryPieceStore {
+    pieces: HashMap<u32, Vec<u8>>,
+}
+
+impl PieceStore for MemoryPieceStore {
+    fn write_block(&mut self, index: u32, begin: u32, block: &[u8]) -> Result<()> {
+        let piece = self.pieces.entry(index).or_default();
+        let begin = begin as usize;
+        let end = begin + block.len();
+        if piece.len() < end {
+            piece.resize(end, 0);
+        }
+        piece[begin..end].copy_from_slice(block);
+        Ok(())
+    }
+
+    fn read_block(&self, index: u32, begin: u32, length: u32) -> Result<Vec<u8>> {
+        let piece = self.pieces.get(&index).ok_or_eyre("Piece not stored")?;
+        let begin = begin as usize;
+        let end = begin + length as usize;
+        if end > piece.len() {
+            bail!("Block {begin}..{end} is past the end of piece {index}");
+        }
+        Ok(piece[begin..end].to_vec())
+    }
+}
+
+/// Keeps pieces in the torrent's files under a download directory: a single file named after
+/// the torrent, or a directory named after it with all of the torrent's files in it.
+///
+/// Pieces are one long stream of bytes, which is cut up into the files in the order the
+/// metainfo lists them, so a piece can be spread over several files.
+#[derive(Debug)]
+pub struct FilePieceStore {
+    piece_length: u64,
+    files: Vec<StoredFile>,
+}
+
+#[derive(Debug)]
+struct StoredFile {
+    /// `None` for a padding file, which isn't stored at all.
+    path: Option<PathBuf>,
+    /// Where the file starts in the torrent's contents.
+    offset: u64,
+    length: u64,
+}
+
+impl FilePieceStore {
+    /// Store the files of `info` under `download_dir`. Empty files are created right away,
+    /// as no block is ever written to them; the rest, and their directories, are created
+    /// once the first block is written to them.
+    ///
+    /// Fails if the name of the torrent or of one of its files would point outside
+    /// `download_dir`.
+    pub fn new(download_dir: impl Into<PathBuf>, info: &Info) -> Result<Self> {
+        let download_dir = download_dir.into();
+        // `Info` can be built by hand too, so this doesn't rely on it having been parsed.
+        check_path_component(&info.name).wrap_err("Invalid torrent name")?;
+        for part in info.files.iter().flat_map(|file| &file.path) {
+            check_path_component(part)?;
+        }
+        let mut files = Vec::new();
+        let mut offset = 0;
+        if info.files.is_empty() {
+            files.push(StoredFile {
+                path: Some(download_dir.join(&info.name)),
+                offset,
+                length: info.length,
+            });
+        }
+        for file in &info.files {
+            let path = (!file.padding).then(|| {
+                let mut path = download_dir.join(&info.name);
+                path.extend(&file.path);
+                path
+            });
+            if let (Some(path), 0) = (&path, file.length) {
+                create_parent_dir(path)?;
+                let _ =
+                    File::create(path).wrap_err_with(|| format!("Failed to create {path:?}"))?;
+            }
+            files.push(StoredFile {
+                path,
+                offset,
+                length: file.length,
+            });
+            offset += file.length;
+        }
+        Ok(Self {
+            piece_length: u64::from(info.piece_length),
+            files,
+        })
+    }
+
+    /// The parts of the files that `length` bytes of `index` are in, starting `begin` bytes
+    /// into the piece, along with where in the file each part starts, and which bytes of the
+    /// block it holds.
+    fn segments(
+        &self,
+        index: u32,
+        begin: u32,
+        length: usize,
+    ) -> Result<Vec<(&StoredFile, u64, Range<usize>)>> {
+        if u64::from(begin) + length as u64 > self.piece_length {
+            bail!(
+                "Block {begin}..{} of piece {index} is past the end of the piece",
+                u64::from(begin) + length as u64
+            );
+        }
+        let start = u64::from(index) * self.piece_length + u64::from(begin);
+        let end = start + length as u64;
+        let total_length = self
+            .files
+            .last()
+            .map_or(0, |file| file.offset + file.length);
+        if end > total_length {
+            bail!(
+                "Block {begin}..{} of piece {index} is past the end of the torrent",
+                u64::from(begin) + length as u64
+            );
+        }
+        Ok(self
+            .files
+            .iter()
+            .filter(|file| file.offset < end && start < file.offset + file.length)
+            .map(|file| {
+                let from = start.max(file.offset);
+                let to = end.min(file.offset + file.length);
+                // Both are within the block, which fits in memory.
+                #[allow(clippy::cast_possible_truncation)]
+                let range = (from - start) as usize..(to - start) as usize;
+                (file, from - file.offset, range)
+            })
+            .collect())
+    }
+}
+
+fn create_parent_dir(path: &Path) -> Result<()> {
+    if let Some(parent) = path.parent() {
+        fs::create_dir_all(parent).wrap_err_with(|| format!("Failed to create {parent:?}"))?;
+    }
+    Ok(())
+}
+
+impl PieceStore for FilePieceStore {
+    fn write_block(&mut self, index: u32, begin: u32, block: &[u8]) -> Result<()> {
+        for (file, position, range) in self.segments(index, begin, block.len())? {
+            let Some(path) = &file.path else {
+                continue;
+            };
+            create_parent_dir(path)?;
+            let mut handle = OpenOptions::new()
+                .write(true)
+                .create(true)
+                .truncate(false)
+                .open(path)
+                .wrap_err_with(|| format!("Failed to open {path:?}"))?;
+            handle.seek(SeekFrom::Start(position))?;
+            handle.write_all(&block[range])?;
+        }
+        Ok(())
+    }
+
+    fn read_block(&self, index: u32, begin: u32, length: u32) -> Result<Vec<u8>> {
+        let mut block = vec![0; length as usize];
+        for (file, position, range) in self.segments(index, begin, block.len())? {
+            let Some(path) = &file.path else {
+                continue;
+            };
+            let mut handle =
+                File::open(path).wrap_err_with(|| format!("Failed to open {path:?}"))?;
+            handle.seek(SeekFrom::Start(position))?;
+            handle
+                .read_exact(&mut block[range])
+                .wrap_err_with(|| format!("Block of piece {index} not written to {path:?}"))?;
+        }
+        Ok(block)
+    }
+
+    fn flush(&mut self) -> Result<()> {
+        // Files are only created once a block is written to them.
+        for path in self.files.iter().filter_map(|file| file.path.as_ref()) {
+            if path.exists() {
+                File::open(path)
+                    .and_then(|handle| handle.sync_all())
+                    .wrap_err_with(|| format!("Failed to flush {path:?}"))?;
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Checks every piece against its hash as its blocks arrive, and then throws it away, for
+/// checking that a torrent can be downloaded intact without writing anything to disk.
+/// Nothing can be read back, so nothing is served to other peers either.
+#[derive(Debug)]
+pub struct VerifyOnlyStore {
+    info: Info,
+    /// The pieces that haven't arrived whole yet.
+    partial: HashMap<u32, PartialPiece>,
+    verified: HashMap<u32, bool>,
+}
+
+/// A piece as far as its blocks have arrived.
+#[derive(Debug, Default)]
+struct PartialPiece {
+    data: Vec<u8>,
+    /// Which bytes of `data` have arrived, in order, without any two overlapping or touching,
+    /// so that blocks that arrive twice or overlap aren't counted twice.
+    received: Vec<Range<usize>>,
+}
+
+impl PartialPiece {
+    /// Write `block` starting `begin` bytes into the piece, and return whether the whole
+    /// piece of `piece_size` bytes has arrived.
+    fn write(&mut self, begin: usize, block: &[u8], piece_size: usize) -> bool {
+        self.data.resize(piece_size, 0);
+        self.data[begin..begin + block.len()].copy_from_slice(block);
+        let mut merged = begin..begin + block.len();
+        self.received.retain(|range| {
+            let touches = range.start <= merged.end && merged.start <= range.end;
+            if touches {
+                merged = merged.start.min(range.start)..merged.end.max(range.end);
+            }
+            !touches
+        });
+        let position = self
+            .received
+            .partition_point(|range| range.start < merged.start);
+        self.received.insert(position, merged);
+        matches!(self.received.as_slice(), [range] if *range == (0..piece_size))
+    }
+}
+
+impl VerifyOnlyStore {
+    /// Check the pieces of the torrent described by `info`.
+    #[must_use]
+    pub fn new(info: Info) -> Self {
+        Self {
+            info,
+            partial: HashMap::new(),
+            verified: HashMap::new(),
+        }
+    }
+}
+
+impl PieceStore for VerifyOnlyStore {
+    fn write_block(&mut self, index: u32, begin: u32, block: &[u8]) -> Result<()> {
+        let piece_size = self.info.piece_size(index);
+        if begin as usize + block.len() > piece_size as usize {
+            bail!("Block at {begin} is past the end of piece {index}");
+        }
+        let piece = self.partial.entry(index).or_default();
+        if piece.write(begin as usize, block, piece_size as usize) {
+            let piece = self.partial.remove(&index).unwrap_or_default();
+            let _ = self
+                .verified
+                .insert(index, self.info.verify_piece(index, &piece.data));
+        }
+        Ok(())
+    }
+
+    fn read_block(&self, index: u32, _begin: u32, _length: u32) -> Result<Vec<u8>> {
+        bail!("Piece {index} was only verified, not stored")
+    }
+
+    fn verified(&self, index: u32) -> Option<bool> {
+        self.verified.get(&index).copied()
+    }
+}
+
+/// Check which of the torrent's pieces are in `store` already, e.g. ones downloaded before
+/// a restart, by hashing each of them. `progress` is called with the fraction of pieces that
+/// have been checked so far after each one.
+pub fn verify_pieces(
+    store: &dyn PieceStore,
+    info: &Info,
+    mut progress: impl FnMut(f32),
+) -> Bitfield {
+    let piece_count = info.pieces.len();
+    let mut pieces = Bitfield::new(vec![0; piece_count.div_ceil(8)]);
+    for (index, position) in (0..).zip(0..piece_count) {
+        let valid = store
+            .read_block(index, 0, info.piece_size(index))
+            .is_ok_and(|piece| info.verify_piece(index, &piece));
+        if valid {
+            pieces.set(position);
+        }
+        progress((position + 1) as f32 / piece_count as f32);
+    }
+    pieces
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::metainfo::FileEntry;
+
+    use super::*;
+
+    fn download_dir(test: &str) -> PathBuf {
+        let dir = std::env::temp_dir().join(format!("torrent-poc-{}-{test}", std::process::id()));
+        let _ = fs::remove_dir_all(&dir);
+        dir
+    }
+
+    fn multi_file_info(files: &[(&str, u64, bool)]) -> Info {
+        let files: Vec<_> = files
+            .iter()
+            .map(|&(path, length, padding)| FileEntry {
+                path: path.split('/').map(str::to_string).collect(),
+                length,
+                padding,
+            })
+            .collect();
+        let length = files.iter().map(|file| file.length).sum();
+        Info {
+            name: "dir".to_string(),
+            piece_length: 8,
+            length,
+            pieces: vec![[0; 20]; length.div_ceil(8) as usize],
+            files,
+        }
+    }
+
+    #[test]
+    fn reads_back_written_blocks() {
+        let mut store = MemoryPieceStore::default();
+        store.write_block(1, 4, &[5, 6, 7]).unwrap();
+        store.write_block(1, 0, &[1, 2, 3, 4]).unwrap();
+
+        assert_eq!(store.read_block(1, 2, 4).unwrap(), vec![3, 4, 5, 6]);
+        assert!(store.read_block(1, 4, 4).is_err());
+        assert!(store.read_block(0, 0, 1).is_err());
+    }
+
+    #[test]
+    fn piece_across_a_file_boundary_is_split_between_the_files() {
+        let dir = download_dir("split");
+        let info = multi_file_info(&[("a", 5, false), ("sub/b", 7, false)]);
+        let mut store = FilePieceStore::new(&dir, &info).unwrap();
+
+        store.write_block(0, 0, &[0, 1, 2, 3, 4, 5, 6, 7]).unwrap();
+        store.write_block(1, 0, &[8, 9, 10, 11]).unwrap();
+
+        assert_eq!(fs::read(dir.join("dir/a")).unwrap(), [0, 1, 2, 3, 4]);
+        assert_eq!(
+            fs::read(dir.join("dir/sub/b")).unwrap(),
+            [5, 6, 7, 8, 9, 10, 11]
+        );
+        assert_eq!(store.read_block(0, 3, 4).unwrap(), [3, 4, 5, 6]);
+        assert!(store.read_block(1, 0, 5).is_err());
+        assert!(store.read_block(0, 6, 4).is_err());
+        fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn verify_only_store_checks_pieces_without_keeping_them() {
+        let content: Vec<u8> = (0..20).collect();
+        let mut store = VerifyOnlyStore::new(Info::from_content("test", 8, &content));
+
+        store.write_block(0, 4, &content[4..8]).unwrap();
+        assert_eq!(store.verified(0), None);
+        store.write_block(0, 0, &content[0..4]).unwrap();
+        store.write_block(1, 0, &[0; 8]).unwrap();
+        store.write_block(2, 0, &content[16..]).unwrap();
+
+        assert_eq!(store.verified(0), Some(true));
+        assert_eq!(store.verified(1), Some(false));
+        assert_eq!(store.verified(2), Some(true));
+        assert!(store.read_block(0, 0, 8).is_err());
+        assert!(store.partial.is_empty());
+        assert!(store.write_block(2, 0, &content[8..]).is_err());
+    }
+
+    #[test]
+    fn verify_only_store_counts_overlapping_blocks_once() {
+        let content: Vec<u8> = (0..8).collect();
+        let mut store = VerifyOnlyStore::new(Info::from_content("test", 8, &content));
+
+        store.write_block(0, 0, &content[0..4]).unwrap();
+        store.write_block(0, 0, &content[0..4]).unwrap();
+        assert_eq!(store.verified(0), None);
+        store.write_block(0, 2, &content[2..6]).unwrap();
+        assert_eq!(store.verified(0), None);
+        store.write_block(0, 6, &content[6..8]).unwrap();
+
+        assert_eq!(store.verified(0), Some(true));
+    }
+
+    #[test]
+    fn names_outside_the_download_dir_are_refused() {
+        let dir = download_dir("traversal");
+        for name in ["..", "../x", "/etc/x"] {
+            let info = Info {
+                name: name.to_string(),
+                ..Info::from_content("test", 8, b"contents")
+            };
+            let _ = FilePieceStore::new(&dir, &info).unwrap_err();
+        }
+        let mut info = multi_file_info(&[("a", 4, false)]);
+        info.files[0].path = vec!["..".to_string(), "a".to_string()];
+        let _ = FilePieceStore::new(&dir, &info).unwrap_err();
+        assert!(!dir.exists());
+    }
+
+    #[test]
+    fn padding_files_are_not_written() {
+        let dir = download_dir("padding");
+        let info = multi_file_info(&[
+            ("a", 5, false),
+            (".pad/3", 3, true),
+            ("b", 4, false),
+            ("empty", 0, false),
+        ]);
+        let mut store = FilePieceStore::new(&dir, &info).unwrap();
+
+        store.write_block(0, 0, &[1, 2, 3, 4, 5, 0, 0, 0]).unwrap();
+        store.write_block(1, 0, &[6, 7, 8, 9]).unwrap();
+
+        assert_eq!(fs::read(dir.join("dir/a")).unwrap(), [1, 2, 3, 4, 5]);
+        assert_eq!(fs::read(dir.join("dir/b")).unwrap(), [6, 7, 8, 9]);
+        assert_eq!(fs::read(dir.join("dir/empty")).unwrap(), b"");
+        assert!(!dir.join("dir/.pad").exists());
+        assert_eq!(store.read_block(0, 0, 8).unwrap(), [1, 2, 3, 4, 5, 0, 0, 0]);
+        fs::remove_dir_all(dir).unwrap();
+    }
+}
@@ -0,0 +1,71 @@
+use std::collections::VecDeque;
+use std::time::{Duration, Instant};
+
+/// Estimates a transfer rate (in bytes per second) over a sliding window of time.
+#[derive(Debug, Clone)]
+pub struct RateEstimator {
+    window: Duration,
+    samples: VecDeque<(Instant, usize)>,
+}
+
+impl RateEstimator {
+    pub fn new(window: Duration) -> Self {
+        Self {
+            window,
+            samples: VecDeque::new(),
+        }
+    }
+
+    /// Record that `bytes` were transferred at `now`.
+    pub fn record(&mut self, now: Instant, bytes: usize) {
+        self.samples.push_back((now, bytes));
+        self.expire(now);
+    }
+
+    /// The average rate over the window ending at `now`, in bytes per second.
+    pub fn rate(&mut self, now: Instant) -> f64 {
+        self.expire(now);
+        let total: usize = self.samples.iter().map(|(_, bytes)| bytes).sum();
+        // Precision loss is irrelevant for an estimate.
+        #[allow(clippy::cast_precision_loss)]
+        let total = total as f64;
+        total / self.window.as_secs_f64()
+    }
+
+    fn expire(&mut self, now: Instant) {
+        while let Some((at, _)) = self.samples.front() {
+            if now.saturating_duration_since(*at) > self.window {
+                self.samples.pop_front();
+            } else {
+                break;
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn rate_averages_over_window() {
+        let start = Instant::now();
+        let mut estimator = RateEstimator::new(Duration::from_secs(10));
+
+        estimator.record(start, 1000);
+        estimator.record(start + Duration::from_secs(5), 1000);
+
+        assert!((estimator.rate(start + Duration::from_secs(5)) - 200.0).abs() < f64::EPSILON);
+    }
+
+    #[test]
+    fn old_samples_expire() {
+        let start = Instant::now();
+        let mut estimator = RateEstimator::new(Duration::from_secs(10));
+
+        estimator.record(start, 1000);
+        estimator.record(start + Duration::from_secs(11), 500);
+
+        assert!((estimator.rate(start + Duration::from_secs(11)) - 50.0).abs() < f64::EPSILON);
+    }
+}
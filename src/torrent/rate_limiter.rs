@@ -0,0 +1,137 @@
+use std::sync::{Arc, Mutex};
+use std::time::Instant;
+
+use crate::clock::Clock;
+
+/// Caps a transfer rate using a token bucket, shared by all of a torrent's connections.
+///
+/// The bucket holds up to a second's worth of bytes. A transfer is allowed as long as the
+/// bucket isn't empty, even if it's bigger than what's left, in which case the bucket goes
+/// into debt that has to be paid off before the next transfer. That way blocks larger than
+/// the rate still get through, and the average stays at the configured rate.
+#[derive(Debug)]
+pub struct RateLimiter {
+    clock: Arc<dyn Clock>,
+    bucket: Mutex<Bucket>,
+}
+
+#[derive(Debug)]
+struct Bucket {
+    /// Bytes per second, or `None` when unlimited.
+    rate: Option<u64>,
+    tokens: f64,
+    refilled_at: Instant,
+}
+
+impl RateLimiter {
+    /// A limiter that lets everything through until [set_rate](Self::set_rate) is called.
+    pub fn unlimited(clock: Arc<dyn Clock>) -> Self {
+        let refilled_at = clock.now();
+        Self {
+            clock,
+            bucket: Mutex::new(Bucket {
+                rate: None,
+                tokens: 0.0,
+                refilled_at,
+            }),
+        }
+    }
+
+    /// Limit the rate to `bytes_per_sec`, or lift the limit with `None`. Starts out with a full
+    /// bucket.
+    pub fn set_rate(&self, bytes_per_sec: Option<u64>) {
+        let mut bucket = self.bucket.lock().expect("lock to not be poisoned");
+        bucket.rate = bytes_per_sec;
+        // Precision loss is irrelevant for a rate limit.
+        #[allow(clippy::cast_precision_loss)]
+        let tokens = bytes_per_sec.unwrap_or(0) as f64;
+        bucket.tokens = tokens;
+        bucket.refilled_at = self.clock.now();
+    }
+
+    /// Take `bytes` out of the bucket, if it isn't empty. Returns `false` if the transfer has
+    /// to wait, in which case nothing is taken.
+    pub fn try_acquire(&self, bytes: usize) -> bool {
+        let now = self.clock.now();
+        let mut bucket = self.bucket.lock().expect("lock to not be poisoned");
+        let Some(rate) = bucket.rate else {
+            return true;
+        };
+        // Precision loss is irrelevant for a rate limit.
+        #[allow(clippy::cast_precision_loss)]
+        let (rate, bytes) = (rate as f64, bytes as f64);
+        let elapsed = now.saturating_duration_since(bucket.refilled_at);
+        bucket.tokens = (bucket.tokens + elapsed.as_secs_f64() * rate).min(rate);
+        bucket.refilled_at = now;
+        if bucket.tokens <= 0.0 {
+            return false;
+        }
+        bucket.tokens -= bytes;
+        true
+    }
+}
+
+/// The upload and download limits of a torrent.
+#[derive(Debug, Clone)]
+pub struct RateLimiters {
+    pub upload: Arc<RateLimiter>,
+    pub download: Arc<RateLimiter>,
+}
+
+impl RateLimiters {
+    pub fn unlimited(clock: Arc<dyn Clock>) -> Self {
+        Self {
+            upload: Arc::new(RateLimiter::unlimited(clock.clone())),
+            download: Arc::new(RateLimiter::unlimited(clock)),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use crate::clock::MockClock;
+
+    use super::*;
+
+    #[test]
+    fn unlimited_lets_everything_through() {
+        let limiter = RateLimiter::unlimited(Arc::new(MockClock::new()));
+
+        assert!((0..1000).all(|_| limiter.try_acquire(1 << 20)));
+    }
+
+    #[test]
+    fn sending_is_throttled_to_the_rate() {
+        let clock = MockClock::new();
+        let limiter = RateLimiter::unlimited(Arc::new(clock.clone()));
+        limiter.set_rate(Some(10_000));
+
+        // Try to send as fast as possible for 10 seconds.
+        let mut sent = 0;
+        for _ in 0..1000 {
+            while limiter.try_acquire(1000) {
+                sent += 1000;
+            }
+            clock.advance(Duration::from_millis(10));
+        }
+
+        // 10 seconds at the rate, plus the initial full bucket and one block of debt.
+        assert!((100_000..=111_000).contains(&sent), "sent {sent} bytes");
+    }
+
+    #[test]
+    fn blocks_larger_than_the_rate_get_through() {
+        let clock = MockClock::new();
+        let limiter = RateLimiter::unlimited(Arc::new(clock.clone()));
+        limiter.set_rate(Some(1000));
+
+        assert!(limiter.try_acquire(16384));
+        assert!(!limiter.try_acquire(16384));
+        clock.advance(Duration::from_secs(15));
+        assert!(!limiter.try_acquire(16384));
+        clock.advance(Duration::from_secs(1));
+        assert!(limiter.try_acquire(16384));
+    }
+}
@@ -0,0 +1,99 @@
+use std::collections::BTreeMap;
+
+use eyre::{ensure, OptionExt, Result, WrapErr};
+
+use crate::bencode::{self, BValue};
+use crate::messages::Bitfield;
+use crate::{InfoHash, SansIo};
+
+/// Bumped whenever the format changes. Files of other versions are refused, rather than
+/// misread.
+const RESUME_VERSION: i64 = 1;
+
+/// What a torrent remembers across restarts, in its resume file. It's a bencoded dictionary,
+/// like the rest of the protocol.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub(crate) struct ResumeState {
+    /// Which torrent the file is for, so it can't be loaded into another one.
+    pub info_hash: InfoHash,
+    /// How many bytes were downloaded over all runs of the torrent.
+    pub downloaded: u64,
+    /// How many bytes were uploaded over all runs of the torrent.
+    pub uploaded: u64,
+    /// The pieces we had.
+    pub have: Bitfield,
+}
+
+impl ResumeState {
+    pub fn to_bytes(&self) -> Vec<u8> {
+        let integer = |value: u64| BValue::Integer(i64::try_from(value).unwrap_or(i64::MAX));
+        let dict = BTreeMap::from([
+            (b"version".to_vec(), BValue::Integer(RESUME_VERSION)),
+            (
+                b"info hash".to_vec(),
+                BValue::Bytes(self.info_hash.encode()),
+            ),
+            (b"downloaded".to_vec(), integer(self.downloaded)),
+            (b"uploaded".to_vec(), integer(self.uploaded)),
+            (b"have".to_vec(), BValue::Bytes(self.have.bytes.clone())),
+        ]);
+        bencode::encode(&BValue::Dict(dict))
+    }
+
+    pub fn from_bytes(bytes: &[u8]) -> Result<Self> {
+        let value = bencode::decode(bytes).wrap_err("Invalid resume file")?;
+        let version = value
+            .get(b"version")
+            .and_then(BValue::as_integer)
+            .ok_or_eyre("Resume file has no version")?;
+        ensure!(
+            version == RESUME_VERSION,
+            "Resume file has version {version}, only version {RESUME_VERSION} is supported"
+        );
+        let total = |key: &[u8]| -> Result<u64> {
+            let total = value
+                .get(key)
+                .and_then(BValue::as_integer)
+                .ok_or_eyre("Resume file is missing a total")?;
+            u64::try_from(total).wrap_err("Resume file has a negative total")
+        };
+        let info_hash = value
+            .get(b"info hash")
+            .and_then(BValue::as_bytes)
+            .ok_or_eyre("Resume file has no info hash")?;
+        let have = value
+            .get(b"have")
+            .and_then(BValue::as_bytes)
+            .ok_or_eyre("Resume file has no pieces")?;
+        Ok(Self {
+            info_hash: InfoHash::try_from(info_hash)?,
+            downloaded: total(b"downloaded")?,
+            uploaded: total(b"uploaded")?,
+            have: Bitfield::new(have.to_vec()),
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn other_versions_are_refused() {
+        let state = ResumeState {
+            info_hash: InfoHash::new([2; 20]),
+            downloaded: 1000,
+            uploaded: 500,
+            have: Bitfield::new(vec![0b1010_0000]),
+        };
+        let bytes = state.to_bytes();
+        assert_eq!(ResumeState::from_bytes(&bytes).unwrap(), state);
+
+        let Ok(BValue::Dict(mut dict)) = bencode::decode(&bytes) else {
+            panic!("resume file to be a dictionary");
+        };
+        dict.insert(b"version".to_vec(), BValue::Integer(RESUME_VERSION + 1));
+        let newer = bencode::encode(&BValue::Dict(dict));
+        let _ = ResumeState::from_bytes(&newer).unwrap_err();
+    }
+}
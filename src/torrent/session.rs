@@ -0,0 +1,511 @@
+use std::collections::HashMap;
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr};
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex};
+use std::thread::JoinHandle;
+use std::time::Duration;
+
+use eyre::{bail, eyre, Result};
+
+use crate::clock::SystemClock;
+use crate::log::{debug, info, warn};
+use crate::messages::{Handshake, Message, ProtocolError};
+use crate::torrent::config::TorrentConfig;
+use crate::torrent::rate_limiter::RateLimiters;
+use crate::{
+    std_io_connection_with_config, ConnectionRead, ConnectionWrite, InfoHash, PeerId, StdIoConfig,
+    TcpTransport, Torrent, Transport,
+};
+
+/// At most this many peers that connected to us are waiting for their handshake at once.
+/// Any more are turned away, so a flood of connections can't start a thread for each.
+const MAX_PENDING_HANDSHAKES: usize = 64;
+/// How long to wait before accepting again after it failed, so that running out of file
+/// descriptors doesn't turn the listener into a busy loop.
+const ACCEPT_RETRY_DELAY: Duration = Duration::from_millis(100);
+
+/// Many torrents running side by side, like in a real client. They share our peer ID, the
+/// upload and download limits, and the port that peers connect to.
+///
+/// Incoming connections are handed to the torrent that their handshake asks for.
+#[derive(Clone)]
+pub struct Session {
+    own_peer_id: PeerId,
+    transport: Arc<dyn Transport>,
+    /// How every connection of the session's torrents buffers its data.
+    io_config: StdIoConfig,
+    rate_limiters: RateLimiters,
+    torrents: Arc<Mutex<HashMap<InfoHash, Torrent>>>,
+}
+
+impl Session {
+    /// Create a session without any torrents, using TCP to talk to peers.
+    /// Every torrent in the session introduces itself with `own_peer_id`.
+    #[must_use]
+    pub fn new(own_peer_id: PeerId) -> Self {
+        if !own_peer_id.is_well_formed() {
+            warn!("Peer ID {own_peer_id} isn't in the usual -XY1234- format");
+        }
+        Self {
+            own_peer_id,
+            transport: Arc::new(TcpTransport),
+            io_config: StdIoConfig::default(),
+            rate_limiters: RateLimiters::unlimited(Arc::new(SystemClock)),
+            torrents: Arc::default(),
+        }
+    }
+
+    /// Create a session like [Session::new], with a peer ID generated by [PeerId::random]
+    /// for the client `identifier` at the given version.
+    pub fn with_random_peer_id(
+        identifier: &[u8; 2],
+        major: u8,
+        minor: u16,
+        patch: u8,
+    ) -> Result<Self> {
+        let own_peer_id = PeerId::random(identifier, major, minor, patch)?;
+        Ok(Self::new(own_peer_id))
+    }
+
+    /// The peer ID that all torrents in the session share.
+    #[must_use]
+    pub fn own_peer_id(&self) -> PeerId {
+        self.own_peer_id
+    }
+
+    /// Talk to the peers of all torrents over `transport`, instead of TCP.
+    #[must_use]
+    pub fn with_transport(mut self, transport: impl Transport + 'static) -> Self {
+        self.transport = Arc::new(transport);
+        self
+    }
+
+    /// Buffer the connections of all torrents as `io_config` says, instead of the defaults.
+    #[must_use]
+    pub fn with_io_config(mut self, io_config: StdIoConfig) -> Self {
+        self.io_config = io_config;
+        self
+    }
+
+    /// Start a torrent in this session, or get the one that's already running for `info_hash`.
+    pub fn add_torrent(&self, info_hash: InfoHash) -> Result<Torrent> {
+        let mut torrents = self.lock_torrents()?;
+        let torrent = torrents.entry(info_hash).or_insert_with(|| {
+            Torrent::in_session(
+                self.own_peer_id,
+                info_hash,
+                self.rate_limiters.clone(),
+                self.transport.clone(),
+                self.io_config,
+            )
+        });
+        Ok(torrent.clone())
+    }
+
+    /// Shut the torrent for `info_hash` down, and stop routing connections to it.
+    pub fn remove_torrent(&self, info_hash: InfoHash) -> Result<()> {
+        let torrent = self
+            .lock_torrents()?
+            .remove(&info_hash)
+            .ok_or_else(|| eyre!("No torrent with info hash {info_hash}"))?;
+        torrent.shutdown()
+    }
+
+    /// Limit the upload rate over all torrents to `bytes_per_sec`, or lift the limit with `None`.
+    pub fn set_upload_limit(&self, bytes_per_sec: Option<u64>) {
+        self.rate_limiters.upload.set_rate(bytes_per_sec);
+    }
+
+    /// Limit the download rate over all torrents to `bytes_per_sec`, or lift the limit with `None`.
+    pub fn set_download_limit(&self, bytes_per_sec: Option<u64>) {
+        self.rate_limiters.download.set_rate(bytes_per_sec);
+    }
+
+    /// Accept connections from peers on `addr` in the background, until the returned
+    /// listener is stopped. Dropping it doesn't stop it.
+    ///
+    /// Peers that connect while 64 others are still waiting for their handshake are
+    /// disconnected right away.
+    pub fn listen(&self, addr: SocketAddr) -> Result<SessionListener> {
+        let listener = self.transport.listen(addr)?;
+        let local_addr = listener.local_addr()?;
+        info!("Session listening on {local_addr}");
+        let session = self.clone();
+        let io_config = self.io_config;
+        let stopped = Arc::new(AtomicBool::new(false));
+        let pending = Arc::new(AtomicUsize::new(0));
+        let thread = std::thread::spawn({
+            let stopped = stopped.clone();
+            move || {
+                loop {
+                    let accepted = listener.accept();
+                    if stopped.load(Ordering::Acquire) {
+                        break;
+                    }
+                    // Failing to accept one peer, e.g. because it hung up already or we're out
+                    // of file descriptors for now, doesn't stop us from accepting the next.
+                    let ((reader, writer), peer_addr) = match accepted {
+                        Ok(accepted) => accepted,
+                        Err(e) => {
+                            warn!("Failed to accept a connection on {local_addr}: {e:?}");
+                            std::thread::sleep(ACCEPT_RETRY_DELAY);
+                            continue;
+                        }
+                    };
+                    if pending.fetch_add(1, Ordering::AcqRel) >= MAX_PENDING_HANDSHAKES {
+                        pending.fetch_sub(1, Ordering::AcqRel);
+                        debug!("Not accepting connection from {peer_addr}, too many are pending");
+                        continue;
+                    }
+                    let session = session.clone();
+                    let pending = pending.clone();
+                    // Waiting for the handshake shouldn't hold up accepting other peers.
+                    let _ = std::thread::spawn(move || {
+                        let (connection_write, connection_read) =
+                            std_io_connection_with_config(io_config, reader, writer);
+                        let accepted = session.accept_connection(
+                            Some(peer_addr),
+                            connection_read,
+                            connection_write,
+                        );
+                        pending.fetch_sub(1, Ordering::AcqRel);
+                        if let Err(e) = accepted {
+                            debug!("Not accepting connection from {peer_addr}: {e}");
+                        }
+                    });
+                }
+            }
+        });
+        Ok(SessionListener {
+            local_addr,
+            transport: self.transport.clone(),
+            stopped,
+            thread,
+        })
+    }
+
+    /// Accept a connection from a peer that connected to us, handing it to the torrent that
+    /// the peer's handshake is for. Blocks until the handshake has arrived.
+    ///
+    /// Fails without accepting the connection if the peer doesn't handshake in time, or
+    /// wants a torrent that isn't part of this session.
+    pub fn accept_connection(
+        &self,
+        peer_addr: Option<SocketAddr>,
+        connection_read: impl ConnectionRead + Send + 'static,
+        connection_write: impl ConnectionWrite + Send + 'static,
+    ) -> Result<()> {
+        let handshake =
+            peek_handshake(&connection_read, TorrentConfig::default().handshake_timeout)?;
+        let Some(torrent) = self.lock_torrents()?.get(&handshake.info_hash).cloned() else {
+            bail!("Peer wants unknown torrent {}", handshake.info_hash);
+        };
+        // The torrent checks the rest of the handshake, like the peer ID, as usual.
+        let connection_read = PeekedRead {
+            handshake: Mutex::new(Some(Message::Handshake(handshake))),
+            inner: connection_read,
+        };
+        torrent.accept_peer_connection(None, peer_addr, connection_read, connection_write)
+    }
+
+    fn lock_torrents(&self) -> Result<std::sync::MutexGuard<'_, HashMap<InfoHash, Torrent>>> {
+        self.torrents
+            .lock()
+            .map_err(|_| eyre!("Session torrents poisoned"))
+    }
+}
+
+/// Accepts connections for a [Session], see [Session::listen].
+pub struct SessionListener {
+    local_addr: SocketAddr,
+    transport: Arc<dyn Transport>,
+    stopped: Arc<AtomicBool>,
+    thread: JoinHandle<()>,
+}
+
+impl SessionListener {
+    /// The address that's actually listened on, useful when listening on port 0.
+    #[must_use]
+    pub fn local_addr(&self) -> SocketAddr {
+        self.local_addr
+    }
+
+    /// Stop accepting connections, and wait for the listener to close. Connections that were
+    /// already accepted are kept.
+    pub fn stop(self) -> Result<()> {
+        self.stopped.store(true, Ordering::Release);
+        // Accepting blocks until someone connects, so that someone is us.
+        let mut wake_addr = self.local_addr;
+        if wake_addr.ip().is_unspecified() {
+            wake_addr.set_ip(match wake_addr.ip() {
+                IpAddr::V4(_) => IpAddr::V4(Ipv4Addr::LOCALHOST),
+                IpAddr::V6(_) => IpAddr::V6(Ipv6Addr::LOCALHOST),
+            });
+        }
+        // If this fails, the listener has most likely closed by itself already.
+        let _ = self.transport.connect(wake_addr);
+        self.thread
+            .join()
+            .map_err(|_| eyre!("Session listener panicked"))
+    }
+}
+
+/// Receive the handshake that starts an incoming connection, e.g. to find out which torrent
+/// the peer wants before handing the connection to it. Nothing about the handshake is checked.
+///
+/// The handshake is consumed, so whoever gets the connection next has to be given it too.
+pub fn peek_handshake(
+    connection_read: &impl ConnectionRead,
+    timeout: Duration,
+) -> Result<Handshake> {
+    match connection_read.receive_timeout(timeout)? {
+        Some(Message::Handshake(handshake)) => Ok(handshake),
+        Some(message) => Err(ProtocolError::UnexpectedMessage {
+            expected: "handshake message",
+            received: Box::new(message),
+        })?,
+        None => Err(ProtocolError::HandshakeTimeout(timeout))?,
+    }
+}
+
+/// A connection whose handshake was received already, to find out which torrent it's for.
+/// The handshake is handed out again first, so that the torrent can check it as usual.
+struct PeekedRead<R> {
+    handshake: Mutex<Option<Message>>,
+    inner: R,
+}
+
+impl<R: ConnectionRead> PeekedRead<R> {
+    fn take_handshake(&self) -> Option<Message> {
+        self.handshake
+            .lock()
+            .expect("mutex to not be poisoned")
+            .take()
+    }
+}
+
+impl<R: ConnectionRead> ConnectionRead for PeekedRead<R> {
+    fn receive(&self) -> Result<Message> {
+        match self.take_handshake() {
+            Some(handshake) => Ok(handshake),
+            None => self.inner.receive(),
+        }
+    }
+
+    fn receive_timeout(&self, timeout: Duration) -> Result<Option<Message>> {
+        match self.take_handshake() {
+            Some(handshake) => Ok(Some(handshake)),
+            None => self.inner.receive_timeout(timeout),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::VecDeque;
+    use std::io::Read;
+    use std::net::TcpStream;
+    use std::thread::sleep;
+
+    use crate::connections::mock_connection::MockConnection;
+    use crate::connections::transport::{BoxedStream, TransportListener};
+    use crate::messages::Handshake;
+    use crate::std_io_connection;
+
+    use super::*;
+
+    #[test]
+    fn incoming_connections_are_routed_by_info_hash() {
+        let session = Session::new(PeerId::new([1; 20]));
+        let first = session.add_torrent(InfoHash::new([2; 20])).unwrap();
+        let second = session.add_torrent(InfoHash::new([3; 20])).unwrap();
+        let peer_id = PeerId::new([10; 20]);
+        let handshake = Handshake::new(InfoHash::new([3; 20]), peer_id);
+        let connection = MockConnection::new(VecDeque::from([Message::Handshake(handshake)]));
+
+        session
+            .accept_connection(None, connection.clone(), connection)
+            .unwrap();
+        sleep(Duration::from_millis(100));
+
+        assert_eq!(first.connected_peers().unwrap(), []);
+        assert_eq!(second.connected_peers().unwrap(), [peer_id]);
+    }
+
+    #[test]
+    fn unknown_info_hash_is_rejected() {
+        let session = Session::new(PeerId::new([1; 20]));
+        let torrent = session.add_torrent(InfoHash::new([2; 20])).unwrap();
+        let handshake = Handshake::new(InfoHash::new([4; 20]), PeerId::new([10; 20]));
+        let connection = MockConnection::new(VecDeque::from([Message::Handshake(handshake)]));
+
+        let _ = session
+            .accept_connection(None, connection.clone(), connection.clone())
+            .unwrap_err();
+        sleep(Duration::from_millis(100));
+
+        assert_eq!(torrent.peer_count().unwrap(), 0);
+        assert!(connection.sent_messages.lock().unwrap().is_empty());
+    }
+
+    #[test]
+    fn removed_torrent_is_no_longer_routed_to() {
+        let session = Session::new(PeerId::new([1; 20]));
+        let info_hash = InfoHash::new([2; 20]);
+        let torrent = session.add_torrent(info_hash).unwrap();
+        let handshake = Handshake::new(info_hash, PeerId::new([10; 20]));
+        let connection = MockConnection::new(VecDeque::from([Message::Handshake(handshake)]));
+
+        session.remove_torrent(info_hash).unwrap();
+
+        assert!(!torrent.is_running());
+        let _ = session
+            .accept_connection(None, connection.clone(), connection)
+            .unwrap_err();
+        let _ = session.remove_torrent(info_hash).unwrap_err();
+    }
+
+    #[test]
+    fn peer_id_is_still_validated_after_peeking() {
+        let own_peer_id = PeerId::new([1; 20]);
+        let session = Session::new(own_peer_id);
+        let info_hash = InfoHash::new([2; 20]);
+        let torrent = session.add_torrent(info_hash).unwrap();
+        let peer_id = PeerId::new([10; 20]);
+        // Connecting to ourselves has the right info hash, but not an acceptable peer ID.
+        for peer_id in [peer_id, own_peer_id] {
+            let handshake = Handshake::new(info_hash, peer_id);
+            let connection = MockConnection::new(VecDeque::from([Message::Handshake(handshake)]));
+
+            assert_eq!(
+                peek_handshake(&connection.clone(), Duration::from_secs(1)).unwrap(),
+                handshake
+            );
+            connection
+                .queued_for_receive
+                .lock()
+                .unwrap()
+                .push_back(Message::Handshake(handshake));
+            session
+                .accept_connection(None, connection.clone(), connection)
+                .unwrap();
+        }
+        sleep(Duration::from_millis(100));
+
+        assert_eq!(torrent.connected_peers().unwrap(), [peer_id]);
+    }
+
+    #[test]
+    fn listener_stops_accepting_once_stopped() {
+        let session = Session::new(PeerId::new([1; 20]));
+        let info_hash = InfoHash::new([2; 20]);
+        let torrent = session.add_torrent(info_hash).unwrap();
+        let listener = session
+            .listen(SocketAddr::from(([127, 0, 0, 1], 0)))
+            .unwrap();
+        let addr = listener.local_addr();
+        let peer_id = PeerId::new([10; 20]);
+
+        let (reader, writer) = TcpTransport.connect(addr).unwrap();
+        let (mut connection_write, _connection_read) = std_io_connection(1024, reader, writer);
+        connection_write
+            .send(Message::Handshake(Handshake::new(info_hash, peer_id)))
+            .unwrap();
+        sleep(Duration::from_millis(100));
+        assert_eq!(torrent.connected_peers().unwrap(), [peer_id]);
+
+        listener.stop().unwrap();
+        assert!(TcpTransport.connect(addr).is_err());
+        assert_eq!(torrent.connected_peers().unwrap(), [peer_id]);
+    }
+
+    /// TCP, but the listener fails to accept the first few times.
+    struct FlakyTransport(usize);
+
+    struct FlakyListener {
+        listener: Box<dyn TransportListener>,
+        failures: AtomicUsize,
+    }
+
+    impl Transport for FlakyTransport {
+        fn connect(&self, addr: SocketAddr) -> Result<BoxedStream> {
+            TcpTransport.connect(addr)
+        }
+
+        fn listen(&self, addr: SocketAddr) -> Result<Box<dyn TransportListener>> {
+            Ok(Box::new(FlakyListener {
+                listener: TcpTransport.listen(addr)?,
+                failures: AtomicUsize::new(self.0),
+            }))
+        }
+    }
+
+    impl TransportListener for FlakyListener {
+        fn local_addr(&self) -> Result<SocketAddr> {
+            self.listener.local_addr()
+        }
+
+        fn accept(&self) -> Result<(BoxedStream, SocketAddr)> {
+            let failures = self.failures.load(Ordering::Acquire);
+            if failures > 0 {
+                self.failures.store(failures - 1, Ordering::Release);
+                bail!("Too many open files");
+            }
+            self.listener.accept()
+        }
+    }
+
+    #[test]
+    fn listener_keeps_accepting_after_a_failure() {
+        let session = Session::new(PeerId::new([1; 20])).with_transport(FlakyTransport(2));
+        let info_hash = InfoHash::new([2; 20]);
+        let torrent = session.add_torrent(info_hash).unwrap();
+        let listener = session
+            .listen(SocketAddr::from(([127, 0, 0, 1], 0)))
+            .unwrap();
+        let peer_id = PeerId::new([10; 20]);
+
+        let (reader, writer) = TcpTransport.connect(listener.local_addr()).unwrap();
+        let (mut connection_write, _connection_read) = std_io_connection(1024, reader, writer);
+        connection_write
+            .send(Message::Handshake(Handshake::new(info_hash, peer_id)))
+            .unwrap();
+        sleep(Duration::from_millis(400));
+
+        assert_eq!(torrent.connected_peers().unwrap(), [peer_id]);
+        listener.stop().unwrap();
+    }
+
+    #[test]
+    fn listener_turns_peers_away_while_too_many_handshakes_are_pending() {
+        let session = Session::new(PeerId::new([1; 20]));
+        let listener = session
+            .listen(SocketAddr::from(([127, 0, 0, 1], 0)))
+            .unwrap();
+        // None of them ever send a handshake.
+        let silent: Vec<_> = (0..MAX_PENDING_HANDSHAKES)
+            .map(|_| TcpStream::connect(listener.local_addr()).unwrap())
+            .collect();
+        sleep(Duration::from_millis(100));
+
+        let mut turned_away = TcpStream::connect(listener.local_addr()).unwrap();
+        turned_away
+            .set_read_timeout(Some(Duration::from_secs(1)))
+            .unwrap();
+        assert_eq!(turned_away.read(&mut [0; 1]).unwrap(), 0);
+
+        drop(silent);
+        listener.stop().unwrap();
+    }
+
+    #[test]
+    fn every_torrent_shares_the_session_peer_id() {
+        let session = Session::with_random_peer_id(b"Rp", 1, 2, 3).unwrap();
+        let first = session.add_torrent(InfoHash::new([2; 20])).unwrap();
+        let second = session.add_torrent(InfoHash::new([3; 20])).unwrap();
+
+        assert!(session.own_peer_id().is_well_formed());
+        assert_eq!(first.own_peer_id().unwrap(), session.own_peer_id());
+        assert_eq!(second.own_peer_id().unwrap(), session.own_peer_id());
+    }
+}
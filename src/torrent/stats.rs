@@ -0,0 +1,30 @@
+/// A snapshot of how a [Torrent](crate::Torrent) is doing, e.g. for a status line.
+/// See [Torrent::stats](crate::Torrent::stats).
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+pub struct TorrentStats {
+    /// How fast we're downloading from all peers together, in bytes per second.
+    pub download_rate: f64,
+    /// How fast we're uploading to all peers together, in bytes per second.
+    pub upload_rate: f64,
+    /// How many bytes of blocks have been downloaded since the torrent was started, including
+    /// from peers that are gone.
+    pub downloaded: u64,
+    /// How many bytes of blocks have been uploaded since the torrent was started, including
+    /// to peers that are gone.
+    pub uploaded: u64,
+    /// Like [downloaded](Self::downloaded), plus what earlier runs downloaded according to the
+    /// resume file. See [Torrent::load_state](crate::Torrent::load_state).
+    pub all_time_downloaded: u64,
+    /// Like [uploaded](Self::uploaded), plus what earlier runs uploaded.
+    pub all_time_uploaded: u64,
+    /// How many peers we're connected to.
+    pub peers: usize,
+    /// How many pieces we have.
+    pub pieces_complete: usize,
+    /// How many pieces the torrent has, or 0 while that's not known yet.
+    pub piece_count: usize,
+    /// How many bytes of pieces we don't have yet, or `None` while the metadata isn't known.
+    pub left: Option<u64>,
+    /// Whether we have every piece, and are only uploading.
+    pub seeding: bool,
+}
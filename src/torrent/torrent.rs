@@ -1,49 +1,377 @@
+use std::net::SocketAddr;
+use std::path::PathBuf;
+use std::sync::mpsc::{Receiver, RecvTimeoutError};
+use std::sync::{Arc, RwLock};
+use std::time::{Duration, Instant};
+
 use eyre::Result;
-use tracing::info;
 
 use crate::actor::handle::Handle;
+use crate::actor::mailbox::Mailbox;
 use crate::actor::outcome::Outcome;
+use crate::actor::supervisor::RestartPolicy;
+use crate::clock::{Clock, SystemClock};
+use crate::log::{info, warn};
+use crate::metainfo::info_hash;
+use crate::peer_source::{Announcers, PexPeers};
+use crate::torrent::config::TorrentConfig;
+use crate::torrent::event::TorrentEvent;
+use crate::torrent::rate_limiter::RateLimiters;
 use crate::torrent::torrent_actor::TorrentActor;
-use crate::{ConnectionRead, ConnectionWrite, InfoHash, PeerId};
+use crate::{
+    std_io_connection_with_config, AnnounceEvent, BoxedConnection, ConnectionFactory,
+    ConnectionRead, ConnectionWrite, EventObserver, Info, InfoHash, IpRange, MetricsSink, PeerId,
+    PeerSource, PieceStore, StdIoConfig, TcpTransport, TorrentStats, Transport, VerifyOnlyStore,
+};
+
+/// How often the torrent does its periodic housekeeping, like choking and unchoking peers.
+const TICK_INTERVAL: Duration = Duration::from_secs(1);
+/// How long to wait before asking a peer source again after it failed. Doubles with every
+/// failure in a row, up to [PEER_SOURCE_MAX_RETRY_DELAY].
+const PEER_SOURCE_RETRY_DELAY: Duration = Duration::from_secs(60);
+const PEER_SOURCE_MAX_RETRY_DELAY: Duration = Duration::from_secs(30 * 60);
+/// How long shutting down waits for trackers to be told that we're stopping.
+const STOPPED_ANNOUNCE_TIMEOUT: Duration = Duration::from_secs(2);
+
+/// The transport can be swapped after the torrent is created, so threads that dial peers in
+/// the background look it up each time.
+type SharedTransport = Arc<RwLock<Arc<dyn Transport>>>;
 
 /// This is the main entry point for this library, a "root aggregate" if you will.
 /// It's a cloneable handle (reference) to the torrent actor.
 #[derive(Clone)]
 pub struct Torrent {
     actor: Handle<TorrentActor>,
+    /// What [Torrent::add_peer] dials peers with.
+    transport: SharedTransport,
+    /// The threads of the peer sources, for [Torrent::announce_now].
+    announcers: Announcers,
+    /// Shared by all clones, and dropped along with the last one.
+    _shutdown: Arc<Shutdown>,
+}
+
+/// Shuts the torrent down once dropped, i.e. once the last [Torrent] clone is gone.
+struct Shutdown {
+    actor: Handle<TorrentActor>,
+    announcers: Announcers,
 }
 
 impl Torrent {
     /// Create a new torrent with the given peer ID and info hash.
     ///
+    /// The info hash is all that's needed: the rest of the metainfo is downloaded from the
+    /// first peers that support the metadata extension, like when starting from a magnet link.
+    ///
     /// After this call, the torrent is not connected to any peers, so make sure to call
     /// `connect_to_peer` or `accept_peer_connection` to actually initiate communication.
     #[must_use]
     pub fn new(own_peer_id: PeerId, info_hash: InfoHash) -> Self {
-        let actor = Handle::spawn(TorrentActor::new(own_peer_id, info_hash));
-        Self { actor }
+        Self::spawn(TorrentActor::with_config(
+            own_peer_id,
+            info_hash,
+            TorrentConfig::default(),
+            Arc::new(SystemClock),
+        ))
+    }
+
+    /// Create a new torrent like [Torrent::new], but with non-default tunables. Fails if
+    /// they don't [validate](TorrentConfig::validate).
+    pub fn with_config(
+        own_peer_id: PeerId,
+        info_hash: InfoHash,
+        config: TorrentConfig,
+    ) -> Result<Self> {
+        Self::with_clock(own_peer_id, info_hash, config, SystemClock)
+    }
+
+    /// Create a new torrent like [Torrent::with_config], which tells the time with `clock`.
+    /// Timeouts, choking and rates all go by it, so tests can move time along themselves.
+    pub fn with_clock(
+        own_peer_id: PeerId,
+        info_hash: InfoHash,
+        config: TorrentConfig,
+        clock: impl Clock,
+    ) -> Result<Self> {
+        config.validate()?;
+        let clock: Arc<dyn Clock> = Arc::new(clock);
+        Ok(Self::spawn(TorrentActor::with_config(
+            own_peer_id,
+            info_hash,
+            config,
+            clock,
+        )))
+    }
+
+    /// Create a torrent that seeds `content`, described by `metadata`, the raw info
+    /// dictionary, like [Torrent::load_content]. As we have every piece, peers are told so
+    /// right away, and nothing is ever requested from them.
+    pub fn new_seed(own_peer_id: PeerId, metadata: Vec<u8>, content: Vec<u8>) -> Result<Self> {
+        let torrent = Self::new(own_peer_id, info_hash(&metadata));
+        torrent.load_content(metadata, content)?;
+        Ok(torrent)
+    }
+
+    /// Create a torrent that downloads everything described by `metadata`, the raw info
+    /// dictionary, only to check it: every piece is hashed as it arrives and then thrown away,
+    /// so nothing is written to disk. Which pieces verified is reported as
+    /// [TorrentEvent::PieceCompleted] and [TorrentEvent::PieceFailed].
+    pub fn new_verify_only(own_peer_id: PeerId, metadata: Vec<u8>) -> Result<Self> {
+        let info = Info::from_bytes(&metadata)?;
+        let info_hash = info_hash(&metadata);
+        let mut actor = TorrentActor::with_config(
+            own_peer_id,
+            info_hash,
+            TorrentConfig::default(),
+            Arc::new(SystemClock),
+        );
+        actor.set_piece_store(Box::new(VerifyOnlyStore::new(info)));
+        let torrent = Self::spawn(actor);
+        torrent.set_metadata(metadata)?;
+        Ok(torrent)
+    }
+
+    /// Create a torrent that's part of a [Session](crate::Session), sharing its rate limits
+    /// and transport with the session's other torrents.
+    pub(crate) fn in_session(
+        own_peer_id: PeerId,
+        info_hash: InfoHash,
+        rate_limiters: RateLimiters,
+        transport: Arc<dyn Transport>,
+        io_config: StdIoConfig,
+    ) -> Self {
+        let config = TorrentConfig {
+            io: io_config,
+            ..TorrentConfig::default()
+        };
+        let mut actor =
+            TorrentActor::with_config(own_peer_id, info_hash, config, Arc::new(SystemClock));
+        actor.share_rate_limiters(rate_limiters);
+        let mut torrent = Self::spawn(actor);
+        torrent.set_transport(transport);
+        torrent
+    }
+
+    /// If the torrent's actor ever fails, it carries on with the next action, so the torrent
+    /// keeps going with everything it had: its peers, pieces and subscribers.
+    ///
+    /// Peers found through peer exchange are dialed in the background, like those of any
+    /// other [PeerSource].
+    fn spawn(mut actor: TorrentActor) -> Self {
+        let pex_peers = PexPeers::default();
+        let announcers = Announcers::default();
+        actor.set_pex_peers(pex_peers.clone());
+        actor.set_announcers(announcers.clone());
+        let actor = Handle::spawn_resuming(actor, Mailbox::Unbounded, RestartPolicy::default());
+        actor.act_every(TICK_INTERVAL, TorrentActor::tick);
+        let torrent = Self {
+            _shutdown: Arc::new(Shutdown {
+                actor: actor.clone(),
+                announcers: announcers.clone(),
+            }),
+            actor,
+            transport: Arc::new(RwLock::new(Arc::new(TcpTransport))),
+            announcers,
+        };
+        torrent.add_peer_source(pex_peers);
+        torrent
+    }
+
+    fn set_transport(&mut self, transport: Arc<dyn Transport>) {
+        *self.transport.write().expect("lock to not be poisoned") = transport;
+    }
+
+    /// Seed `content` straight from memory, given the raw info dictionary it's described by.
+    /// Every piece is checked against its hash first, and fails if any doesn't match.
+    ///
+    /// Call this before connecting to any peers, already connected ones aren't told about
+    /// the new pieces.
+    pub fn load_content(&self, metadata: Vec<u8>, content: Vec<u8>) -> Result<()> {
+        self.actor
+            .ask(move |torrent| torrent.load_content(metadata, &content))
+    }
+
+    /// Hand the torrent its metadata, the raw info dictionary, if it's known from e.g. a
+    /// .torrent file. Otherwise it's downloaded from peers.
+    pub fn set_metadata(&self, metadata: Vec<u8>) -> Result<()> {
+        self.actor
+            .ask(move |torrent| torrent.set_metadata(metadata))
+    }
+
+    /// Keep the torrent's pieces in `store`, instead of in memory. Any pieces already in there
+    /// are only used once they're [verified](Torrent::verify_existing_files).
+    pub fn set_piece_store(&self, store: impl PieceStore + 'static) -> Result<()> {
+        self.actor.act(move |torrent| {
+            torrent.set_piece_store(Box::new(store));
+            Ok(Outcome::Continue)
+        })
+    }
+
+    /// Store the torrent's files under `download_dir`, laid out like its metadata says: a
+    /// single file, or a directory with the torrent's name. Set it before anything is
+    /// downloaded, pieces that are already stored elsewhere aren't moved.
+    pub fn set_download_dir(&self, download_dir: impl Into<PathBuf>) -> Result<()> {
+        let download_dir = download_dir.into();
+        self.actor
+            .ask(move |torrent| torrent.set_download_dir(download_dir))
+    }
+
+    /// Tell `observer` about the important things that happen to the torrent, like peers
+    /// connecting and pieces completing. Set it before adding peers, as connections that are
+    /// already open don't switch to it.
+    pub fn set_event_observer(&self, observer: impl EventObserver) -> Result<()> {
+        let observer: Arc<dyn EventObserver> = Arc::new(observer);
+        self.actor.act(move |torrent| {
+            torrent.set_event_observer(observer);
+            Ok(Outcome::Continue)
+        })
+    }
+
+    /// Save the all-time totals of [stats](Self::stats) and the pieces we have to a small
+    /// resume file at `path`, e.g. alongside the data, to [load](Self::load_state) on the
+    /// next run. It's saved there again when the torrent shuts down.
+    pub fn save_state(&self, path: impl Into<PathBuf>) -> Result<()> {
+        let path = path.into();
+        self.actor.ask(move |torrent| torrent.save_state(&path))
+    }
+
+    /// Load a resume file saved by [save_state](Self::save_state) on an earlier run, so the
+    /// all-time totals carry on from there and the pieces aren't downloaded again. The pieces
+    /// aren't checked, use [verify_existing_files](Self::verify_existing_files) for that.
+    ///
+    /// The torrent's metadata must be known.
+    pub fn load_state(&self, path: impl Into<PathBuf>) -> Result<()> {
+        let path = path.into();
+        self.actor.ask(move |torrent| torrent.load_state(&path))
+    }
+
+    /// Report counters and gauges, like the number of connections, to `sink`. Set it before
+    /// adding peers, as connections that are already open don't switch to it.
+    pub fn set_metrics_sink(&self, sink: impl MetricsSink) -> Result<()> {
+        let sink: Arc<dyn MetricsSink> = Arc::new(sink);
+        self.actor.act(move |torrent| {
+            torrent.set_metrics_sink(sink);
+            Ok(Outcome::Continue)
+        })
+    }
+
+    /// Resume a download from before a restart: hash every piece that's in the store already,
+    /// and only download the ones that are missing or corrupt. The metadata has to be known.
+    ///
+    /// Runs in the background, with [TorrentEvent::VerifyProgress] reporting how far along it
+    /// is, and a [TorrentEvent::Progress] with the downloaded fraction once it's done.
+    pub fn verify_existing_files(&self) -> Result<()> {
+        self.actor.ask(TorrentActor::verify_existing_files)
+    }
+
+    /// The piece with this index, once it's been downloaded and has passed its hash check.
+    pub fn read_piece(&self, index: u32) -> Result<Vec<u8>> {
+        self.actor.ask(move |torrent| torrent.read_piece(index))
+    }
+
+    /// Dial peers added with [Torrent::add_peer] over `transport`, instead of TCP.
+    #[must_use]
+    pub fn with_transport(mut self, transport: impl Transport + 'static) -> Self {
+        self.set_transport(Arc::new(transport));
+        self
+    }
+
+    /// Connects to the peer at `peer_addr` over the torrent's transport, reconnecting
+    /// like [Torrent::connect_with_factory] whenever the connection closes. Peers that were
+    /// already added, or that are already connected, are ignored.
+    ///
+    /// This is all that's needed for peers found through e.g. a tracker, use
+    /// [Torrent::connect_to_peer] to bring your own connection instead.
+    pub fn add_peer(&self, peer_addr: SocketAddr, expected_peer_id: Option<PeerId>) -> Result<()> {
+        add_peer(&self.actor, &self.transport, peer_addr, expected_peer_id)
+    }
+
+    /// Keep asking `source` for peers, and [add](Torrent::add_peer) every one it finds.
+    ///
+    /// The source is asked on a separate thread, as often as it says to, until it has no more
+    /// peers or the torrent is shut down.
+    pub fn add_peer_source(&self, source: impl PeerSource + 'static) {
+        let actor = self.actor.clone();
+        let transport = self.transport.clone();
+        let (announces, finished) = self.announcers.add();
+        let _ = std::thread::spawn(move || {
+            discover_peers(source, &actor, &transport, &announces);
+            drop(finished);
+        });
+    }
+
+    /// Ask every [peer source](Torrent::add_peer_source) for peers right away, rather than
+    /// when they're next due, e.g. after the network came back. Trackers are told about
+    /// `event`, and announce again on their usual interval from then on.
+    ///
+    /// Trackers that were announced to more recently than their min interval are skipped,
+    /// as they might ban us for announcing too often, unless we completed or are stopping.
+    /// The torrent announces those by itself though, and after announcing that it's stopping
+    /// a source isn't asked for more peers.
+    pub fn announce_now(&self, event: AnnounceEvent) {
+        self.announcers.announce(event);
     }
 
-    /// Connects to a known peer, optionally with an expected peer ID.
+    /// Connects to a known peer, optionally with an expected peer ID and its address.
     /// In a real application peers would be discovered using a DHT or a tracker.
     ///
     /// If a specific peer ID is expected and the connection's peer ID does not match,
     /// the connection will be closed. If the info hash of the `Torrent` does not match
     /// the connection's info hash, the connection will be closed. If the first received
     /// message is not a handshake, the connection will be closed.
+    ///
+    /// The halves are given separately, like everywhere else a connection is handed over,
+    /// so any [Connection](crate::Connection) is [split](crate::Connection::split) first:
+    ///
+    /// ```no_run
+    /// use std::net::SocketAddr;
+    ///
+    /// use torrent_poc::{
+    ///     std_io_connection_with_config, Connection, InfoHash, PeerId, StdIoConfig, TcpTransport,
+    ///     Torrent, Transport,
+    /// };
+    ///
+    /// let torrent = Torrent::new(PeerId::new([1; 20]), InfoHash::new([2; 20]));
+    /// let peer_addr: SocketAddr = "127.0.0.1:6881".parse()?;
+    /// let (reader, writer) = TcpTransport.connect(peer_addr)?;
+    /// let (connection_write, connection_read) =
+    ///     std_io_connection_with_config(StdIoConfig::default(), reader, writer).split();
+    /// torrent.connect_to_peer(None, Some(peer_addr), connection_read, connection_write)?;
+    /// # Ok::<(), eyre::Report>(())
+    /// ```
     pub fn connect_to_peer(
         &self,
         expected_peer_id: Option<PeerId>,
+        peer_addr: Option<SocketAddr>,
         connection_read: impl ConnectionRead + Send + 'static,
         connection_write: impl ConnectionWrite + Send + 'static,
     ) -> Result<()> {
         self.actor.act(move |torrent| {
-            torrent.connect_to_peer(expected_peer_id, connection_read, connection_write)?;
+            torrent.connect_to_peer(
+                expected_peer_id,
+                peer_addr,
+                connection_read,
+                connection_write,
+            )?;
             Ok(Outcome::Continue)
         })
     }
 
-    /// Accept a connection from a peer that connected to us, optionally with an expected peer ID.
+    /// Connects to a peer like [Torrent::connect_to_peer], but opens the connection with
+    /// `factory`. Whenever the connection closes, the torrent uses the factory to reconnect,
+    /// backing off exponentially if the peer can't be reached.
+    pub fn connect_with_factory(
+        &self,
+        expected_peer_id: Option<PeerId>,
+        peer_addr: SocketAddr,
+        factory: impl ConnectionFactory + 'static,
+    ) -> Result<()> {
+        self.actor.act(move |torrent| {
+            torrent.connect_with_factory(expected_peer_id, peer_addr, Box::new(factory))
+        })
+    }
+
+    /// Accept a connection from a peer that connected to us, optionally with an expected peer ID
+    /// and its address.
     ///
     /// If a specific peer ID is expected and the connection's peer ID does not match,
     /// the connection will be closed. If the info hash of the `Torrent` does not match
@@ -52,15 +380,142 @@ impl Torrent {
     pub fn accept_peer_connection(
         &self,
         expected_peer_id: Option<PeerId>,
+        peer_addr: Option<SocketAddr>,
         connection_read: impl ConnectionRead + Send + 'static,
         connection_write: impl ConnectionWrite + Send + 'static,
     ) -> Result<()> {
         self.actor.act(move |torrent| {
-            torrent.accept_peer_connection(expected_peer_id, connection_read, connection_write)?;
+            torrent.accept_peer_connection(
+                expected_peer_id,
+                peer_addr,
+                connection_read,
+                connection_write,
+            )?;
             Ok(Outcome::Continue)
         })
     }
 
+    /// Call `callback` with the address of every DHT node that a peer announces.
+    /// Only peers whose address is known can be reported.
+    pub fn set_dht_node_callback(
+        &self,
+        callback: impl Fn(SocketAddr) + Send + 'static,
+    ) -> Result<()> {
+        self.actor.act(move |torrent| {
+            torrent.set_dht_node_callback(callback);
+            Ok(Outcome::Continue)
+        })
+    }
+
+    /// Stop downloading and uploading, while staying connected to peers so that
+    /// [resuming](Torrent::resume) is instant.
+    pub fn pause(&self) -> Result<()> {
+        self.actor.act(|torrent| {
+            torrent.pause()?;
+            Ok(Outcome::Continue)
+        })
+    }
+
+    /// Start downloading and uploading again after [Torrent::pause].
+    pub fn resume(&self) -> Result<()> {
+        self.actor.act(|torrent| {
+            torrent.resume()?;
+            Ok(Outcome::Continue)
+        })
+    }
+
+    /// Limit the upload rate over all of the torrent's connections to `bytes_per_sec`,
+    /// or lift the limit with `None`.
+    pub fn set_upload_limit(&self, bytes_per_sec: Option<u64>) -> Result<()> {
+        self.actor.act(move |torrent| {
+            torrent.set_upload_limit(bytes_per_sec);
+            Ok(Outcome::Continue)
+        })
+    }
+
+    /// Limit the download rate over all of the torrent's connections to `bytes_per_sec`,
+    /// or lift the limit with `None`.
+    pub fn set_download_limit(&self, bytes_per_sec: Option<u64>) -> Result<()> {
+        self.actor.act(move |torrent| {
+            torrent.set_download_limit(bytes_per_sec);
+            Ok(Outcome::Continue)
+        })
+    }
+
+    /// Receive a [TorrentEvent] for everything that happens to the torrent from now on.
+    /// Dropping the receiver is fine, the torrent stops sending to it.
+    pub fn subscribe(&self) -> Result<Receiver<TorrentEvent>> {
+        self.actor.ask(|torrent| Ok(torrent.subscribe()))
+    }
+
+    /// The peer ID that the torrent introduces itself to peers with.
+    pub fn own_peer_id(&self) -> Result<PeerId> {
+        self.actor.ask(|torrent| Ok(torrent.own_peer_id()))
+    }
+
+    /// The peers that the torrent is currently connected to, in no particular order.
+    /// Connections that are still handshaking aren't included.
+    pub fn connected_peers(&self) -> Result<Vec<PeerId>> {
+        self.actor.ask(|torrent| Ok(torrent.connected_peers()))
+    }
+
+    /// Disconnect from a peer on purpose, e.g. because it sends garbage or is on a ban list.
+    /// Fails if the torrent isn't connected to the peer.
+    pub fn remove_peer(&self, peer_id: PeerId) -> Result<()> {
+        self.actor.ask(move |torrent| torrent.remove_peer(peer_id))
+    }
+
+    /// Refuse to connect to, or accept connections from, peers in `range`. Connected peers in
+    /// it are dropped right away.
+    pub fn ban(&self, range: impl Into<IpRange>) -> Result<()> {
+        let range = range.into();
+        self.actor.ask(move |torrent| {
+            torrent.ban(range);
+            Ok(())
+        })
+    }
+
+    /// Lift a ban added with [ban](Self::ban) for the exact same range.
+    pub fn unban(&self, range: impl Into<IpRange>) -> Result<()> {
+        let range = range.into();
+        self.actor.ask(move |torrent| {
+            torrent.unban(range);
+            Ok(())
+        })
+    }
+
+    /// The number of peers that the torrent is currently connected to.
+    pub fn peer_count(&self) -> Result<usize> {
+        self.actor.ask(|torrent| Ok(torrent.peer_count()))
+    }
+
+    /// How fast the torrent is transferring, how much it has, and who it's connected to.
+    pub fn stats(&self) -> Result<TorrentStats> {
+        self.actor.ask(|torrent| Ok(torrent.stats()))
+    }
+
+    /// Whether the torrent is still running, i.e. hasn't been shut down.
+    #[must_use]
+    pub fn is_running(&self) -> bool {
+        self.actor.is_running()
+    }
+
+    /// Block until the torrent is shut down from elsewhere.
+    pub fn wait(&self) -> Result<()> {
+        self.actor.wait()
+    }
+
+    /// Shut the torrent down, returning once all connections have been closed and all
+    /// previously queued work has finished.
+    ///
+    /// Dropping the last `Torrent` does the same, but this makes it explicit and reports
+    /// any errors.
+    pub fn shutdown(self) -> Result<()> {
+        self.announcers.stop(STOPPED_ANNOUNCE_TIMEOUT);
+        self.actor.act(TorrentActor::shutdown)?;
+        self.actor.stop()
+    }
+
     /// Dummy method to send a "message" to a peer.
     pub fn send(&self, peer_id: PeerId, message: String) -> Result<()> {
         self.actor.act(move |torrent| {
@@ -79,10 +534,548 @@ impl Torrent {
     }
 }
 
+fn add_peer(
+    actor: &Handle<TorrentActor>,
+    transport: &SharedTransport,
+    peer_addr: SocketAddr,
+    expected_peer_id: Option<PeerId>,
+) -> Result<()> {
+    let transport = transport.read().expect("lock to not be poisoned").clone();
+    actor.act(move |torrent| {
+        let io_config = torrent.config().io;
+        let factory = move || -> Result<BoxedConnection> {
+            let (reader, writer) = transport.connect(peer_addr)?;
+            let (connection_write, connection_read) =
+                std_io_connection_with_config(io_config, reader, writer);
+            Ok((Box::new(connection_write), Box::new(connection_read)))
+        };
+        torrent.add_peer(expected_peer_id, peer_addr, Box::new(factory))
+    })
+}
+
+/// Ask `source` for peers until it has no more, or the torrent stops running. Anything
+/// received on `announces` asks it right away, and it's asked one last time once stopped.
+fn discover_peers(
+    mut source: impl PeerSource,
+    actor: &Handle<TorrentActor>,
+    transport: &SharedTransport,
+    announces: &Receiver<AnnounceEvent>,
+) {
+    let mut due = Instant::now();
+    let mut announce = None;
+    let mut failures = 0;
+    while actor.is_running() {
+        if announce.is_some() || Instant::now() >= due {
+            update_stats(&mut source, actor);
+            let discovered = match announce.take() {
+                Some(event) => source.discover_now(event),
+                None => source.discover().map(Some),
+            };
+            match discovered {
+                Ok(Some(discovered)) => {
+                    failures = 0;
+                    for peer_addr in discovered.peers {
+                        if add_peer(actor, transport, peer_addr, None).is_err() {
+                            return;
+                        }
+                    }
+                    match discovered.next_discovery {
+                        Some(next_discovery) => due = Instant::now() + next_discovery,
+                        None => return,
+                    }
+                }
+                // Too soon for the source, so the regular discovery stays due when it was.
+                Ok(None) => {}
+                Err(e) => {
+                    failures += 1;
+                    let delay = peer_source_retry_delay(failures);
+                    warn!("Failed to discover peers, trying again in {delay:?}: {e:?}");
+                    due = Instant::now() + delay;
+                }
+            }
+        }
+        // Wait in short steps, so the thread doesn't outlive the torrent by long.
+        let step = TICK_INTERVAL.min(due.saturating_duration_since(Instant::now()));
+        match announces.recv_timeout(step) {
+            Ok(AnnounceEvent::Stopped) => {
+                update_stats(&mut source, actor);
+                if let Err(e) = source.discover_now(AnnounceEvent::Stopped) {
+                    warn!("Failed to announce that we're stopping: {e:?}");
+                }
+                return;
+            }
+            Ok(event) => announce = Some(event),
+            Err(RecvTimeoutError::Timeout) => {}
+            // Every handle to the torrent is gone, but it might still be shutting down.
+            Err(RecvTimeoutError::Disconnected) => std::thread::sleep(step),
+        }
+    }
+}
+
+/// Tell `source` how the torrent is doing, so that trackers are told the current totals
+/// rather than the ones from when the source was added.
+fn update_stats(source: &mut impl PeerSource, actor: &Handle<TorrentActor>) {
+    if let Ok(stats) = actor.ask(|torrent| Ok(torrent.stats())) {
+        source.update_stats(&stats);
+    }
+}
+
+/// How long to wait after `failures` failures in a row, so that a tracker that keeps
+/// refusing us isn't asked every minute.
+fn peer_source_retry_delay(failures: u32) -> Duration {
+    let doublings = failures.saturating_sub(1).min(16);
+    PEER_SOURCE_RETRY_DELAY
+        .saturating_mul(1 << doublings)
+        .min(PEER_SOURCE_MAX_RETRY_DELAY)
+}
+
 /// Ensures any in-progress actions finish running before the torrent is dropped, avoiding
 /// disk corruption.
-impl Drop for Torrent {
+impl Drop for Shutdown {
     fn drop(&mut self) {
+        self.announcers.stop(STOPPED_ANNOUNCE_TIMEOUT);
         let _ = self.actor.stop();
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use std::collections::VecDeque;
+    use std::io::{Read, Write};
+    use std::sync::atomic::{AtomicBool, Ordering};
+    use std::sync::mpsc::TryRecvError;
+    use std::sync::Mutex;
+    use std::thread::sleep;
+
+    use crate::connections::loopback::loopback;
+    use crate::connections::mock_connection::MockConnection;
+    use crate::messages::{Handshake, Message};
+    use crate::observer::RecordingObserver;
+    use crate::{
+        AnnounceRequest, BoxedStream, Connection, DiscoveredPeers, EventLevel, HttpTracker, SansIo,
+        StaticPeers, TransportListener,
+    };
+
+    use super::*;
+
+    #[test]
+    fn peer_connected_event_after_handshake() {
+        let info_hash = InfoHash::new([2; 20]);
+        let torrent = Torrent::new(PeerId::new([1; 20]), info_hash);
+        let events = torrent.subscribe().unwrap();
+        let peer_id = PeerId::new([10; 20]);
+        let handshake = Message::Handshake(Handshake::new(info_hash, peer_id));
+        let connection = MockConnection::new(VecDeque::from([handshake]));
+
+        torrent
+            .connect_to_peer(Some(peer_id), None, connection.clone(), connection)
+            .unwrap();
+
+        assert_eq!(
+            events.recv_timeout(Duration::from_secs(1)),
+            Ok(TorrentEvent::PeerConnected(peer_id, None))
+        );
+        torrent.shutdown().unwrap();
+    }
+
+    #[test]
+    fn peer_connected_event_has_the_address() {
+        let info_hash = InfoHash::new([2; 20]);
+        let torrent = Torrent::new(PeerId::new([1; 20]), info_hash);
+        let events = torrent.subscribe().unwrap();
+        let peer_id = PeerId::new([10; 20]);
+        let peer_addr = SocketAddr::from(([10, 0, 0, 1], 51413));
+        let handshake = Message::Handshake(Handshake::new(info_hash, peer_id));
+        let connection = MockConnection::new(VecDeque::from([handshake]));
+
+        torrent
+            .accept_peer_connection(None, Some(peer_addr), connection.clone(), connection)
+            .unwrap();
+
+        assert_eq!(
+            events.recv_timeout(Duration::from_secs(1)),
+            Ok(TorrentEvent::PeerConnected(peer_id, Some(peer_addr)))
+        );
+        torrent.shutdown().unwrap();
+    }
+
+    #[test]
+    fn connected_peers_lists_every_peer() {
+        let info_hash = InfoHash::new([2; 20]);
+        let torrent = Torrent::new(PeerId::new([1; 20]), info_hash);
+        let peer_ids = [PeerId::new([10; 20]), PeerId::new([11; 20])];
+        for peer_id in peer_ids {
+            let handshake = Message::Handshake(Handshake::new(info_hash, peer_id));
+            let connection = MockConnection::new(VecDeque::from([handshake]));
+            torrent
+                .connect_to_peer(Some(peer_id), None, connection.clone(), connection)
+                .unwrap();
+        }
+        sleep(Duration::from_millis(100));
+
+        let mut connected = torrent.connected_peers().unwrap();
+        connected.sort();
+
+        assert_eq!(connected, peer_ids);
+        assert_eq!(torrent.peer_count().unwrap(), 2);
+        torrent.shutdown().unwrap();
+    }
+
+    /// A transport that records what's dialed and written, while the peer stays silent.
+    #[derive(Default)]
+    struct RecordingTransport {
+        dialed: Arc<Mutex<Vec<SocketAddr>>>,
+        written: Arc<Mutex<Vec<u8>>>,
+    }
+
+    struct SilentReader;
+
+    impl Read for SilentReader {
+        fn read(&mut self, _buf: &mut [u8]) -> std::io::Result<usize> {
+            sleep(Duration::from_secs(1));
+            Ok(0)
+        }
+    }
+
+    struct SharedWriter(Arc<Mutex<Vec<u8>>>);
+
+    impl Write for SharedWriter {
+        fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+            self.0.lock().unwrap().extend_from_slice(buf);
+            Ok(buf.len())
+        }
+
+        fn flush(&mut self) -> std::io::Result<()> {
+            Ok(())
+        }
+    }
+
+    impl Transport for RecordingTransport {
+        fn connect(&self, addr: SocketAddr) -> Result<BoxedStream> {
+            self.dialed.lock().unwrap().push(addr);
+            Ok((
+                Box::new(SilentReader),
+                Box::new(SharedWriter(self.written.clone())),
+            ))
+        }
+
+        fn listen(&self, _addr: SocketAddr) -> Result<Box<dyn TransportListener>> {
+            Err(eyre::eyre!("not supported"))
+        }
+    }
+
+    #[test]
+    fn add_peer_dials_and_sends_a_handshake() {
+        let own_peer_id = PeerId::new([1; 20]);
+        let info_hash = InfoHash::new([2; 20]);
+        let peer_addr = SocketAddr::from(([10, 0, 0, 1], 6881));
+        let transport = RecordingTransport::default();
+        let (dialed, written) = (transport.dialed.clone(), transport.written.clone());
+        let torrent = Torrent::new(own_peer_id, info_hash).with_transport(transport);
+
+        torrent.add_peer(peer_addr, None).unwrap();
+        sleep(Duration::from_millis(100));
+
+        assert_eq!(*dialed.lock().unwrap(), vec![peer_addr]);
+        let written = written.lock().unwrap().clone();
+        let (_, handshake) = Handshake::decode(&written).unwrap();
+        assert_eq!(handshake.info_hash, info_hash);
+        assert_eq!(handshake.peer_id, own_peer_id);
+        torrent.shutdown().unwrap();
+    }
+
+    #[test]
+    fn peers_from_a_source_are_dialed_once() {
+        let peer_addrs = [
+            SocketAddr::from(([10, 0, 0, 1], 6881)),
+            SocketAddr::from(([10, 0, 0, 2], 6881)),
+        ];
+        let transport = RecordingTransport::default();
+        let dialed = transport.dialed.clone();
+        let torrent =
+            Torrent::new(PeerId::new([1; 20]), InfoHash::new([2; 20])).with_transport(transport);
+
+        torrent.add_peer(peer_addrs[0], None).unwrap();
+        torrent.add_peer_source(StaticPeers::new(vec![
+            peer_addrs[0],
+            peer_addrs[1],
+            peer_addrs[1],
+        ]));
+        sleep(Duration::from_millis(100));
+
+        let mut dialed = dialed.lock().unwrap().clone();
+        dialed.sort();
+        assert_eq!(dialed, peer_addrs);
+        torrent.shutdown().unwrap();
+    }
+
+    /// A [PeerSource] that remembers what it was asked to announce, and the latest stats it
+    /// was given.
+    #[derive(Debug, Clone, Default)]
+    struct RecordingSource(
+        Arc<Mutex<Vec<AnnounceEvent>>>,
+        Arc<Mutex<Option<TorrentStats>>>,
+    );
+
+    impl PeerSource for RecordingSource {
+        fn discover(&mut self) -> Result<DiscoveredPeers> {
+            Ok(DiscoveredPeers {
+                peers: vec![],
+                next_discovery: Some(Duration::from_secs(3600)),
+            })
+        }
+
+        fn discover_now(&mut self, event: AnnounceEvent) -> Result<Option<DiscoveredPeers>> {
+            self.0.lock().unwrap().push(event);
+            self.discover().map(Some)
+        }
+
+        fn update_stats(&mut self, stats: &TorrentStats) {
+            *self.1.lock().unwrap() = Some(*stats);
+        }
+    }
+
+    #[test]
+    fn failing_peer_sources_are_backed_off() {
+        assert_eq!(peer_source_retry_delay(1), PEER_SOURCE_RETRY_DELAY);
+        assert_eq!(peer_source_retry_delay(2), 2 * PEER_SOURCE_RETRY_DELAY);
+        assert_eq!(peer_source_retry_delay(3), 4 * PEER_SOURCE_RETRY_DELAY);
+        assert_eq!(peer_source_retry_delay(100), PEER_SOURCE_MAX_RETRY_DELAY);
+    }
+
+    #[test]
+    fn stopping_is_announced_on_shutdown() {
+        let torrent = Torrent::new(PeerId::new([1; 20]), InfoHash::new([2; 20]));
+        let source = RecordingSource::default();
+        torrent.add_peer_source(source.clone());
+        sleep(Duration::from_millis(50));
+
+        torrent.shutdown().unwrap();
+
+        assert_eq!(*source.0.lock().unwrap(), [AnnounceEvent::Stopped]);
+    }
+
+    #[test]
+    fn stopping_is_announced_with_the_current_stats() {
+        let content = vec![7; 64];
+        let info = Info::from_content("test", 16, &content);
+        let torrent = Torrent::new(PeerId::new([1; 20]), info.info_hash());
+        let source = RecordingSource::default();
+        torrent.add_peer_source(source.clone());
+        sleep(Duration::from_millis(50));
+        assert_eq!(source.1.lock().unwrap().unwrap().left, None);
+
+        // Everything is there once the metadata is known, so nothing is left.
+        torrent.load_content(info.to_bytes(), content).unwrap();
+        torrent.shutdown().unwrap();
+
+        assert_eq!(*source.0.lock().unwrap(), [AnnounceEvent::Stopped]);
+        assert_eq!(source.1.lock().unwrap().unwrap().left, Some(0));
+    }
+
+    #[test]
+    fn announce_now_respects_the_min_interval() {
+        // Asks for a min interval from the second announce on.
+        let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+        let announce_url = format!("http://{}/announce", listener.local_addr().unwrap());
+        let announces = Arc::new(Mutex::new(Vec::new()));
+        let _ = std::thread::spawn({
+            let announces = announces.clone();
+            move || {
+                for mut stream in listener.incoming().map(Result::unwrap) {
+                    let mut request = Vec::new();
+                    let mut buffer = [0; 1024];
+                    while !request.ends_with(b"\r\n\r\n") {
+                        let length = stream.read(&mut buffer).unwrap();
+                        request.extend_from_slice(&buffer[..length]);
+                    }
+                    let mut announces = announces.lock().unwrap();
+                    announces.push(String::from_utf8_lossy(&request).into_owned());
+                    let body: &[u8] = if announces.len() == 1 {
+                        b"d8:intervali1800e5:peers0:e"
+                    } else {
+                        b"d8:intervali1800e12:min intervali60e5:peers0:e"
+                    };
+                    stream.write_all(b"HTTP/1.0 200 OK\r\n\r\n").unwrap();
+                    stream.write_all(body).unwrap();
+                }
+            }
+        });
+        let announce_count = || announces.lock().unwrap().len();
+        let wait_for_announces = |count| {
+            let deadline = Instant::now() + Duration::from_secs(2);
+            while announce_count() < count && Instant::now() < deadline {
+                sleep(Duration::from_millis(10));
+            }
+        };
+        let (own_peer_id, info_hash) = (PeerId::new([1; 20]), InfoHash::new([2; 20]));
+        let torrent = Torrent::new(own_peer_id, info_hash);
+        let request = AnnounceRequest {
+            info_hash,
+            peer_id: own_peer_id,
+            port: 6881,
+            uploaded: 0,
+            downloaded: 0,
+            left: 100,
+            event: AnnounceEvent::Started,
+        };
+
+        torrent.add_peer_source(HttpTracker::new(announce_url, request));
+        wait_for_announces(1);
+        torrent.announce_now(AnnounceEvent::Completed);
+        wait_for_announces(2);
+        // Within the min interval now, so this one is skipped.
+        torrent.announce_now(AnnounceEvent::None);
+        sleep(Duration::from_millis(200));
+
+        let announces = announces.lock().unwrap().clone();
+        assert_eq!(announces.len(), 2);
+        assert!(announces[0].contains("&event=started"), "{}", announces[0]);
+        assert!(
+            announces[1].contains("&event=completed"),
+            "{}",
+            announces[1]
+        );
+        torrent.shutdown().unwrap();
+    }
+
+    /// A torrent that seeds four pieces, and its info hash.
+    fn seeder(peer_id: PeerId) -> (Torrent, InfoHash) {
+        let content = vec![7; 64];
+        let info = Info::from_content("test", 16, &content);
+        let info_hash = info.info_hash();
+        let seeder = Torrent::new_seed(peer_id, info.to_bytes(), content).unwrap();
+        (seeder, info_hash)
+    }
+
+    #[test]
+    fn torrent_shuts_down_when_clones_are_dropped_at_once() {
+        let torrent = Torrent::new(PeerId::new([1; 20]), InfoHash::new([2; 20]));
+        let actor = torrent.actor.clone();
+
+        let threads: Vec<_> = (0..8)
+            .map(|_| {
+                let torrent = torrent.clone();
+                std::thread::spawn(move || drop(torrent))
+            })
+            .collect();
+        drop(torrent);
+        for thread in threads {
+            thread.join().unwrap();
+        }
+
+        assert!(!actor.is_running());
+    }
+
+    #[test]
+    fn torrent_keeps_its_state_when_an_action_panics() {
+        let (seeder, _) = seeder(PeerId::new([1; 20]));
+        let events = seeder.subscribe().unwrap();
+
+        let _ = seeder
+            .actor
+            .ask(|_| -> Result<()> { panic!("bug in an action") });
+
+        let stats = seeder.stats().unwrap();
+        assert_eq!(stats.pieces_complete, 4);
+        assert!(stats.seeding);
+        // Still subscribed, rather than cut off with the actor that failed.
+        assert_eq!(events.try_recv(), Err(TryRecvError::Empty));
+    }
+
+    #[test]
+    fn seeder_and_leecher_connect_over_loopback() {
+        let (seeder_id, leecher_id) = (PeerId::new([1; 20]), PeerId::new([3; 20]));
+        let (seeder, info_hash) = seeder(seeder_id);
+        let leecher = Torrent::new(leecher_id, info_hash);
+        let ((seeder_write, seeder_read), (leecher_write, leecher_read)) = loopback();
+
+        seeder
+            .accept_peer_connection(None, None, seeder_read, seeder_write)
+            .unwrap();
+        leecher
+            .connect_to_peer(Some(seeder_id), None, leecher_read, leecher_write)
+            .unwrap();
+        sleep(Duration::from_millis(100));
+
+        assert_eq!(seeder.connected_peers().unwrap(), [leecher_id]);
+        assert_eq!(leecher.connected_peers().unwrap(), [seeder_id]);
+        leecher.shutdown().unwrap();
+        seeder.shutdown().unwrap();
+    }
+
+    #[test]
+    fn connections_are_split_into_halves_for_the_torrent() {
+        fn connect(torrent: &Torrent, peer_id: PeerId, connection: impl Connection) {
+            let (write, read) = connection.split();
+            torrent
+                .connect_to_peer(Some(peer_id), None, read, write)
+                .unwrap();
+        }
+        let (seeder_id, leecher_id) = (PeerId::new([1; 20]), PeerId::new([3; 20]));
+        let (seeder, info_hash) = seeder(seeder_id);
+        let leecher = Torrent::new(leecher_id, info_hash);
+        let (seeder_connection, leecher_connection) = loopback();
+
+        let (write, read) = seeder_connection.split();
+        seeder
+            .accept_peer_connection(None, None, read, write)
+            .unwrap();
+        connect(&leecher, seeder_id, leecher_connection);
+        sleep(Duration::from_millis(100));
+
+        assert_eq!(leecher.connected_peers().unwrap(), [seeder_id]);
+        leecher.shutdown().unwrap();
+        seeder.shutdown().unwrap();
+    }
+
+    #[test]
+    fn event_observer_hears_about_completed_handshakes() {
+        let (seeder_id, leecher_id) = (PeerId::new([1; 20]), PeerId::new([3; 20]));
+        let (seeder, info_hash) = seeder(seeder_id);
+        let leecher = Torrent::new(leecher_id, info_hash);
+        let observer = RecordingObserver::default();
+        leecher.set_event_observer(observer.clone()).unwrap();
+        let ((seeder_write, seeder_read), (leecher_write, leecher_read)) = loopback();
+
+        seeder
+            .accept_peer_connection(None, None, seeder_read, seeder_write)
+            .unwrap();
+        leecher
+            .connect_to_peer(Some(seeder_id), None, leecher_read, leecher_write)
+            .unwrap();
+        sleep(Duration::from_millis(100));
+
+        let records = observer.records();
+        let handshake = records
+            .iter()
+            .find(|record| record.event == "handshake_completed")
+            .expect("a handshake_completed record");
+        assert_eq!(handshake.level, EventLevel::Info);
+        assert_eq!(handshake.peer_id, Some(seeder_id));
+        assert!(handshake.message.contains(&seeder_id.to_string()));
+        leecher.shutdown().unwrap();
+        seeder.shutdown().unwrap();
+    }
+
+    #[test]
+    fn shutdown_waits_for_pending_actions() {
+        let torrent = Torrent::new(PeerId::new([1; 20]), InfoHash::new([2; 20]));
+        let done = Arc::new(AtomicBool::new(false));
+        torrent
+            .actor
+            .act({
+                let done = done.clone();
+                move |_| {
+                    sleep(Duration::from_millis(100));
+                    done.store(true, Ordering::SeqCst);
+                    Ok(Outcome::Continue)
+                }
+            })
+            .unwrap();
+
+        torrent.shutdown().unwrap();
+
+        assert!(done.load(Ordering::SeqCst));
+    }
+}
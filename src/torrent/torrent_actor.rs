@@ -1,13 +1,69 @@
-use std::collections::HashMap;
+use std::collections::{BTreeSet, HashMap, HashSet, VecDeque};
+use std::fmt::Debug;
+use std::fs::{self, File};
+use std::io::Write;
+use std::net::SocketAddr;
+use std::panic::{catch_unwind, AssertUnwindSafe};
+use std::path::{Path, PathBuf};
+use std::sync::mpsc::Receiver;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
 
-use eyre::{OptionExt, Result};
-use tracing::info;
+use eyre::{bail, ensure, OptionExt, Result, WrapErr};
+use rand::rngs::StdRng;
+use rand::{Rng, SeedableRng};
 
 use crate::actor::actor::Actor;
 use crate::actor::handle::Handle;
+use crate::actor::mailbox::{Mailbox, Overflow};
 use crate::actor::outcome::Outcome;
+use crate::actor::supervisor::RestartPolicy;
+use crate::clock::Clock;
+use crate::log::{error, info, trace, warn};
+use crate::messages::{
+    Bitfield, Metadata, Piece, ProtocolError, Request, METADATA_PIECE_SIZE, PEX_INTERVAL,
+};
+use crate::metainfo::{info_hash, Info};
+use crate::metrics::{MetricsSink, NoMetrics};
+use crate::observer::{EventLevel, EventObserver, EventRecord, NoObserver};
+use crate::peer_source::{Announcers, PexPeers};
+use crate::torrent::ban_list::{BanList, IpRange};
+use crate::torrent::choking::{ChokeCandidate, ChokingManager};
+use crate::torrent::config::TorrentConfig;
 use crate::torrent::connection_actor::ConnectionActor;
-use crate::{ConnectionRead, ConnectionWrite, InfoHash, PeerId};
+use crate::torrent::event::{EventSubscribers, TorrentEvent};
+use crate::torrent::metadata_download::MetadataDownload;
+use crate::torrent::piece_selector::{Completion, PieceSelector};
+use crate::torrent::piece_store::{verify_pieces, FilePieceStore, MemoryPieceStore, PieceStore};
+use crate::torrent::rate_estimator::RateEstimator;
+use crate::torrent::rate_limiter::RateLimiters;
+use crate::torrent::resume::ResumeState;
+use crate::torrent::stats::TorrentStats;
+use crate::{
+    AnnounceEvent, BoxedConnection, ConnectionFactory, ConnectionRead, ConnectionWrite, InfoHash,
+    PeerId,
+};
+
+/// The window over which peer transfer rates are averaged.
+const RATE_WINDOW: Duration = Duration::from_secs(20);
+/// How many actions can be queued up for a connection, e.g. messages from a peer that sends
+/// them faster than we handle them. Sending more waits for the connection to catch up, except
+/// for the torrent: a connection that far behind on what the torrent sends it is dropped, see
+/// [TorrentActor::add_connection].
+const CONNECTION_MAILBOX: Mailbox = Mailbox::Bounded {
+    capacity: 1024,
+    overflow: Overflow::Block,
+};
+/// A connection is kept going after a panic, as a bug in handling one message is no reason to
+/// drop the peer: it's made afresh if we dialed it, and carries on otherwise. An error still
+/// closes it, as that's how a misbehaving peer is dropped.
+const CONNECTION_RESTARTS: RestartPolicy = RestartPolicy {
+    max_restarts: 3,
+    window: Duration::from_secs(60),
+    panics_only: true,
+};
+/// At most this many peers found through peer exchange are waiting to be dialed at once.
+const MAX_PEX_QUEUE: usize = 200;
 
 /// This actor handles the lifecycle of a single torrent, and its multiple connections to peers.
 #[derive(Debug)]
@@ -15,59 +71,974 @@ pub struct TorrentActor {
     handle: Option<Handle<TorrentActor>>,
     own_peer_id: PeerId,
     info_hash: InfoHash,
-    connections: HashMap<PeerId, Handle<ConnectionActor>>,
+    config: TorrentConfig,
+    clock: Arc<dyn Clock>,
+    /// Shared with every connection, which report their own metrics.
+    metrics: Arc<dyn MetricsSink>,
+    /// Shared with every connection too, like the metrics.
+    observer: Arc<dyn EventObserver>,
+    connections: HashMap<PeerId, PeerConnection>,
+    choking: ChokingManager,
+    piece_selector: PieceSelector,
+    piece_store: Box<dyn PieceStore>,
+    /// Where the torrent's files are stored, which takes effect once the metadata is known.
+    download_dir: Option<PathBuf>,
+    /// The resume file last saved to or loaded from, which is saved again on shutdown.
+    resume_path: Option<PathBuf>,
+    /// The raw info dictionary and its parsed form, once known.
+    metainfo: Option<(Vec<u8>, Info)>,
+    /// Only set while the metadata is being downloaded from peers.
+    metadata_download: Option<MetadataDownload>,
+    dht_node_callback: Option<DhtNodeCallback>,
+    subscribers: EventSubscribers,
+    /// Peers we know how to connect to, by address, so we can reconnect when they drop.
+    redials: HashMap<SocketAddr, Redial>,
+    /// Peers from `redials` that are being dialed, until their handshake is done or fails.
+    pending_dials: HashSet<SocketAddr>,
+    /// Peers from `redials` waiting to be dialed, as too many others are being dialed.
+    dial_queue: VecDeque<SocketAddr>,
+    /// Shared with every connection, so the limits apply to the torrent as a whole.
+    rate_limiters: RateLimiters,
+    /// For every random choice, seeded from [TorrentConfig::rng_seed] if set.
+    rng: StdRng,
+    /// While paused, nothing is requested from or served to peers, but they stay connected.
+    paused: bool,
+    /// Set while the pieces in the store are being checked, which is done with the store
+    /// handed off to another thread. Nothing is requested or served in the meantime.
+    verifying: bool,
+    /// Bytes of blocks received from and sent to peers, since the torrent was started.
+    downloaded: u64,
+    uploaded: u64,
+    /// Bytes of blocks received and sent before that, according to the resume file.
+    earlier_downloaded: u64,
+    earlier_uploaded: u64,
+    /// Peers that aren't connected to, or accepted connections from.
+    ban_list: BanList,
+    /// Peers that connected peers told us about, waiting to be dialed.
+    pex_peers: PexPeers,
+    /// When connected peers are next told about the peers we're connected to.
+    next_pex: Option<Instant>,
+    /// Pieces completed since the last tick, which peers haven't been told about yet.
+    pending_haves: BTreeSet<u32>,
+    /// Told once the download completes, to announce it to trackers.
+    announcers: Announcers,
+}
+
+/// Called with the address of every DHT node announced by a peer.
+struct DhtNodeCallback(Box<dyn Fn(SocketAddr) + Send>);
+
+impl Debug for DhtNodeCallback {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("DhtNodeCallback").finish_non_exhaustive()
+    }
+}
+
+/// A factory that's shared with the connections it made, to make them afresh if they fail.
+type SharedFactory = Arc<Mutex<Box<dyn ConnectionFactory>>>;
+
+/// How to reach a peer again once its connection closes.
+struct Redial {
+    /// Handed to the thread that dials the peer for as long as it's dialing.
+    factory: Option<SharedFactory>,
+    expected_peer_id: Option<PeerId>,
+    /// How many attempts in a row failed to get a connection going.
+    failures: u32,
+    /// When to dial next, if a connection isn't open or being opened.
+    due: Option<Instant>,
+    last_dialed: Option<Instant>,
+}
+
+impl Debug for Redial {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("Redial")
+            .field("expected_peer_id", &self.expected_peer_id)
+            .field("failures", &self.failures)
+            .field("due", &self.due)
+            .field("last_dialed", &self.last_dialed)
+            .finish_non_exhaustive()
+    }
+}
+
+/// What the torrent knows about a single connected peer.
+#[derive(Debug)]
+struct PeerConnection {
+    actor: Handle<ConnectionActor>,
+    /// Whether we initiated the connection.
+    outgoing: bool,
+    /// Where the peer is, if the connection knows.
+    peer_addr: Option<SocketAddr>,
+    am_choking: bool,
+    peer_interested: bool,
+    download_rate: RateEstimator,
+    upload_rate: RateEstimator,
+    /// The size of the metadata the peer has, if it supports sharing it.
+    metadata_size: Option<usize>,
+    /// The pieces the peer has told us it has.
+    pieces: Bitfield,
+    /// Whether the peer said it has everything, which it can do before we know how many
+    /// pieces "everything" is.
+    has_all: bool,
+    /// How fast the peer delivers what we request, over the [TorrentConfig::snub_window].
+    delivery_rate: RateEstimator,
+    /// Since when the peer has had blocks of ours in flight, without a break.
+    requested_since: Option<Instant>,
+    /// Whether the peer is too slow to be given more than one block at a time.
+    snubbed: bool,
 }
 
 impl TorrentActor {
+    #[cfg(test)]
     pub fn new(own_peer_id: PeerId, info_hash: InfoHash) -> Self {
+        Self::with_config(
+            own_peer_id,
+            info_hash,
+            TorrentConfig::default(),
+            Arc::new(crate::clock::SystemClock),
+        )
+    }
+
+    pub fn with_config(
+        own_peer_id: PeerId,
+        info_hash: InfoHash,
+        config: TorrentConfig,
+        clock: Arc<dyn Clock>,
+    ) -> Self {
         Self {
             handle: None,
             own_peer_id,
             info_hash,
+            config,
+            choking: ChokingManager::new(clock.now()),
+            rate_limiters: RateLimiters::unlimited(clock.clone()),
+            rng: config
+                .rng_seed
+                .map_or_else(StdRng::from_entropy, StdRng::seed_from_u64),
+            paused: false,
+            verifying: false,
+            downloaded: 0,
+            uploaded: 0,
+            earlier_downloaded: 0,
+            earlier_uploaded: 0,
+            ban_list: BanList::default(),
+            pex_peers: PexPeers::default(),
+            next_pex: None,
+            pending_haves: BTreeSet::new(),
+            announcers: Announcers::default(),
+            clock,
+            metrics: Arc::new(NoMetrics),
+            observer: Arc::new(NoObserver),
             connections: HashMap::new(),
+            piece_selector: PieceSelector::default(),
+            piece_store: Box::<MemoryPieceStore>::default(),
+            download_dir: None,
+            resume_path: None,
+            metainfo: None,
+            metadata_download: None,
+            dht_node_callback: None,
+            subscribers: EventSubscribers::default(),
+            redials: HashMap::new(),
+            pending_dials: HashSet::new(),
+            dial_queue: VecDeque::new(),
+        }
+    }
+
+    /// Share the upload and download limits with other torrents, instead of having our own.
+    pub fn share_rate_limiters(&mut self, rate_limiters: RateLimiters) {
+        self.rate_limiters = rate_limiters;
+    }
+
+    /// Stop downloading and uploading, without closing any connections: peers are told we're
+    /// not interested and are choked, and whatever they still request is rejected.
+    pub fn pause(&mut self) -> Result<()> {
+        info!("Pausing torrent {}", self.info_hash);
+        self.paused = true;
+        self.update_interest()?;
+        for connection in self.connections.values_mut() {
+            if !connection.am_choking {
+                connection.am_choking = true;
+                connection.actor.act(ConnectionActor::choke)?;
+            }
+        }
+        Ok(())
+    }
+
+    /// Pick up where [pause](Self::pause) left off, right away instead of on the next tick.
+    pub fn resume(&mut self) -> Result<()> {
+        info!("Resuming torrent {}", self.info_hash);
+        self.paused = false;
+        self.choking = ChokingManager::new(self.clock.now());
+        self.rechoke()?;
+        self.update_interest()?;
+        for connection in self.connections.values() {
+            connection.actor.act(ConnectionActor::resume_transfers)?;
+        }
+        Ok(())
+    }
+
+    /// Limit the upload rate over all connections, or lift the limit with `None`.
+    pub fn set_upload_limit(&mut self, bytes_per_sec: Option<u64>) {
+        self.rate_limiters.upload.set_rate(bytes_per_sec);
+    }
+
+    /// Limit the download rate over all connections, or lift the limit with `None`.
+    pub fn set_download_limit(&mut self, bytes_per_sec: Option<u64>) {
+        self.rate_limiters.download.set_rate(bytes_per_sec);
+    }
+
+    pub fn set_piece_layout(&mut self, piece_length: u32, total_length: u64) {
+        self.piece_selector =
+            PieceSelector::with_block_size(piece_length, total_length, self.config.block_size);
+    }
+
+    /// Start out with the torrent's `metadata` and all of its `content`, so there's nothing
+    /// left to download and every piece can be served to peers. Peers that are already
+    /// connected aren't told about the new pieces.
+    pub fn load_content(&mut self, metadata: Vec<u8>, content: &[u8]) -> Result<()> {
+        if info_hash(&metadata) != self.info_hash {
+            bail!("Metadata doesn't match the info hash");
+        }
+        let info = Info::from_bytes(&metadata)?;
+        if content.len() as u64 != info.length {
+            bail!(
+                "Content is {} bytes, but the torrent is {} bytes",
+                content.len(),
+                info.length
+            );
+        }
+        self.set_piece_layout(info.piece_length, info.length);
+        for (index, piece) in (0..).zip(content.chunks(info.piece_length as usize)) {
+            if !info.verify_piece(index, piece) {
+                bail!("Piece {index} of the content doesn't match its hash");
+            }
+            self.piece_store.write_block(index, 0, piece)?;
+            self.piece_selector.complete_piece(index);
+        }
+        self.metainfo = Some((metadata, info));
+        Ok(())
+    }
+
+    /// Keep pieces in `store` instead of in memory.
+    pub fn set_piece_store(&mut self, store: Box<dyn PieceStore>) {
+        self.piece_store = store;
+    }
+
+    /// Store the torrent's files under `download_dir`. The file layout comes from the
+    /// metadata, so if that isn't known yet, pieces are stored there once it is.
+    pub fn set_download_dir(&mut self, download_dir: PathBuf) -> Result<()> {
+        if let Some(info) = self.info() {
+            self.piece_store = Box::new(FilePieceStore::new(&download_dir, info)?);
+        }
+        self.download_dir = Some(download_dir);
+        Ok(())
+    }
+
+    /// Queue the peers that connected peers tell us about in `pex_peers`, from now on.
+    pub(crate) fn set_pex_peers(&mut self, pex_peers: PexPeers) {
+        self.pex_peers = pex_peers;
+    }
+
+    pub(crate) fn set_announcers(&mut self, announcers: Announcers) {
+        self.announcers = announcers;
+    }
+
+    /// Report metrics to `metrics`, from now on. Connections that are already open keep
+    /// reporting to the previous sink.
+    pub fn set_metrics_sink(&mut self, metrics: Arc<dyn MetricsSink>) {
+        self.metrics = metrics;
+        self.report_connections();
+    }
+
+    /// Tell `observer` about the important events, from now on. Like with
+    /// [set_metrics_sink](Self::set_metrics_sink), open connections keep the previous one.
+    pub fn set_event_observer(&mut self, observer: Arc<dyn EventObserver>) {
+        self.observer = observer;
+    }
+
+    fn observe(&self, level: EventLevel, event: &'static str, message: String) {
+        self.observer.observe(&EventRecord {
+            level,
+            event,
+            peer_id: None,
+            peer_addr: None,
+            message,
+        });
+    }
+
+    fn report_connections(&self) {
+        self.metrics
+            .gauge("connections", self.connections.len() as f64);
+    }
+
+    /// Find out which pieces the store has already, e.g. from before a restart, by hashing
+    /// every one of them. That takes a while, so it's done on another thread, and the pieces
+    /// are only marked as downloaded once it's done.
+    pub fn verify_existing_files(&mut self) -> Result<()> {
+        let info = self.info().ok_or_eyre("Metadata not known yet")?.clone();
+        let handle = self.handle.clone().ok_or_eyre("Torrent not running")?;
+        if self.verifying {
+            bail!("Already verifying");
+        }
+        self.verifying = true;
+        // Nothing is requested meanwhile, and blocks that were already on their way are
+        // dropped, so nothing is ever written to the placeholder.
+        let placeholder = Box::new(MemoryPieceStore::default());
+        let store = std::mem::replace(&mut self.piece_store, placeholder);
+        let _ = std::thread::spawn(move || {
+            let verify = || {
+                verify_pieces(store.as_ref(), &info, |fraction| {
+                    let _ = handle.act(move |torrent| {
+                        torrent
+                            .subscribers
+                            .send(&TorrentEvent::VerifyProgress(fraction));
+                        Ok(Outcome::Continue)
+                    });
+                })
+            };
+            // The store still has to go back if a read panics, or the torrent is stuck
+            // verifying forever. It's only read from, so it's not left in a broken state.
+            let pieces = catch_unwind(AssertUnwindSafe(verify)).unwrap_or_else(|_| {
+                error!("Verifying existing files panicked, assuming no pieces are there");
+                Bitfield::default()
+            });
+            let _ = handle.act(move |torrent| {
+                torrent.existing_pieces_verified(store, &pieces)?;
+                Ok(Outcome::Continue)
+            });
+        });
+        Ok(())
+    }
+
+    fn existing_pieces_verified(
+        &mut self,
+        store: Box<dyn PieceStore>,
+        pieces: &Bitfield,
+    ) -> Result<()> {
+        self.piece_store = store;
+        self.verifying = false;
+        self.complete_pieces(pieces)?;
+        info!(
+            "Found {} of {} pieces already downloaded",
+            pieces.count_ones(self.piece_selector.piece_count()),
+            self.piece_selector.piece_count()
+        );
+        for connection in self.connections.values() {
+            connection.actor.act(ConnectionActor::resume_transfers)?;
+        }
+        Ok(())
+    }
+
+    /// Mark every piece in `pieces` as downloaded, without checking them. Peers that are
+    /// already connected are told about the new ones on the next tick.
+    fn complete_pieces(&mut self, pieces: &Bitfield) -> Result<()> {
+        for index in 0..self.piece_selector.piece_count() {
+            // the piece count comes from u32 piece indices, so the cast is safe
+            #[allow(clippy::cast_possible_truncation)]
+            let index = index as u32;
+            if pieces.has(index as usize) && !self.piece_selector.is_piece_complete(index) {
+                self.piece_selector.complete_piece(index);
+                self.pending_haves.insert(index);
+            }
+        }
+        self.subscribers
+            .send(&TorrentEvent::Progress(self.piece_selector.progress()));
+        self.update_interest()
+    }
+
+    /// Write the all-time totals and the pieces we have to the resume file at `path`, which
+    /// is written again on shutdown.
+    pub fn save_state(&mut self, path: &Path) -> Result<()> {
+        self.write_state(path)?;
+        self.resume_path = Some(path.to_path_buf());
+        Ok(())
+    }
+
+    fn write_state(&self, path: &Path) -> Result<()> {
+        let state = ResumeState {
+            info_hash: self.info_hash,
+            downloaded: self.earlier_downloaded + self.downloaded,
+            uploaded: self.earlier_uploaded + self.uploaded,
+            have: self.own_pieces(),
+        };
+        // Written next to it first, so a crash halfway doesn't leave a broken resume file.
+        // It's synced before replacing the old one, or the rename could reach the disk before
+        // the contents do.
+        let partial = path.with_extension("partial");
+        File::create(&partial)
+            .and_then(|mut file| {
+                file.write_all(&state.to_bytes())?;
+                file.sync_all()
+            })
+            .wrap_err_with(|| format!("Failed to write {}", partial.display()))?;
+        fs::rename(&partial, path)
+            .wrap_err_with(|| format!("Failed to replace {}", path.display()))?;
+        // And the directory too, so the rename itself survives a crash. Only Unix can open a
+        // directory to sync it.
+        #[cfg(unix)]
+        {
+            let dir = match path.parent() {
+                Some(dir) if !dir.as_os_str().is_empty() => dir,
+                _ => Path::new("."),
+            };
+            File::open(dir)
+                .and_then(|dir| dir.sync_all())
+                .wrap_err_with(|| format!("Failed to sync {}", dir.display()))?;
+        }
+        Ok(())
+    }
+
+    /// Pick up where a previous run left off, from the resume file it saved at `path`. The
+    /// pieces it had are trusted to still be in the store, without checking them again.
+    ///
+    /// Needs the metadata, to know how many pieces there are. The resume file is written
+    /// again on shutdown.
+    pub fn load_state(&mut self, path: &Path) -> Result<()> {
+        let bytes =
+            fs::read(path).wrap_err_with(|| format!("Failed to read {}", path.display()))?;
+        let state = ResumeState::from_bytes(&bytes)?;
+        ensure!(
+            state.info_hash == self.info_hash,
+            "Resume file is for torrent {}, not {}",
+            state.info_hash,
+            self.info_hash
+        );
+        let piece_count = self.piece_selector.piece_count();
+        ensure!(
+            state.have.fits(piece_count),
+            "Resume file doesn't match the torrent's {piece_count} pieces"
+        );
+        self.earlier_downloaded = state.downloaded;
+        self.earlier_uploaded = state.uploaded;
+        self.resume_path = Some(path.to_path_buf());
+        self.complete_pieces(&state.have)
+    }
+
+    /// Whether blocks are requested from and served to peers right now.
+    fn is_transferring(&self) -> bool {
+        !self.paused && !self.verifying
+    }
+
+    /// A whole downloaded piece, e.g. to hand it to whoever is using the torrent.
+    pub fn read_piece(&self, index: u32) -> Result<Vec<u8>> {
+        let info = self.info().ok_or_eyre("Metadata not known yet")?;
+        if !self.piece_selector.is_piece_complete(index) {
+            bail!("Piece {index} isn't downloaded");
+        }
+        self.piece_store
+            .read_block(index, 0, info.piece_size(index))
+    }
+
+    fn info(&self) -> Option<&Info> {
+        self.metainfo.as_ref().map(|(_, info)| info)
+    }
+
+    /// How many pieces the torrent has, if the piece layout is known yet.
+    fn piece_count(&self) -> Option<usize> {
+        Some(self.piece_selector.piece_count()).filter(|piece_count| *piece_count > 0)
+    }
+
+    /// The pieces we have, to tell new peers about.
+    fn own_pieces(&self) -> Bitfield {
+        let piece_count = self.piece_selector.piece_count();
+        let mut pieces = Bitfield::new(vec![0; piece_count.div_ceil(8)]);
+        for index in 0..piece_count {
+            // the piece count comes from u32 piece indices, so the cast is safe
+            #[allow(clippy::cast_possible_truncation)]
+            if self.piece_selector.is_piece_complete(index as u32) {
+                pieces.set(index);
+            }
+        }
+        pieces
+    }
+
+    /// Tell every peer whether it has anything we still need, e.g. after our own pieces changed.
+    fn update_interest(&self) -> Result<()> {
+        for peer_id in self.connections.keys() {
+            self.update_peer_interest(*peer_id)?;
+        }
+        Ok(())
+    }
+
+    fn update_peer_interest(&self, peer_id: PeerId) -> Result<()> {
+        let Some(connection) = self.connections.get(&peer_id) else {
+            return Ok(());
+        };
+        let interested = !self.paused && self.piece_selector.wants_any(&connection.pieces);
+        connection
+            .actor
+            .act(move |connection| connection.set_interested(interested))
+    }
+
+    /// The peer sent its bitfield, replacing whatever we knew about its pieces.
+    pub fn peer_has_pieces(&mut self, peer_id: PeerId, pieces: Bitfield) -> Result<()> {
+        let Some(connection) = self.connections.get_mut(&peer_id) else {
+            return Ok(());
+        };
+        connection.pieces = pieces;
+        connection.has_all = false;
+        self.update_peer_interest(peer_id)
+    }
+
+    /// The peer completed another piece. A piece that the torrent doesn't have breaks the
+    /// protocol, so the peer is dropped. The connection checks this too, but might not know
+    /// the number of pieces yet.
+    pub fn peer_has_piece(&mut self, peer_id: PeerId, index: u32) -> Result<()> {
+        let piece_count = self.piece_count();
+        let Some(connection) = self.connections.get_mut(&peer_id) else {
+            return Ok(());
+        };
+        if let Some(piece_count) = piece_count.filter(|count| index as usize >= *count) {
+            let error = ProtocolError::InvalidPieceIndex { index, piece_count };
+            warn!("Dropping peer {peer_id}: {error}");
+            return self.remove_peer(peer_id);
+        }
+        connection.pieces.set(index as usize);
+        self.update_peer_interest(peer_id)
+    }
+
+    /// The peer has every piece, which it can only tell us with the Fast Extension.
+    pub fn peer_has_all(&mut self, peer_id: PeerId) -> Result<()> {
+        let pieces = Bitfield::full(self.piece_selector.piece_count());
+        self.peer_has_pieces(peer_id, pieces)?;
+        if let Some(connection) = self.connections.get_mut(&peer_id) {
+            connection.has_all = true;
         }
+        Ok(())
     }
 
     pub fn connect_to_peer(
         &mut self,
         expected_peer_id: Option<PeerId>,
+        peer_addr: Option<SocketAddr>,
         connection_read: impl ConnectionRead + Send + 'static,
         connection_write: impl ConnectionWrite + Send + 'static,
     ) -> Result<Outcome> {
-        let actor = Handle::spawn(ConnectionActor::new(
-            self.own_peer_id,
+        if self.is_banned(peer_addr) {
+            info!("Not connecting to banned peer {peer_addr:?}");
+            return Ok(Outcome::Continue);
+        }
+        if self.at_connection_limit() {
+            info!(
+                "Not connecting to peer {peer_addr:?}, already at the limit of {} connections",
+                self.config.max_connections
+            );
+            return Ok(Outcome::Continue);
+        }
+        let actor = Handle::spawn_resuming(
+            self.connection_actor(
+                expected_peer_id,
+                peer_addr,
+                connection_read,
+                connection_write,
+            )?,
+            CONNECTION_MAILBOX,
+            CONNECTION_RESTARTS,
+        );
+        actor.act(ConnectionActor::initiate_handshake)?;
+        Ok(Outcome::Continue)
+    }
+
+    /// Connect to a peer over a connection that was dialed with `factory`. If the connection
+    /// panics, the factory dials the peer again for a fresh one to take over, rather than
+    /// carrying on with whatever state the panic left behind.
+    fn connect_dialed(
+        &mut self,
+        expected_peer_id: Option<PeerId>,
+        peer_addr: SocketAddr,
+        (connection_write, connection_read): BoxedConnection,
+        factory: SharedFactory,
+    ) -> Result<Outcome> {
+        if self.is_banned(Some(peer_addr)) {
+            info!("Not connecting to banned peer {peer_addr}");
+            return Ok(Outcome::Continue);
+        }
+        if self.at_connection_limit() {
+            info!(
+                "Not connecting to peer {peer_addr}, already at the limit of {} connections",
+                self.config.max_connections
+            );
+            return Ok(Outcome::Continue);
+        }
+        let actor = self.connection_actor(
             expected_peer_id,
+            Some(peer_addr),
             connection_read,
             connection_write,
-            self.info_hash,
-            self.handle.clone().ok_or_eyre("Handle not set")?,
-        ));
-        actor.act(ConnectionActor::initiate_handshake)?;
+        )?;
+        let _ = Handle::spawn_supervised(
+            actor,
+            ConnectionActor::initiate_handshake,
+            CONNECTION_MAILBOX,
+            CONNECTION_RESTARTS,
+            move |failed: &ConnectionActor| {
+                info!("Reconnecting to peer {peer_addr} after its connection failed");
+                let (connection_write, connection_read) = factory
+                    .lock()
+                    .expect("mutex to not be poisoned")
+                    .connect()?;
+                Ok(failed.reconnect(connection_read, connection_write))
+            },
+        );
         Ok(Outcome::Continue)
     }
 
     pub fn accept_peer_connection(
         &mut self,
         expected_peer_id: Option<PeerId>,
+        peer_addr: Option<SocketAddr>,
         connection_read: impl ConnectionRead + Send + 'static,
         connection_write: impl ConnectionWrite + Send + 'static,
     ) -> Result<Outcome> {
-        let actor = Handle::spawn(ConnectionActor::new(
+        if self.is_banned(peer_addr) {
+            info!("Rejecting connection from banned peer {peer_addr:?}");
+            return Ok(Outcome::Continue);
+        }
+        if self.at_connection_limit() {
+            info!(
+                "Rejecting connection from peer {peer_addr:?}, already at the limit of {} connections",
+                self.config.max_connections
+            );
+            return Ok(Outcome::Continue);
+        }
+        let actor = Handle::spawn_resuming(
+            self.connection_actor(
+                expected_peer_id,
+                peer_addr,
+                connection_read,
+                connection_write,
+            )?,
+            CONNECTION_MAILBOX,
+            CONNECTION_RESTARTS,
+        );
+        actor.act(ConnectionActor::await_handshake)?;
+        Ok(Outcome::Continue)
+    }
+
+    /// A connection to a peer, set up like every other one of the torrent's.
+    fn connection_actor(
+        &self,
+        expected_peer_id: Option<PeerId>,
+        peer_addr: Option<SocketAddr>,
+        connection_read: impl ConnectionRead + Send + 'static,
+        connection_write: impl ConnectionWrite + Send + 'static,
+    ) -> Result<ConnectionActor> {
+        Ok(ConnectionActor::new(
             self.own_peer_id,
             expected_peer_id,
             connection_read,
             connection_write,
             self.info_hash,
             self.handle.clone().ok_or_eyre("Handle not set")?,
-        ));
-        actor.act(ConnectionActor::await_handshake)?;
+            self.config,
+        )
+        .with_peer_addr(peer_addr)
+        .with_piece_count(self.piece_count())
+        .with_own_pieces(self.own_pieces())
+        .with_rate_limiters(self.rate_limiters.clone())
+        .with_clock(self.clock.clone())
+        .with_metrics(self.metrics.clone())
+        .with_observer(self.observer.clone()))
+    }
+
+    /// Connect to a peer using `factory`, and keep reconnecting whenever the connection closes.
+    pub fn connect_with_factory(
+        &mut self,
+        expected_peer_id: Option<PeerId>,
+        peer_addr: SocketAddr,
+        factory: Box<dyn ConnectionFactory>,
+    ) -> Result<Outcome> {
+        if self.is_banned(Some(peer_addr)) {
+            info!("Not connecting to banned peer {peer_addr}");
+            return Ok(Outcome::Continue);
+        }
+        self.redials.insert(
+            peer_addr,
+            Redial {
+                factory: Some(Arc::new(Mutex::new(factory))),
+                expected_peer_id,
+                failures: 0,
+                due: None,
+                last_dialed: None,
+            },
+        );
+        self.redial(peer_addr)?;
         Ok(Outcome::Continue)
     }
 
+    /// Connect to a peer like [connect_with_factory](Self::connect_with_factory), unless we
+    /// already know how to reach it, as peers are often discovered more than once, or it's
+    /// already connected, e.g. because it connected to us.
+    pub fn add_peer(
+        &mut self,
+        expected_peer_id: Option<PeerId>,
+        peer_addr: SocketAddr,
+        factory: Box<dyn ConnectionFactory>,
+    ) -> Result<Outcome> {
+        if self.redials.contains_key(&peer_addr) || self.is_connected_to(peer_addr) {
+            trace!("Already know peer {peer_addr}, not adding it again");
+            return Ok(Outcome::Continue);
+        }
+        self.connect_with_factory(expected_peer_id, peer_addr, factory)
+    }
+
+    fn redial(&mut self, peer_addr: SocketAddr) -> Result<()> {
+        let now = self.clock.now();
+        let at_connection_limit = self.at_connection_limit();
+        let Some(redial) = self.redials.get_mut(&peer_addr) else {
+            return Ok(());
+        };
+        if at_connection_limit {
+            // Not the peer's fault, so this doesn't count as a failed attempt.
+            redial.due = Some(now + self.config.reconnect_base_delay);
+            return Ok(());
+        }
+        redial.due = None;
+        if self.pending_dials.len() >= self.config.max_pending_dials {
+            if !self.dial_queue.contains(&peer_addr) {
+                trace!("Already dialing too many peers, queueing {peer_addr}");
+                self.dial_queue.push_back(peer_addr);
+            }
+            return Ok(());
+        }
+        let Some(factory) = redial.factory.take() else {
+            trace!("Already dialing peer {peer_addr}");
+            return Ok(());
+        };
+        let handle = self.handle.clone().ok_or_eyre("Torrent not running")?;
+        redial.last_dialed = Some(now);
+        let _ = self.pending_dials.insert(peer_addr);
+        // Connecting can take as long as the peer takes to time out, which shouldn't hold up
+        // the rest of the torrent.
+        let _ = std::thread::spawn(move || {
+            let connection = factory.lock().expect("mutex to not be poisoned").connect();
+            let _ = handle.act(move |torrent| torrent.dialed(peer_addr, factory, connection));
+        });
+        Ok(())
+    }
+
+    /// A dial started by [redial](Self::redial) finished, with a connection if it succeeded.
+    fn dialed(
+        &mut self,
+        peer_addr: SocketAddr,
+        factory: SharedFactory,
+        connection: Result<BoxedConnection>,
+    ) -> Result<Outcome> {
+        // The peer might have been removed or banned while it was being dialed.
+        let Some(redial) = self.redials.get_mut(&peer_addr) else {
+            trace!("Peer {peer_addr} was removed while dialing it, dropping the connection");
+            self.dial_resolved(peer_addr);
+            return Ok(Outcome::Continue);
+        };
+        // If the peer was added again meanwhile, the new factory wins.
+        let factory = redial.factory.get_or_insert(factory).clone();
+        if !self.pending_dials.contains(&peer_addr) {
+            trace!("Dial to peer {peer_addr} was given up on, dropping the connection");
+            // The attempt already counted as failed, but its redial might have come due while
+            // the dial still held the factory.
+            let _ = redial.due.get_or_insert(self.clock.now());
+            return Ok(Outcome::Continue);
+        }
+        match connection {
+            Ok(connection) => {
+                let expected_peer_id = redial.expected_peer_id;
+                self.connect_dialed(expected_peer_id, peer_addr, connection, factory)
+            }
+            Err(e) => {
+                warn!("Failed to connect to peer {peer_addr}: {e:?}");
+                let _ = self.pending_dials.remove(&peer_addr);
+                self.schedule_redial(peer_addr);
+                self.dial_queued()?;
+                Ok(Outcome::Continue)
+            }
+        }
+    }
+
+    /// A dial to `peer_addr` got through its handshake, or failed, making room for the next
+    /// queued one.
+    fn dial_resolved(&mut self, peer_addr: SocketAddr) {
+        if self.pending_dials.remove(&peer_addr) {
+            if let Err(e) = self.dial_queued() {
+                warn!("Failed to dial queued peers: {e:?}");
+            }
+        }
+    }
+
+    /// Give up on dials that have been connecting for longer than the handshake timeout, e.g.
+    /// to blackholed peers that only time out once the OS does, so they don't keep the slots
+    /// of peers waiting their turn. Whatever the dial comes up with in the end is dropped.
+    fn expire_dials(&mut self) {
+        let now = self.clock.now();
+        let expired: Vec<_> = self
+            .pending_dials
+            .iter()
+            .copied()
+            .filter(|peer_addr| {
+                self.redials.get(peer_addr).is_some_and(|redial| {
+                    redial.factory.is_none()
+                        && redial.last_dialed.is_some_and(|last_dialed| {
+                            now.duration_since(last_dialed) >= self.config.handshake_timeout
+                        })
+                })
+            })
+            .collect();
+        for peer_addr in expired {
+            warn!("Connecting to peer {peer_addr} timed out");
+            let _ = self.pending_dials.remove(&peer_addr);
+            self.schedule_redial(peer_addr);
+        }
+    }
+
+    /// Dial queued peers, for as long as there's room for more dials.
+    fn dial_queued(&mut self) -> Result<()> {
+        while self.pending_dials.len() < self.config.max_pending_dials {
+            let Some(peer_addr) = self.dial_queue.pop_front() else {
+                break;
+            };
+            // Peers that were given up on or banned while queued are gone from the redials,
+            // so nothing happens for those.
+            self.redial(peer_addr)?;
+        }
+        Ok(())
+    }
+
+    /// An attempt to connect failed, so try again later, waiting longer the more attempts
+    /// have failed in a row. Gives up on the peer after too many failures.
+    fn schedule_redial(&mut self, peer_addr: SocketAddr) {
+        let config = self.config;
+        let Some(redial) = self.redials.get_mut(&peer_addr) else {
+            return;
+        };
+        redial.failures += 1;
+        if redial.failures >= config.max_reconnect_attempts {
+            info!(
+                "Giving up on peer {peer_addr} after {} failed attempts",
+                redial.failures
+            );
+            self.redials.remove(&peer_addr);
+            return;
+        }
+        let backoff = config
+            .reconnect_base_delay
+            .saturating_mul(2u32.saturating_pow(redial.failures - 1))
+            .min(config.reconnect_max_delay);
+        let jitter = if config.reconnect_jitter.is_zero() {
+            Duration::ZERO
+        } else {
+            self.rng.gen_range(Duration::ZERO..=config.reconnect_jitter)
+        };
+        redial.due = Some(self.clock.now() + backoff + jitter);
+        info!("Reconnecting to peer {peer_addr} in {:?}", backoff + jitter);
+    }
+
+    fn redial_due(&mut self) -> Result<()> {
+        let now = self.clock.now();
+        let due: Vec<_> = self
+            .redials
+            .iter()
+            .filter(|(_, redial)| redial.due.is_some_and(|due| due <= now))
+            .map(|(peer_addr, _)| *peer_addr)
+            .collect();
+        for peer_addr in due {
+            self.redial(peer_addr)?;
+        }
+        Ok(())
+    }
+
+    /// A connection to `peer_addr` closed, which is redialed if it was made by a factory.
+    /// `established` is whether the connection got past the handshake. Only if it did, and then
+    /// stayed up for a while, does the backoff start over; a peer that keeps dropping us right
+    /// away is backed off from like one that can't be reached.
+    pub fn connection_closed(&mut self, peer_addr: SocketAddr, established: bool) {
+        let now = self.clock.now();
+        self.dial_resolved(peer_addr);
+        if let Some(redial) = self.redials.get_mut(&peer_addr) {
+            let stable = redial.last_dialed.is_some_and(|last_dialed| {
+                now.duration_since(last_dialed) >= self.config.reconnect_max_delay
+            });
+            if established && stable {
+                redial.failures = 0;
+            }
+            self.schedule_redial(peer_addr);
+        }
+    }
+
+    /// A peer told us about `added` through peer exchange. The ones we aren't already
+    /// connected to, or dialing, are queued to be dialed.
+    pub fn pex_received(&mut self, added: Vec<SocketAddr>) {
+        for peer_addr in added {
+            if self.pex_peers.len() >= MAX_PEX_QUEUE {
+                trace!("Too many peers waiting to be dialed, ignoring the rest");
+                return;
+            }
+            if self.is_connected_to(peer_addr)
+                || self.redials.contains_key(&peer_addr)
+                || self.is_banned(Some(peer_addr))
+            {
+                continue;
+            }
+            self.pex_peers.push(peer_addr);
+        }
+    }
+
+    /// Whether there's a connection to `peer_addr`, whichever side made it.
+    fn is_connected_to(&self, peer_addr: SocketAddr) -> bool {
+        self.connections
+            .values()
+            .any(|connection| connection.peer_addr == Some(peer_addr))
+    }
+
+    /// Tell every connected peer about the others, which is the whole point of peer exchange.
+    /// Only the addresses of peers we dialed are shared, as incoming connections come from
+    /// ports that nobody else can connect to.
+    fn exchange_peers(&mut self) -> Result<()> {
+        let now = self.clock.now();
+        if self.next_pex.is_some_and(|next_pex| now < next_pex) {
+            return Ok(());
+        }
+        self.next_pex = Some(now + PEX_INTERVAL);
+        let peers: BTreeSet<_> = self
+            .connections
+            .values()
+            .filter(|connection| connection.outgoing)
+            .filter_map(|connection| connection.peer_addr)
+            .collect();
+        for connection in self.connections.values() {
+            let mut peers = peers.clone();
+            if let Some(peer_addr) = connection.peer_addr {
+                peers.remove(&peer_addr);
+            }
+            connection.actor.act(move |actor| actor.send_pex(peers))?;
+        }
+        Ok(())
+    }
+
+    /// Connections are only counted once their handshake is done, so more handshakes than
+    /// this can be in progress; those are turned away in [TorrentActor::add_connection].
+    fn at_connection_limit(&self) -> bool {
+        self.connections.len() >= self.config.max_connections
+    }
+
+    /// Get notified of everything that happens to the torrent from now on.
+    pub fn subscribe(&mut self) -> Receiver<TorrentEvent> {
+        self.subscribers.subscribe()
+    }
+
+    pub fn set_dht_node_callback(&mut self, callback: impl Fn(SocketAddr) + Send + 'static) {
+        self.dht_node_callback = Some(DhtNodeCallback(Box::new(callback)));
+    }
+
+    /// A peer announced the address of its DHT node.
+    pub fn dht_node_found(&self, addr: SocketAddr) {
+        trace!("Peer announced DHT node {addr}");
+        if let Some(DhtNodeCallback(callback)) = &self.dht_node_callback {
+            callback(addr);
+        }
+    }
+
     pub fn send(&mut self, peer_id: PeerId, message: String) -> Result<Outcome> {
         self.connections
             .get(&peer_id)
             .ok_or_eyre("Peer not connected")?
+            .actor
             .act(move |connection| {
                 info!("TorrentActor sending message to peer {}", peer_id);
                 connection.send(message)?;
@@ -76,42 +1047,2181 @@ impl TorrentActor {
         Ok(Outcome::Continue)
     }
 
-    pub fn add_connection(&mut self, peer_id: PeerId, connection: Handle<ConnectionActor>) {
-        self.connections.insert(peer_id, connection);
-        info!("TorrentActor added connection to peer {}", peer_id);
+    /// Register a connection whose handshake is done. `outgoing` is whether we initiated it.
+    pub fn add_connection(
+        &mut self,
+        peer_id: PeerId,
+        outgoing: bool,
+        peer_addr: Option<SocketAddr>,
+        connection: Handle<ConnectionActor>,
+    ) -> Result<()> {
+        if let Some(peer_addr) = peer_addr.filter(|_| outgoing) {
+            self.dial_resolved(peer_addr);
+            // A connection made afresh after the previous one panicked is quicker than the
+            // redial that the previous one closing scheduled.
+            if let Some(redial) = self.redials.get_mut(&peer_addr) {
+                redial.due = None;
+            }
+        }
+        // The peer might have been banned while it was still handshaking.
+        if self.is_banned(peer_addr) {
+            return connection.act(|connection| connection.reject("banned"));
+        }
+        if let Some(existing) = self.connections.get(&peer_id) {
+            // Both sides have to agree on which connection to keep, or they might each close
+            // a different one. Like other clients, keep the one initiated by the lower peer ID.
+            let initiator = |outgoing| if outgoing { self.own_peer_id } else { peer_id };
+            if initiator(outgoing) >= initiator(existing.outgoing) {
+                return connection
+                    .act(|connection| connection.reject("already connected to this peer"));
+            }
+            existing.actor.act(|connection| {
+                connection.reject("replaced by another connection to this peer")
+            })?;
+            self.remove_connection(peer_id);
+        } else if self.at_connection_limit() {
+            info!(
+                "Closing connection to peer {peer_id}, already at the limit of {} connections",
+                self.config.max_connections
+            );
+            return connection.act(|connection| connection.reject("too many connections"));
+        }
+        let metadata_size = self.metainfo.as_ref().map(|(metadata, _)| metadata.len());
+        connection.act(move |connection| connection.start_extension_protocol(metadata_size))?;
+        self.connections.insert(
+            peer_id,
+            PeerConnection {
+                // Waiting for a peer that doesn't read what we send would hold up every other
+                // peer, so the connection stops instead.
+                actor: connection.with_overflow(Overflow::Stop),
+                outgoing,
+                peer_addr,
+                am_choking: true,
+                peer_interested: false,
+                download_rate: RateEstimator::new(RATE_WINDOW),
+                upload_rate: RateEstimator::new(RATE_WINDOW),
+                metadata_size: None,
+                pieces: Bitfield::default(),
+                has_all: false,
+                delivery_rate: RateEstimator::new(self.config.snub_window),
+                requested_since: None,
+                snubbed: false,
+            },
+        );
+        info!("TorrentActor added connection to peer {peer_id} at {peer_addr:?}");
+        self.subscribers
+            .send(&TorrentEvent::PeerConnected(peer_id, peer_addr));
+        self.report_connections();
+        Ok(())
+    }
+
+    /// The peer can send us the metadata, which is `metadata_size` bytes long.
+    pub fn peer_has_metadata(&mut self, peer_id: PeerId, metadata_size: usize) -> Result<()> {
+        let Some(connection) = self.connections.get_mut(&peer_id) else {
+            return Ok(());
+        };
+        connection.metadata_size = Some(metadata_size);
+        if self.metainfo.is_some() {
+            return Ok(());
+        }
+        let download = match &mut self.metadata_download {
+            Some(download) => download,
+            None => match MetadataDownload::new(self.info_hash, metadata_size) {
+                Ok(download) => self.metadata_download.insert(download),
+                Err(e) => {
+                    warn!("Not downloading metadata from peer {peer_id}: {e}");
+                    return Ok(());
+                }
+            },
+        };
+        if download.total_size() != metadata_size {
+            // Either this peer or the one we started with is lying, the hash check will tell.
+            return Ok(());
+        }
+        let missing = download.missing_pieces();
+        connection
+            .actor
+            .act(move |connection| connection.request_metadata(missing))
+    }
+
+    /// Handle a metadata extension message from a peer.
+    pub fn metadata_message(&mut self, peer_id: PeerId, metadata: Metadata) -> Result<()> {
+        match metadata {
+            Metadata::Request { piece } => self.metadata_requested(peer_id, piece),
+            Metadata::Data {
+                piece,
+                total_size,
+                data,
+            } => self.metadata_received(peer_id, piece, total_size, data),
+            Metadata::Reject { piece } => {
+                info!("Peer {peer_id} rejected request for metadata piece {piece}");
+                Ok(())
+            }
+        }
+    }
+
+    fn metadata_requested(&mut self, peer_id: PeerId, piece: usize) -> Result<()> {
+        let Some(connection) = self.connections.get(&peer_id) else {
+            return Ok(());
+        };
+        let response = match &self.metainfo {
+            Some((metadata, _)) if piece * METADATA_PIECE_SIZE < metadata.len() => {
+                let start = piece * METADATA_PIECE_SIZE;
+                let end = metadata.len().min(start + METADATA_PIECE_SIZE);
+                Metadata::Data {
+                    piece,
+                    total_size: metadata.len(),
+                    data: metadata[start..end].to_vec(),
+                }
+            }
+            _ => Metadata::Reject { piece },
+        };
+        connection
+            .actor
+            .act(move |connection| connection.send_metadata(response))
+    }
+
+    /// Use `metadata`, the raw info dictionary, instead of downloading it from peers.
+    pub fn set_metadata(&mut self, metadata: Vec<u8>) -> Result<()> {
+        if self.metainfo.is_some() {
+            bail!("Metadata is known already");
+        }
+        if info_hash(&metadata) != self.info_hash {
+            bail!("Metadata doesn't match the info hash");
+        }
+        let info = Info::from_bytes(&metadata)?;
+        self.metadata_known(metadata, info)
+    }
+
+    /// Now that we know what the torrent looks like, start downloading its pieces.
+    fn metadata_known(&mut self, metadata: Vec<u8>, info: Info) -> Result<()> {
+        self.metadata_download = None;
+        self.set_piece_layout(info.piece_length, info.length);
+        if let Some(download_dir) = &self.download_dir {
+            self.piece_store = Box::new(FilePieceStore::new(download_dir, &info)?);
+        }
+        let piece_count = info.pieces.len();
+        self.metainfo = Some((metadata, info));
+        for connection in self.connections.values_mut() {
+            if connection.has_all {
+                connection.pieces = Bitfield::full(piece_count);
+            }
+            connection
+                .actor
+                .act(move |connection| connection.set_piece_count(piece_count))?;
+        }
+        self.update_interest()
+    }
+
+    fn metadata_received(
+        &mut self,
+        peer_id: PeerId,
+        piece: usize,
+        total_size: usize,
+        data: Vec<u8>,
+    ) -> Result<()> {
+        let Some(download) = &mut self.metadata_download else {
+            trace!("Ignoring unrequested metadata piece {piece} from peer {peer_id}");
+            return Ok(());
+        };
+        if let Err(e) = download.receive(piece, total_size, data) {
+            warn!("Peer {peer_id} sent a bad metadata piece: {e}");
+            return Ok(());
+        }
+        if !download.is_complete() {
+            return Ok(());
+        }
+        match download.finish() {
+            Ok((metadata, info)) => {
+                info!("Downloaded metadata for torrent {:?}", info.name);
+                self.metadata_known(metadata, info)
+            }
+            Err(e) => {
+                // There's no telling which peer sent the bad piece, so start over with everyone
+                // except the one that sent the last piece.
+                warn!("Discarding downloaded metadata: {e}");
+                let missing = download.missing_pieces();
+                for (other_peer_id, connection) in &self.connections {
+                    if *other_peer_id != peer_id && connection.metadata_size == Some(total_size) {
+                        let missing = missing.clone();
+                        connection
+                            .actor
+                            .act(move |connection| connection.request_metadata(missing))?;
+                    }
+                }
+                Ok(())
+            }
+        }
+    }
+
+    /// A connection to `peer_id` stopped by itself. It's only removed if it's still the one
+    /// we have to that peer: one that closes late might have been replaced by a newer one.
+    pub fn connection_stopped(&mut self, peer_id: PeerId, connection: &Handle<ConnectionActor>) {
+        let current = self
+            .connections
+            .get(&peer_id)
+            .is_some_and(|existing| existing.actor.is_same(connection));
+        if current {
+            self.remove_connection(peer_id);
+        }
     }
 
-    pub fn remove_connection(&mut self, peer_id: PeerId) {
-        self.connections.remove(&peer_id);
+    fn remove_connection(&mut self, peer_id: PeerId) {
+        if self.connections.remove(&peer_id).is_some() {
+            self.subscribers
+                .send(&TorrentEvent::PeerDisconnected(peer_id));
+            self.report_connections();
+        }
+        self.piece_selector.release_peer(peer_id);
         info!("TorrentActor removed connection to peer {}", peer_id);
     }
 
-    pub fn send_keep_alive(&self) -> Result<()> {
-        for connection in self.connections.values() {
-            connection.act(move |connection| {
-                connection.send_keep_alive()?;
-                Ok(Outcome::Continue)
-            })?;
+    /// Refuse to talk to peers in `range` from now on, dropping those that are connected.
+    pub fn ban(&mut self, range: IpRange) {
+        info!("Banning peers in {range}");
+        self.ban_list.ban(range);
+        self.redials
+            .retain(|peer_addr, _| !range.contains(peer_addr.ip()));
+        let banned: Vec<_> = self
+            .connections
+            .iter()
+            .filter(|(_, connection)| {
+                connection
+                    .peer_addr
+                    .is_some_and(|peer_addr| range.contains(peer_addr.ip()))
+            })
+            .map(|(peer_id, _)| *peer_id)
+            .collect();
+        for peer_id in banned {
+            let _ = self.remove_peer(peer_id);
         }
-        Ok(())
     }
 
-    #[cfg(test)]
-    pub fn has_connection(&self, peer_id: PeerId) -> bool {
-        self.connections.contains_key(&peer_id)
+    /// Lift a ban added with [ban](Self::ban). Peers that were dropped aren't redialed.
+    pub fn unban(&mut self, range: IpRange) {
+        info!("Unbanning peers in {range}");
+        self.ban_list.unban(range);
     }
-}
 
-impl Actor for TorrentActor {
-    fn set_handle(&mut self, handle: &Handle<TorrentActor>) {
-        self.handle = Some(handle.clone());
+    fn is_banned(&self, peer_addr: Option<SocketAddr>) -> bool {
+        peer_addr.is_some_and(|peer_addr| self.ban_list.is_banned(peer_addr.ip()))
     }
-}
 
-impl Drop for TorrentActor {
-    fn drop(&mut self) {
-        for connection in self.connections.values() {
-            let _ = connection.stop();
+    /// Disconnect from a peer on purpose, e.g. because it misbehaves. It isn't redialed, but
+    /// can be added again later.
+    pub fn remove_peer(&mut self, peer_id: PeerId) -> Result<()> {
+        let connection = self
+            .connections
+            .get(&peer_id)
+            .ok_or_eyre("Peer not connected")?;
+        // A rejected connection doesn't remove itself again when it stops. If it's already
+        // stopping, it's on its way out anyway.
+        let _ = connection
+            .actor
+            .act(|connection| connection.reject("removed from the torrent"));
+        if let Some(peer_addr) = connection.peer_addr {
+            self.forget_dials(peer_addr);
         }
+        self.remove_connection(peer_id);
+        Ok(())
+    }
+
+    /// Stop dialing `peer_addr`, whether it's due to be redialed, queued or being dialed, so
+    /// that adding it again starts over.
+    fn forget_dials(&mut self, peer_addr: SocketAddr) {
+        self.redials.remove(&peer_addr);
+        self.dial_queue.retain(|queued| *queued != peer_addr);
+        self.dial_resolved(peer_addr);
+    }
+
+    pub fn set_peer_interested(&mut self, peer_id: PeerId, interested: bool) {
+        if let Some(connection) = self.connections.get_mut(&peer_id) {
+            connection.peer_interested = interested;
+        }
+    }
+
+    /// Record that `bytes` were downloaded from a peer, for the purpose of rate estimation.
+    pub fn record_download(&mut self, peer_id: PeerId, bytes: usize) {
+        self.downloaded += bytes as u64;
+        if let Some(connection) = self.connections.get_mut(&peer_id) {
+            connection.download_rate.record(self.clock.now(), bytes);
+            connection.delivery_rate.record(self.clock.now(), bytes);
+        }
+    }
+
+    /// Record that `bytes` were uploaded to a peer.
+    pub fn record_upload(&mut self, peer_id: PeerId, bytes: usize) {
+        self.uploaded += bytes as u64;
+        if let Some(connection) = self.connections.get_mut(&peer_id) {
+            connection.upload_rate.record(self.clock.now(), bytes);
+        }
+    }
+
+    /// Hand out up to `count` blocks for a peer to request.
+    pub fn assign_blocks(&mut self, peer_id: PeerId, count: usize) -> Result<()> {
+        let transferring = self.is_transferring();
+        let connection = self
+            .connections
+            .get_mut(&peer_id)
+            .ok_or_eyre("Peer not connected")?;
+        let now = self.clock.now();
+        let count_to_assign = match (transferring, connection.snubbed) {
+            (false, _) => 0,
+            // A snubbed peer gets one block at a time, so that we notice when it speeds up.
+            (true, true) if self.piece_selector.in_flight_to(peer_id) > 0 => 0,
+            (true, true) => count.min(1),
+            (true, false) => count,
+        };
+        let blocks: Vec<_> = (0..count_to_assign)
+            .map_while(|_| self.piece_selector.assign(peer_id, now))
+            .collect();
+        if !blocks.is_empty() {
+            connection.requested_since.get_or_insert(now);
+        }
+        connection
+            .actor
+            .act(move |connection| connection.request_blocks(count, blocks))
+    }
+
+    pub fn block_received(
+        &mut self,
+        peer_id: PeerId,
+        request: Request,
+        block: Vec<u8>,
+    ) -> Result<()> {
+        if self.verifying {
+            // The store is busy being verified, so the block is requested again afterwards.
+            trace!("Dropping block {request:?} from peer {peer_id} while verifying");
+            self.piece_selector.release(peer_id, request);
+            return Ok(());
+        }
+        match self.piece_selector.complete(peer_id, request) {
+            Completion::Done => {
+                trace!("Received block {request:?} from peer {peer_id}");
+            }
+            Completion::DoneElsewhere(other_peer_id) => {
+                trace!("Received block {request:?} from peer {peer_id}, cancelling it for {other_peer_id}");
+                if let Some(other) = self.connections.get(&other_peer_id) {
+                    other
+                        .actor
+                        .act(move |connection| connection.cancel_request(request))?;
+                }
+            }
+            Completion::NotNeeded => {
+                trace!("Received unneeded block {request:?} from peer {peer_id}");
+                return Ok(());
+            }
+        }
+        if let Err(e) = self
+            .piece_store
+            .write_block(request.index, request.begin, &block)
+        {
+            warn!("Failed to store block {request:?}, downloading it again: {e:?}");
+            self.piece_selector.uncomplete(request);
+        }
+        if self.piece_selector.is_piece_complete(request.index) {
+            self.piece_completed(request.index)?;
+        }
+        self.subscribers
+            .send(&TorrentEvent::Progress(self.piece_selector.progress()));
+        Ok(())
+    }
+
+    /// Check a piece whose blocks have all arrived against its hash, downloading it again if
+    /// it's corrupt. Without the metadata it can't be checked, but then it can't be complete.
+    fn piece_completed(&mut self, index: u32) -> Result<()> {
+        if let Some(info) = self.info() {
+            let verified = self.piece_store.verified(index).unwrap_or_else(|| {
+                self.piece_store
+                    .read_block(index, 0, info.piece_size(index))
+                    .is_ok_and(|piece| info.verify_piece(index, &piece))
+            });
+            if !verified {
+                let message = format!("Piece {index} failed its hash check, downloading it again");
+                warn!("{message}");
+                self.observe(EventLevel::Warn, "piece_failed", message);
+                self.piece_selector.discard_piece(index);
+                self.subscribers.send(&TorrentEvent::PieceFailed(index));
+                return Ok(());
+            }
+        }
+        self.pending_haves.insert(index);
+        self.metrics.incr("pieces_completed", 1);
+        let message = format!("Piece {index} completed");
+        self.observe(EventLevel::Info, "piece_completed", message);
+        self.subscribers.send(&TorrentEvent::PieceCompleted(index));
+        if self.piece_selector.is_complete() {
+            info!("Download complete");
+            self.announcers.announce(AnnounceEvent::Completed);
+        }
+        self.update_interest()
+    }
+
+    /// A peer asked us for a block, send it if we have it.
+    pub fn block_requested(&mut self, peer_id: PeerId, request: Request) -> Result<()> {
+        let Some(connection) = self.connections.get(&peer_id) else {
+            return Ok(());
+        };
+        if !self.is_transferring() {
+            trace!("Peer {peer_id} requested {request:?} while we're paused or verifying");
+            return connection
+                .actor
+                .act(move |connection| connection.reject_request(request));
+        }
+        if !self.piece_selector.is_piece_complete(request.index) {
+            trace!("Peer {peer_id} requested {request:?}, which we don't have");
+            return connection
+                .actor
+                .act(move |connection| connection.reject_request(request));
+        }
+        let end = u64::from(request.begin) + u64::from(request.length);
+        if end > u64::from(self.piece_selector.piece_size(request.index)) {
+            trace!("Peer {peer_id} requested {request:?}, which is past the end of the piece");
+            return connection
+                .actor
+                .act(move |connection| connection.reject_request(request));
+        }
+        match self
+            .piece_store
+            .read_block(request.index, request.begin, request.length)
+        {
+            Ok(block) => {
+                let piece = Piece::new(request.index, request.begin, block);
+                connection
+                    .actor
+                    .act(move |connection| connection.send_block(piece))
+            }
+            Err(e) => {
+                warn!("Couldn't read {request:?} requested by peer {peer_id}: {e:?}");
+                connection
+                    .actor
+                    .act(move |connection| connection.reject_request(request))
+            }
+        }
+    }
+
+    /// Give back a block that a peer won't be downloading after all.
+    pub fn release_block(&mut self, peer_id: PeerId, request: Request) {
+        self.piece_selector.release(peer_id, request);
+    }
+
+    pub fn send_keep_alive(&self) -> Result<()> {
+        for connection in self.connections.values() {
+            connection.actor.act(move |connection| {
+                connection.send_keep_alive()?;
+                Ok(Outcome::Continue)
+            })?;
+        }
+        Ok(())
+    }
+
+    /// Periodic housekeeping, meant to be run often (about once a second).
+    /// Anything time-based checks the clock here instead of keeping its own timer.
+    pub fn tick(&mut self) -> Result<Outcome> {
+        self.drop_overflowed_connections();
+        self.rechoke()?;
+        self.update_snubbing();
+        self.expire_requests()?;
+        self.expire_dials();
+        self.redial_due()?;
+        self.dial_queued()?;
+        self.exchange_peers()?;
+        self.send_haves()?;
+        for connection in self.connections.values() {
+            connection.actor.act(ConnectionActor::resume_transfers)?;
+            connection.actor.act(ConnectionActor::check_activity)?;
+        }
+        Ok(Outcome::Continue)
+    }
+
+    /// Forget about connections that fell too far behind on what we sent them. They're
+    /// stopping already, this only makes sure nothing else is sent their way meanwhile.
+    fn drop_overflowed_connections(&mut self) {
+        let overflowed: Vec<_> = self
+            .connections
+            .iter()
+            .filter(|(_, connection)| connection.actor.has_overflowed())
+            .map(|(peer_id, _)| *peer_id)
+            .collect();
+        for peer_id in overflowed {
+            warn!("Dropping peer {peer_id}, it isn't keeping up with what we send it");
+            self.remove_connection(peer_id);
+        }
+    }
+
+    /// Tell every peer about the pieces completed since the last tick at once, rather than
+    /// a message per piece as they complete. Peers that already have a piece aren't told
+    /// about it, as they'd have no use for it.
+    fn send_haves(&mut self) -> Result<()> {
+        let completed = std::mem::take(&mut self.pending_haves);
+        if completed.is_empty() {
+            return Ok(());
+        }
+        for connection in self.connections.values() {
+            if connection.has_all {
+                continue;
+            }
+            let haves: Vec<_> = completed
+                .iter()
+                .copied()
+                .filter(|index| !connection.pieces.has(*index as usize))
+                .collect();
+            if !haves.is_empty() {
+                connection
+                    .actor
+                    .act(move |connection| connection.send_haves(haves))?;
+            }
+        }
+        Ok(())
+    }
+
+    /// Snub the peers that have been too slow to deliver for the whole snub window, and forgive
+    /// the ones that sped up again.
+    fn update_snubbing(&mut self) {
+        let now = self.clock.now();
+        for (peer_id, connection) in &mut self.connections {
+            if self.piece_selector.in_flight_to(*peer_id) == 0 {
+                connection.requested_since = None;
+            }
+            let fast_enough =
+                connection.delivery_rate.rate(now) >= self.config.snub_threshold as f64;
+            let requested_for_window = connection.requested_since.is_some_and(|since| {
+                now.saturating_duration_since(since) >= self.config.snub_window
+            });
+            if connection.snubbed && fast_enough {
+                info!("Peer {peer_id} sped up, no longer snubbing it");
+                connection.snubbed = false;
+            } else if !connection.snubbed && !fast_enough && requested_for_window {
+                info!("Peer {peer_id} is delivering too slowly, snubbing it");
+                connection.snubbed = true;
+            }
+        }
+    }
+
+    /// Take blocks away from peers that have been sitting on them for too long,
+    /// so that they can be requested from someone else.
+    fn expire_requests(&mut self) -> Result<()> {
+        let expired = self
+            .piece_selector
+            .expire(self.clock.now(), self.config.request_timeout);
+        for (peer_id, request) in expired {
+            info!("Request for {request:?} to peer {peer_id} timed out");
+            if let Some(connection) = self.connections.get(&peer_id) {
+                connection
+                    .actor
+                    .act(move |connection| connection.cancel_request(request))?;
+            }
+        }
+        Ok(())
+    }
+
+    fn rechoke(&mut self) -> Result<()> {
+        if self.paused {
+            return Ok(());
+        }
+        let now = self.clock.now();
+        let candidates: Vec<_> = self
+            .connections
+            .iter_mut()
+            .map(|(peer_id, connection)| ChokeCandidate {
+                peer_id: *peer_id,
+                interested: connection.peer_interested,
+                download_rate: connection.download_rate.rate(now),
+                upload_rate: connection.upload_rate.rate(now),
+                unchoked: !connection.am_choking,
+            })
+            .collect();
+        let seeding = self.piece_selector.is_complete();
+        let Some(decision) = self.choking.tick(now, &candidates, seeding, &mut self.rng) else {
+            return Ok(());
+        };
+
+        for peer_id in decision.choke {
+            if let Some(connection) = self.connections.get_mut(&peer_id) {
+                connection.am_choking = true;
+                connection.actor.act(ConnectionActor::choke)?;
+            }
+        }
+        for peer_id in decision.unchoke {
+            if let Some(connection) = self.connections.get_mut(&peer_id) {
+                connection.am_choking = false;
+                connection.actor.act(ConnectionActor::unchoke)?;
+            }
+        }
+        Ok(())
+    }
+
+    /// The peers that we're currently connected to.
+    pub fn connected_peers(&self) -> Vec<PeerId> {
+        self.connections.keys().copied().collect()
+    }
+
+    pub fn own_peer_id(&self) -> PeerId {
+        self.own_peer_id
+    }
+
+    pub fn config(&self) -> TorrentConfig {
+        self.config
+    }
+
+    pub fn peer_count(&self) -> usize {
+        self.connections.len()
+    }
+
+    /// Sum up the transfer rates of every connection. Only the rate estimators are touched,
+    /// so this is cheap enough to call several times a second.
+    pub fn stats(&mut self) -> TorrentStats {
+        let now = self.clock.now();
+        let (download_rate, upload_rate) =
+            self.connections
+                .values_mut()
+                .fold((0.0, 0.0), |(down, up), connection| {
+                    (
+                        down + connection.download_rate.rate(now),
+                        up + connection.upload_rate.rate(now),
+                    )
+                });
+        let piece_count = self.piece_count().unwrap_or(0);
+        let pieces_complete = self.piece_selector.complete_piece_count();
+        let left = self.info().map(|info| {
+            (0..info.pieces.len())
+                // the piece count comes from u32 piece indices, so the cast is safe
+                .map(|index| index as u32)
+                .filter(|index| !self.piece_selector.is_piece_complete(*index))
+                .map(|index| u64::from(info.piece_size(index)))
+                .sum()
+        });
+        TorrentStats {
+            download_rate,
+            upload_rate,
+            downloaded: self.downloaded,
+            uploaded: self.uploaded,
+            all_time_downloaded: self.earlier_downloaded + self.downloaded,
+            all_time_uploaded: self.earlier_uploaded + self.uploaded,
+            peers: self.connections.len(),
+            pieces_complete,
+            piece_count,
+            left,
+            seeding: piece_count > 0 && pieces_complete == piece_count,
+        }
+    }
+
+    /// Stop every connection, waiting for each of them to finish what it has queued up,
+    /// and then stop the torrent itself.
+    pub fn shutdown(&mut self) -> Result<Outcome> {
+        for (peer_id, connection) in self.connections.drain() {
+            if let Err(e) = connection.actor.stop() {
+                warn!("Error stopping connection to peer {peer_id}: {e:?}");
+            }
+        }
+        if let Err(e) = self.piece_store.flush() {
+            warn!("Error flushing downloaded pieces: {e:?}");
+        }
+        if let Some(path) = &self.resume_path {
+            if let Err(e) = self.write_state(path) {
+                warn!("Error saving resume state: {e:?}");
+            }
+        }
+        info!("TorrentActor shut down");
+        Ok(Outcome::Stop)
+    }
+
+    #[cfg(test)]
+    pub fn has_connection(&self, peer_id: PeerId) -> bool {
+        self.connections.contains_key(&peer_id)
+    }
+}
+
+impl Actor for TorrentActor {
+    fn set_handle(&mut self, handle: &Handle<TorrentActor>) {
+        self.handle = Some(handle.clone());
+    }
+}
+
+impl Drop for TorrentActor {
+    fn drop(&mut self) {
+        for connection in self.connections.values() {
+            let _ = connection.actor.stop();
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::VecDeque;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use std::sync::Mutex;
+    use std::thread::sleep;
+
+    use crate::clock::MockClock;
+    use crate::connections::mock_connection::MockConnection;
+    use crate::messages::{
+        Cancel, Handshake, Have, HaveAll, HaveNone, KeepAlive, Message, Pex, Unchoke,
+        FAST_EXTENSION_BIT, UT_PEX_ID,
+    };
+    use crate::torrent::piece_selector::BLOCK_SIZE;
+    use crate::BoxedConnection;
+
+    use super::*;
+
+    #[test]
+    fn rechoke_unchokes_top_uploaders_and_one_optimistic() {
+        let own_peer_id = PeerId::new([1; 20]);
+        let info_hash = InfoHash::new([2; 20]);
+        let clock = MockClock::new();
+        let mut torrent = TorrentActor::with_config(
+            own_peer_id,
+            info_hash,
+            TorrentConfig::default(),
+            Arc::new(clock.clone()),
+        );
+        // The connection actors need a torrent to report to, but it's not the one under test.
+        let other_torrent = Handle::spawn(TorrentActor::new(own_peer_id, info_hash));
+
+        // Peers 10..=15 are interested and upload at increasing rates,
+        // peer 16 is the fastest but not interested.
+        let mut connections = HashMap::new();
+        for i in 10..=16u8 {
+            let peer_id = PeerId::new([i; 20]);
+            let connection = MockConnection::new(VecDeque::new());
+            let actor = Handle::spawn(ConnectionActor::new(
+                own_peer_id,
+                Some(peer_id),
+                connection.clone(),
+                connection.clone(),
+                info_hash,
+                other_torrent.clone(),
+                TorrentConfig::default(),
+            ));
+            torrent.add_connection(peer_id, true, None, actor).unwrap();
+            torrent.set_peer_interested(peer_id, i != 16);
+            torrent.record_download(peer_id, usize::from(i) * 1000);
+            connections.insert(peer_id, connection);
+        }
+
+        torrent.tick().unwrap();
+
+        let is_unchoked =
+            |torrent: &TorrentActor, i: u8| !torrent.connections[&PeerId::new([i; 20])].am_choking;
+        for i in 12..=15 {
+            assert!(is_unchoked(&torrent, i), "peer {i} should be unchoked");
+        }
+        assert!(
+            !is_unchoked(&torrent, 16),
+            "uninterested peer should be choked"
+        );
+        assert!(
+            is_unchoked(&torrent, 10) ^ is_unchoked(&torrent, 11),
+            "exactly one slow peer should be optimistically unchoked"
+        );
+
+        sleep(Duration::from_millis(100));
+        for (peer_id, connection) in &connections {
+            let expected = if torrent.connections[peer_id].am_choking {
+                vec![]
+            } else {
+                vec![Message::Unchoke(Unchoke)]
+            };
+            assert_eq!(*connection.sent_messages.lock().unwrap(), expected);
+        }
+
+        // Nothing changes until the rechoke interval has passed.
+        clock.advance(Duration::from_secs(5));
+        torrent.set_peer_interested(PeerId::new([15; 20]), false);
+        torrent.tick().unwrap();
+        assert!(is_unchoked(&torrent, 15));
+
+        clock.advance(Duration::from_secs(5));
+        torrent.tick().unwrap();
+        assert!(!is_unchoked(&torrent, 15));
+
+        drop(torrent);
+        other_torrent.stop().unwrap();
+    }
+
+    #[test]
+    fn seed_unchokes_the_peers_it_uploads_to_fastest() {
+        let own_peer_id = PeerId::new([1; 20]);
+        let content = vec![7; 40];
+        let info = Info::from_content("test", 16, &content);
+        let info_hash = info.info_hash();
+        let mut torrent = TorrentActor::with_config(
+            own_peer_id,
+            info_hash,
+            TorrentConfig::default(),
+            Arc::new(MockClock::new()),
+        );
+        torrent.load_content(info.to_bytes(), &content).unwrap();
+        let other_torrent = Handle::spawn(TorrentActor::new(own_peer_id, info_hash));
+
+        // No one uploads to a seed. We upload the fastest to the peers with the highest IDs.
+        for i in 10..=16u8 {
+            let peer_id = PeerId::new([i; 20]);
+            let connection = MockConnection::new(VecDeque::new());
+            let actor = Handle::spawn(ConnectionActor::new(
+                own_peer_id,
+                Some(peer_id),
+                connection.clone(),
+                connection,
+                info_hash,
+                other_torrent.clone(),
+                TorrentConfig::default(),
+            ));
+            torrent.add_connection(peer_id, true, None, actor).unwrap();
+            torrent.set_peer_interested(peer_id, true);
+            torrent.record_upload(peer_id, usize::from(i) * 1000);
+        }
+
+        torrent.tick().unwrap();
+
+        let is_unchoked =
+            |torrent: &TorrentActor, i: u8| !torrent.connections[&PeerId::new([i; 20])].am_choking;
+        for i in 13..=16 {
+            assert!(is_unchoked(&torrent, i), "peer {i} should be unchoked");
+        }
+        let optimistic = (10..=12).filter(|i| is_unchoked(&torrent, *i)).count();
+        assert_eq!(optimistic, 1, "exactly one slow peer should be optimistic");
+
+        drop(torrent);
+        other_torrent.stop().unwrap();
+    }
+
+    #[test]
+    fn connections_beyond_the_limit_are_rejected() {
+        let own_peer_id = PeerId::new([1; 20]);
+        let info_hash = InfoHash::new([2; 20]);
+        let config = TorrentConfig {
+            max_connections: 2,
+            ..TorrentConfig::default()
+        };
+        let torrent = Handle::spawn(TorrentActor::with_config(
+            own_peer_id,
+            info_hash,
+            config,
+            Arc::new(crate::clock::SystemClock),
+        ));
+
+        for i in 10..=12u8 {
+            let handshake = Handshake::new(info_hash, PeerId::new([i; 20]));
+            let connection = MockConnection::new(VecDeque::from([Message::Handshake(handshake)]));
+            torrent
+                .act(move |torrent| {
+                    torrent.accept_peer_connection(None, None, connection.clone(), connection)
+                })
+                .unwrap();
+            sleep(Duration::from_millis(100));
+        }
+
+        let connected = Arc::new(Mutex::new(vec![]));
+        torrent
+            .act({
+                let connected = connected.clone();
+                move |torrent| {
+                    *connected.lock().unwrap() = (10..=12u8)
+                        .map(|i| torrent.has_connection(PeerId::new([i; 20])))
+                        .collect();
+                    Ok(Outcome::Continue)
+                }
+            })
+            .unwrap();
+        sleep(Duration::from_millis(100));
+        assert_eq!(*connected.lock().unwrap(), vec![true, true, false]);
+
+        torrent.stop().unwrap();
+    }
+
+    #[test]
+    fn failed_connection_is_redialed_later() {
+        let own_peer_id = PeerId::new([1; 20]);
+        let peer_id = PeerId::new([3; 20]);
+        let info_hash = InfoHash::new([2; 20]);
+        let peer_addr = SocketAddr::from(([127, 0, 0, 1], 6881));
+        let clock = MockClock::new();
+        let config = TorrentConfig {
+            reconnect_jitter: Duration::ZERO,
+            ..TorrentConfig::default()
+        };
+        let torrent = Handle::spawn(TorrentActor::with_config(
+            own_peer_id,
+            info_hash,
+            config,
+            Arc::new(clock.clone()),
+        ));
+        let attempts = Arc::new(Mutex::new(0));
+        let factory = {
+            let attempts = attempts.clone();
+            move || -> Result<BoxedConnection> {
+                let mut attempts = attempts.lock().unwrap();
+                *attempts += 1;
+                if *attempts == 1 {
+                    return Err(eyre::eyre!("connection refused"));
+                }
+                let handshake = Handshake::new(info_hash, peer_id);
+                let connection =
+                    MockConnection::new(VecDeque::from([Message::Handshake(handshake)]));
+                Ok((Box::new(connection.clone()), Box::new(connection)))
+            }
+        };
+
+        torrent
+            .act(move |torrent| torrent.connect_with_factory(None, peer_addr, Box::new(factory)))
+            .unwrap();
+        torrent.act(TorrentActor::tick).unwrap();
+        sleep(Duration::from_millis(100));
+        assert_eq!(*attempts.lock().unwrap(), 1);
+
+        clock.advance(config.reconnect_base_delay);
+        torrent.act(TorrentActor::tick).unwrap();
+        sleep(Duration::from_millis(100));
+
+        assert_eq!(*attempts.lock().unwrap(), 2);
+        assert!(torrent
+            .ask(move |torrent| Ok(torrent.has_connection(peer_id)))
+            .unwrap());
+
+        torrent.stop().unwrap();
+    }
+
+    #[test]
+    fn dialed_connection_is_made_afresh_after_a_panic() {
+        let own_peer_id = PeerId::new([1; 20]);
+        let peer_id = PeerId::new([3; 20]);
+        let info_hash = InfoHash::new([2; 20]);
+        let peer_addr = SocketAddr::from(([127, 0, 0, 1], 6881));
+        let torrent = Handle::spawn(TorrentActor::new(own_peer_id, info_hash));
+        let attempts = Arc::new(Mutex::new(0));
+        let factory = {
+            let attempts = attempts.clone();
+            move || -> Result<BoxedConnection> {
+                *attempts.lock().unwrap() += 1;
+                let handshake = Handshake::new(info_hash, peer_id);
+                let connection =
+                    MockConnection::new(VecDeque::from([Message::Handshake(handshake)]));
+                Ok((Box::new(connection.clone()), Box::new(connection)))
+            }
+        };
+        let connection = || {
+            torrent
+                .ask(move |torrent| {
+                    Ok(torrent
+                        .connections
+                        .get(&peer_id)
+                        .map(|connection| connection.actor.clone()))
+                })
+                .unwrap()
+        };
+
+        torrent
+            .act(move |torrent| torrent.connect_with_factory(None, peer_addr, Box::new(factory)))
+            .unwrap();
+        let failing = loop {
+            match connection() {
+                Some(connection) => break connection,
+                None => sleep(Duration::from_millis(10)),
+            }
+        };
+        failing
+            .act(|_| panic!("bug in handling a message"))
+            .unwrap();
+        while *attempts.lock().unwrap() < 2 {
+            sleep(Duration::from_millis(10));
+        }
+        let replacement = loop {
+            match connection() {
+                Some(connection) => break connection,
+                None => sleep(Duration::from_millis(10)),
+            }
+        };
+
+        // It's the same actor as far as anyone holding on to its handle can tell, and there's
+        // no need for the torrent to redial the peer on top of that.
+        assert!(replacement.is_same(&failing));
+        let redial_due = torrent
+            .ask(move |torrent| Ok(torrent.redials[&peer_addr].due))
+            .unwrap();
+        assert_eq!(redial_due, None);
+        assert_eq!(*attempts.lock().unwrap(), 2);
+        torrent.stop().unwrap();
+    }
+
+    #[test]
+    fn removed_peer_can_be_added_again() {
+        let own_peer_id = PeerId::new([1; 20]);
+        let peer_id = PeerId::new([3; 20]);
+        let info_hash = InfoHash::new([2; 20]);
+        let peer_addr = SocketAddr::from(([127, 0, 0, 1], 6881));
+        let torrent = Handle::spawn(TorrentActor::new(own_peer_id, info_hash));
+        let attempts = Arc::new(Mutex::new(0));
+        let factory = || {
+            let attempts = attempts.clone();
+            Box::new(move || -> Result<BoxedConnection> {
+                *attempts.lock().unwrap() += 1;
+                let handshake = Handshake::new(info_hash, peer_id);
+                let connection =
+                    MockConnection::new(VecDeque::from([Message::Handshake(handshake)]));
+                Ok((Box::new(connection.clone()), Box::new(connection)))
+            })
+        };
+        let connected = || {
+            torrent
+                .ask(move |torrent| Ok(torrent.has_connection(peer_id)))
+                .unwrap()
+        };
+
+        let first = factory();
+        torrent
+            .act(move |torrent| torrent.add_peer(None, peer_addr, first))
+            .unwrap();
+        while !connected() {
+            sleep(Duration::from_millis(10));
+        }
+        torrent
+            .ask(move |torrent| torrent.remove_peer(peer_id))
+            .unwrap();
+        let forgotten = torrent
+            .ask(move |torrent| {
+                Ok(!torrent.redials.contains_key(&peer_addr)
+                    && !torrent.pending_dials.contains(&peer_addr)
+                    && !torrent.dial_queue.contains(&peer_addr))
+            })
+            .unwrap();
+        assert!(forgotten);
+
+        let second = factory();
+        torrent
+            .act(move |torrent| torrent.add_peer(None, peer_addr, second))
+            .unwrap();
+        while !connected() {
+            sleep(Duration::from_millis(10));
+        }
+
+        assert_eq!(*attempts.lock().unwrap(), 2);
+        torrent.stop().unwrap();
+    }
+
+    #[test]
+    fn peers_that_connected_to_us_arent_added_again() {
+        let own_peer_id = PeerId::new([1; 20]);
+        let peer_id = PeerId::new([3; 20]);
+        let info_hash = InfoHash::new([2; 20]);
+        let peer_addr = SocketAddr::from(([127, 0, 0, 1], 6881));
+        let torrent = Handle::spawn(TorrentActor::new(own_peer_id, info_hash));
+        let handshake = Handshake::new(info_hash, peer_id);
+        let connection = MockConnection::new(VecDeque::from([Message::Handshake(handshake)]));
+        torrent
+            .act(move |torrent| {
+                torrent.accept_peer_connection(
+                    None,
+                    Some(peer_addr),
+                    connection.clone(),
+                    connection,
+                )
+            })
+            .unwrap();
+        while !torrent
+            .ask(move |torrent| Ok(torrent.has_connection(peer_id)))
+            .unwrap()
+        {
+            sleep(Duration::from_millis(10));
+        }
+
+        let attempts = Arc::new(Mutex::new(0));
+        let factory = unreachable_peer(attempts.clone());
+        let known = torrent
+            .ask(move |torrent| {
+                torrent.add_peer(None, peer_addr, factory)?;
+                Ok(torrent.redials.contains_key(&peer_addr))
+            })
+            .unwrap();
+
+        assert!(!known);
+        assert_eq!(*attempts.lock().unwrap(), 0);
+        torrent.stop().unwrap();
+    }
+
+    #[test]
+    fn only_so_many_dials_are_pending_at_once() {
+        let info_hash = InfoHash::new([2; 20]);
+        let config = TorrentConfig {
+            max_pending_dials: 5,
+            max_connections: 100,
+            handshake_timeout: Duration::from_millis(50),
+            reconnect_base_delay: Duration::from_secs(60),
+            ..TorrentConfig::default()
+        };
+        let torrent = Handle::spawn(TorrentActor::with_config(
+            PeerId::new([1; 20]),
+            info_hash,
+            config,
+            Arc::new(MockClock::new()),
+        ));
+        let attempts = Arc::new(Mutex::new(0));
+        for port in 0..50 {
+            let attempts = attempts.clone();
+            // The peers never send their handshake, so every dial times out.
+            let factory = move || -> Result<BoxedConnection> {
+                *attempts.lock().unwrap() += 1;
+                let connection = MockConnection::new(VecDeque::new());
+                Ok((Box::new(connection.clone()), Box::new(connection)))
+            };
+            let peer_addr = SocketAddr::from(([10, 0, 0, 1], 6881 + port));
+            torrent
+                .act(move |torrent| torrent.add_peer(None, peer_addr, Box::new(factory)))
+                .unwrap();
+        }
+
+        let pending = || {
+            torrent
+                .ask(|torrent| Ok(torrent.pending_dials.len()))
+                .unwrap()
+        };
+        assert_eq!(pending(), 5);
+        assert!(*attempts.lock().unwrap() <= 5);
+        while *attempts.lock().unwrap() < 50 {
+            assert!(pending() <= 5);
+            sleep(Duration::from_millis(10));
+        }
+        torrent.stop().unwrap();
+    }
+
+    /// A factory for a peer that can't be reached, counting how often it's been tried.
+    fn unreachable_peer(attempts: Arc<Mutex<u32>>) -> Box<dyn ConnectionFactory> {
+        Box::new(move || -> Result<BoxedConnection> {
+            *attempts.lock().unwrap() += 1;
+            Err(eyre::eyre!("connection refused"))
+        })
+    }
+
+    #[test]
+    fn slow_dials_dont_hold_up_the_torrent() {
+        let torrent = Handle::spawn(TorrentActor::new(
+            PeerId::new([1; 20]),
+            InfoHash::new([2; 20]),
+        ));
+        let (answer, wait_for_answer) = std::sync::mpsc::channel::<()>();
+        let wait_for_answer = Mutex::new(wait_for_answer);
+        // Like a peer that's blackholed, which only fails once the connect times out.
+        let factory = move || -> Result<BoxedConnection> {
+            let _ = wait_for_answer.lock().unwrap().recv();
+            Err(eyre::eyre!("connection timed out"))
+        };
+        let peer_addr = SocketAddr::from(([10, 0, 0, 1], 6881));
+        torrent
+            .ask(move |torrent| torrent.add_peer(None, peer_addr, Box::new(factory)))
+            .unwrap();
+
+        torrent.ask(TorrentActor::tick).unwrap();
+        assert!(torrent
+            .ask(move |torrent| Ok(torrent.pending_dials.contains(&peer_addr)))
+            .unwrap());
+
+        drop(answer);
+        torrent.stop().unwrap();
+    }
+
+    #[test]
+    fn dials_that_never_connect_are_given_up_on() {
+        let clock = MockClock::new();
+        let config = TorrentConfig {
+            max_pending_dials: 1,
+            ..TorrentConfig::default()
+        };
+        let torrent = Handle::spawn(TorrentActor::with_config(
+            PeerId::new([1; 20]),
+            InfoHash::new([2; 20]),
+            config,
+            Arc::new(clock.clone()),
+        ));
+        let (answer, wait_for_answer) = std::sync::mpsc::channel::<()>();
+        let wait_for_answer = Mutex::new(wait_for_answer);
+        // Never returns for as long as the test runs.
+        let factory = move || -> Result<BoxedConnection> {
+            let _ = wait_for_answer.lock().unwrap().recv();
+            Err(eyre::eyre!("connection timed out"))
+        };
+        let stalled = SocketAddr::from(([10, 0, 0, 1], 6881));
+        let queued = SocketAddr::from(([10, 0, 0, 2], 6881));
+        torrent
+            .ask(move |torrent| {
+                torrent.add_peer(None, stalled, Box::new(factory))?;
+                torrent.add_peer(None, queued, unreachable_peer(Arc::default()))
+            })
+            .unwrap();
+        let pending = move |torrent: &mut TorrentActor| {
+            Ok((
+                torrent.pending_dials.contains(&stalled),
+                torrent.dial_queue.contains(&queued),
+            ))
+        };
+        assert_eq!(torrent.ask(pending).unwrap(), (true, true));
+
+        clock.advance(config.handshake_timeout);
+        torrent.ask(TorrentActor::tick).unwrap();
+
+        assert_eq!(torrent.ask(pending).unwrap(), (false, false));
+        drop(answer);
+        torrent.stop().unwrap();
+    }
+
+    /// Wait for the dial to `peer_addr` to finish, and have the torrent deal with the result.
+    fn finish_dial(torrent: &Handle<TorrentActor>, peer_addr: SocketAddr) {
+        let dialing = move |torrent: &mut TorrentActor| {
+            let redial = torrent.redials.get(&peer_addr);
+            Ok(redial.is_some_and(|redial| redial.factory.is_none()))
+        };
+        while torrent.ask(dialing).unwrap() {
+            std::thread::yield_now();
+        }
+    }
+
+    #[test]
+    fn redial_delay_doubles_up_to_the_cap() {
+        let info_hash = InfoHash::new([2; 20]);
+        let clock = MockClock::new();
+        let config = TorrentConfig {
+            reconnect_base_delay: Duration::from_secs(1),
+            reconnect_max_delay: Duration::from_secs(4),
+            reconnect_jitter: Duration::ZERO,
+            ..TorrentConfig::default()
+        };
+        let torrent = Handle::spawn_manual(TorrentActor::with_config(
+            PeerId::new([1; 20]),
+            info_hash,
+            config,
+            Arc::new(clock.clone()),
+        ));
+        let attempts = Arc::new(Mutex::new(0));
+        let peer_addr = SocketAddr::from(([127, 0, 0, 1], 6881));
+
+        let factory = unreachable_peer(attempts.clone());
+        torrent
+            .ask(move |torrent| torrent.connect_with_factory(None, peer_addr, factory))
+            .unwrap();
+        finish_dial(&torrent, peer_addr);
+        assert_eq!(*attempts.lock().unwrap(), 1);
+
+        for (delay, attempt) in [(1, 2), (2, 3), (4, 4), (4, 5)] {
+            clock.advance(Duration::from_secs(delay) - Duration::from_millis(1));
+            torrent.ask(TorrentActor::tick).unwrap();
+            finish_dial(&torrent, peer_addr);
+            assert_eq!(
+                *attempts.lock().unwrap(),
+                attempt - 1,
+                "too early for {attempt}"
+            );
+            clock.advance(Duration::from_millis(1));
+            torrent.ask(TorrentActor::tick).unwrap();
+            finish_dial(&torrent, peer_addr);
+            assert_eq!(
+                *attempts.lock().unwrap(),
+                attempt,
+                "waited {delay}s for {attempt}"
+            );
+        }
+    }
+
+    #[test]
+    fn redialing_gives_up_after_max_attempts() {
+        let clock = MockClock::new();
+        let config = TorrentConfig {
+            reconnect_jitter: Duration::ZERO,
+            max_reconnect_attempts: 3,
+            ..TorrentConfig::default()
+        };
+        let torrent = Handle::spawn_manual(TorrentActor::with_config(
+            PeerId::new([1; 20]),
+            InfoHash::new([2; 20]),
+            config,
+            Arc::new(clock.clone()),
+        ));
+        let attempts = Arc::new(Mutex::new(0));
+        let peer_addr = SocketAddr::from(([127, 0, 0, 1], 6881));
+
+        let factory = unreachable_peer(attempts.clone());
+        torrent
+            .ask(move |torrent| torrent.connect_with_factory(None, peer_addr, factory))
+            .unwrap();
+        finish_dial(&torrent, peer_addr);
+        for _ in 0..5 {
+            clock.advance(config.reconnect_max_delay);
+            torrent.ask(TorrentActor::tick).unwrap();
+            finish_dial(&torrent, peer_addr);
+        }
+
+        assert_eq!(*attempts.lock().unwrap(), 3);
+    }
+
+    #[test]
+    fn duplicate_connections_are_resolved_by_lowest_initiator() {
+        let own_peer_id = PeerId::new([1; 20]);
+        let info_hash = InfoHash::new([2; 20]);
+        let mut torrent = TorrentActor::new(own_peer_id, info_hash);
+        let other_torrent = Handle::spawn(TorrentActor::new(own_peer_id, info_hash));
+        let spawn_connection = |peer_id| {
+            let connection = MockConnection::new(VecDeque::new());
+            Handle::spawn(ConnectionActor::new(
+                own_peer_id,
+                Some(peer_id),
+                connection.clone(),
+                connection,
+                info_hash,
+                other_torrent.clone(),
+                TorrentConfig::default(),
+            ))
+        };
+        let is_alive =
+            |actor: &Handle<ConnectionActor>| actor.act(|_| Ok(Outcome::Continue)).is_ok();
+
+        // We have the lower peer ID, so the connection we initiated wins.
+        let higher_peer_id = PeerId::new([5; 20]);
+        let outgoing = spawn_connection(higher_peer_id);
+        let incoming = spawn_connection(higher_peer_id);
+        torrent
+            .add_connection(higher_peer_id, true, None, outgoing.clone())
+            .unwrap();
+        torrent
+            .add_connection(higher_peer_id, false, None, incoming.clone())
+            .unwrap();
+
+        // The peer has the lower peer ID, so the connection it initiated wins.
+        let lower_peer_id = PeerId::new([0; 20]);
+        let outgoing_to_lower = spawn_connection(lower_peer_id);
+        let incoming_from_lower = spawn_connection(lower_peer_id);
+        torrent
+            .add_connection(lower_peer_id, true, None, outgoing_to_lower.clone())
+            .unwrap();
+        torrent
+            .add_connection(lower_peer_id, false, None, incoming_from_lower.clone())
+            .unwrap();
+
+        sleep(Duration::from_millis(100));
+        assert_eq!(torrent.connections.len(), 2);
+        assert!(is_alive(&outgoing));
+        assert!(!is_alive(&incoming));
+        assert!(!is_alive(&outgoing_to_lower));
+        assert!(is_alive(&incoming_from_lower));
+        assert!(!torrent.connections[&lower_peer_id].outgoing);
+
+        drop(torrent);
+        other_torrent.stop().unwrap();
+    }
+
+    #[test]
+    fn removed_peer_is_disconnected() {
+        let own_peer_id = PeerId::new([1; 20]);
+        let info_hash = InfoHash::new([2; 20]);
+        let mut torrent = TorrentActor::new(own_peer_id, info_hash);
+        let other_torrent = Handle::spawn(TorrentActor::new(own_peer_id, info_hash));
+        let peer_id = PeerId::new([5; 20]);
+        let connection = MockConnection::new(VecDeque::new());
+        let actor = Handle::spawn(ConnectionActor::new(
+            own_peer_id,
+            Some(peer_id),
+            connection.clone(),
+            connection,
+            info_hash,
+            other_torrent.clone(),
+            TorrentConfig::default(),
+        ));
+        torrent
+            .add_connection(peer_id, true, None, actor.clone())
+            .unwrap();
+
+        torrent.remove_peer(peer_id).unwrap();
+        actor.wait().unwrap();
+
+        assert!(!actor.is_running());
+        assert_eq!(torrent.connected_peers(), []);
+        let _ = torrent.remove_peer(peer_id).unwrap_err();
+        other_torrent.stop().unwrap();
+    }
+
+    #[test]
+    fn late_close_leaves_a_newer_connection_alone() {
+        let own_peer_id = PeerId::new([1; 20]);
+        let info_hash = InfoHash::new([2; 20]);
+        let mut torrent = TorrentActor::new(own_peer_id, info_hash);
+        let other_torrent = Handle::spawn(TorrentActor::new(own_peer_id, info_hash));
+        let peer_id = PeerId::new([5; 20]);
+        let spawn_connection = || {
+            let connection = MockConnection::new(VecDeque::new());
+            Handle::spawn(ConnectionActor::new(
+                own_peer_id,
+                Some(peer_id),
+                connection.clone(),
+                connection,
+                info_hash,
+                other_torrent.clone(),
+                TorrentConfig::default(),
+            ))
+        };
+        let (old, new) = (spawn_connection(), spawn_connection());
+        torrent
+            .add_connection(peer_id, true, None, old.clone())
+            .unwrap();
+        torrent.remove_peer(peer_id).unwrap();
+        torrent
+            .add_connection(peer_id, true, None, new.clone())
+            .unwrap();
+
+        torrent.connection_stopped(peer_id, &old);
+        assert_eq!(torrent.connected_peers(), [peer_id]);
+        torrent.connection_stopped(peer_id, &new);
+        assert_eq!(torrent.connected_peers(), []);
+
+        old.stop().unwrap();
+        new.stop().unwrap();
+        other_torrent.stop().unwrap();
+    }
+
+    #[test]
+    fn banned_peers_are_dropped_and_refused() {
+        let info_hash = InfoHash::new([2; 20]);
+        let torrent = Handle::spawn(TorrentActor::new(PeerId::new([1; 20]), info_hash));
+        let banned_addr = SocketAddr::from(([10, 0, 0, 5], 6881));
+        let other_addr = SocketAddr::from(([10, 1, 0, 5], 6881));
+        let accept = |i: u8, peer_addr| {
+            let handshake = Handshake::new(info_hash, PeerId::new([i; 20]));
+            let connection = MockConnection::new(VecDeque::from([Message::Handshake(handshake)]));
+            torrent
+                .act(move |torrent| {
+                    torrent.accept_peer_connection(
+                        None,
+                        Some(peer_addr),
+                        connection.clone(),
+                        connection,
+                    )
+                })
+                .unwrap();
+        };
+        let connected = || {
+            let mut peers = torrent
+                .ask(|torrent| Ok(torrent.connected_peers()))
+                .unwrap();
+            peers.sort();
+            peers
+        };
+        accept(10, banned_addr);
+        accept(11, other_addr);
+        sleep(Duration::from_millis(100));
+        assert_eq!(connected(), [PeerId::new([10; 20]), PeerId::new([11; 20])]);
+
+        torrent
+            .ask(|torrent| {
+                torrent.ban("10.0.0.0/16".parse()?);
+                Ok(())
+            })
+            .unwrap();
+        assert_eq!(connected(), [PeerId::new([11; 20])]);
+
+        accept(12, SocketAddr::from(([10, 0, 9, 9], 51413)));
+        sleep(Duration::from_millis(100));
+        assert_eq!(connected(), [PeerId::new([11; 20])]);
+
+        torrent
+            .ask(|torrent| {
+                torrent.unban("10.0.0.0/16".parse()?);
+                Ok(())
+            })
+            .unwrap();
+        accept(12, SocketAddr::from(([10, 0, 9, 9], 51413)));
+        sleep(Duration::from_millis(100));
+        assert_eq!(connected(), [PeerId::new([11; 20]), PeerId::new([12; 20])]);
+        torrent.stop().unwrap();
+    }
+
+    #[test]
+    fn exchanged_peers_are_queued_for_dialing() {
+        let own_peer_id = PeerId::new([1; 20]);
+        let info_hash = InfoHash::new([2; 20]);
+        let mut torrent = TorrentActor::new(own_peer_id, info_hash);
+        let pex_peers = PexPeers::default();
+        torrent.set_pex_peers(pex_peers.clone());
+        let other_torrent = Handle::spawn(TorrentActor::new(own_peer_id, info_hash));
+        let connected_addr = SocketAddr::from(([10, 0, 0, 1], 6881));
+        let new_addr = SocketAddr::from(([10, 0, 0, 2], 6881));
+        let banned_addr = SocketAddr::from(([10, 9, 0, 1], 6881));
+        let peer_id = PeerId::new([5; 20]);
+        let connection = MockConnection::new(VecDeque::new());
+        let actor = Handle::spawn(ConnectionActor::new(
+            own_peer_id,
+            Some(peer_id),
+            connection.clone(),
+            connection,
+            info_hash,
+            other_torrent.clone(),
+            TorrentConfig::default(),
+        ));
+        torrent
+            .add_connection(peer_id, true, Some(connected_addr), actor.clone())
+            .unwrap();
+        torrent.ban(banned_addr.ip().into());
+        let payload = Pex {
+            added: vec![connected_addr, new_addr, banned_addr],
+            dropped: vec![],
+        }
+        .to_message(UT_PEX_ID)
+        .payload;
+
+        torrent.pex_received(Pex::from_payload(&payload).unwrap().added);
+        torrent.pex_received(vec![new_addr]);
+
+        assert_eq!(pex_peers.queued(), [new_addr]);
+        drop(torrent);
+        other_torrent.stop().unwrap();
+    }
+
+    #[test]
+    fn stalled_block_is_requested_from_another_peer() {
+        let own_peer_id = PeerId::new([1; 20]);
+        let info_hash = InfoHash::new([2; 20]);
+        let clock = MockClock::new();
+        let config = TorrentConfig {
+            max_pipeline_depth: 1,
+            ..TorrentConfig::default()
+        };
+        let mut torrent =
+            TorrentActor::with_config(own_peer_id, info_hash, config, Arc::new(clock.clone()));
+        torrent.set_piece_layout(BLOCK_SIZE, u64::from(2 * BLOCK_SIZE));
+        let torrent = Handle::spawn(torrent);
+
+        // Both peers unchoke us, get one block each, and then never deliver.
+        let connections: Vec<_> = [10u8, 11]
+            .into_iter()
+            .map(|i| {
+                let handshake = Handshake::new(info_hash, PeerId::new([i; 20]));
+                MockConnection::new(VecDeque::from([
+                    Message::Handshake(handshake),
+                    Message::Unchoke(Unchoke),
+                ]))
+            })
+            .collect();
+        for connection in &connections {
+            let connection = connection.clone();
+            torrent
+                .act(move |torrent| {
+                    torrent.connect_to_peer(None, None, connection.clone(), connection)
+                })
+                .unwrap();
+        }
+        sleep(Duration::from_millis(200));
+
+        clock.advance(config.request_timeout);
+        torrent.act(TorrentActor::tick).unwrap();
+        sleep(Duration::from_millis(200));
+
+        let requests: Vec<_> = connections
+            .iter()
+            .map(|connection| {
+                let sent = connection.sent_messages.lock().unwrap();
+                let Message::Request(first) = sent[1] else {
+                    panic!("expected a request, got {:?}", sent[1]);
+                };
+                assert_eq!(sent[2], Message::Cancel(Cancel::from(first)));
+                let Message::Request(second) = sent[3] else {
+                    panic!("expected a request, got {:?}", sent[3]);
+                };
+                assert_eq!(sent.len(), 4);
+                (first, second)
+            })
+            .collect();
+        // Each peer got the block the other one stalled on.
+        assert_eq!(requests[0].0, requests[1].1);
+        assert_eq!(requests[1].0, requests[0].1);
+
+        torrent.stop().unwrap();
+    }
+
+    #[test]
+    fn peer_with_a_piece_past_the_end_is_dropped() {
+        let own_peer_id = PeerId::new([1; 20]);
+        let info_hash = InfoHash::new([2; 20]);
+        let peer_id = PeerId::new([10; 20]);
+        let mut torrent = TorrentActor::new(own_peer_id, info_hash);
+        torrent.set_piece_layout(BLOCK_SIZE, u64::from(4 * BLOCK_SIZE));
+        let torrent = Handle::spawn(torrent);
+        let connection = MockConnection::new(VecDeque::from([Message::Handshake(Handshake::new(
+            info_hash, peer_id,
+        ))]));
+        torrent
+            .act({
+                let connection = connection.clone();
+                move |torrent| torrent.connect_to_peer(None, None, connection.clone(), connection)
+            })
+            .unwrap();
+        sleep(Duration::from_millis(200));
+
+        let still_connected = torrent
+            .ask(move |torrent| {
+                torrent.peer_has_piece(peer_id, 3)?;
+                Ok(torrent.has_connection(peer_id))
+            })
+            .unwrap();
+        assert!(still_connected);
+        let still_connected = torrent
+            .ask(move |torrent| {
+                torrent.peer_has_piece(peer_id, u32::MAX)?;
+                Ok(torrent.has_connection(peer_id))
+            })
+            .unwrap();
+        assert!(!still_connected);
+        torrent.stop().unwrap();
+    }
+
+    #[test]
+    fn haves_are_batched_and_skip_pieces_the_peer_has() {
+        let own_peer_id = PeerId::new([1; 20]);
+        let info_hash = InfoHash::new([2; 20]);
+        let peer_id = PeerId::new([10; 20]);
+        let mut torrent = TorrentActor::new(own_peer_id, info_hash);
+        torrent.set_piece_layout(BLOCK_SIZE, u64::from(4 * BLOCK_SIZE));
+        let torrent = Handle::spawn(torrent);
+        // The peer already has piece 1.
+        let connection = MockConnection::new(VecDeque::from([
+            Message::Handshake(Handshake::new(info_hash, peer_id)),
+            Message::Bitfield(Bitfield::new(vec![0b0100_0000])),
+        ]));
+        torrent
+            .act({
+                let connection = connection.clone();
+                move |torrent| torrent.connect_to_peer(None, None, connection.clone(), connection)
+            })
+            .unwrap();
+        sleep(Duration::from_millis(200));
+
+        for index in 0..3 {
+            let request = Request::new(index, 0, BLOCK_SIZE);
+            let block = vec![index as u8; BLOCK_SIZE as usize];
+            torrent
+                .act(move |torrent| {
+                    torrent.block_received(peer_id, request, block)?;
+                    Ok(Outcome::Continue)
+                })
+                .unwrap();
+        }
+        // Nothing is sent until the next tick.
+        sleep(Duration::from_millis(100));
+        let haves = || -> Vec<_> {
+            let sent = connection.sent_messages.lock().unwrap();
+            sent.iter()
+                .filter(|message| matches!(message, Message::Have(_)))
+                .cloned()
+                .collect()
+        };
+        assert_eq!(haves(), []);
+
+        torrent
+            .act(|torrent| {
+                torrent.send_haves()?;
+                Ok(Outcome::Continue)
+            })
+            .unwrap();
+        sleep(Duration::from_millis(100));
+        assert_eq!(
+            haves(),
+            [Message::Have(Have::new(0)), Message::Have(Have::new(2))]
+        );
+
+        torrent.stop().unwrap();
+    }
+
+    #[test]
+    fn saved_state_restores_totals_and_pieces() {
+        let path = std::env::temp_dir().join(format!("torrent-poc-{}-resume", std::process::id()));
+        let (own_peer_id, peer_id) = (PeerId::new([1; 20]), PeerId::new([10; 20]));
+        let info_hash = InfoHash::new([2; 20]);
+        let mut torrent = TorrentActor::new(own_peer_id, info_hash);
+        torrent.set_piece_layout(BLOCK_SIZE, u64::from(4 * BLOCK_SIZE));
+        torrent.piece_selector.complete_piece(1);
+        torrent.piece_selector.complete_piece(3);
+        torrent.record_download(peer_id, 1000);
+        torrent.record_upload(peer_id, 500);
+
+        torrent.save_state(&path).unwrap();
+        let mut restarted = TorrentActor::new(own_peer_id, info_hash);
+        restarted.set_piece_layout(BLOCK_SIZE, u64::from(4 * BLOCK_SIZE));
+        restarted.load_state(&path).unwrap();
+        restarted.record_download(peer_id, 24);
+        fs::remove_file(&path).unwrap();
+
+        let stats = restarted.stats();
+        assert_eq!((stats.downloaded, stats.uploaded), (24, 0));
+        assert_eq!(
+            (stats.all_time_downloaded, stats.all_time_uploaded),
+            (1024, 500)
+        );
+        assert_eq!(restarted.own_pieces(), Bitfield::new(vec![0b0101_0000]));
+        assert_eq!(restarted.pending_haves, BTreeSet::from([1, 3]));
+        // Another torrent's resume file doesn't fit.
+        torrent.save_state(&path).unwrap();
+        let mut other = TorrentActor::new(own_peer_id, InfoHash::new([3; 20]));
+        let _ = other.load_state(&path).unwrap_err();
+        fs::remove_file(&path).unwrap();
+    }
+
+    /// A store that counts how often it's flushed.
+    #[derive(Debug, Default)]
+    struct FlushCountingStore(Arc<AtomicUsize>);
+
+    impl PieceStore for FlushCountingStore {
+        fn write_block(&mut self, _index: u32, _begin: u32, _block: &[u8]) -> Result<()> {
+            Ok(())
+        }
+
+        fn read_block(&self, _index: u32, _begin: u32, _length: u32) -> Result<Vec<u8>> {
+            bail!("Nothing stored")
+        }
+
+        fn flush(&mut self) -> Result<()> {
+            self.0.fetch_add(1, Ordering::SeqCst);
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn shutdown_flushes_the_store_and_saves_the_state() {
+        let path = std::env::temp_dir().join(format!(
+            "torrent-poc-{}-shutdown-resume",
+            std::process::id()
+        ));
+        let (own_peer_id, info_hash) = (PeerId::new([1; 20]), InfoHash::new([2; 20]));
+        let mut torrent = TorrentActor::new(own_peer_id, info_hash);
+        torrent.set_piece_layout(BLOCK_SIZE, u64::from(4 * BLOCK_SIZE));
+        let flushes = Arc::new(AtomicUsize::new(0));
+        torrent.set_piece_store(Box::new(FlushCountingStore(flushes.clone())));
+        torrent.save_state(&path).unwrap();
+        torrent.piece_selector.complete_piece(2);
+
+        let _ = torrent.shutdown().unwrap();
+        assert_eq!(flushes.load(Ordering::SeqCst), 1);
+        let mut restarted = TorrentActor::new(own_peer_id, info_hash);
+        restarted.set_piece_layout(BLOCK_SIZE, u64::from(4 * BLOCK_SIZE));
+        restarted.load_state(&path).unwrap();
+        fs::remove_file(&path).unwrap();
+        assert_eq!(restarted.own_pieces(), Bitfield::new(vec![0b0010_0000]));
+    }
+
+    #[test]
+    fn stats_add_up_every_connection() {
+        let own_peer_id = PeerId::new([1; 20]);
+        let info_hash = InfoHash::new([2; 20]);
+        let clock = MockClock::new();
+        let mut torrent = TorrentActor::with_config(
+            own_peer_id,
+            info_hash,
+            TorrentConfig::default(),
+            Arc::new(clock.clone()),
+        );
+        torrent.set_piece_layout(BLOCK_SIZE, u64::from(4 * BLOCK_SIZE));
+        let other_torrent = Handle::spawn(TorrentActor::new(own_peer_id, info_hash));
+        for (i, downloaded, uploaded) in [(10u8, 1000, 0), (11, 3000, 500)] {
+            let peer_id = PeerId::new([i; 20]);
+            let connection = MockConnection::new(VecDeque::new());
+            let actor = Handle::spawn(ConnectionActor::new(
+                own_peer_id,
+                Some(peer_id),
+                connection.clone(),
+                connection,
+                info_hash,
+                other_torrent.clone(),
+                TorrentConfig::default(),
+            ));
+            torrent.add_connection(peer_id, true, None, actor).unwrap();
+            torrent.record_download(peer_id, downloaded);
+            torrent.record_upload(peer_id, uploaded);
+        }
+        torrent.piece_selector.complete_piece(2);
+
+        let stats = torrent.stats();
+
+        assert_eq!(stats.peers, 2);
+        assert_eq!(stats.downloaded, 4000);
+        assert_eq!(stats.uploaded, 500);
+        assert!((stats.download_rate - 4000.0 / RATE_WINDOW.as_secs_f64()).abs() < 1e-9);
+        assert!((stats.upload_rate - 500.0 / RATE_WINDOW.as_secs_f64()).abs() < 1e-9);
+        assert_eq!((stats.pieces_complete, stats.piece_count), (1, 4));
+        assert!(!stats.seeding);
+
+        // The totals outlive the connections, the rates don't.
+        torrent.remove_connection(PeerId::new([11; 20]));
+        clock.advance(RATE_WINDOW * 2);
+        let stats = torrent.stats();
+        assert_eq!(
+            (stats.peers, stats.downloaded, stats.uploaded),
+            (1, 4000, 500)
+        );
+        assert_eq!(stats.download_rate, 0.0);
+        other_torrent.stop().unwrap();
+    }
+
+    #[test]
+    fn slow_peer_is_snubbed_until_it_speeds_up() {
+        let own_peer_id = PeerId::new([1; 20]);
+        let info_hash = InfoHash::new([2; 20]);
+        let peer_id = PeerId::new([10; 20]);
+        let clock = MockClock::new();
+        let config = TorrentConfig {
+            max_pipeline_depth: 2,
+            snub_threshold: 1024,
+            snub_window: Duration::from_secs(10),
+            ..TorrentConfig::default()
+        };
+        let mut torrent =
+            TorrentActor::with_config(own_peer_id, info_hash, config, Arc::new(clock.clone()));
+        torrent.set_piece_layout(BLOCK_SIZE, u64::from(8 * BLOCK_SIZE));
+        let torrent = Handle::spawn(torrent);
+        let connection = MockConnection::new(VecDeque::from([
+            Message::Handshake(Handshake::new(info_hash, peer_id)),
+            Message::Unchoke(Unchoke),
+        ]));
+        torrent
+            .act({
+                let connection = connection.clone();
+                move |torrent| torrent.connect_to_peer(None, None, connection.clone(), connection)
+            })
+            .unwrap();
+        sleep(Duration::from_millis(200));
+        let is_snubbed = || {
+            torrent
+                .ask(move |torrent| Ok(torrent.connections[&peer_id].snubbed))
+                .unwrap()
+        };
+        let advance = |by: Duration| {
+            clock.advance(by);
+            torrent.act(TorrentActor::tick).unwrap();
+            sleep(Duration::from_millis(50));
+        };
+        // The handshake and a full pipeline of requests.
+        assert_eq!(connection.sent_messages.lock().unwrap().len(), 3);
+
+        // A trickle of data, but the peer hasn't had the whole window to prove itself yet.
+        torrent
+            .act(move |torrent| {
+                torrent.record_download(peer_id, 1000);
+                Ok(Outcome::Continue)
+            })
+            .unwrap();
+        advance(Duration::from_secs(5));
+        assert!(!is_snubbed());
+
+        advance(Duration::from_secs(5));
+        assert!(is_snubbed());
+        // Once its requests time out, it only gets one block instead of a full pipeline.
+        advance(config.request_timeout);
+        assert!(is_snubbed());
+        {
+            let sent = connection.sent_messages.lock().unwrap();
+            assert!(
+                matches!(
+                    sent[3..],
+                    [Message::Cancel(_), Message::Cancel(_), Message::Request(_)]
+                ),
+                "{sent:?}"
+            );
+        }
+
+        torrent
+            .act(move |torrent| {
+                torrent.record_download(peer_id, 20 * 1024);
+                Ok(Outcome::Continue)
+            })
+            .unwrap();
+        advance(Duration::from_secs(1));
+        assert!(!is_snubbed());
+
+        torrent.stop().unwrap();
+    }
+
+    #[test]
+    fn paused_torrent_requests_nothing_until_resumed() {
+        let info_hash = InfoHash::new([2; 20]);
+        let mut torrent = TorrentActor::new(PeerId::new([1; 20]), info_hash);
+        torrent.set_piece_layout(BLOCK_SIZE, u64::from(2 * BLOCK_SIZE));
+        let torrent = Handle::spawn(torrent);
+        let connection = MockConnection::new(VecDeque::from([
+            Message::Handshake(Handshake::new(info_hash, PeerId::new([10; 20]))),
+            Message::KeepAlive(KeepAlive),
+            Message::Unchoke(Unchoke),
+        ]));
+        let requests = || {
+            connection
+                .sent_messages
+                .lock()
+                .unwrap()
+                .iter()
+                .filter(|message| matches!(message, Message::Request(_)))
+                .count()
+        };
+
+        torrent
+            .act(|torrent| {
+                torrent.pause()?;
+                Ok(Outcome::Continue)
+            })
+            .unwrap();
+        torrent
+            .act({
+                let connection = connection.clone();
+                move |torrent| torrent.connect_to_peer(None, None, connection.clone(), connection)
+            })
+            .unwrap();
+        sleep(Duration::from_millis(200));
+
+        assert_eq!(torrent.ask(|torrent| Ok(torrent.peer_count())).unwrap(), 1);
+        assert_eq!(requests(), 0);
+
+        torrent
+            .act(|torrent| {
+                torrent.resume()?;
+                Ok(Outcome::Continue)
+            })
+            .unwrap();
+        sleep(Duration::from_millis(200));
+
+        assert_eq!(requests(), 2);
+        torrent.stop().unwrap();
+    }
+
+    #[test]
+    fn seed_has_all_and_is_never_interested() {
+        let own_peer_id = PeerId::new([1; 20]);
+        let content = vec![7; 40];
+        let info = Info::from_content("test", 16, &content);
+        let info_hash = info.info_hash();
+        let mut torrent = TorrentActor::new(own_peer_id, info_hash);
+        torrent.load_content(info.to_bytes(), &content).unwrap();
+        let torrent = Handle::spawn(torrent);
+
+        let mut handshake = Handshake::new(info_hash, PeerId::new([3; 20]));
+        handshake.reserved[FAST_EXTENSION_BIT.0] |= FAST_EXTENSION_BIT.1;
+        let fast_connection = MockConnection::new(VecDeque::from([
+            Message::Handshake(handshake),
+            Message::Bitfield(Bitfield::new(vec![0b1110_0000])),
+            Message::Unchoke(Unchoke),
+        ]));
+        let connection = MockConnection::new(VecDeque::from([
+            Message::Handshake(Handshake::new(info_hash, PeerId::new([4; 20]))),
+            Message::HaveNone(HaveNone),
+            Message::Unchoke(Unchoke),
+        ]));
+        for connection in [fast_connection.clone(), connection.clone()] {
+            torrent
+                .act(move |torrent| {
+                    torrent.connect_to_peer(None, None, connection.clone(), connection)
+                })
+                .unwrap();
+        }
+        sleep(Duration::from_millis(200));
+        torrent.act(TorrentActor::tick).unwrap();
+        sleep(Duration::from_millis(100));
+
+        for (connection, have) in [
+            (fast_connection, Message::HaveAll(HaveAll)),
+            (
+                connection,
+                Message::Bitfield(Bitfield::new(vec![0b1110_0000])),
+            ),
+        ] {
+            let sent = connection.sent_messages.lock().unwrap();
+            assert_eq!(sent[1], have);
+            assert!(!sent
+                .iter()
+                .any(|message| matches!(message, Message::Interested(_) | Message::Request(_))));
+        }
+
+        torrent.stop().unwrap();
+    }
+
+    #[test]
+    fn corrupt_piece_is_downloaded_again() {
+        let content = vec![7; 100];
+        let info = Info::from_content("test", 64, &content);
+        let peer_id = PeerId::new([3; 20]);
+        let mut torrent = TorrentActor::new(PeerId::new([1; 20]), info.info_hash());
+        torrent.set_piece_layout(info.piece_length, info.length);
+        torrent.metainfo = Some((info.to_bytes(), info));
+        let events = torrent.subscribe();
+
+        let request = Request::new(1, 0, 36);
+        torrent
+            .block_received(peer_id, request, vec![8; 36])
+            .unwrap();
+        assert!(torrent.read_piece(1).is_err());
+        assert_eq!(
+            torrent.piece_selector.assign(peer_id, Instant::now()),
+            Some(Request::new(0, 0, 64))
+        );
+        assert_eq!(
+            torrent.piece_selector.assign(peer_id, Instant::now()),
+            Some(request)
+        );
+
+        torrent
+            .block_received(peer_id, request, vec![7; 36])
+            .unwrap();
+        assert_eq!(torrent.read_piece(1).unwrap(), vec![7; 36]);
+        let completed: Vec<_> = events
+            .try_iter()
+            .filter(|event| matches!(event, TorrentEvent::PieceCompleted(_)))
+            .collect();
+        assert_eq!(completed, [TorrentEvent::PieceCompleted(1)]);
+    }
+
+    /// A store whose writes fail, like one on a full disk.
+    #[derive(Debug)]
+    struct FullStore;
+
+    impl PieceStore for FullStore {
+        fn write_block(&mut self, _index: u32, _begin: u32, _block: &[u8]) -> Result<()> {
+            bail!("No space left on device")
+        }
+
+        fn read_block(&self, _index: u32, _begin: u32, _length: u32) -> Result<Vec<u8>> {
+            bail!("Nothing stored")
+        }
+    }
+
+    #[test]
+    fn block_that_fails_to_store_is_downloaded_again() {
+        let info = Info::from_content("test", 64, &[7; 100]);
+        let peer_id = PeerId::new([3; 20]);
+        let mut torrent = TorrentActor::new(PeerId::new([1; 20]), info.info_hash());
+        torrent.set_piece_layout(info.piece_length, info.length);
+        torrent.metainfo = Some((info.to_bytes(), info));
+        torrent.set_piece_store(Box::new(FullStore));
+
+        let request = Request::new(1, 0, 36);
+        torrent
+            .block_received(peer_id, request, vec![7; 36])
+            .unwrap();
+        assert!(!torrent.piece_selector.is_piece_complete(1));
+        assert_eq!(
+            torrent.piece_selector.assign(peer_id, Instant::now()),
+            Some(request)
+        );
+    }
+
+    #[test]
+    fn completion_is_announced_once_the_last_piece_verifies() {
+        let content = vec![7; 100];
+        let info = Info::from_content("test", 64, &content);
+        let peer_id = PeerId::new([3; 20]);
+        let mut torrent = TorrentActor::new(PeerId::new([1; 20]), info.info_hash());
+        torrent.set_piece_layout(info.piece_length, info.length);
+        torrent.metainfo = Some((info.to_bytes(), info));
+        let announcers = Announcers::default();
+        let (announces, _finished) = announcers.add();
+        torrent.set_announcers(announcers);
+
+        torrent
+            .block_received(peer_id, Request::new(0, 0, 64), vec![7; 64])
+            .unwrap();
+        assert_eq!(announces.try_iter().collect::<Vec<_>>(), []);
+        torrent
+            .block_received(peer_id, Request::new(1, 0, 36), vec![7; 36])
+            .unwrap();
+        // The same block again isn't another completion.
+        torrent
+            .block_received(peer_id, Request::new(1, 0, 36), vec![7; 36])
+            .unwrap();
+        assert_eq!(
+            announces.try_iter().collect::<Vec<_>>(),
+            [AnnounceEvent::Completed]
+        );
+    }
+
+    #[test]
+    fn existing_files_are_verified_in_the_background() {
+        let content: Vec<u8> = (0..250).collect();
+        let info = Info::from_content("test", 64, &content);
+        let mut torrent = TorrentActor::new(PeerId::new([1; 20]), info.info_hash());
+        torrent.set_metadata(info.to_bytes()).unwrap();
+        // The last piece is only 58 bytes long, and the one before it is corrupt.
+        let mut store = MemoryPieceStore::default();
+        store.write_block(0, 0, &content[..64]).unwrap();
+        store.write_block(2, 0, &[0; 64]).unwrap();
+        store.write_block(3, 0, &content[192..]).unwrap();
+        torrent.set_piece_store(Box::new(store));
+        let events = torrent.subscribe();
+        let torrent = Handle::spawn(torrent);
+
+        torrent.ask(TorrentActor::verify_existing_files).unwrap();
+        let events: Vec<_> = events
+            .iter()
+            .take_while(|event| !matches!(event, TorrentEvent::Progress(_)))
+            .collect();
+
+        assert_eq!(
+            events,
+            [0.25, 0.5, 0.75, 1.0].map(TorrentEvent::VerifyProgress)
+        );
+        let pieces = torrent.ask(|torrent| Ok(torrent.own_pieces())).unwrap();
+        assert_eq!(pieces, Bitfield::new(vec![0b1001_0000]));
+        let haves = torrent
+            .ask(|torrent| Ok(torrent.pending_haves.clone()))
+            .unwrap();
+        assert_eq!(haves, BTreeSet::from([0, 3]));
+        assert_eq!(
+            torrent.ask(|torrent| torrent.read_piece(3)).unwrap(),
+            &content[192..]
+        );
+        torrent.stop().unwrap();
+    }
+
+    #[test]
+    fn blocks_arriving_while_verifying_are_requested_again() {
+        let content = vec![7; 64];
+        let info = Info::from_content("test", 64, &content);
+        let peer_id = PeerId::new([3; 20]);
+        let mut torrent = TorrentActor::new(PeerId::new([1; 20]), info.info_hash());
+        torrent.set_metadata(info.to_bytes()).unwrap();
+        let request = torrent
+            .piece_selector
+            .assign(peer_id, Instant::now())
+            .unwrap();
+
+        torrent.verifying = true;
+        torrent.block_received(peer_id, request, content).unwrap();
+        torrent.verifying = false;
+
+        assert!(!torrent.piece_selector.is_piece_complete(0));
+        assert_eq!(
+            torrent.piece_selector.assign(peer_id, Instant::now()),
+            Some(request)
+        );
+    }
+
+    /// A store whose reads panic, like one on a disk that went away.
+    #[derive(Debug)]
+    struct PanickingStore;
+
+    impl PieceStore for PanickingStore {
+        fn write_block(&mut self, _index: u32, _begin: u32, _block: &[u8]) -> Result<()> {
+            Ok(())
+        }
+
+        fn read_block(&self, _index: u32, _begin: u32, _length: u32) -> Result<Vec<u8>> {
+            panic!("disk went away")
+        }
+    }
+
+    #[test]
+    fn verifying_ends_when_the_store_panics() {
+        let info = Info::from_content("test", 64, &[7; 64]);
+        let mut torrent = TorrentActor::new(PeerId::new([1; 20]), info.info_hash());
+        torrent.set_metadata(info.to_bytes()).unwrap();
+        torrent.set_piece_store(Box::new(PanickingStore));
+        let events = torrent.subscribe();
+        let torrent = Handle::spawn(torrent);
+
+        torrent.ask(TorrentActor::verify_existing_files).unwrap();
+        assert!(events
+            .iter()
+            .any(|event| matches!(event, TorrentEvent::Progress(_))));
+
+        assert!(torrent
+            .ask(|torrent| Ok(torrent.is_transferring()))
+            .unwrap());
+        torrent.stop().unwrap();
     }
 }
@@ -0,0 +1,863 @@
+use std::fmt::{Display, Formatter, Write as _};
+use std::io::{ErrorKind, Read, Write};
+use std::net::{
+    Ipv4Addr, Ipv6Addr, SocketAddr, SocketAddrV4, SocketAddrV6, TcpStream, ToSocketAddrs, UdpSocket,
+};
+use std::time::{Duration, Instant};
+
+use eyre::{bail, eyre, OptionExt, Result};
+use rand::Rng;
+
+use crate::bencode::BValue;
+use crate::log::{info, warn};
+use crate::{DiscoveredPeers, InfoHash, PeerId, PeerSource, SansIo, TorrentStats};
+
+/// How long to wait for a tracker to respond.
+const TRACKER_TIMEOUT: Duration = Duration::from_secs(15);
+/// The magic number that identifies a UDP tracker connect request.
+const UDP_PROTOCOL_ID: u64 = 0x0417_2710_1980;
+const UDP_ACTION_CONNECT: u32 = 0;
+const UDP_ACTION_ANNOUNCE: u32 = 1;
+const UDP_ACTION_ERROR: u32 = 3;
+/// How many times to send a UDP request before giving up, as packets can get lost.
+const UDP_ATTEMPTS: u32 = 3;
+/// The biggest HTTP tracker response we read, far more than any list of peers needs.
+const MAX_HTTP_RESPONSE_SIZE: u64 = 1024 * 1024;
+/// The shortest interval we announce on, whatever the tracker asks for, so that a broken
+/// tracker answering with an interval of 0 isn't announced to over and over.
+const MIN_ANNOUNCE_INTERVAL: Duration = Duration::from_secs(60);
+
+/// Why we're announcing, other than to get more peers.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum AnnounceEvent {
+    /// A regular announce, on the tracker's interval.
+    #[default]
+    None,
+    /// We just started downloading the torrent.
+    Started,
+    /// We just finished downloading the torrent.
+    Completed,
+    /// We're stopping, so the tracker can stop handing us out.
+    Stopped,
+}
+
+impl AnnounceEvent {
+    /// The `event` parameter of an HTTP announce, which is left out for regular announces.
+    fn query_value(self) -> Option<&'static str> {
+        match self {
+            AnnounceEvent::None => None,
+            AnnounceEvent::Started => Some("started"),
+            AnnounceEvent::Completed => Some("completed"),
+            AnnounceEvent::Stopped => Some("stopped"),
+        }
+    }
+
+    /// The event field of a UDP announce, which numbers them differently.
+    fn udp_code(self) -> u32 {
+        match self {
+            AnnounceEvent::None => 0,
+            AnnounceEvent::Completed => 1,
+            AnnounceEvent::Started => 2,
+            AnnounceEvent::Stopped => 3,
+        }
+    }
+}
+
+/// What we tell an HTTP tracker when announcing ourselves, in exchange for a list of peers.
+///
+/// This only builds the request and parses the response, [HttpTracker] and [UdpTracker]
+/// take care of actually sending it.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct AnnounceRequest {
+    /// The torrent we want peers for.
+    pub info_hash: InfoHash,
+    /// Our own peer ID.
+    pub peer_id: PeerId,
+    /// The port we accept incoming connections on.
+    pub port: u16,
+    /// Bytes uploaded so far.
+    pub uploaded: u64,
+    /// Bytes downloaded so far.
+    pub downloaded: u64,
+    /// Bytes left until we have the whole torrent.
+    pub left: u64,
+    /// Why we're announcing. [HttpTracker] and [UdpTracker] fill this in themselves, the
+    /// first announce to them is [Started](AnnounceEvent::Started).
+    pub event: AnnounceEvent,
+}
+
+impl AnnounceRequest {
+    /// Report what `stats` say we've transferred, and have left, from now on.
+    pub fn update_stats(&mut self, stats: &TorrentStats) {
+        self.uploaded = stats.uploaded;
+        self.downloaded = stats.downloaded;
+        if let Some(left) = stats.left {
+            self.left = left;
+        }
+    }
+
+    /// The URL to `GET` for this announce, given the tracker's announce URL.
+    ///
+    /// Always asks for the compact peer format, which is the only one most trackers serve.
+    #[must_use]
+    pub fn url(&self, announce_url: &str) -> String {
+        let separator = if announce_url.contains('?') { '&' } else { '?' };
+        let mut url = format!("{announce_url}{separator}info_hash=");
+        percent_encode(self.info_hash.as_bytes(), &mut url);
+        url.push_str("&peer_id=");
+        percent_encode(self.peer_id.as_bytes(), &mut url);
+        write!(
+            url,
+            "&port={}&uploaded={}&downloaded={}&left={}&compact=1",
+            self.port, self.uploaded, self.downloaded, self.left
+        )
+        .expect("writing to a string to succeed");
+        if let Some(event) = self.event.query_value() {
+            write!(url, "&event={event}").expect("writing to a string to succeed");
+        }
+        url
+    }
+
+    /// The announce packet for a UDP tracker, once it has handed out a `connection_id`.
+    fn udp_packet(&self, connection_id: u64, transaction_id: u32) -> Vec<u8> {
+        let mut packet = Vec::with_capacity(98);
+        packet.extend(connection_id.to_be_bytes());
+        packet.extend(UDP_ACTION_ANNOUNCE.to_be_bytes());
+        packet.extend(transaction_id.to_be_bytes());
+        self.info_hash.encode_into(&mut packet);
+        self.peer_id.encode_into(&mut packet);
+        packet.extend(self.downloaded.to_be_bytes());
+        packet.extend(self.left.to_be_bytes());
+        packet.extend(self.uploaded.to_be_bytes());
+        packet.extend(self.event.udp_code().to_be_bytes());
+        // The tracker figures out our IP, no key, and the default number of peers.
+        packet.extend(0u32.to_be_bytes());
+        packet.extend(0u32.to_be_bytes());
+        packet.extend((-1i32).to_be_bytes());
+        packet.extend(self.port.to_be_bytes());
+        packet
+    }
+}
+
+/// Percent-encode everything but the characters that are unreserved in a URL.
+fn percent_encode(bytes: &[u8], url: &mut String) {
+    for byte in bytes {
+        if byte.is_ascii_alphanumeric() || b"-._~".contains(byte) {
+            url.push(char::from(*byte));
+        } else {
+            write!(url, "%{byte:02X}").expect("writing to a string to succeed");
+        }
+    }
+}
+
+/// A tracker that responded, but refused the announce.
+///
+/// Converts into an [eyre::Report] like any other error, and can be recovered from one
+/// with [eyre::Report::downcast_ref].
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum TrackerError {
+    /// The tracker's `failure reason`, e.g. that it doesn't track the torrent.
+    Failure(String),
+}
+
+impl Display for TrackerError {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        match self {
+            TrackerError::Failure(reason) => write!(f, "Tracker refused announce: {reason}"),
+        }
+    }
+}
+
+impl std::error::Error for TrackerError {}
+
+/// A tracker's reply to an [AnnounceRequest].
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct AnnounceResponse {
+    /// How long to wait before announcing again.
+    pub interval: Duration,
+    /// How long to wait at least before announcing again, even when asked to right away.
+    pub min_interval: Option<Duration>,
+    /// Peers of the torrent, from both the IPv4 `peers` and the IPv6 `peers6` lists.
+    pub peers: Vec<SocketAddr>,
+    /// The tracker's `warning message`, about something that didn't stop it from answering.
+    pub warning: Option<String>,
+}
+
+impl AnnounceResponse {
+    /// Parse the bencoded body of a tracker's response.
+    pub fn from_bytes(bytes: &[u8]) -> Result<Self> {
+        let (_, value) =
+            BValue::decode(bytes).map_err(|e| eyre!("Invalid tracker response: {e:?}"))?;
+        if let Some(reason) = value.get(b"failure reason").and_then(BValue::as_bytes) {
+            let reason = String::from_utf8_lossy(reason).into_owned();
+            return Err(TrackerError::Failure(reason).into());
+        }
+        let warning = value
+            .get(b"warning message")
+            .and_then(BValue::as_bytes)
+            .map(|warning| String::from_utf8_lossy(warning).into_owned());
+
+        let interval = value
+            .get(b"interval")
+            .and_then(BValue::as_integer)
+            .ok_or_eyre("Tracker response is missing 'interval'")?;
+        let interval = u64::try_from(interval)
+            .map(Duration::from_secs)
+            .map_err(|_| eyre!("Invalid interval {interval}"))?;
+        let min_interval = value
+            .get(b"min interval")
+            .and_then(BValue::as_integer)
+            .map(|min_interval| {
+                u64::try_from(min_interval)
+                    .map(Duration::from_secs)
+                    .map_err(|_| eyre!("Invalid min interval {min_interval}"))
+            })
+            .transpose()?;
+
+        let mut peers = Vec::new();
+        if let Some(v4) = value.get(b"peers") {
+            let v4 = v4
+                .as_bytes()
+                .ok_or_eyre("Only compact 'peers' are supported")?;
+            peers.extend(compact_peers_v4(v4)?);
+        }
+        if let Some(v6) = value.get(b"peers6") {
+            let v6 = v6.as_bytes().ok_or_eyre("'peers6' must be compact")?;
+            peers.extend(compact_peers_v6(v6)?);
+        }
+
+        Ok(Self {
+            interval,
+            min_interval,
+            peers,
+            warning,
+        })
+    }
+
+    /// Parse the body of a UDP tracker's announce response, the part after the action and
+    /// transaction ID. The peers are IPv6 if we talked to the tracker over IPv6.
+    fn from_udp(bytes: &[u8], ipv6: bool) -> Result<Self> {
+        let (header, peers) = bytes
+            .split_at_checked(12)
+            .ok_or_eyre("UDP tracker response is too short")?;
+        // followed by the number of leechers and seeders, which we don't need
+        let interval = u32::from_be_bytes(header[..4].try_into().expect("header to be 12 bytes"));
+        let peers = if ipv6 {
+            compact_peers_v6(peers)?
+        } else {
+            compact_peers_v4(peers)?
+        };
+        Ok(Self {
+            interval: Duration::from_secs(interval.into()),
+            min_interval: None,
+            peers,
+            warning: None,
+        })
+    }
+}
+
+impl From<AnnounceResponse> for DiscoveredPeers {
+    fn from(response: AnnounceResponse) -> Self {
+        let next_discovery = response
+            .interval
+            .max(response.min_interval.unwrap_or_default())
+            .max(MIN_ANNOUNCE_INTERVAL);
+        Self {
+            peers: response.peers,
+            next_discovery: Some(next_discovery),
+        }
+    }
+}
+
+/// Keeps track of a tracker's min interval, so that announces asked for with
+/// [PeerSource::discover_now] don't come faster than it wants them to.
+#[derive(Debug, Clone, Default)]
+struct MinInterval {
+    min_interval: Option<Duration>,
+    last_announce: Option<Instant>,
+}
+
+impl MinInterval {
+    /// Whether an announce for `event` has to wait. Completing and stopping never do, as the
+    /// tracker wants to know about those.
+    fn too_soon(&self, event: AnnounceEvent) -> bool {
+        matches!(event, AnnounceEvent::None | AnnounceEvent::Started)
+            && self
+                .min_interval
+                .zip(self.last_announce)
+                .is_some_and(|(min_interval, last)| last.elapsed() < min_interval)
+    }
+
+    /// What a regular announce tells the tracker.
+    fn regular_event(&self) -> AnnounceEvent {
+        if self.last_announce.is_none() {
+            AnnounceEvent::Started
+        } else {
+            AnnounceEvent::None
+        }
+    }
+
+    fn announced(&mut self, response: AnnounceResponse) -> DiscoveredPeers {
+        self.min_interval = response.min_interval;
+        self.last_announce = Some(Instant::now());
+        response.into()
+    }
+}
+
+/// A [PeerSource] that announces to an HTTP tracker.
+#[derive(Debug, Clone)]
+pub struct HttpTracker {
+    announce_url: String,
+    request: AnnounceRequest,
+    min_interval: MinInterval,
+}
+
+impl HttpTracker {
+    /// Announce `request` to the tracker at `announce_url`, which has to be `http://`.
+    #[must_use]
+    pub fn new(announce_url: impl Into<String>, request: AnnounceRequest) -> Self {
+        Self {
+            announce_url: announce_url.into(),
+            request,
+            min_interval: MinInterval::default(),
+        }
+    }
+
+    fn announce(&self, request: &AnnounceRequest) -> Result<AnnounceResponse> {
+        let body = http_get(&request.url(&self.announce_url))?;
+        let response = AnnounceResponse::from_bytes(&body)?;
+        if let Some(warning) = &response.warning {
+            warn!("Tracker {} warns: {warning}", self.announce_url);
+        }
+        Ok(response)
+    }
+}
+
+impl PeerSource for HttpTracker {
+    fn discover(&mut self) -> Result<DiscoveredPeers> {
+        let request = AnnounceRequest {
+            event: self.min_interval.regular_event(),
+            ..self.request.clone()
+        };
+        let response = self.announce(&request)?;
+        Ok(self.min_interval.announced(response))
+    }
+
+    fn discover_now(&mut self, event: AnnounceEvent) -> Result<Option<DiscoveredPeers>> {
+        if self.min_interval.too_soon(event) {
+            info!(
+                "Not announcing to {} within its min interval",
+                self.announce_url
+            );
+            return Ok(None);
+        }
+        let request = AnnounceRequest {
+            event,
+            ..self.request.clone()
+        };
+        let response = self.announce(&request)?;
+        Ok(Some(self.min_interval.announced(response)))
+    }
+
+    fn update_stats(&mut self, stats: &TorrentStats) {
+        self.request.update_stats(stats);
+    }
+}
+
+/// A bare-bones HTTP `GET`, which is all that trackers need. Asking for HTTP/1.0 keeps the
+/// response simple: no chunked encoding, and the body ends when the connection closes.
+fn http_get(url: &str) -> Result<Vec<u8>> {
+    let rest = url
+        .strip_prefix("http://")
+        .ok_or_eyre("Only http:// trackers are supported")?;
+    let (host, path) = rest.split_at(rest.find('/').unwrap_or(rest.len()));
+    let path = if path.is_empty() { "/" } else { path };
+    let has_port = host
+        .rsplit_once(':')
+        .is_some_and(|(_, port)| port.parse::<u16>().is_ok());
+    let addr = if has_port {
+        host.to_string()
+    } else {
+        format!("{host}:80")
+    };
+    let addr = addr
+        .to_socket_addrs()?
+        .next()
+        .ok_or_else(|| eyre!("Tracker host {host} not found"))?;
+
+    let mut stream = TcpStream::connect_timeout(&addr, TRACKER_TIMEOUT)?;
+    stream.set_read_timeout(Some(TRACKER_TIMEOUT))?;
+    // Written in one go, as the tracker might respond as soon as it has the request line.
+    let request = format!("GET {path} HTTP/1.0\r\nHost: {host}\r\nConnection: close\r\n\r\n");
+    stream.write_all(request.as_bytes())?;
+    let mut response = Vec::new();
+    // One byte more than allowed, to tell a response that's too big from one that just fits.
+    stream
+        .take(MAX_HTTP_RESPONSE_SIZE + 1)
+        .read_to_end(&mut response)?;
+    if response.len() as u64 > MAX_HTTP_RESPONSE_SIZE {
+        bail!("Tracker response is bigger than {MAX_HTTP_RESPONSE_SIZE} bytes");
+    }
+
+    let header_end = response
+        .windows(4)
+        .position(|window| window == b"\r\n\r\n")
+        .ok_or_eyre("Tracker response has no end of headers")?;
+    let status_line = response
+        .split(|byte| *byte == b'\n')
+        .next()
+        .unwrap_or_default();
+    let status_line = String::from_utf8_lossy(status_line);
+    if status_line.split(' ').nth(1) != Some("200") {
+        bail!("Tracker responded with {}", status_line.trim_end());
+    }
+    Ok(response.split_off(header_end + 4))
+}
+
+/// A [PeerSource] that announces to a UDP tracker, as described in BEP 15.
+#[derive(Debug, Clone)]
+pub struct UdpTracker {
+    tracker_addr: SocketAddr,
+    request: AnnounceRequest,
+    min_interval: MinInterval,
+}
+
+impl UdpTracker {
+    /// Announce `request` to the tracker at `tracker_addr`.
+    #[must_use]
+    pub fn new(tracker_addr: SocketAddr, request: AnnounceRequest) -> Self {
+        Self {
+            tracker_addr,
+            request,
+            min_interval: MinInterval::default(),
+        }
+    }
+
+    fn announce(&self, request: &AnnounceRequest) -> Result<AnnounceResponse> {
+        let unspecified = if self.tracker_addr.is_ipv4() {
+            SocketAddr::from((Ipv4Addr::UNSPECIFIED, 0))
+        } else {
+            SocketAddr::from((Ipv6Addr::UNSPECIFIED, 0))
+        };
+        let socket = UdpSocket::bind(unspecified)?;
+        socket.connect(self.tracker_addr)?;
+
+        // Connecting first proves to the tracker that we're not spoofing our address.
+        let transaction_id: u32 = rand::thread_rng().gen();
+        let mut connect = UDP_PROTOCOL_ID.to_be_bytes().to_vec();
+        connect.extend(UDP_ACTION_CONNECT.to_be_bytes());
+        connect.extend(transaction_id.to_be_bytes());
+        let connection_id = udp_exchange(&socket, &connect, UDP_ACTION_CONNECT, transaction_id)?;
+        let connection_id = connection_id
+            .first_chunk()
+            .map(|id| u64::from_be_bytes(*id))
+            .ok_or_eyre("UDP tracker connect response is too short")?;
+
+        let transaction_id = rand::thread_rng().gen();
+        let announce = request.udp_packet(connection_id, transaction_id);
+        let response = udp_exchange(&socket, &announce, UDP_ACTION_ANNOUNCE, transaction_id)?;
+        AnnounceResponse::from_udp(&response, self.tracker_addr.is_ipv6())
+    }
+}
+
+impl PeerSource for UdpTracker {
+    fn discover(&mut self) -> Result<DiscoveredPeers> {
+        let request = AnnounceRequest {
+            event: self.min_interval.regular_event(),
+            ..self.request.clone()
+        };
+        let response = self.announce(&request)?;
+        Ok(self.min_interval.announced(response))
+    }
+
+    fn discover_now(&mut self, event: AnnounceEvent) -> Result<Option<DiscoveredPeers>> {
+        if self.min_interval.too_soon(event) {
+            info!(
+                "Not announcing to {} within its min interval",
+                self.tracker_addr
+            );
+            return Ok(None);
+        }
+        let request = AnnounceRequest {
+            event,
+            ..self.request.clone()
+        };
+        let response = self.announce(&request)?;
+        Ok(Some(self.min_interval.announced(response)))
+    }
+
+    fn update_stats(&mut self, stats: &TorrentStats) {
+        self.request.update_stats(stats);
+    }
+}
+
+/// Send `packet` to a UDP tracker until it responds, returning what follows the response's
+/// action and transaction ID.
+fn udp_exchange(
+    socket: &UdpSocket,
+    packet: &[u8],
+    action: u32,
+    transaction_id: u32,
+) -> Result<Vec<u8>> {
+    let mut buffer = vec![0; 64 * 1024];
+    for _ in 0..UDP_ATTEMPTS {
+        socket.send(packet)?;
+        let Some((received_action, body)) =
+            udp_receive(socket, &mut buffer, transaction_id, TRACKER_TIMEOUT)?
+        else {
+            continue;
+        };
+        if received_action == UDP_ACTION_ERROR {
+            let reason = String::from_utf8_lossy(body).into_owned();
+            return Err(TrackerError::Failure(reason).into());
+        }
+        if received_action != action {
+            bail!("UDP tracker responded with action {received_action}, expected {action}");
+        }
+        return Ok(body.to_vec());
+    }
+    bail!("UDP tracker didn't respond after {UDP_ATTEMPTS} attempts")
+}
+
+/// Wait up to `timeout` for the response to `transaction_id`, returning its action and what
+/// follows it. Anything else that arrives, like a late response to an earlier attempt, is
+/// ignored.
+fn udp_receive<'a>(
+    socket: &UdpSocket,
+    buffer: &'a mut [u8],
+    transaction_id: u32,
+    timeout: Duration,
+) -> Result<Option<(u32, &'a [u8])>> {
+    let deadline = Instant::now() + timeout;
+    loop {
+        let remaining = deadline.saturating_duration_since(Instant::now());
+        if remaining.is_zero() {
+            return Ok(None);
+        }
+        socket.set_read_timeout(Some(remaining))?;
+        let length = match socket.recv(buffer) {
+            Ok(length) => length,
+            Err(e) if matches!(e.kind(), ErrorKind::WouldBlock | ErrorKind::TimedOut) => {
+                return Ok(None)
+            }
+            Err(e) => return Err(e.into()),
+        };
+        let matches = buffer[..length]
+            .get(4..8)
+            .is_some_and(|id| *id == transaction_id.to_be_bytes());
+        if matches {
+            let action = u32::from_be_bytes(buffer[..4].try_into().expect("header to be 8 bytes"));
+            return Ok(Some((action, &buffer[8..length])));
+        }
+    }
+}
+
+/// Decode compact IPv4 peers, each a 4 byte address followed by a 2 byte port.
+pub(crate) fn compact_peers_v4(bytes: &[u8]) -> Result<Vec<SocketAddr>> {
+    if !bytes.len().is_multiple_of(6) {
+        bail!("Length of compact peers is not a multiple of 6");
+    }
+    Ok(bytes
+        .chunks_exact(6)
+        .map(|peer| {
+            let ip: [u8; 4] = peer[..4].try_into().expect("chunks to be 6 bytes");
+            let port = u16::from_be_bytes([peer[4], peer[5]]);
+            SocketAddr::V4(SocketAddrV4::new(Ipv4Addr::from(ip), port))
+        })
+        .collect())
+}
+
+/// Decode compact IPv6 peers, each a 16 byte address followed by a 2 byte port.
+pub(crate) fn compact_peers_v6(bytes: &[u8]) -> Result<Vec<SocketAddr>> {
+    if !bytes.len().is_multiple_of(18) {
+        bail!("Length of compact IPv6 peers is not a multiple of 18");
+    }
+    Ok(bytes
+        .chunks_exact(18)
+        .map(|peer| {
+            let ip: [u8; 16] = peer[..16].try_into().expect("chunks to be 18 bytes");
+            let port = u16::from_be_bytes([peer[16], peer[17]]);
+            SocketAddr::V6(SocketAddrV6::new(Ipv6Addr::from(ip), port, 0, 0))
+        })
+        .collect())
+}
+
+#[cfg(test)]
+mod tests {
+    use std::net::TcpListener;
+
+    use super::*;
+
+    fn request() -> AnnounceRequest {
+        AnnounceRequest {
+            info_hash: InfoHash::new([0xab; 20]),
+            peer_id: PeerId::new(*b"-Rp0100-abcdefghijkl"),
+            port: 6881,
+            uploaded: 0,
+            downloaded: 0,
+            left: 100,
+            event: AnnounceEvent::None,
+        }
+    }
+
+    #[test]
+    fn stats_update_the_totals_but_keep_left_until_known() {
+        let mut request = request();
+        let stats = TorrentStats {
+            uploaded: 5,
+            downloaded: 7,
+            ..TorrentStats::default()
+        };
+
+        request.update_stats(&stats);
+        assert_eq!(
+            (request.uploaded, request.downloaded, request.left),
+            (5, 7, 100)
+        );
+        request.update_stats(&TorrentStats {
+            left: Some(0),
+            ..stats
+        });
+        assert_eq!(request.left, 0);
+    }
+
+    #[test]
+    fn announce_url_asks_for_compact_peers() {
+        let url = request().url("http://tracker.example/announce");
+
+        assert_eq!(
+            url,
+            format!(
+                "http://tracker.example/announce?info_hash={}\
+                &peer_id=-Rp0100-abcdefghijkl&port=6881&uploaded=0&downloaded=0&left=100&compact=1",
+                "%AB".repeat(20)
+            )
+        );
+        let started = AnnounceRequest {
+            event: AnnounceEvent::Started,
+            ..request()
+        };
+        assert!(started
+            .url("http://tracker.example/announce")
+            .ends_with("&compact=1&event=started"));
+    }
+
+    #[test]
+    fn decode_peers6() {
+        let mut bytes = Vec::new();
+        bytes.extend(Ipv6Addr::LOCALHOST.octets());
+        bytes.extend(6881u16.to_be_bytes());
+        bytes.extend("2001:db8::1".parse::<Ipv6Addr>().unwrap().octets());
+        bytes.extend(51413u16.to_be_bytes());
+
+        let peers = compact_peers_v6(&bytes).unwrap();
+
+        assert_eq!(
+            peers,
+            vec![
+                "[::1]:6881".parse::<SocketAddr>().unwrap(),
+                "[2001:db8::1]:51413".parse().unwrap(),
+            ]
+        );
+        assert!(compact_peers_v6(&bytes[..17]).is_err());
+    }
+
+    #[test]
+    fn response_with_both_peer_lists() {
+        let mut bytes = b"d8:intervali1800e5:peers6:".to_vec();
+        bytes.extend([10, 0, 0, 1, 0x1a, 0xe1]);
+        bytes.extend(b"6:peers618:");
+        bytes.extend(Ipv6Addr::LOCALHOST.octets());
+        bytes.extend([0x1a, 0xe2]);
+        bytes.push(b'e');
+
+        let response = AnnounceResponse::from_bytes(&bytes).unwrap();
+
+        assert_eq!(
+            response,
+            AnnounceResponse {
+                interval: Duration::from_secs(1800),
+                min_interval: None,
+                peers: vec![
+                    "10.0.0.1:6881".parse().unwrap(),
+                    "[::1]:6882".parse().unwrap(),
+                ],
+                warning: None,
+            }
+        );
+    }
+
+    #[test]
+    fn failure_reason_is_an_error() {
+        let error = AnnounceResponse::from_bytes(b"d14:failure reason7:go awaye").unwrap_err();
+
+        assert_eq!(error.to_string(), "Tracker refused announce: go away");
+        assert_eq!(
+            error.downcast_ref::<TrackerError>(),
+            Some(&TrackerError::Failure("go away".to_string()))
+        );
+    }
+
+    #[test]
+    fn warning_message_comes_with_the_peers() {
+        let bytes =
+            b"d8:intervali900e5:peers6:\x0a\x00\x00\x01\x1a\xe115:warning message9:slow downe";
+
+        let response = AnnounceResponse::from_bytes(bytes).unwrap();
+
+        assert_eq!(response.warning.as_deref(), Some("slow down"));
+        assert_eq!(response.peers, ["10.0.0.1:6881".parse().unwrap()]);
+    }
+
+    #[test]
+    fn http_tracker_announces_over_http() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let announce_url = format!("http://{}/announce", listener.local_addr().unwrap());
+        let tracker = std::thread::spawn(move || {
+            let (mut stream, _) = listener.accept().unwrap();
+            let mut request = Vec::new();
+            let mut buffer = [0; 1024];
+            while !request.ends_with(b"\r\n\r\n") {
+                let length = stream.read(&mut buffer).unwrap();
+                request.extend_from_slice(&buffer[..length]);
+            }
+            stream
+                .write_all(
+                    b"HTTP/1.0 200 OK\r\n\r\nd8:intervali900e5:peers6:\x0a\x00\x00\x01\x1a\xe1e",
+                )
+                .unwrap();
+            String::from_utf8_lossy(&request).into_owned()
+        });
+
+        let peers = HttpTracker::new(announce_url, request())
+            .discover()
+            .unwrap();
+
+        let request = tracker.join().unwrap();
+        assert!(
+            request.starts_with("GET /announce?info_hash=%AB"),
+            "{request}"
+        );
+        assert_eq!(
+            peers,
+            DiscoveredPeers {
+                peers: vec!["10.0.0.1:6881".parse().unwrap()],
+                next_discovery: Some(Duration::from_secs(900)),
+            }
+        );
+    }
+
+    #[test]
+    fn announce_interval_is_clamped() {
+        let response = |interval, min_interval: Option<u64>| AnnounceResponse {
+            interval: Duration::from_secs(interval),
+            min_interval: min_interval.map(Duration::from_secs),
+            peers: vec![],
+            warning: None,
+        };
+        let next_discovery =
+            |response: AnnounceResponse| DiscoveredPeers::from(response).next_discovery;
+
+        assert_eq!(
+            next_discovery(response(0, None)),
+            Some(MIN_ANNOUNCE_INTERVAL)
+        );
+        assert_eq!(
+            next_discovery(response(0, Some(300))),
+            Some(Duration::from_secs(300))
+        );
+        assert_eq!(
+            next_discovery(response(1800, Some(300))),
+            Some(Duration::from_secs(1800))
+        );
+    }
+
+    #[test]
+    fn http_tracker_response_is_capped() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let announce_url = format!("http://{}/announce", listener.local_addr().unwrap());
+        let tracker = std::thread::spawn(move || {
+            let (mut stream, _) = listener.accept().unwrap();
+            let _ = stream.write_all(b"HTTP/1.0 200 OK\r\n\r\n");
+            // Stops once the client hangs up.
+            let padding = vec![b' '; 64 * 1024];
+            while stream.write_all(&padding).is_ok() {}
+        });
+
+        let _ = HttpTracker::new(announce_url, request())
+            .discover()
+            .unwrap_err();
+        tracker.join().unwrap();
+    }
+
+    #[test]
+    fn udp_tracker_ignores_responses_to_other_transactions() {
+        let socket = UdpSocket::bind("127.0.0.1:0").unwrap();
+        let tracker_addr = socket.local_addr().unwrap();
+        let tracker = std::thread::spawn(move || {
+            let mut packet = [0; 1024];
+            let (_, from) = socket.recv_from(&mut packet).unwrap();
+            // Like a late response to an earlier attempt.
+            let mut stale = UDP_ACTION_CONNECT.to_be_bytes().to_vec();
+            stale
+                .extend((u32::from_be_bytes(packet[12..16].try_into().unwrap()) ^ 1).to_be_bytes());
+            stale.extend(7u64.to_be_bytes());
+            socket.send_to(&stale, from).unwrap();
+            let mut response = packet[8..16].to_vec();
+            response.extend(42u64.to_be_bytes());
+            socket.send_to(&response, from).unwrap();
+        });
+
+        let socket = UdpSocket::bind("127.0.0.1:0").unwrap();
+        socket.connect(tracker_addr).unwrap();
+        let mut connect = UDP_PROTOCOL_ID.to_be_bytes().to_vec();
+        connect.extend(UDP_ACTION_CONNECT.to_be_bytes());
+        connect.extend(1234u32.to_be_bytes());
+        let connection_id = udp_exchange(&socket, &connect, UDP_ACTION_CONNECT, 1234).unwrap();
+
+        tracker.join().unwrap();
+        assert_eq!(connection_id, 42u64.to_be_bytes());
+    }
+
+    #[test]
+    fn udp_tracker_connects_then_announces() {
+        let socket = UdpSocket::bind("127.0.0.1:0").unwrap();
+        let tracker_addr = socket.local_addr().unwrap();
+        let tracker = std::thread::spawn(move || {
+            let mut packet = [0; 1024];
+            let (length, from) = socket.recv_from(&mut packet).unwrap();
+            assert_eq!(length, 16);
+            assert_eq!(
+                packet[..12],
+                [0, 0, 4, 0x17, 0x27, 0x10, 0x19, 0x80, 0, 0, 0, 0]
+            );
+            let mut response = packet[8..16].to_vec();
+            response.extend(42u64.to_be_bytes());
+            socket.send_to(&response, from).unwrap();
+
+            let (length, from) = socket.recv_from(&mut packet).unwrap();
+            assert_eq!(length, 98);
+            assert_eq!(packet[..12], [0, 0, 0, 0, 0, 0, 0, 42, 0, 0, 0, 1]);
+            assert_eq!(packet[16..36], [0xab; 20]);
+            // The first announce says that we started.
+            assert_eq!(packet[80..84], 2u32.to_be_bytes());
+            assert_eq!(packet[96..98], 6881u16.to_be_bytes());
+            let mut response = packet[8..16].to_vec();
+            response.extend(1800u32.to_be_bytes());
+            response.extend([0, 0, 0, 1, 0, 0, 0, 2]);
+            response.extend([10, 0, 0, 1, 0x1a, 0xe1]);
+            socket.send_to(&response, from).unwrap();
+        });
+
+        let peers = UdpTracker::new(tracker_addr, request()).discover().unwrap();
+
+        tracker.join().unwrap();
+        assert_eq!(
+            peers,
+            DiscoveredPeers {
+                peers: vec!["10.0.0.1:6881".parse().unwrap()],
+                next_discovery: Some(Duration::from_secs(1800)),
+            }
+        );
+    }
+}
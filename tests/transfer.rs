@@ -0,0 +1,63 @@
+use std::sync::mpsc::{Receiver, RecvTimeoutError};
+use std::time::Duration;
+
+use torrent_poc::{loopback, Info, MockClock, PeerId, Torrent, TorrentConfig, TorrentEvent};
+
+/// Long enough for any rechoke that's due to have happened, as torrents tick every second.
+const EVENT_TIMEOUT: Duration = Duration::from_millis(1500);
+
+/// Wait for every piece to be completed, moving the clock along whenever nothing happens so
+/// that the seeder gets around to unchoking the leecher.
+fn await_completion(events: &Receiver<TorrentEvent>, clock: &MockClock, piece_count: usize) {
+    let mut completed = Vec::new();
+    let mut idle = 0;
+    while completed.len() < piece_count {
+        match events.recv_timeout(EVENT_TIMEOUT) {
+            Ok(TorrentEvent::PieceCompleted(index)) => completed.push(index),
+            Ok(_) => {}
+            Err(RecvTimeoutError::Timeout) if idle < 10 => {
+                idle += 1;
+                clock.advance(Duration::from_secs(10));
+            }
+            Err(e) => panic!("Completed only pieces {completed:?}: {e}"),
+        }
+    }
+    completed.sort_unstable();
+    assert_eq!(completed, (0..piece_count as u32).collect::<Vec<_>>());
+}
+
+#[test]
+fn leecher_downloads_a_verified_copy_from_a_seeder() {
+    // A few pieces of a few blocks each, with a shorter last piece.
+    let content: Vec<u8> = (0..100_000u32).map(|i| (i * 7 % 251) as u8).collect();
+    let info = Info::from_content("file.bin", 32 * 1024, &content);
+    let info_hash = info.info_hash();
+    let (seeder_id, leecher_id) = (PeerId::new([1; 20]), PeerId::new([2; 20]));
+    let clock = MockClock::new();
+    let config = TorrentConfig::default();
+
+    let seeder = Torrent::with_clock(seeder_id, info_hash, config, clock.clone()).unwrap();
+    seeder
+        .load_content(info.to_bytes(), content.clone())
+        .unwrap();
+    // The leecher only has the info hash, and gets the rest of the metadata from the seeder.
+    let leecher = Torrent::with_clock(leecher_id, info_hash, config, clock.clone()).unwrap();
+    let events = leecher.subscribe().unwrap();
+    let ((seeder_write, seeder_read), (leecher_write, leecher_read)) = loopback();
+    seeder
+        .accept_peer_connection(None, None, seeder_read, seeder_write)
+        .unwrap();
+    leecher
+        .connect_to_peer(Some(seeder_id), None, leecher_read, leecher_write)
+        .unwrap();
+
+    await_completion(&events, &clock, info.pieces.len());
+
+    let downloaded: Vec<u8> = (0..info.pieces.len() as u32)
+        .flat_map(|index| leecher.read_piece(index).unwrap())
+        .collect();
+    assert_eq!(downloaded, content);
+    assert_eq!(Info::from_content("file.bin", 32 * 1024, &downloaded), info);
+    leecher.shutdown().unwrap();
+    seeder.shutdown().unwrap();
+}